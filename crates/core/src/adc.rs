@@ -0,0 +1,99 @@
+//! ADC1 shared by the analog inputs: the supply divider and the analog
+//! sensors.
+//!
+//! Every input is set up on a [`Config`] first, then [`start`] brings the
+//! converter up with all of them. Readings are in millivolts at the pin,
+//! with the curve calibration from the eFuses, and averaged over a burst of
+//! conversions with the extremes left out, which takes the WiFi noise off
+//! the supply.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use esp_hal::Blocking;
+use esp_hal::analog::adc::{self, Adc, AdcCalCurve, AdcChannel, AdcPin, AnalogPin};
+use esp_hal::peripherals::ADC1;
+
+pub use esp_hal::analog::adc::Attenuation;
+
+/// Conversions per reading.
+const SAMPLES: usize = 16;
+/// Lowest and highest ones left out of the average.
+const TRIMMED: usize = 2;
+
+type Adc1 = Adc<'static, ADC1<'static>, Blocking>;
+
+static ADC: Mutex<CriticalSectionRawMutex, RefCell<Option<Adc1>>> = Mutex::new(RefCell::new(None));
+
+/// The inputs of ADC1, before [`start`].
+pub struct Config(adc::AdcConfig<ADC1<'static>>);
+
+impl Config {
+    pub fn new() -> Self {
+        Self(adc::AdcConfig::new())
+    }
+
+    /// `attenuation` sets the range: up to about 1 V at 0 dB, 2.5 V at 11 dB.
+    pub fn channel<PIN: AdcChannel + AnalogPin>(
+        &mut self,
+        pin: PIN,
+        attenuation: Attenuation,
+    ) -> Channel<PIN> {
+        Channel(
+            self.0
+                .enable_pin_with_cal::<_, AdcCalCurve<ADC1>>(pin, attenuation),
+        )
+    }
+}
+
+/// Brings up ADC1 with the inputs of `config`.
+pub fn start(adc1: ADC1<'static>, config: Config) {
+    let adc = Adc::new(adc1, config.0);
+    ADC.lock(|current| current.replace(Some(adc)));
+}
+
+/// A [`Channel`] without the type of its pin, for the inputs the settings
+/// choose.
+pub trait Input: Send {
+    fn millivolts(&mut self) -> Option<u16>;
+}
+
+/// An analog input on ADC1.
+pub struct Channel<PIN>(AdcPin<PIN, ADC1<'static>, AdcCalCurve<ADC1<'static>>>);
+
+impl<PIN: AdcChannel> Channel<PIN> {
+    /// Millivolts at the pin, none before [`start`] or when the conversions
+    /// fail.
+    ///
+    /// The converter is locked only for each poll of a conversion, the
+    /// interrupts don't wait out the whole burst.
+    pub fn millivolts(&mut self) -> Option<u16> {
+        let mut samples = [0u16; SAMPLES];
+
+        for sample in samples.iter_mut() {
+            *sample = loop {
+                let polled = ADC.lock(|adc| {
+                    let mut adc = adc.borrow_mut();
+                    adc.as_mut().map(|adc| adc.read_oneshot(&mut self.0))
+                });
+                match polled? {
+                    Ok(millivolts) => break millivolts,
+                    Err(nb::Error::WouldBlock) => {}
+                    Err(nb::Error::Other(())) => return None,
+                }
+            };
+        }
+
+        samples.sort_unstable();
+        let kept = &samples[TRIMMED..SAMPLES - TRIMMED];
+        let sum: u32 = kept.iter().map(|&sample| sample as u32).sum();
+
+        Some((sum / kept.len() as u32) as u16)
+    }
+}
+
+impl<PIN: AdcChannel + Send> Input for Channel<PIN> {
+    fn millivolts(&mut self) -> Option<u16> {
+        Channel::millivolts(self)
+    }
+}
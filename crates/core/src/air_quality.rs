@@ -1,4 +1,6 @@
-#[derive(defmt::Format)]
+use crate::sensors;
+
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
 pub enum AirQuality {
     Good,
     Moderate,
@@ -8,6 +10,19 @@ pub enum AirQuality {
     Hazardous,
 }
 
+impl AirQuality {
+    pub fn label(&self) -> &'static str {
+        match self {
+            AirQuality::Good => "Good",
+            AirQuality::Moderate => "Moderate",
+            AirQuality::UnhealthyForSensitiveGroups => "Sensitive",
+            AirQuality::Unhealthy => "Unhealthy",
+            AirQuality::VeryUnhealthy => "Very bad",
+            AirQuality::Hazardous => "Hazardous",
+        }
+    }
+}
+
 fn aiq_from_score(score: u32) -> AirQuality {
     match score {
         0..50 => AirQuality::Good,
@@ -19,15 +34,17 @@ fn aiq_from_score(score: u32) -> AirQuality {
     }
 }
 
-pub fn calculate(humidity: f32, gas: u32) -> (u32, AirQuality) {
+fn humidity_score(humidity: f32) -> u32 {
     const HUM_REF: f32 = 40.0;
 
-    let hum_score: u32 = match humidity {
+    match humidity {
         0.0..38.0 => 25 * (humidity / HUM_REF) as u32,
         38.0..=42.0 => 25,
         _ => 41 + 25 * (humidity / (100.0 - HUM_REF)) as u32,
-    };
+    }
+}
 
+pub fn calculate(humidity: f32, gas: u32) -> (u32, AirQuality) {
     const GAS_LOWER_LIMIT: u32 = 5000;
     const GAS_UPPER_LIMIT: u32 = 50000;
     const GAS_LIMITS_DIFF: u32 = GAS_UPPER_LIMIT - GAS_LOWER_LIMIT;
@@ -40,7 +57,27 @@ pub fn calculate(humidity: f32, gas: u32) -> (u32, AirQuality) {
 
     let gas_score = 75 * (gas_ref - GAS_LOWER_LIMIT) / GAS_LIMITS_DIFF;
 
-    let score = hum_score + gas_score;
+    let score = humidity_score(humidity) + gas_score;
 
     (score, aiq_from_score(score))
 }
+
+/// Like [`calculate`], with the VOC index of an SGP40 in place of the gas
+/// resistance. Up to the average of 100 adds nothing, the 500 at most adds
+/// what the worst gas resistance does.
+pub fn calculate_voc(humidity: f32, voc_index: u16) -> (u32, AirQuality) {
+    let voc_score = 75 * (voc_index.clamp(100, 500) as u32 - 100) / 400;
+    let score = humidity_score(humidity) + voc_score;
+
+    (score, aiq_from_score(score))
+}
+
+/// Score out of the SGP40 VOC index if there is one, else out of the BME680
+/// readings, when the gas heater produced a valid one.
+pub fn from_sample(sample: &sensors::Sample) -> Option<(u32, AirQuality)> {
+    if let (Some(humidity), Some(voc_index)) = (sample.humidity(), sample.voc_index) {
+        return Some(calculate_voc(humidity, voc_index));
+    }
+
+    Some(calculate(sample.hum_bme680?, sample.gas_bme680?))
+}
@@ -0,0 +1,289 @@
+//! Alerts on the readings, so a node tells when a room gets too warm or too
+//! stuffy without any rules on the server.
+//!
+//! A rule is `<metric> above|below <at> <clear at> [<minutes>]`, the rules of
+//! the settings are separated by semicolons. "co2 above 1200 1000 5" raises
+//! the alert once the CO2 has stayed at 1200 ppm or more for five minutes and
+//! clears it when it's back to 1000 ppm, "temperature below 5 7" raises it
+//! right away. Between the two levels the alert stays as it is.
+//!
+//! Each alert is published retained on `<topic>/alerts/<metric>` when it
+//! changes, the LED and the display show the active ones.
+
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use defmt::{info, warn};
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+
+use crate::led;
+use crate::sensors::Sample;
+
+pub const MAX_RULES: usize = 4;
+
+static ALERTS: Mutex<CriticalSectionRawMutex, RefCell<Vec<Alert, MAX_RULES>>> =
+    Mutex::new(RefCell::new(Vec::new()));
+/// A bit per alert that was raised or cleared since the last call of
+/// [`take_changed`].
+static CHANGED: AtomicU8 = AtomicU8::new(0);
+
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub enum Metric {
+    Temperature,
+    Humidity,
+    Light,
+    Pressure,
+    Noise,
+    Co2,
+    Pm2_5,
+    Voc,
+    Battery,
+}
+
+impl Metric {
+    const ALL: [Metric; 9] = [
+        Metric::Temperature,
+        Metric::Humidity,
+        Metric::Light,
+        Metric::Pressure,
+        Metric::Noise,
+        Metric::Co2,
+        Metric::Pm2_5,
+        Metric::Voc,
+        Metric::Battery,
+    ];
+
+    /// As the rules and the topics have it.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Metric::Temperature => "temperature",
+            Metric::Humidity => "humidity",
+            Metric::Light => "light",
+            Metric::Pressure => "pressure",
+            Metric::Noise => "noise",
+            Metric::Co2 => "co2",
+            Metric::Pm2_5 => "pm2_5",
+            Metric::Voc => "voc",
+            Metric::Battery => "battery",
+        }
+    }
+
+    fn read(&self, sample: &Sample) -> Option<f32> {
+        match self {
+            Metric::Temperature => sample.temperature(),
+            Metric::Humidity => sample.humidity(),
+            Metric::Light => sample.light(),
+            Metric::Pressure => sample.pressure(),
+            Metric::Noise => sample.noise_dba,
+            Metric::Co2 => sample.co2().map(f32::from),
+            Metric::Pm2_5 => sample.pm2_5.map(f32::from),
+            Metric::Voc => sample.voc_index.map(f32::from),
+            Metric::Battery => sample.battery_percent,
+        }
+    }
+}
+
+#[derive(Clone, Copy, defmt::Format)]
+struct Rule {
+    metric: Metric,
+    /// Raised at high readings, at low ones otherwise.
+    above: bool,
+    at: f32,
+    clear_at: f32,
+    /// How long the reading has to stay past `at`.
+    hold: Duration,
+}
+
+impl Rule {
+    fn parse(text: &str) -> Option<Self> {
+        let mut words = text.split_whitespace();
+
+        let name = words.next()?;
+        let metric = *Metric::ALL.iter().find(|metric| metric.name() == name)?;
+        let above = match words.next()? {
+            "above" => true,
+            "below" => false,
+            _ => return None,
+        };
+        let at: f32 = words.next()?.parse().ok()?;
+        let clear_at: f32 = words.next()?.parse().ok()?;
+        // Up to about six weeks, the seconds of any of them fit the ticks.
+        let minutes: u16 = match words.next() {
+            Some(minutes) => minutes.parse().ok()?,
+            None => 0,
+        };
+
+        // The clear level is on the safe side of the one raising the alert.
+        let ordered = if above {
+            clear_at <= at
+        } else {
+            clear_at >= at
+        };
+
+        (words.next().is_none() && ordered).then_some(Self {
+            metric,
+            above,
+            at,
+            clear_at,
+            hold: Duration::from_secs(u64::from(minutes) * 60),
+        })
+    }
+
+    fn is_past(&self, value: f32) -> bool {
+        if self.above {
+            value >= self.at
+        } else {
+            value <= self.at
+        }
+    }
+
+    fn is_clear(&self, value: f32) -> bool {
+        if self.above {
+            value <= self.clear_at
+        } else {
+            value >= self.clear_at
+        }
+    }
+}
+
+struct Alert {
+    rule: Rule,
+    active: bool,
+    /// When the reading went past the level, while the alert waits for the
+    /// hold time.
+    past_since: Option<Instant>,
+    value: Option<f32>,
+}
+
+impl Alert {
+    /// Follows `value`, true when the alert was raised or cleared. A missing
+    /// reading leaves it as it is.
+    fn update(&mut self, value: Option<f32>, now: Instant) -> bool {
+        let Some(value) = value else {
+            return false;
+        };
+        self.value = Some(value);
+
+        if self.active {
+            if self.rule.is_clear(value) {
+                self.active = false;
+                return true;
+            }
+        } else if self.rule.is_past(value) {
+            let since = *self.past_since.get_or_insert(now);
+            if now - since >= self.rule.hold {
+                self.active = true;
+                self.past_since = None;
+                return true;
+            }
+        } else {
+            self.past_since = None;
+        }
+
+        false
+    }
+}
+
+/// An alert as the MQTT task and the display show it. Published on the
+/// topic of its metric, so that one is left out of the payload.
+#[derive(Clone, Copy, defmt::Format, serde::Serialize)]
+pub struct State {
+    #[serde(skip)]
+    pub metric: Metric,
+    #[serde(skip)]
+    pub above: bool,
+    pub active: bool,
+    /// The last reading, none before the metric was read.
+    pub value: Option<f32>,
+    /// The level raising the alert.
+    pub threshold: f32,
+}
+
+/// Sets the alerts up from the `rules` of the settings, an empty one for
+/// none. A metric gets one rule, the later ones for it are left out since
+/// they would share the topic.
+pub fn configure(rules: &str) {
+    let mut alerts: Vec<Alert, MAX_RULES> = Vec::new();
+
+    for text in rules.split(';').filter(|text| !text.trim().is_empty()) {
+        let Some(rule) = Rule::parse(text) else {
+            warn!("Alerts: can't make sense of the rule \"{}\"", text);
+            continue;
+        };
+        if alerts.iter().any(|alert| alert.rule.metric == rule.metric) {
+            warn!("Alerts: {} already has a rule", rule.metric);
+            continue;
+        }
+
+        let alert = Alert {
+            rule,
+            active: false,
+            past_since: None,
+            value: None,
+        };
+        if alerts.push(alert).is_err() {
+            warn!("Alerts: more than {} rules", MAX_RULES);
+            break;
+        }
+        info!("Alerts: {}", rule);
+    }
+
+    ALERTS.lock(|current| current.replace(alerts));
+}
+
+/// Raises and clears the alerts by the readings of a new sample.
+pub fn check(sample: &Sample) {
+    let now = Instant::now();
+
+    let (changed, any_active) = ALERTS.lock(|alerts| {
+        let mut alerts = alerts.borrow_mut();
+        let mut changed = 0u8;
+        for (i, alert) in alerts.iter_mut().enumerate() {
+            if alert.update(alert.rule.metric.read(sample), now) {
+                info!(
+                    "Alerts: {} {}",
+                    alert.rule.metric,
+                    if alert.active { "raised" } else { "cleared" }
+                );
+                changed |= 1 << i;
+            }
+        }
+
+        (changed, alerts.iter().any(|alert| alert.active))
+    });
+
+    if changed != 0 {
+        CHANGED.fetch_or(changed, Ordering::Relaxed);
+        led::alert(any_active);
+    }
+}
+
+/// Every alert, in the order of the rules.
+pub fn states() -> Vec<State, MAX_RULES> {
+    ALERTS.lock(|alerts| {
+        alerts
+            .borrow()
+            .iter()
+            .map(|alert| State {
+                metric: alert.rule.metric,
+                above: alert.rule.above,
+                active: alert.active,
+                value: alert.value,
+                threshold: alert.rule.at,
+            })
+            .collect()
+    })
+}
+
+/// The first active alert, if there is one.
+pub fn active() -> Option<State> {
+    states().into_iter().find(|state| state.active)
+}
+
+/// A bit per alert, by its index in [`states`], that changed since the last
+/// call.
+pub fn take_changed() -> u8 {
+    CHANGED.swap(0, Ordering::Relaxed)
+}
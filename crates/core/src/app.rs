@@ -0,0 +1,454 @@
+//! Everything after the chip specific setup, the same for every board. The
+//! binaries bring up the heap, the scheduler, the radio and what only their
+//! board has, then hand over to [`start`].
+
+use core::net::Ipv4Addr;
+
+use defmt::{info, warn};
+use embassy_executor::Spawner;
+use embassy_net::{Runner, Stack, StackResources};
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_time::Timer;
+use esp_hal::peripherals::{SPI2, UART1};
+use esp_hal::tsens::TemperatureSensor;
+use esp_radio::wifi::{self, Interfaces, WifiController, WifiDevice};
+use static_cell::StaticCell;
+
+use crate::config::{self, MqttTransport, Settings, SettingsEnum};
+use crate::uart_bus::UartBus;
+use crate::wifi::print_wifi_error;
+use crate::{
+    alerts, availability, board, dhcp, event_log, events, i2c_debug, inputs, kv_storage, mqtt,
+    net_time, occupancy, ota, payload, power, presence, relay, sensors, syslog, system, web,
+};
+
+static RESOURCES: StaticCell<StackResources<16>> = StaticCell::new();
+
+/// What the binaries hand over.
+pub struct Node {
+    pub spawner: Spawner,
+    pub db: &'static kv_storage::Db,
+    pub wifi_controller: WifiController<'static>,
+    pub interfaces: Interfaces<'static>,
+    pub i2c: &'static sensors::I2cBus,
+    /// The second sensor bus, on the boards that have one.
+    pub i2c1: Option<&'static sensors::I2cBus>,
+    pub chip_sensor: Option<TemperatureSensor<'static>>,
+    pub supply: &'static mut dyn power::SupplyVoltage,
+    /// For the SD card or the LoRa radio, whichever the node uses.
+    pub spi2: SPI2<'static>,
+    /// For the UART sensors.
+    pub uart1: UART1<'static>,
+    /// `CARGO_PKG_VERSION` of the binary.
+    pub firmware_version: &'static str,
+}
+
+/// Runs the node with `settings`, or the setup access point when they are
+/// incomplete, a reconfiguration was asked for or there is a `safe_mode`
+/// fault to show.
+pub async fn start(node: Node, settings: SettingsEnum, safe_mode: Option<system::Fault>) -> ! {
+    node.spawner.must_spawn(event_log::task(node.db));
+
+    // Sensors, BLE and MQTT stay off, the settings can still be fixed.
+    if let Some(fault) = safe_mode {
+        system::report_fault(fault);
+        init_start(node, settings).await
+    }
+
+    match settings {
+        SettingsEnum::FilledIn(settings) => {
+            info!("###    WiFi SSID:        {}", settings.wifi_ssid);
+            info!("###    MQTT broker:      {}", settings.mqtt_broker);
+            info!("###    MQTT client id:   {}", settings.mqtt_client_id);
+            info!("###    MQTT topic:       {}", settings.mqtt_topic);
+            info!(
+                "###    Reconfigure:      {:?}",
+                settings.reboot_to_reconfigure
+            );
+
+            if settings.reboot_to_reconfigure {
+                init_start(node, SettingsEnum::FilledIn(settings)).await
+            } else {
+                run(node, settings).await
+            }
+        }
+        settings @ SettingsEnum::Optional(_) => init_start(node, settings).await,
+    }
+}
+
+#[embassy_executor::task]
+async fn net_task(mut runner: Runner<'static, WifiDevice<'static>>) -> ! {
+    runner.run().await;
+}
+
+async fn run(node: Node, settings: Settings) -> ! {
+    let Node {
+        spawner,
+        db,
+        wifi_controller,
+        interfaces,
+        i2c,
+        i2c1,
+        chip_sensor,
+        supply,
+        spi2,
+        uart1,
+        firmware_version,
+    } = node;
+
+    let settings = {
+        static SETTINGS_STATIC: StaticCell<Settings> = StaticCell::new();
+        SETTINGS_STATIC.init(settings)
+    };
+
+    if power::set_profile(settings.power_profile) {
+        info!("Restarting for the CPU clock of the power profile");
+        esp_hal::system::software_reset();
+    }
+    power::set_supply_limits(settings.supply_low_mv, settings.gas_heater);
+    sensors::set_warm_up(settings.sensor_warm_up.as_str());
+    i2c_debug::set_access(settings.i2c_debug);
+    sensors::calibration::set(settings.calibration.as_str());
+    sensors::filter::set(settings.sensor_filter.as_str());
+    sensors::analog::set_maps([settings.adc0_map.as_str(), settings.adc1_map.as_str()]);
+
+    if settings.deep_sleep_minutes() > 0 {
+        power::enable_sleep();
+    }
+
+    let pins = board::pins();
+    let buses = sensors::Buses { i2c, i2c1 };
+
+    if settings.mqtt_transport == MqttTransport::Lora {
+        #[cfg(feature = "lora")]
+        if pins.lora() {
+            spawner.must_spawn(crate::lora::task(
+                spi2,
+                pins,
+                settings.mqtt_client_id.as_str(),
+                settings.lora_frequency_khz,
+            ));
+
+            sense(spawner, db, settings, buses, chip_sensor, supply, uart1).await
+        }
+
+        warn!("LoRa: no radio on this node, going on with MQTT over TCP");
+    }
+
+    spawner.must_spawn(crate::wifi::task(
+        wifi_controller,
+        settings.wifi_ssid.as_str(),
+        settings.wifi_password.as_str(),
+    ));
+
+    let net_config = embassy_net::Config::dhcpv4(Default::default());
+
+    let (stack, runner) = embassy_net::new(
+        interfaces.sta,
+        net_config,
+        RESOURCES.init(StackResources::new()),
+        embassy_time::Instant::now().as_millis(),
+    );
+
+    spawner.must_spawn(net_task(runner));
+
+    system::transition(&[system::State::Booting], system::State::WifiConnecting);
+    info!("Waiting for link...");
+    stack.wait_link_up().await;
+    info!("  Link is up!");
+
+    info!("Waiting for DHCP...");
+    crate::wifi::wait_config_up(stack).await;
+    info!("  IPv4 config: {:?}", stack.config_v4());
+    system::transition(&[system::State::Dhcp], system::State::MqttConnecting);
+
+    start_web_server(
+        spawner,
+        db,
+        stack,
+        SettingsEnum::FilledIn(settings.clone()),
+        false,
+    );
+
+    net_time::set_servers(settings.ntp_servers.as_str());
+    spawner.must_spawn(net_time::sync_task(stack));
+
+    if !settings.syslog_host.is_empty() {
+        spawner.must_spawn(syslog::task(
+            stack,
+            settings.syslog_host.as_str(),
+            settings.mqtt_client_id.as_str(),
+        ));
+    }
+
+    spawner.must_spawn(ota::confirm_task());
+
+    if !settings.ota_url.is_empty() {
+        spawner.must_spawn(ota::task(
+            stack,
+            settings.ota_url.as_str(),
+            firmware_version,
+            settings.ota_check_hours,
+        ));
+    }
+
+    let broker_address = match Ipv4Addr::parse_ascii(settings.mqtt_broker.as_bytes()) {
+        Err(err) => {
+            warn!("Error parsing broker IP: {}", err);
+            config::set_reboot(db).await.unwrap();
+            unreachable!();
+        }
+        Ok(address) => address,
+    };
+
+    spawner.must_spawn(availability::task(db));
+
+    payload::select_fields(settings.mqtt_fields.as_str());
+    payload::set_place(
+        settings.location.as_str(),
+        settings.room.as_str(),
+        settings.labels.as_str(),
+    );
+    alerts::configure(settings.alert_rules.as_str());
+    mqtt::set_keep_alive(settings.mqtt_keep_alive_secs);
+    if settings.mqtt_transport == MqttTransport::Tcp {
+        presence::configure(settings.presence_beacons.as_str());
+        spawner.must_spawn(events::task());
+        if board::pins().motion().is_some() {
+            occupancy::set_hold(settings.motion_hold_secs);
+            spawner.must_spawn(occupancy::task());
+        }
+    }
+    spawner.must_spawn(mqtt::task(
+        db,
+        stack,
+        broker_address,
+        settings.mqtt_client_id.as_str(),
+        settings.mqtt_topic.as_str(),
+        firmware_version,
+        settings.mqtt_transport,
+        settings.payload_format,
+    ));
+
+    #[cfg(feature = "sd-log")]
+    if pins.sd_card() {
+        spawner.must_spawn(crate::sd_log::task(spi2, pins, settings.sd_format));
+    }
+
+    sense(spawner, db, settings, buses, chip_sensor, supply, uart1).await
+}
+
+/// The relays, the sensors and the sleep between the samples, whichever way
+/// the samples leave the node.
+async fn sense(
+    spawner: Spawner,
+    db: &'static kv_storage::Db,
+    settings: &'static Settings,
+    buses: sensors::Buses,
+    chip_sensor: Option<TemperatureSensor<'static>>,
+    supply: &'static mut dyn power::SupplyVoltage,
+    uart1: UART1<'static>,
+) -> ! {
+    let pins = board::pins();
+
+    let relays = pins.relays();
+    if relays.iter().any(Option::is_some) {
+        spawner.must_spawn(relay::task(
+            db,
+            relays,
+            [
+                settings.relay_rule_1.as_str(),
+                settings.relay_rule_2.as_str(),
+            ],
+        ));
+    }
+
+    if let Some(pin) = pins.door() {
+        inputs::watch(inputs::DOOR, "door");
+        spawner.must_spawn(inputs::task(inputs::DOOR, pin, inputs::Config::DOOR));
+    }
+    let generic = [settings.input_1.as_str(), settings.input_2.as_str()];
+    for (i, (pin, text)) in pins.inputs().into_iter().zip(generic).enumerate() {
+        let Some(pin) = pin else {
+            continue;
+        };
+
+        let id = inputs::GENERIC[i];
+        let (config, name) = if text.trim().is_empty() {
+            (inputs::Config::GENERIC, ["input1", "input2"][i])
+        } else if let Some(parsed) = inputs::Config::parse(text) {
+            parsed
+        } else {
+            warn!("Input {}: can't make sense of \"{}\"", id, text);
+            continue;
+        };
+        inputs::watch(id, name);
+        spawner.must_spawn(inputs::task(id, pin, config));
+    }
+    if let Some(pin) = pins.motion() {
+        spawner.must_spawn(inputs::task(inputs::MOTION, pin, inputs::Config::MOTION));
+    }
+    if let Some(pin) = pins.wind() {
+        spawner.must_spawn(inputs::task(inputs::WIND, pin, inputs::Config::WIND));
+    }
+    if let Some(pin) = pins.rain() {
+        spawner.must_spawn(inputs::task(inputs::RAIN, pin, inputs::Config::RAIN));
+    }
+    sensors::weather::set(
+        pins.wind().map(|_| settings.wind_pulses.as_str()),
+        pins.rain().map(|_| settings.rain_pulses.as_str()),
+    );
+
+    sensors::co2::set_mode(settings.co2_calibration);
+    if pins.pms5003() || pins.mhz19() {
+        let bus = UartBus::new(uart1);
+        if pins.pms5003() {
+            spawner.must_spawn(sensors::pms5003::task(bus, pins));
+        }
+        if pins.mhz19() {
+            spawner.must_spawn(sensors::mhz19::task(bus, pins));
+        }
+    }
+
+    spawner.must_spawn(sensors::co2::task(db));
+
+    i2c_debug::set_bus(buses.i2c);
+    spawner.must_spawn(sensors::task(
+        buses,
+        chip_sensor,
+        power::supply_monitored().then_some(supply),
+    ));
+
+    if power::sleep_enabled() {
+        power::sleep_after_publish(embassy_time::Duration::from_secs(
+            settings.deep_sleep_minutes() as u64 * 60,
+        ))
+        .await
+    }
+
+    loop {
+        let forever = embassy_sync::signal::Signal::<NoopRawMutex, ()>::new();
+        forever.wait().await;
+    }
+}
+
+/// Without the storage there are no settings to run with, so just tell about
+/// it on the display.
+pub async fn storage_fault(spawner: Spawner, i2c: &'static sensors::I2cBus) -> ! {
+    #[cfg(feature = "display")]
+    spawner.must_spawn(crate::display::task(i2c, crate::display::Config::default()));
+    #[cfg(not(feature = "display"))]
+    let _ = (spawner, i2c);
+    system::report_fault(system::Fault::Storage);
+
+    loop {
+        let forever = embassy_sync::signal::Signal::<NoopRawMutex, ()>::new();
+        forever.wait().await;
+    }
+}
+
+async fn init_start(node: Node, settings: SettingsEnum) -> ! {
+    let Node {
+        spawner,
+        db,
+        mut wifi_controller,
+        interfaces,
+        ..
+    } = node;
+
+    system::set_state(system::State::Setup);
+
+    let net_config = embassy_net::Config::ipv4_static(embassy_net::StaticConfigV4 {
+        address: embassy_net::Ipv4Cidr::new(crate::wifi::SETUP_ADDRESS, 24),
+        dns_servers: heapless_08::Vec::new(),
+        gateway: None,
+    });
+
+    let setup_ap = crate::wifi::SetupAp::from_settings(&settings);
+    info!(
+        "Setup access point {} on channel {}, {}",
+        setup_ap.ssid,
+        setup_ap.channel,
+        if setup_ap.password.is_empty() {
+            "open"
+        } else {
+            "WPA2"
+        }
+    );
+
+    let _ = wifi_controller.set_config(&wifi::ModeConfig::AccessPoint(setup_ap.config()));
+    crate::wifi::count_setup_clients();
+
+    let (stack, runner) = embassy_net::new(
+        interfaces.ap,
+        net_config,
+        RESOURCES.init(StackResources::new()),
+        embassy_time::Instant::now().as_millis(),
+    );
+
+    spawner.must_spawn(net_task(runner));
+
+    loop {
+        info!("Starting WIFI");
+        if let Err(err) = wifi_controller.start_async().await {
+            print_wifi_error(err);
+            Timer::after_secs(5).await;
+        } else {
+            break;
+        }
+    }
+
+    spawner.must_spawn(dhcp_task(stack));
+
+    info!("Waiting for link...");
+    stack.wait_link_up().await;
+    info!("  Link is up!");
+
+    info!("Waiting for DHCP...");
+    stack.wait_config_up().await;
+    info!("  IPv4 config: {:?}", stack.config_v4());
+
+    start_web_server(spawner, db, stack, settings, true);
+
+    loop {
+        let forever = embassy_sync::signal::Signal::<NoopRawMutex, ()>::new();
+        forever.wait().await;
+    }
+}
+
+/// The pages and the API on every connection the settings ask for, plus the
+/// restart the form asks for. The settings form and the other writes answer
+/// only on the `setup` access point, which has a password of its own.
+fn start_web_server(
+    spawner: Spawner,
+    db: &'static kv_storage::Db,
+    stack: Stack<'static>,
+    settings: SettingsEnum,
+    setup: bool,
+) {
+    spawner.must_spawn(system::reboot_on_request());
+
+    info!("Starting up web-server");
+    let web_app = {
+        static WEB_APP_STATIC: StaticCell<web::WebApp> = StaticCell::new();
+        WEB_APP_STATIC.init(web::WebApp::new(db, settings, setup))
+    };
+
+    for task_id in 0..web_app.capacity.tasks as usize {
+        spawner.must_spawn(web::task(
+            task_id,
+            stack,
+            web_app.router,
+            web_app.config,
+            web_app.capacity,
+        ));
+    }
+}
+
+#[embassy_executor::task]
+async fn dhcp_task(stack: Stack<'static>) -> ! {
+    let buffers = edge_nal_embassy::UdpBuffers::<2, 1024, 1024, 8>::new();
+    let unbound_socket = edge_nal_embassy::Udp::new(stack, &buffers);
+
+    dhcp::run(unbound_socket).await
+}
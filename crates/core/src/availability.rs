@@ -0,0 +1,125 @@
+//! How much of the time the node was up, and how much of that it had the
+//! WiFi and MQTT connected. Kept across restarts in the key-value storage,
+//! to tell a flaky node from a good one over days, not just since the boot.
+
+use core::cell::Cell;
+use core::sync::atomic::Ordering;
+
+use defmt::warn;
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_time::{Duration, Ticker};
+use serde::Serialize;
+
+use crate::{kv_storage, mqtt, wifi};
+
+const TICK: Duration = Duration::from_secs(10);
+/// Ticks between the writes to the flash. Up to that much is lost on a reset.
+const SAVE_TICKS: u32 = 60;
+
+// Written in one transaction, so they have to stay sorted.
+static MQTT_KEY: &str = "stats.mqtt";
+static UPTIME_KEY: &str = "stats.up";
+static WIFI_KEY: &str = "stats.wifi";
+
+static TOTALS: Mutex<CriticalSectionRawMutex, Cell<Totals>> = Mutex::new(Cell::new(Totals {
+    uptime_secs: 0,
+    wifi_secs: 0,
+    mqtt_secs: 0,
+}));
+
+/// Seconds in total, over all the runs.
+#[derive(Clone, Copy, Serialize, defmt::Format)]
+pub struct Totals {
+    pub uptime_secs: u32,
+    pub wifi_secs: u32,
+    pub mqtt_secs: u32,
+}
+
+impl Totals {
+    /// Share of the uptime with the WiFi connected, in percent.
+    pub fn wifi_percent(&self) -> f32 {
+        percent(self.wifi_secs, self.uptime_secs)
+    }
+
+    /// Share of the uptime with MQTT connected, in percent.
+    pub fn mqtt_percent(&self) -> f32 {
+        percent(self.mqtt_secs, self.uptime_secs)
+    }
+}
+
+fn percent(part: u32, total: u32) -> f32 {
+    if total == 0 {
+        return 0.0;
+    }
+
+    part as f32 * 100.0 / total as f32
+}
+
+pub fn totals() -> Totals {
+    TOTALS.lock(|totals| totals.get())
+}
+
+#[embassy_executor::task]
+pub async fn task(db: &'static kv_storage::Db) -> ! {
+    match load(db).await {
+        Ok(totals) => TOTALS.lock(|cell| cell.set(totals)),
+        Err(err) => warn!("Availability: could not load the totals: {:?}", err),
+    }
+
+    let mut ticker = Ticker::every(TICK);
+    let mut ticks = 0u32;
+
+    loop {
+        ticker.next().await;
+
+        let secs = TICK.as_secs() as u32;
+        let wifi = wifi::CONNECTED.load(Ordering::Relaxed);
+        let mqtt = mqtt::CONNECTED.load(Ordering::Relaxed);
+
+        let totals = TOTALS.lock(|cell| {
+            let mut totals = cell.get();
+            totals.uptime_secs = totals.uptime_secs.saturating_add(secs);
+            if wifi {
+                totals.wifi_secs = totals.wifi_secs.saturating_add(secs);
+            }
+            if mqtt {
+                totals.mqtt_secs = totals.mqtt_secs.saturating_add(secs);
+            }
+            cell.set(totals);
+            totals
+        });
+
+        ticks += 1;
+        if ticks % SAVE_TICKS == 0 {
+            if let Err(err) = save(db, &totals).await {
+                warn!("Availability: could not save the totals: {:?}", err);
+            }
+        }
+    }
+}
+
+async fn load(db: &'static kv_storage::Db) -> kv_storage::DbResult<Totals> {
+    let mut tx = db.read_transaction().await;
+
+    Ok(Totals {
+        uptime_secs: kv_storage::read_u32(&mut tx, UPTIME_KEY)
+            .await?
+            .unwrap_or_default(),
+        wifi_secs: kv_storage::read_u32(&mut tx, WIFI_KEY)
+            .await?
+            .unwrap_or_default(),
+        mqtt_secs: kv_storage::read_u32(&mut tx, MQTT_KEY)
+            .await?
+            .unwrap_or_default(),
+    })
+}
+
+async fn save(db: &'static kv_storage::Db, totals: &Totals) -> kv_storage::DbResult<()> {
+    let mut tx = db.write_transaction().await;
+    kv_storage::write_u32(&mut tx, MQTT_KEY, totals.mqtt_secs).await?;
+    kv_storage::write_u32(&mut tx, UPTIME_KEY, totals.uptime_secs).await?;
+    kv_storage::write_u32(&mut tx, WIFI_KEY, totals.wifi_secs).await?;
+    tx.commit().await?;
+
+    Ok(())
+}
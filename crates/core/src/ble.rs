@@ -4,10 +4,13 @@ use defmt::{Debug2Format, error, info, warn};
 use embassy_futures::select::select;
 use embassy_time::Timer;
 use esp_radio::ble::controller::BleConnector;
+
+use crate::{led, presence, sensors};
 use trouble_host::{
     Address, Host, HostResources,
     gap::{GapConfig, PeripheralConfig},
     prelude::*,
+    scan::{ScanConfig, Scanner},
 };
 
 const CONNECTIONS_MAX: usize = 1;
@@ -17,13 +20,20 @@ const L2CAP_CHANNELS_MAX: usize = 2;
 struct Server {
     // device_info: DeviceInformation,
     battery_service: BatteryService,
+    environment: EnvironmentalSensing,
+    node: Node,
 }
 
 // #[gatt_service(uuid = "7d4ad3b7-0ca8-41c3-8e19-dd5cbe2f780c")]
 // struct Settings {
 // }
 
-// b7a709de-2d41-4e84-a898-70551e33cb71
+#[gatt_service(uuid = "b7a709de-2d41-4e84-a898-70551e33cb71")]
+struct Node {
+    /// Writing the number of seconds strobes the LED to find the node.
+    #[characteristic(uuid = "b7a709de-2d41-4e84-a898-70551e33cb72", write)]
+    identify: u8,
+}
 
 #[gatt_service(uuid = service::DEVICE_INFORMATION)]
 struct DeviceInformation {
@@ -40,6 +50,17 @@ struct DeviceInformation {
     serial_number: HeaplessString<16>,
 }
 
+/// Latest sensor readings, in the units of the GATT specification.
+#[gatt_service(uuid = service::ENVIRONMENTAL_SENSING)]
+struct EnvironmentalSensing {
+    /// 0.01 degC
+    #[characteristic(uuid = characteristic::TEMPERATURE, read, notify)]
+    temperature: i16,
+    /// 0.01 %
+    #[characteristic(uuid = characteristic::HUMIDITY, read, notify)]
+    humidity: u16,
+}
+
 /// Battery service
 #[gatt_service(uuid = service::BATTERY)]
 struct BatteryService {
@@ -64,6 +85,7 @@ pub async fn task(controller: ExternalController<BleConnector<'static>, 20>) ->
     let stack = trouble_host::new(controller, &mut resources).set_random_address(addr);
 
     let Host {
+        central,
         mut peripheral,
         runner,
         ..
@@ -78,7 +100,7 @@ pub async fn task(controller: ExternalController<BleConnector<'static>, 20>) ->
 
     let server = Server::new_with_config(config).unwrap();
 
-    let _ = embassy_futures::join::join(ble_task(runner), async {
+    let _ = embassy_futures::join::join3(ble_task(runner), scan(central), async {
         loop {
             match advertise("ESP32 text instance", &mut peripheral, &server).await {
                 Ok(conn) => {
@@ -116,13 +138,46 @@ pub async fn task(controller: ExternalController<BleConnector<'static>, 20>) ->
 /// ```
 async fn ble_task<C: Controller, P: PacketPool>(mut runner: Runner<'_, C, P>) -> ! {
     loop {
-        if let Err(err) = runner.run().await {
+        if let Err(err) = runner.run_with_handler(&Beacons).await {
             error!("BLE: runner error: {}", Debug2Format(&err));
             Timer::after_secs(2).await;
         }
     }
 }
 
+/// Passes the advertisements the scanner picks up on to [`presence`].
+struct Beacons;
+
+impl EventHandler for Beacons {
+    fn on_adv_reports(&self, mut reports: LeAdvReportsIter<'_>) {
+        while let Some(Ok(report)) = reports.next() {
+            presence::heard(report.addr.raw(), report.rssi);
+        }
+    }
+}
+
+/// Scans for the beacons of [`presence`] once there are some, passively and
+/// a tenth of the time, so the WiFi keeps most of the radio.
+async fn scan<C: Controller>(central: Central<'_, C, DefaultPacketPool>) {
+    presence::wait_configured().await;
+
+    let mut scanner = Scanner::new(central);
+    let config = ScanConfig {
+        active: false,
+        interval: embassy_time::Duration::from_millis(300),
+        window: embassy_time::Duration::from_millis(30),
+        ..Default::default()
+    };
+
+    match scanner.scan(&config).await {
+        Ok(_session) => {
+            info!("BLE: scanning for beacons");
+            core::future::pending::<()>().await;
+        }
+        Err(err) => warn!("BLE: can't scan: {:?}", Debug2Format(&err)),
+    }
+}
+
 /// Create an advertiser to use to connect to a BLE Central, and wait for it to connect.
 async fn advertise<'values, 'server, C: Controller>(
     name: &'values str,
@@ -165,6 +220,7 @@ async fn gatt_events_task<P: PacketPool>(
     conn: &GattConnection<'_, '_, P>,
 ) -> Result<(), Error> {
     let level = &server.battery_service.level;
+    let identify = &server.node.identify;
     
     let reason = loop {
         match conn.next().await {
@@ -182,6 +238,15 @@ async fn gatt_events_task<P: PacketPool>(
                         }
                     }
                     GattEvent::Write(event) => {
+                        if event.handle() == identify.handle {
+                            let secs = event.data().first().copied().unwrap_or_default();
+                            led::identify(if secs == 0 {
+                                led::IDENTIFY_DURATION
+                            } else {
+                                embassy_time::Duration::from_secs(secs as u64)
+                            });
+                        }
+
                         if event.handle() == level.handle {
                             info!(
                                 "[ GATT ] Write Event to Level Characteristic: {:?}",
@@ -208,25 +273,40 @@ async fn gatt_events_task<P: PacketPool>(
     Ok(())
 }
 
-/// Example task to use the BLE notifier interface.
-/// This task will notify the connected central of a counter value every 2 seconds.
-/// It will also read the RSSI value every 2 seconds.
-/// and will stop when the connection is closed by the central or an error occurs.
+/// Notifies the connected central of the battery level and the readings of
+/// every new sample, and reads the RSSI of the connection every 2 seconds.
+/// Stops when the connection is closed by the central or an error occurs.
 async fn custom_task<C: Controller, P: PacketPool>(
     server: &Server<'_>,
     conn: &GattConnection<'_, '_, P>,
     stack: &Stack<'_, C, P>,
 ) {
-    let mut tick: u8 = 0;
     let level = server.battery_service.level;
+    let temperature = server.environment.temperature;
+    let humidity = server.environment.humidity;
+    let mut samples = sensors::LATEST.anon_receiver();
+
+    // Reads get the level of the latest sample until the next one comes.
+    if let Some(percent) = samples.try_get().and_then(|sample| sample.battery_level()) {
+        level.set(server, &percent).ok();
+    }
 
     loop {
-        tick = tick.wrapping_add(1);
-        info!("[custom_task] notifying connection of tick {}", tick);
+        if let Some(sample) = samples.try_changed() {
+            if let Some(percent) = sample.battery_level() {
+                if level.notify(conn, &percent).await.is_err() {
+                    info!("[custom_task] error notifying connection");
+                    break;
+                }
+            }
 
-        if level.notify(conn, &tick).await.is_err() {
-            info!("[custom_task] error notifying connection");
-            break;
+            if let Some(value) = sample.temperature() {
+                temperature.notify(conn, &((value * 100.0) as i16)).await.ok();
+            }
+
+            if let Some(value) = sample.humidity() {
+                humidity.notify(conn, &((value * 100.0) as u16)).await.ok();
+            }
         }
 
         // read RSSI (Received Signal Strength Indicator) of the connection.
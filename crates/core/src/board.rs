@@ -0,0 +1,572 @@
+//! The GPIOs a board uses. Each chip comes with the pins of the board the
+//! firmware was first built for, and any of them can be moved in the storage
+//! for a board that is wired differently.
+
+use core::cell::Cell;
+
+use defmt::{info, warn};
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use esp_hal::gpio::AnyPin;
+use esp_hal::i2c;
+use esp_hal::peripherals::I2C0;
+#[cfg(feature = "esp32s3")]
+use esp_hal::peripherals::I2C1;
+use serde::{Deserialize, Serialize};
+use static_cell::StaticCell;
+
+use crate::kv_storage::{self, Db, DbResult};
+use crate::sensors;
+
+static I2C_SDA_KEY: &'static str = "board.i2c_sda";
+static I2C_SCL_KEY: &'static str = "board.i2c_scl";
+static I2C1_SDA_KEY: &'static str = "board.i2c1_sda";
+static I2C1_SCL_KEY: &'static str = "board.i2c1_scl";
+static LED_KEY: &'static str = "board.led";
+static BUTTON_KEY: &'static str = "board.button";
+static SD_SCK_KEY: &'static str = "board.sd_sck";
+static SD_MOSI_KEY: &'static str = "board.sd_mosi";
+static SD_MISO_KEY: &'static str = "board.sd_miso";
+static SD_CS_KEY: &'static str = "board.sd_cs";
+static MIC_SCK_KEY: &'static str = "board.mic_sck";
+static MIC_WS_KEY: &'static str = "board.mic_ws";
+static MIC_SD_KEY: &'static str = "board.mic_sd";
+static RELAY_1_KEY: &'static str = "board.relay_1";
+static RELAY_2_KEY: &'static str = "board.relay_2";
+static LORA_NSS_KEY: &'static str = "board.lora_nss";
+static LORA_RESET_KEY: &'static str = "board.lora_reset";
+static LORA_BUSY_KEY: &'static str = "board.lora_busy";
+static LORA_DIO1_KEY: &'static str = "board.lora_dio1";
+static EXT_WDT_KEY: &'static str = "board.ext_wdt";
+static PMS_TX_KEY: &'static str = "board.pms_tx";
+static PMS_RX_KEY: &'static str = "board.pms_rx";
+static PMS_SET_KEY: &'static str = "board.pms_set";
+static MHZ_TX_KEY: &'static str = "board.mhz_tx";
+static MHZ_RX_KEY: &'static str = "board.mhz_rx";
+static ADC0_KEY: &'static str = "board.adc0";
+static ADC1_KEY: &'static str = "board.adc1";
+static DOOR_KEY: &'static str = "board.door";
+static MOTION_KEY: &'static str = "board.motion";
+static INPUT1_KEY: &'static str = "board.input1";
+static INPUT2_KEY: &'static str = "board.input2";
+static WIND_KEY: &'static str = "board.wind";
+static RAIN_KEY: &'static str = "board.rain";
+
+/// Stands for a pin the board doesn't have.
+pub const NO_PIN: u8 = 0xFF;
+
+#[cfg(feature = "esp32c6")]
+pub const DEFAULT_PINS: Pins = Pins {
+    i2c_sda: 0,
+    i2c_scl: 1,
+    i2c1_sda: NO_PIN,
+    i2c1_scl: NO_PIN,
+    led: 8,
+    button: 9,
+    sd_sck: NO_PIN,
+    sd_mosi: NO_PIN,
+    sd_miso: NO_PIN,
+    sd_cs: NO_PIN,
+    mic_sck: NO_PIN,
+    mic_ws: NO_PIN,
+    mic_sd: NO_PIN,
+    relay_1: NO_PIN,
+    relay_2: NO_PIN,
+    lora_nss: NO_PIN,
+    lora_reset: NO_PIN,
+    lora_busy: NO_PIN,
+    lora_dio1: NO_PIN,
+    ext_wdt: NO_PIN,
+    pms_tx: NO_PIN,
+    pms_rx: NO_PIN,
+    pms_set: NO_PIN,
+    mhz_tx: NO_PIN,
+    mhz_rx: NO_PIN,
+    adc0: NO_PIN,
+    adc1: NO_PIN,
+    door: NO_PIN,
+    motion: NO_PIN,
+    input1: NO_PIN,
+    input2: NO_PIN,
+    wind: NO_PIN,
+    rain: NO_PIN,
+};
+#[cfg(feature = "esp32s3")]
+pub const DEFAULT_PINS: Pins = Pins {
+    i2c_sda: 2,
+    i2c_scl: 1,
+    i2c1_sda: NO_PIN,
+    i2c1_scl: NO_PIN,
+    led: NO_PIN,
+    button: 0,
+    sd_sck: NO_PIN,
+    sd_mosi: NO_PIN,
+    sd_miso: NO_PIN,
+    sd_cs: NO_PIN,
+    mic_sck: NO_PIN,
+    mic_ws: NO_PIN,
+    mic_sd: NO_PIN,
+    relay_1: NO_PIN,
+    relay_2: NO_PIN,
+    lora_nss: NO_PIN,
+    lora_reset: NO_PIN,
+    lora_busy: NO_PIN,
+    lora_dio1: NO_PIN,
+    ext_wdt: NO_PIN,
+    pms_tx: NO_PIN,
+    pms_rx: NO_PIN,
+    pms_set: NO_PIN,
+    mhz_tx: NO_PIN,
+    mhz_rx: NO_PIN,
+    adc0: NO_PIN,
+    adc1: NO_PIN,
+    door: NO_PIN,
+    motion: NO_PIN,
+    input1: NO_PIN,
+    input2: NO_PIN,
+    wind: NO_PIN,
+    rain: NO_PIN,
+};
+
+static PINS: Mutex<CriticalSectionRawMutex, Cell<Pins>> = Mutex::new(Cell::new(DEFAULT_PINS));
+
+/// The GPIOs a pin can be moved to. GPIO24 to GPIO30 go to the SPI flash,
+/// GPIO12 and GPIO13 to the USB port of the console.
+#[cfg(feature = "esp32c6")]
+const fn usable(pin: u8) -> bool {
+    matches!(pin, 0..=11 | 14..=23)
+}
+/// The GPIOs a pin can be moved to. GPIO22 to GPIO25 don't exist, GPIO26
+/// to GPIO37 go to the SPI flash and the octal PSRAM, GPIO19 and GPIO20 to
+/// the USB port.
+#[cfg(feature = "esp32s3")]
+const fn usable(pin: u8) -> bool {
+    matches!(pin, 0..=18 | 21 | 38..=48)
+}
+
+/// Why [`Pins`] can't be used.
+#[derive(Clone, Copy, defmt::Format)]
+pub enum InvalidPin {
+    /// Not a GPIO of the chip, or one the flash or the USB port needs.
+    Unusable(u8),
+    /// Given to two roles.
+    Twice(u8),
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize, defmt::Format)]
+pub struct Pins {
+    pub i2c_sda: u8,
+    pub i2c_scl: u8,
+    /// A second I2C bus for the sensors only, [`NO_PIN`] without one. Two
+    /// sensors on the same address go one on each.
+    pub i2c1_sda: u8,
+    pub i2c1_scl: u8,
+    /// Data line of the addressable LEDs, [`NO_PIN`] without them.
+    pub led: u8,
+    /// Active low, the factory reset and the safe mode are held on it.
+    pub button: u8,
+    /// SPI of the SD card slot, [`NO_PIN`] without one. The LoRa radio uses
+    /// the same bus, a node has one or the other.
+    pub sd_sck: u8,
+    pub sd_mosi: u8,
+    pub sd_miso: u8,
+    pub sd_cs: u8,
+    /// I2S of the microphone, [`NO_PIN`] without one.
+    pub mic_sck: u8,
+    pub mic_ws: u8,
+    pub mic_sd: u8,
+    /// Outputs driving a relay or a MOSFET, high is on. [`NO_PIN`] without.
+    pub relay_1: u8,
+    pub relay_2: u8,
+    /// Chip select and control lines of an SX1262, [`NO_PIN`] without one.
+    pub lora_nss: u8,
+    pub lora_reset: u8,
+    pub lora_busy: u8,
+    pub lora_dio1: u8,
+    /// DONE input of an external watchdog like the TPL5010, pulsed with every
+    /// feed. [`NO_PIN`] without one.
+    pub ext_wdt: u8,
+    /// UART of a PMS5003, TX of the node to RX of the sensor, [`NO_PIN`]
+    /// without one. SET is optional, the sensor sleeps on a command then.
+    pub pms_tx: u8,
+    pub pms_rx: u8,
+    pub pms_set: u8,
+    /// UART of an MH-Z19B, TX of the node to RX of the sensor, [`NO_PIN`]
+    /// without one.
+    pub mhz_tx: u8,
+    pub mhz_rx: u8,
+    /// Generic analog channels, on ADC1 GPIOs. [`NO_PIN`] without one.
+    pub adc0: u8,
+    pub adc1: u8,
+    /// A reed contact on the door, to ground and open when the door is, and
+    /// the output of a PIR motion sensor. [`NO_PIN`] without them.
+    pub door: u8,
+    pub motion: u8,
+    /// Generic contacts and binary sensors, their pull, debounce and name
+    /// are in the settings. [`NO_PIN`] without them.
+    pub input1: u8,
+    pub input2: u8,
+    /// Reed contacts of an anemometer and a tipping-bucket rain gauge, to
+    /// ground, counted as pulses. [`NO_PIN`] without them.
+    pub wind: u8,
+    pub rain: u8,
+}
+
+impl Pins {
+    fn numbers(&self) -> [u8; 33] {
+        [
+            self.i2c_sda,
+            self.i2c_scl,
+            self.i2c1_sda,
+            self.i2c1_scl,
+            self.led,
+            self.button,
+            self.sd_sck,
+            self.sd_mosi,
+            self.sd_miso,
+            self.sd_cs,
+            self.mic_sck,
+            self.mic_ws,
+            self.mic_sd,
+            self.relay_1,
+            self.relay_2,
+            self.lora_nss,
+            self.lora_reset,
+            self.lora_busy,
+            self.lora_dio1,
+            self.ext_wdt,
+            self.pms_tx,
+            self.pms_rx,
+            self.pms_set,
+            self.mhz_tx,
+            self.mhz_rx,
+            self.adc0,
+            self.adc1,
+            self.door,
+            self.motion,
+            self.input1,
+            self.input2,
+            self.wind,
+            self.rain,
+        ]
+    }
+
+    /// Every pin set is a usable GPIO, and none of them has two roles.
+    pub fn check(&self) -> Result<(), InvalidPin> {
+        let numbers = self.numbers();
+        for (i, &pin) in numbers.iter().enumerate() {
+            if pin == NO_PIN {
+                continue;
+            }
+            if !usable(pin) {
+                return Err(InvalidPin::Unusable(pin));
+            }
+            if numbers[..i].contains(&pin) {
+                return Err(InvalidPin::Twice(pin));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn led(&self) -> Option<u8> {
+        (self.led != NO_PIN).then_some(self.led)
+    }
+
+    /// Both pins of the second I2C bus are set.
+    pub fn i2c1(&self) -> bool {
+        ![self.i2c1_sda, self.i2c1_scl].contains(&NO_PIN)
+    }
+
+    /// All four SD card pins are set.
+    pub fn sd_card(&self) -> bool {
+        ![self.sd_sck, self.sd_mosi, self.sd_miso, self.sd_cs].contains(&NO_PIN)
+    }
+
+    pub fn external_watchdog(&self) -> Option<u8> {
+        (self.ext_wdt != NO_PIN).then_some(self.ext_wdt)
+    }
+
+    pub fn relays(&self) -> [Option<u8>; 2] {
+        [self.relay_1, self.relay_2].map(|pin| (pin != NO_PIN).then_some(pin))
+    }
+
+    /// The control lines and the SPI bus of the LoRa radio are set.
+    pub fn lora(&self) -> bool {
+        ![
+            self.sd_sck,
+            self.sd_mosi,
+            self.sd_miso,
+            self.lora_nss,
+            self.lora_reset,
+            self.lora_busy,
+            self.lora_dio1,
+        ]
+        .contains(&NO_PIN)
+    }
+
+    /// Both UART pins of the PMS5003 are set.
+    pub fn pms5003(&self) -> bool {
+        ![self.pms_tx, self.pms_rx].contains(&NO_PIN)
+    }
+
+    pub fn pms5003_set(&self) -> Option<u8> {
+        (self.pms_set != NO_PIN).then_some(self.pms_set)
+    }
+
+    /// Both UART pins of the MH-Z19B are set.
+    pub fn mhz19(&self) -> bool {
+        ![self.mhz_tx, self.mhz_rx].contains(&NO_PIN)
+    }
+
+    /// The pins of the `adc0` and `adc1` channels.
+    pub fn analog(&self) -> [Option<u8>; 2] {
+        [self.adc0, self.adc1].map(|pin| (pin != NO_PIN).then_some(pin))
+    }
+
+    pub fn door(&self) -> Option<u8> {
+        (self.door != NO_PIN).then_some(self.door)
+    }
+
+    pub fn motion(&self) -> Option<u8> {
+        (self.motion != NO_PIN).then_some(self.motion)
+    }
+
+    /// The pins of the generic inputs, see [`crate::inputs::GENERIC`].
+    pub fn inputs(&self) -> [Option<u8>; 2] {
+        [self.input1, self.input2].map(|pin| (pin != NO_PIN).then_some(pin))
+    }
+
+    pub fn wind(&self) -> Option<u8> {
+        (self.wind != NO_PIN).then_some(self.wind)
+    }
+
+    pub fn rain(&self) -> Option<u8> {
+        (self.rain != NO_PIN).then_some(self.rain)
+    }
+
+    /// All three microphone pins are set.
+    pub fn microphone(&self) -> bool {
+        ![self.mic_sck, self.mic_ws, self.mic_sd].contains(&NO_PIN)
+    }
+}
+
+/// The pins in use, the defaults until [`load`] ran.
+pub fn pins() -> Pins {
+    PINS.lock(|pins| pins.get())
+}
+
+/// Reads the overrides from the storage. Falls back to the defaults of the
+/// chip for the ones that aren't there, and for all of them when they don't
+/// [`check`](Pins::check) out.
+pub async fn load(db: &'static Db) -> Pins {
+    let pins = match read(db).await {
+        Ok(pins) => match pins.check() {
+            Ok(()) => pins,
+            Err(err) => {
+                warn!("Board: stored pins unusable, {}, taking the defaults", err);
+                DEFAULT_PINS
+            }
+        },
+        Err(err) => {
+            warn!("Board: could not read the pins: {:?}", err);
+            DEFAULT_PINS
+        }
+    };
+
+    if pins != DEFAULT_PINS {
+        info!("Board: pins {}", pins);
+    }
+    PINS.lock(|current| current.set(pins));
+
+    pins
+}
+
+async fn read(db: &'static Db) -> DbResult<Pins> {
+    let mut tx = db.read_transaction().await;
+
+    Ok(Pins {
+        i2c_sda: kv_storage::read_u8(&mut tx, I2C_SDA_KEY)
+            .await?
+            .unwrap_or(DEFAULT_PINS.i2c_sda),
+        i2c_scl: kv_storage::read_u8(&mut tx, I2C_SCL_KEY)
+            .await?
+            .unwrap_or(DEFAULT_PINS.i2c_scl),
+        i2c1_sda: kv_storage::read_u8(&mut tx, I2C1_SDA_KEY)
+            .await?
+            .unwrap_or(DEFAULT_PINS.i2c1_sda),
+        i2c1_scl: kv_storage::read_u8(&mut tx, I2C1_SCL_KEY)
+            .await?
+            .unwrap_or(DEFAULT_PINS.i2c1_scl),
+        led: kv_storage::read_u8(&mut tx, LED_KEY)
+            .await?
+            .unwrap_or(DEFAULT_PINS.led),
+        button: kv_storage::read_u8(&mut tx, BUTTON_KEY)
+            .await?
+            .unwrap_or(DEFAULT_PINS.button),
+        sd_sck: kv_storage::read_u8(&mut tx, SD_SCK_KEY)
+            .await?
+            .unwrap_or(DEFAULT_PINS.sd_sck),
+        sd_mosi: kv_storage::read_u8(&mut tx, SD_MOSI_KEY)
+            .await?
+            .unwrap_or(DEFAULT_PINS.sd_mosi),
+        sd_miso: kv_storage::read_u8(&mut tx, SD_MISO_KEY)
+            .await?
+            .unwrap_or(DEFAULT_PINS.sd_miso),
+        sd_cs: kv_storage::read_u8(&mut tx, SD_CS_KEY)
+            .await?
+            .unwrap_or(DEFAULT_PINS.sd_cs),
+        mic_sck: kv_storage::read_u8(&mut tx, MIC_SCK_KEY)
+            .await?
+            .unwrap_or(DEFAULT_PINS.mic_sck),
+        mic_ws: kv_storage::read_u8(&mut tx, MIC_WS_KEY)
+            .await?
+            .unwrap_or(DEFAULT_PINS.mic_ws),
+        mic_sd: kv_storage::read_u8(&mut tx, MIC_SD_KEY)
+            .await?
+            .unwrap_or(DEFAULT_PINS.mic_sd),
+        relay_1: kv_storage::read_u8(&mut tx, RELAY_1_KEY)
+            .await?
+            .unwrap_or(DEFAULT_PINS.relay_1),
+        relay_2: kv_storage::read_u8(&mut tx, RELAY_2_KEY)
+            .await?
+            .unwrap_or(DEFAULT_PINS.relay_2),
+        lora_nss: kv_storage::read_u8(&mut tx, LORA_NSS_KEY)
+            .await?
+            .unwrap_or(DEFAULT_PINS.lora_nss),
+        lora_reset: kv_storage::read_u8(&mut tx, LORA_RESET_KEY)
+            .await?
+            .unwrap_or(DEFAULT_PINS.lora_reset),
+        lora_busy: kv_storage::read_u8(&mut tx, LORA_BUSY_KEY)
+            .await?
+            .unwrap_or(DEFAULT_PINS.lora_busy),
+        lora_dio1: kv_storage::read_u8(&mut tx, LORA_DIO1_KEY)
+            .await?
+            .unwrap_or(DEFAULT_PINS.lora_dio1),
+        ext_wdt: kv_storage::read_u8(&mut tx, EXT_WDT_KEY)
+            .await?
+            .unwrap_or(DEFAULT_PINS.ext_wdt),
+        pms_tx: kv_storage::read_u8(&mut tx, PMS_TX_KEY)
+            .await?
+            .unwrap_or(DEFAULT_PINS.pms_tx),
+        pms_rx: kv_storage::read_u8(&mut tx, PMS_RX_KEY)
+            .await?
+            .unwrap_or(DEFAULT_PINS.pms_rx),
+        pms_set: kv_storage::read_u8(&mut tx, PMS_SET_KEY)
+            .await?
+            .unwrap_or(DEFAULT_PINS.pms_set),
+        mhz_tx: kv_storage::read_u8(&mut tx, MHZ_TX_KEY)
+            .await?
+            .unwrap_or(DEFAULT_PINS.mhz_tx),
+        mhz_rx: kv_storage::read_u8(&mut tx, MHZ_RX_KEY)
+            .await?
+            .unwrap_or(DEFAULT_PINS.mhz_rx),
+        adc0: kv_storage::read_u8(&mut tx, ADC0_KEY)
+            .await?
+            .unwrap_or(DEFAULT_PINS.adc0),
+        adc1: kv_storage::read_u8(&mut tx, ADC1_KEY)
+            .await?
+            .unwrap_or(DEFAULT_PINS.adc1),
+        door: kv_storage::read_u8(&mut tx, DOOR_KEY)
+            .await?
+            .unwrap_or(DEFAULT_PINS.door),
+        motion: kv_storage::read_u8(&mut tx, MOTION_KEY)
+            .await?
+            .unwrap_or(DEFAULT_PINS.motion),
+        input1: kv_storage::read_u8(&mut tx, INPUT1_KEY)
+            .await?
+            .unwrap_or(DEFAULT_PINS.input1),
+        input2: kv_storage::read_u8(&mut tx, INPUT2_KEY)
+            .await?
+            .unwrap_or(DEFAULT_PINS.input2),
+        wind: kv_storage::read_u8(&mut tx, WIND_KEY)
+            .await?
+            .unwrap_or(DEFAULT_PINS.wind),
+        rain: kv_storage::read_u8(&mut tx, RAIN_KEY)
+            .await?
+            .unwrap_or(DEFAULT_PINS.rain),
+    })
+}
+
+/// Takes effect with the next boot. The pins are to [`check`](Pins::check)
+/// out first.
+pub async fn save(db: &'static Db, pins: &Pins) -> DbResult<()> {
+    let mut tx = db.write_transaction().await;
+    kv_storage::write_u8(&mut tx, ADC0_KEY, pins.adc0).await?;
+    kv_storage::write_u8(&mut tx, ADC1_KEY, pins.adc1).await?;
+    kv_storage::write_u8(&mut tx, BUTTON_KEY, pins.button).await?;
+    kv_storage::write_u8(&mut tx, DOOR_KEY, pins.door).await?;
+    kv_storage::write_u8(&mut tx, EXT_WDT_KEY, pins.ext_wdt).await?;
+    kv_storage::write_u8(&mut tx, I2C1_SCL_KEY, pins.i2c1_scl).await?;
+    kv_storage::write_u8(&mut tx, I2C1_SDA_KEY, pins.i2c1_sda).await?;
+    kv_storage::write_u8(&mut tx, I2C_SCL_KEY, pins.i2c_scl).await?;
+    kv_storage::write_u8(&mut tx, I2C_SDA_KEY, pins.i2c_sda).await?;
+    kv_storage::write_u8(&mut tx, INPUT1_KEY, pins.input1).await?;
+    kv_storage::write_u8(&mut tx, INPUT2_KEY, pins.input2).await?;
+    kv_storage::write_u8(&mut tx, LED_KEY, pins.led).await?;
+    kv_storage::write_u8(&mut tx, LORA_BUSY_KEY, pins.lora_busy).await?;
+    kv_storage::write_u8(&mut tx, LORA_DIO1_KEY, pins.lora_dio1).await?;
+    kv_storage::write_u8(&mut tx, LORA_NSS_KEY, pins.lora_nss).await?;
+    kv_storage::write_u8(&mut tx, LORA_RESET_KEY, pins.lora_reset).await?;
+    kv_storage::write_u8(&mut tx, MHZ_RX_KEY, pins.mhz_rx).await?;
+    kv_storage::write_u8(&mut tx, MHZ_TX_KEY, pins.mhz_tx).await?;
+    kv_storage::write_u8(&mut tx, MIC_SCK_KEY, pins.mic_sck).await?;
+    kv_storage::write_u8(&mut tx, MIC_SD_KEY, pins.mic_sd).await?;
+    kv_storage::write_u8(&mut tx, MIC_WS_KEY, pins.mic_ws).await?;
+    kv_storage::write_u8(&mut tx, MOTION_KEY, pins.motion).await?;
+    kv_storage::write_u8(&mut tx, PMS_RX_KEY, pins.pms_rx).await?;
+    kv_storage::write_u8(&mut tx, PMS_SET_KEY, pins.pms_set).await?;
+    kv_storage::write_u8(&mut tx, PMS_TX_KEY, pins.pms_tx).await?;
+    kv_storage::write_u8(&mut tx, RAIN_KEY, pins.rain).await?;
+    kv_storage::write_u8(&mut tx, RELAY_1_KEY, pins.relay_1).await?;
+    kv_storage::write_u8(&mut tx, RELAY_2_KEY, pins.relay_2).await?;
+    kv_storage::write_u8(&mut tx, SD_CS_KEY, pins.sd_cs).await?;
+    kv_storage::write_u8(&mut tx, SD_MISO_KEY, pins.sd_miso).await?;
+    kv_storage::write_u8(&mut tx, SD_MOSI_KEY, pins.sd_mosi).await?;
+    kv_storage::write_u8(&mut tx, SD_SCK_KEY, pins.sd_sck).await?;
+    kv_storage::write_u8(&mut tx, WIND_KEY, pins.wind).await?;
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// The GPIO by its number.
+///
+/// # Safety
+///
+/// Nothing else may use the pin, and it has to be one the chip has, like
+/// the ones of [`pins`] after they [`check`](Pins::check)ed out.
+pub unsafe fn pin(number: u8) -> AnyPin<'static> {
+    unsafe { AnyPin::steal(number) }
+}
+
+/// Sets up the I2C bus the sensors and the display share.
+pub fn i2c(i2c0: I2C0<'static>, pins: &Pins) -> &'static sensors::I2cBus {
+    static I2C_STATIC: StaticCell<sensors::I2cBus> = StaticCell::new();
+
+    let i2c = i2c::master::I2c::new(i2c0, i2c::master::Config::default())
+        .unwrap()
+        .with_sda(unsafe { pin(pins.i2c_sda) })
+        .with_scl(unsafe { pin(pins.i2c_scl) })
+        .into_async();
+
+    I2C_STATIC.init(sensors::I2cBus::new(i2c))
+}
+
+/// Sets up the second I2C bus, for the sensors only, none when the board
+/// doesn't have one. The C6 has a single I2C controller, so it is the S3's.
+#[cfg(feature = "esp32s3")]
+pub fn i2c1(i2c1: I2C1<'static>, pins: &Pins) -> Option<&'static sensors::I2cBus> {
+    static I2C1_STATIC: StaticCell<sensors::I2cBus> = StaticCell::new();
+
+    if !pins.i2c1() {
+        return None;
+    }
+
+    let i2c = i2c::master::I2c::new(i2c1, i2c::master::Config::default())
+        .unwrap()
+        .with_sda(unsafe { pin(pins.i2c1_sda) })
+        .with_scl(unsafe { pin(pins.i2c1_scl) })
+        .into_async();
+
+    Some(I2C1_STATIC.init(sensors::I2cBus::new(i2c)))
+}
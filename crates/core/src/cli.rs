@@ -0,0 +1,332 @@
+//! Serial console on the USB port, for when the network is what needs
+//! debugging. One command per line, `help` lists them.
+//!
+//! Only on boards that log over the debug probe, otherwise the log and the
+//! console would share the port.
+
+use core::fmt::Write as _;
+use core::sync::atomic::Ordering;
+
+use defmt::info;
+use embassy_time::{Duration, Instant, with_timeout};
+use embedded_io_async::{Read, Write};
+use esp_hal::Async;
+use esp_hal::usb_serial_jtag::{UsbSerialJtag, UsbSerialJtagTx};
+use heapless::String;
+
+use crate::config::{self, Kind};
+use crate::kv_storage::{self, Db};
+use crate::provisioning::{self, Channel};
+use crate::{diagnostics, mqtt, sensors, shutdown, system, wifi};
+
+extern crate alloc;
+
+const LINE_LEN: usize = 128;
+/// Longest value `get` and `set` handle, the longest setting is the OTA URL.
+const VALUE_LEN: usize = 128;
+const SCAN_TIMEOUT: Duration = Duration::from_secs(15);
+const PROMPT: &[u8] = b"> ";
+
+const HELP: &str = "\
+status              state, faults, WiFi, MQTT and memory
+wifi scan           access points around, while connected
+i2c scan            addresses that answer on the bus
+get <key>           a value from the storage
+set <key> <value>   a setting, checked against its type, used from the next boot
+done                end the settings changes, the web and MQTT may write again
+measure             take a sample right away
+reboot              restart the node
+";
+
+pub type Serial = UsbSerialJtag<'static, Async>;
+type Tx = UsbSerialJtagTx<'static, Async>;
+
+#[embassy_executor::task]
+pub async fn task(serial: Serial, db: &'static Db, i2c: &'static sensors::I2cBus) -> ! {
+    info!("Console started");
+
+    let (mut rx, mut tx) = serial.split();
+    let mut line = String::<LINE_LEN>::new();
+    let mut buf = [0u8; 32];
+
+    tx.write_all(PROMPT).await.ok();
+
+    loop {
+        let Ok(len) = rx.read(&mut buf).await else {
+            continue;
+        };
+
+        for &byte in &buf[..len] {
+            match byte {
+                b'\r' | b'\n' => {
+                    tx.write_all(b"\r\n").await.ok();
+                    if !line.trim().is_empty() {
+                        let output = execute(db, i2c, line.trim()).await;
+                        write_lines(&mut tx, &output).await;
+                    }
+                    line.clear();
+                    tx.write_all(PROMPT).await.ok();
+                }
+                // Backspace and delete.
+                0x08 | 0x7F => {
+                    if line.pop().is_some() {
+                        tx.write_all(b"\x08 \x08").await.ok();
+                    }
+                }
+                byte if byte.is_ascii_graphic() || byte == b' ' => {
+                    if line.push(byte as char).is_ok() {
+                        tx.write_all(&[byte]).await.ok();
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Terminals want a carriage return before each new line.
+async fn write_lines(tx: &mut Tx, output: &str) {
+    for line in output.lines() {
+        tx.write_all(line.as_bytes()).await.ok();
+        tx.write_all(b"\r\n").await.ok();
+    }
+}
+
+async fn execute(db: &'static Db, i2c: &sensors::I2cBus, line: &str) -> alloc::string::String {
+    let mut out = alloc::string::String::new();
+    let mut words = line.split_whitespace();
+
+    match (words.next(), words.next()) {
+        (Some("help"), None) => out.push_str(HELP),
+        (Some("status"), None) => status(&mut out),
+        (Some("wifi"), Some("scan")) => wifi_scan(&mut out).await,
+        (Some("i2c"), Some("scan")) => i2c_scan(&mut out, i2c).await,
+        (Some("get"), Some(key)) => get(&mut out, db, key).await,
+        (Some("set"), Some(key)) => {
+            // The value is the rest of the line, spaces included.
+            let value = line[3..].trim_start()[key.len()..].trim();
+            set(&mut out, db, key, value).await
+        }
+        (Some("done"), None) => {
+            provisioning::release(Channel::Console);
+            out.push_str("Settings released");
+        }
+        (Some("measure"), None) => {
+            sensors::MEASURE_NOW.signal(());
+            out.push_str("Measuring");
+        }
+        (Some("reboot"), None) => shutdown::restart(shutdown::Reason::Reboot).await,
+        _ => {
+            writeln!(out, "Unknown command: {line}").ok();
+            out.push_str(HELP);
+        }
+    }
+
+    out
+}
+
+fn status(out: &mut alloc::string::String) {
+    writeln!(out, "state:    {:?}", system::state()).ok();
+    writeln!(out, "uptime:   {} s", Instant::now().as_secs()).ok();
+
+    if let Some(fault) = system::FAULT.try_get() {
+        writeln!(out, "fault:    {} ({})", fault.code(), fault.hint()).ok();
+    }
+
+    if wifi::CONNECTED.load(Ordering::Relaxed) {
+        let rssi = wifi::RSSI.load(Ordering::Relaxed);
+        writeln!(out, "wifi:     connected, {rssi} dBm").ok();
+    } else {
+        writeln!(out, "wifi:     down").ok();
+    }
+
+    let mqtt = if mqtt::CONNECTED.load(Ordering::Relaxed) {
+        "connected"
+    } else {
+        "down"
+    };
+    writeln!(out, "mqtt:     {mqtt}").ok();
+
+    match provisioning::status() {
+        Some(session) => writeln!(
+            out,
+            "settings: changed by {:?}, {} s ago",
+            session.channel, session.idle_secs
+        ),
+        None => writeln!(out, "settings: free"),
+    }
+    .ok();
+
+    let diagnostics = diagnostics::latest();
+    writeln!(
+        out,
+        "heap:     {} free, {} lowest, {} largest block",
+        diagnostics.heap_free, diagnostics.heap_min_free, diagnostics.heap_max_block
+    )
+    .ok();
+    writeln!(out, "stack:    {} never used", diagnostics.stack_free).ok();
+    let dropped = diagnostics::drops();
+    writeln!(
+        out,
+        "dropped:  {} queue full, {} publish failed, {} overflow, {} connection lost",
+        dropped.queue_full, dropped.publish_failed, dropped.overflow, dropped.connection_lost
+    )
+    .ok();
+
+    match sensors::latest() {
+        Some(sample) => {
+            writeln!(out, "sample:   at {}", sample.timestamp).ok();
+            let values = [
+                ("temperature", sample.temperature(), "C"),
+                ("humidity", sample.humidity(), "%"),
+                ("pressure", sample.pressure(), "hPa"),
+                ("light", sample.light(), "lx"),
+            ];
+            for (name, value, unit) in values {
+                if let Some(value) = value {
+                    writeln!(out, "  {name:<12}{value:.1} {unit}").ok();
+                }
+            }
+        }
+        None => {
+            writeln!(out, "sample:   none yet").ok();
+        }
+    }
+}
+
+async fn wifi_scan(out: &mut alloc::string::String) {
+    if !wifi::CONNECTED.load(Ordering::Relaxed) {
+        out.push_str("Scans only run while the WiFi is connected");
+        return;
+    }
+
+    wifi::SCAN_RESULT.reset();
+    wifi::SCAN_REQUEST.signal(());
+
+    match with_timeout(SCAN_TIMEOUT, wifi::SCAN_RESULT.wait()).await {
+        Ok(found) if found.is_empty() => out.push_str("Nothing found"),
+        Ok(found) => {
+            for ap in found {
+                writeln!(out, "{:>4} dBm  ch {:>2}  {}", ap.rssi, ap.channel, ap.ssid).ok();
+            }
+        }
+        Err(_) => out.push_str("No answer from the WiFi task"),
+    }
+}
+
+/// Reads a byte from every address that isn't reserved. Holds the bus for
+/// the whole scan, so the sensors can't get in between.
+async fn i2c_scan(out: &mut alloc::string::String, i2c: &sensors::I2cBus) {
+    let mut i2c = i2c.lock().await;
+
+    let mut found = 0;
+    for address in 0x08..0x78 {
+        if i2c.read_async(address, &mut [0u8; 1]).await.is_ok() {
+            writeln!(out, "0x{address:02X}").ok();
+            found += 1;
+        }
+    }
+
+    if found == 0 {
+        out.push_str("Nothing answers");
+    }
+}
+
+async fn get(out: &mut alloc::string::String, db: &'static Db, key: &str) {
+    let mut tx = db.read_transaction().await;
+    let mut buf = [0u8; VALUE_LEN];
+
+    match kv_storage::read_bytes(&mut tx, key, &mut buf).await {
+        Ok(Some(len)) => {
+            let value = &buf[..len];
+            let text = core::str::from_utf8(value).ok().filter(|text| {
+                text.bytes()
+                    .all(|byte| byte.is_ascii_graphic() || byte == b' ')
+            });
+            match (value_as_number(config::kind(key), value), text) {
+                (Some(number), _) => writeln!(out, "{number}"),
+                (None, Some(text)) => writeln!(out, "{text}"),
+                (None, None) => writeln!(out, "<{len} bytes>"),
+            }
+            .ok();
+        }
+        Ok(None) => out.push_str("Not set"),
+        Err(err) => {
+            writeln!(out, "Read failed: {err:?}").ok();
+        }
+    }
+}
+
+/// Writes `value` the way the settings read `key`, see [`config::kind`].
+async fn set(out: &mut alloc::string::String, db: &'static Db, key: &str, value: &str) {
+    let Some(kind) = config::kind(key) else {
+        writeln!(out, "Not a setting: {key}").ok();
+        return;
+    };
+    let mut buf = [0u8; VALUE_LEN];
+    let Some(len) = encode(kind, value, &mut buf) else {
+        match kind {
+            Kind::Bool => writeln!(out, "Not true or false: {value}"),
+            Kind::Text(max) => writeln!(out, "Longer than {max} bytes"),
+            kind => writeln!(out, "Not a {kind:?} number: {value}"),
+        }
+        .ok();
+        return;
+    };
+    let bytes = &buf[..len];
+
+    if let Err(busy) = provisioning::claim(Channel::Console) {
+        out.push_str(busy.message());
+        return;
+    }
+
+    let mut tx = db.write_transaction().await;
+    let result = async {
+        kv_storage::write_bytes(&mut tx, key, bytes).await?;
+        tx.commit().await?;
+        Ok::<_, kv_storage::DbError>(())
+    }
+    .await;
+
+    match result {
+        Ok(()) => out.push_str("Saved, takes effect with the next boot"),
+        Err(err) => {
+            writeln!(out, "Write failed: {err:?}").ok();
+        }
+    }
+}
+
+/// A stored number, as the settings keep them: little endian, the UTC
+/// offset signed.
+fn value_as_number(kind: Option<Kind>, value: &[u8]) -> Option<i64> {
+    match (kind?, *value) {
+        (Kind::Bool | Kind::U8, [byte]) => Some(byte as i64),
+        (Kind::U16, [low, high]) => Some(u16::from_le_bytes([low, high]) as i64),
+        (Kind::I16, [low, high]) => Some(i16::from_le_bytes([low, high]) as i64),
+        (Kind::U32, [a, b, c, d]) => Some(u32::from_le_bytes([a, b, c, d]) as i64),
+        _ => None,
+    }
+}
+
+/// `value` in the bytes a setting of `kind` is stored as. Returns their
+/// length, none when it doesn't parse or fit.
+fn encode(kind: Kind, value: &str, buf: &mut [u8; VALUE_LEN]) -> Option<usize> {
+    let mut put = |bytes: &[u8]| {
+        buf.get_mut(..bytes.len())?.copy_from_slice(bytes);
+        Some(bytes.len())
+    };
+
+    match kind {
+        Kind::Bool => match value {
+            "true" => put(&[1]),
+            "false" => put(&[0]),
+            _ => None,
+        },
+        Kind::U8 => put(&value.parse::<u8>().ok()?.to_le_bytes()),
+        Kind::U16 => put(&value.parse::<u16>().ok()?.to_le_bytes()),
+        Kind::I16 => put(&value.parse::<i16>().ok()?.to_le_bytes()),
+        Kind::U32 => put(&value.parse::<u32>().ok()?.to_le_bytes()),
+        Kind::Text(max) if value.len() <= max => put(value.as_bytes()),
+        Kind::Text(_) => None,
+    }
+}
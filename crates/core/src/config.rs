@@ -2,6 +2,28 @@ use heapless::String;
 use serde::Deserialize;
 
 use crate::kv_storage;
+use crate::schedule::{NightMode, Schedule};
+use crate::shutdown;
+use crate::units::Units;
+
+pub const DEFAULT_DISPLAY_CONTRAST: u8 = 0x7F;
+pub const DEFAULT_NIGHT_START_HOUR: u8 = 22;
+pub const DEFAULT_NIGHT_END_HOUR: u8 = 7;
+/// Percent of the LED's base brightness, 0 turns it off.
+pub const DEFAULT_LED_BRIGHTNESS: u8 = 100;
+pub const DEFAULT_LED_COUNT: u8 = 1;
+/// The first channel of the EU868 plan.
+pub const DEFAULT_LORA_FREQUENCY_KHZ: u32 = 868_100;
+pub const DEFAULT_MQTT_KEEP_ALIVE_SECS: u16 = 120;
+/// The room stays occupied that long after the last motion.
+pub const DEFAULT_MOTION_HOLD_SECS: u16 = 300;
+pub const DEFAULT_SETUP_CHANNEL: u8 = 1;
+pub const MQTT_CLIENT_ID_LEN: usize = 32;
+pub const MQTT_TOPIC_LEN: usize = 64;
+/// Browsers open a couple of connections at once.
+pub const DEFAULT_WEB_TASKS: u8 = 2;
+pub const DEFAULT_WEB_HTTP_BUFFER: u16 = 2048;
+pub const DEFAULT_WEB_TCP_BUFFER: u16 = 1024;
 
 static WIFI_SSID_KEY: &'static str = "wifi.ssid";
 static WIFI_PASSWORD_KEY: &'static str = "wifi.password";
@@ -9,14 +31,201 @@ static MQTT_BROKER_KEY: &'static str = "mqtt.broker";
 static MQTT_CLIENT_ID_KEY: &'static str = "mqtt.client_id";
 static MQTT_TOPIC_KEY: &'static str = "mqtt.topic";
 static SYSTEM_REBOOT_TO_RECONFIGURE: &'static str = "system.reconfig";
+static DISPLAY_UNITS_KEY: &'static str = "display.units";
+static TIME_UTC_OFFSET_KEY: &'static str = "time.utc_offset";
+static NIGHT_START_KEY: &'static str = "night.start";
+static NIGHT_END_KEY: &'static str = "night.end";
+static DISPLAY_CONTRAST_KEY: &'static str = "display.contrast";
+static DISPLAY_NIGHT_KEY: &'static str = "display.night";
+static DISPLAY_LARGE_KEY: &'static str = "display.large";
+static DISPLAY_ROTATION_KEY: &'static str = "display.rotation";
+static LED_BRIGHTNESS_KEY: &'static str = "led.brightness";
+static LED_MODE_KEY: &'static str = "led.mode";
+static LED_COUNT_KEY: &'static str = "led.count";
+static LED_STRIP_KEY: &'static str = "led.strip";
+static LED_NIGHT_KEY: &'static str = "led.night";
+static POWER_SLEEP_KEY: &'static str = "power.sleep";
+static OTA_URL_KEY: &'static str = "ota.url";
+static OTA_CHECK_KEY: &'static str = "ota.check";
+static SYSLOG_HOST_KEY: &'static str = "syslog.host";
+static POWER_PROFILE_KEY: &'static str = "power.profile";
+static SUPPLY_LOW_KEY: &'static str = "power.lowmv";
+static GAS_HEATER_KEY: &'static str = "power.gas";
+static MQTT_TRANSPORT_KEY: &'static str = "mqtt.transport";
+static PAYLOAD_FORMAT_KEY: &'static str = "payload.format";
+static SD_FORMAT_KEY: &'static str = "sd.format";
+static RELAY_RULE_1_KEY: &'static str = "relay.rule1";
+static RELAY_RULE_2_KEY: &'static str = "relay.rule2";
+static LORA_FREQUENCY_KEY: &'static str = "lora.frequency";
+static MQTT_FIELDS_KEY: &'static str = "mqtt.fields";
+static PRESENCE_BEACONS_KEY: &'static str = "presence.beacons";
+static CO2_CALIBRATION_KEY: &'static str = "co2.abc";
+static MQTT_KEEP_ALIVE_KEY: &'static str = "mqtt.keepalive";
+static ADC0_MAP_KEY: &'static str = "adc.map0";
+static ADC1_MAP_KEY: &'static str = "adc.map1";
+static ALERT_RULES_KEY: &'static str = "alert.rules";
+static MOTION_HOLD_KEY: &'static str = "motion.hold";
+static SENSOR_WARM_UP_KEY: &'static str = "sensors.warmup";
+static INPUT_1_KEY: &'static str = "input.1";
+static INPUT_2_KEY: &'static str = "input.2";
+static LOCATION_KEY: &'static str = "meta.location";
+static ROOM_KEY: &'static str = "meta.room";
+static LABELS_KEY: &'static str = "meta.labels";
+static NTP_SERVERS_KEY: &'static str = "ntp.servers";
+static SENSOR_FILTER_KEY: &'static str = "sensors.filter";
+static I2C_DEBUG_KEY: &'static str = "debug.i2c";
+static CALIBRATION_KEY: &'static str = "sensors.calib";
+static SETUP_SUFFIX_KEY: &'static str = "setup.suffix";
+static SETUP_PASSWORD_KEY: &'static str = "setup.password";
+static SETUP_CHANNEL_KEY: &'static str = "setup.channel";
+static WEB_TASKS_KEY: &'static str = "web.tasks";
+static WEB_HTTP_BUFFER_KEY: &'static str = "web.http_buffer";
+static WEB_TCP_BUFFER_KEY: &'static str = "web.tcp_buffer";
+static WIND_PULSES_KEY: &'static str = "weather.wind";
+static RAIN_PULSES_KEY: &'static str = "weather.rain";
+
+/// How a setting is stored, for the console that writes them one at a time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Bool,
+    U8,
+    U16,
+    U32,
+    I16,
+    /// UTF-8 of at most that many bytes.
+    Text(usize),
+}
+
+/// Every stored setting with the type [`get_initial_settings`] reads it as.
+static KINDS: [(&str, Kind); 58] = [
+    (WIFI_SSID_KEY, Kind::Text(32)),
+    (WIFI_PASSWORD_KEY, Kind::Text(64)),
+    (MQTT_BROKER_KEY, Kind::Text(64)),
+    (MQTT_CLIENT_ID_KEY, Kind::Text(MQTT_CLIENT_ID_LEN)),
+    (MQTT_TOPIC_KEY, Kind::Text(MQTT_TOPIC_LEN)),
+    (SYSTEM_REBOOT_TO_RECONFIGURE, Kind::Bool),
+    (DISPLAY_UNITS_KEY, Kind::U8),
+    (TIME_UTC_OFFSET_KEY, Kind::I16),
+    (NIGHT_START_KEY, Kind::U8),
+    (NIGHT_END_KEY, Kind::U8),
+    (DISPLAY_CONTRAST_KEY, Kind::U8),
+    (DISPLAY_NIGHT_KEY, Kind::U8),
+    (DISPLAY_LARGE_KEY, Kind::U8),
+    (DISPLAY_ROTATION_KEY, Kind::U8),
+    (LED_BRIGHTNESS_KEY, Kind::U8),
+    (LED_MODE_KEY, Kind::U8),
+    (LED_COUNT_KEY, Kind::U8),
+    (LED_STRIP_KEY, Kind::U8),
+    (LED_NIGHT_KEY, Kind::U8),
+    (POWER_SLEEP_KEY, Kind::U8),
+    (OTA_URL_KEY, Kind::Text(128)),
+    (OTA_CHECK_KEY, Kind::U8),
+    (SYSLOG_HOST_KEY, Kind::Text(64)),
+    (POWER_PROFILE_KEY, Kind::U8),
+    (SUPPLY_LOW_KEY, Kind::U16),
+    (GAS_HEATER_KEY, Kind::U8),
+    (MQTT_TRANSPORT_KEY, Kind::U8),
+    (PAYLOAD_FORMAT_KEY, Kind::U8),
+    (SD_FORMAT_KEY, Kind::U8),
+    (RELAY_RULE_1_KEY, Kind::Text(32)),
+    (RELAY_RULE_2_KEY, Kind::Text(32)),
+    (LORA_FREQUENCY_KEY, Kind::U32),
+    (MQTT_FIELDS_KEY, Kind::Text(128)),
+    (PRESENCE_BEACONS_KEY, Kind::Text(128)),
+    (CO2_CALIBRATION_KEY, Kind::U8),
+    (MQTT_KEEP_ALIVE_KEY, Kind::U16),
+    (ADC0_MAP_KEY, Kind::Text(32)),
+    (ADC1_MAP_KEY, Kind::Text(32)),
+    (ALERT_RULES_KEY, Kind::Text(128)),
+    (MOTION_HOLD_KEY, Kind::U16),
+    (SENSOR_WARM_UP_KEY, Kind::Text(64)),
+    (INPUT_1_KEY, Kind::Text(32)),
+    (INPUT_2_KEY, Kind::Text(32)),
+    (LOCATION_KEY, Kind::Text(32)),
+    (ROOM_KEY, Kind::Text(32)),
+    (LABELS_KEY, Kind::Text(64)),
+    (NTP_SERVERS_KEY, Kind::Text(96)),
+    (SENSOR_FILTER_KEY, Kind::Text(64)),
+    (I2C_DEBUG_KEY, Kind::U8),
+    (CALIBRATION_KEY, Kind::Text(128)),
+    (SETUP_SUFFIX_KEY, Kind::Text(16)),
+    (SETUP_PASSWORD_KEY, Kind::Text(64)),
+    (SETUP_CHANNEL_KEY, Kind::U8),
+    (WEB_TASKS_KEY, Kind::U8),
+    (WEB_HTTP_BUFFER_KEY, Kind::U16),
+    (WEB_TCP_BUFFER_KEY, Kind::U16),
+    (WIND_PULSES_KEY, Kind::Text(16)),
+    (RAIN_PULSES_KEY, Kind::Text(16)),
+];
+
+/// The type `key` is stored as, none for a key that isn't a setting.
+pub fn kind(key: &str) -> Option<Kind> {
+    KINDS
+        .iter()
+        .find(|(setting, _)| *setting == key)
+        .map(|(_, kind)| *kind)
+}
 
+#[derive(Clone)]
 pub struct OptionalSettings {
     pub wifi_ssid: Option<String<32>>,
     pub wifi_password: Option<String<64>>,
     pub mqtt_broker: Option<String<64>>,
-    pub mqtt_client_id: Option<String<32>>,
-    pub mqtt_topic: Option<String<64>>,
+    pub mqtt_client_id: Option<String<MQTT_CLIENT_ID_LEN>>,
+    pub mqtt_topic: Option<String<MQTT_TOPIC_LEN>>,
     pub reboot_to_reconfigure: Option<bool>,
+    pub units: Option<Units>,
+    pub utc_offset_min: Option<i16>,
+    pub night_start_hour: Option<u8>,
+    pub night_end_hour: Option<u8>,
+    pub display_contrast: Option<u8>,
+    pub display_night: Option<NightMode>,
+    pub display_large: Option<LargeMetric>,
+    pub display_rotation: Option<Rotation>,
+    pub led_brightness: Option<u8>,
+    pub led_mode: Option<LedMode>,
+    pub led_count: Option<u8>,
+    pub led_strip: Option<StripMode>,
+    pub led_night: Option<NightMode>,
+    pub sleep_minutes: Option<u8>,
+    pub ota_url: Option<String<128>>,
+    pub ota_check_hours: Option<u8>,
+    pub syslog_host: Option<String<64>>,
+    pub power_profile: Option<PowerProfile>,
+    pub supply_low_mv: Option<u16>,
+    pub gas_heater: Option<GasHeater>,
+    pub mqtt_transport: Option<MqttTransport>,
+    pub payload_format: Option<PayloadFormat>,
+    pub sd_format: Option<SdFormat>,
+    pub relay_rule_1: Option<String<32>>,
+    pub relay_rule_2: Option<String<32>>,
+    pub lora_frequency_khz: Option<u32>,
+    pub mqtt_fields: Option<String<128>>,
+    pub presence_beacons: Option<String<128>>,
+    pub co2_calibration: Option<Co2Calibration>,
+    pub mqtt_keep_alive_secs: Option<u16>,
+    pub adc0_map: Option<String<32>>,
+    pub adc1_map: Option<String<32>>,
+    pub alert_rules: Option<String<128>>,
+    pub motion_hold_secs: Option<u16>,
+    pub sensor_warm_up: Option<String<64>>,
+    pub input_1: Option<String<32>>,
+    pub input_2: Option<String<32>>,
+    pub location: Option<String<32>>,
+    pub room: Option<String<32>>,
+    pub labels: Option<String<64>>,
+    pub ntp_servers: Option<String<96>>,
+    pub sensor_filter: Option<String<64>>,
+    pub i2c_debug: Option<I2cDebug>,
+    pub calibration: Option<String<128>>,
+    pub setup_suffix: Option<String<16>>,
+    pub setup_password: Option<String<64>>,
+    pub setup_channel: Option<u8>,
+    pub web_tasks: Option<u8>,
+    pub web_http_buffer: Option<u16>,
+    pub web_tcp_buffer: Option<u16>,
+    pub wind_pulses: Option<String<16>>,
+    pub rain_pulses: Option<String<16>>,
 }
 
 impl OptionalSettings {
@@ -33,16 +242,607 @@ impl OptionalSettings {
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct Settings {
     pub wifi_ssid: String<32>,
     pub wifi_password: String<64>,
     pub mqtt_broker: String<64>,
-    pub mqtt_client_id: String<32>,
-    pub mqtt_topic: String<64>,
+    pub mqtt_client_id: String<MQTT_CLIENT_ID_LEN>,
+    pub mqtt_topic: String<MQTT_TOPIC_LEN>,
     pub reboot_to_reconfigure: bool,
+    #[serde(default)]
+    pub units: Units,
+    #[serde(default)]
+    pub utc_offset_min: i16,
+    #[serde(default = "default_night_start_hour")]
+    pub night_start_hour: u8,
+    #[serde(default = "default_night_end_hour")]
+    pub night_end_hour: u8,
+    #[serde(default = "default_display_contrast")]
+    pub display_contrast: u8,
+    #[serde(default)]
+    pub display_night: NightMode,
+    #[serde(default)]
+    pub display_large: LargeMetric,
+    #[serde(default)]
+    pub display_rotation: Rotation,
+    #[serde(default = "default_led_brightness")]
+    pub led_brightness: u8,
+    #[serde(default)]
+    pub led_mode: LedMode,
+    #[serde(default = "default_led_count")]
+    pub led_count: u8,
+    #[serde(default)]
+    pub led_strip: StripMode,
+    #[serde(default)]
+    pub led_night: NightMode,
+    #[serde(default)]
+    pub sleep_minutes: u8,
+    #[serde(default)]
+    pub ota_url: String<128>,
+    #[serde(default)]
+    pub ota_check_hours: u8,
+    #[serde(default)]
+    pub syslog_host: String<64>,
+    #[serde(default)]
+    pub power_profile: PowerProfile,
+    #[serde(default)]
+    pub supply_low_mv: u16,
+    #[serde(default)]
+    pub gas_heater: GasHeater,
+    #[serde(default)]
+    pub mqtt_transport: MqttTransport,
+    #[serde(default)]
+    pub payload_format: PayloadFormat,
+    #[serde(default)]
+    pub sd_format: SdFormat,
+    #[serde(default)]
+    pub relay_rule_1: String<32>,
+    #[serde(default)]
+    pub relay_rule_2: String<32>,
+    #[serde(default = "default_lora_frequency_khz")]
+    pub lora_frequency_khz: u32,
+    #[serde(default)]
+    pub mqtt_fields: String<128>,
+    #[serde(default)]
+    pub presence_beacons: String<128>,
+    #[serde(default)]
+    pub co2_calibration: Co2Calibration,
+    #[serde(default = "default_mqtt_keep_alive_secs")]
+    pub mqtt_keep_alive_secs: u16,
+    #[serde(default)]
+    pub adc0_map: String<32>,
+    #[serde(default)]
+    pub adc1_map: String<32>,
+    #[serde(default)]
+    pub alert_rules: String<128>,
+    #[serde(default = "default_motion_hold_secs")]
+    pub motion_hold_secs: u16,
+    #[serde(default)]
+    pub sensor_warm_up: String<64>,
+    #[serde(default)]
+    pub input_1: String<32>,
+    #[serde(default)]
+    pub input_2: String<32>,
+    #[serde(default)]
+    pub location: String<32>,
+    #[serde(default)]
+    pub room: String<32>,
+    #[serde(default)]
+    pub labels: String<64>,
+    #[serde(default)]
+    pub ntp_servers: String<96>,
+    #[serde(default)]
+    pub sensor_filter: String<64>,
+    #[serde(default)]
+    pub i2c_debug: I2cDebug,
+    #[serde(default)]
+    pub calibration: String<128>,
+    #[serde(default)]
+    pub setup_suffix: String<16>,
+    #[serde(default)]
+    pub setup_password: String<64>,
+    #[serde(default = "default_setup_channel")]
+    pub setup_channel: u8,
+    #[serde(default = "default_web_tasks")]
+    pub web_tasks: u8,
+    #[serde(default = "default_web_http_buffer")]
+    pub web_http_buffer: u16,
+    #[serde(default = "default_web_tcp_buffer")]
+    pub web_tcp_buffer: u16,
+    #[serde(default)]
+    pub wind_pulses: String<16>,
+    #[serde(default)]
+    pub rain_pulses: String<16>,
+}
+
+impl Settings {
+    pub fn night_schedule(&self) -> Schedule {
+        Schedule {
+            start_hour: self.night_start_hour,
+            end_hour: self.night_end_hour,
+            utc_offset_min: self.utc_offset_min,
+        }
+    }
+
+    /// Deep sleep between the samples in minutes, 0 stays awake. The
+    /// setting wins over the power profile's interval when it is set.
+    pub fn deep_sleep_minutes(&self) -> u8 {
+        match self.sleep_minutes {
+            0 => self.power_profile.preset().sleep_minutes,
+            minutes => minutes,
+        }
+    }
+}
+
+/// Metric shown in a large font on its own display page.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, defmt::Format)]
+#[serde(rename_all = "lowercase")]
+pub enum LargeMetric {
+    /// Regular pages only.
+    #[default]
+    Off,
+    /// Large pages only, one metric after another.
+    Cycle,
+    Temperature,
+    Humidity,
+    Light,
+    Pressure,
+}
+
+impl From<u8> for LargeMetric {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => LargeMetric::Cycle,
+            2 => LargeMetric::Temperature,
+            3 => LargeMetric::Humidity,
+            4 => LargeMetric::Light,
+            5 => LargeMetric::Pressure,
+            _ => LargeMetric::Off,
+        }
+    }
+}
+
+impl From<LargeMetric> for u8 {
+    fn from(value: LargeMetric) -> Self {
+        match value {
+            LargeMetric::Off => 0,
+            LargeMetric::Cycle => 1,
+            LargeMetric::Temperature => 2,
+            LargeMetric::Humidity => 3,
+            LargeMetric::Light => 4,
+            LargeMetric::Pressure => 5,
+        }
+    }
+}
+
+/// How the display is mounted. Quarter turns are only honoured by 128x64
+/// panels, the 128x32 ones are too short for a portrait layout.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, defmt::Format)]
+#[serde(rename_all = "lowercase")]
+pub enum Rotation {
+    #[default]
+    Rotate0,
+    Rotate90,
+    Rotate180,
+    Rotate270,
+}
+
+impl From<u8> for Rotation {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => Rotation::Rotate90,
+            2 => Rotation::Rotate180,
+            3 => Rotation::Rotate270,
+            _ => Rotation::Rotate0,
+        }
+    }
+}
+
+impl From<Rotation> for u8 {
+    fn from(value: Rotation) -> Self {
+        match value {
+            Rotation::Rotate0 => 0,
+            Rotation::Rotate90 => 1,
+            Rotation::Rotate180 => 2,
+            Rotation::Rotate270 => 3,
+        }
+    }
+}
+
+/// What the status LED shows once the node is up.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, defmt::Format)]
+#[serde(rename_all = "lowercase")]
+pub enum LedMode {
+    /// The decorative pattern.
+    #[default]
+    Pattern,
+    /// Color of the air quality band.
+    Air,
+}
+
+impl From<u8> for LedMode {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => LedMode::Air,
+            _ => LedMode::Pattern,
+        }
+    }
+}
+
+impl From<LedMode> for u8 {
+    fn from(value: LedMode) -> Self {
+        match value {
+            LedMode::Pattern => 0,
+            LedMode::Air => 1,
+        }
+    }
+}
+
+/// How the node trades speed for power, see [`PowerPreset`] for what each
+/// one sets.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, defmt::Format)]
+#[serde(rename_all = "lowercase")]
+pub enum PowerProfile {
+    /// Full CPU clock, the WiFi radio always on.
+    #[default]
+    Mains,
+    /// On mains, but frugal: lower CPU clock and the WiFi radio sleeping
+    /// between the beacons.
+    Frugal,
+    /// Deep sleep between the samples, the LED off and the display dimmed.
+    Battery,
+    /// Like the battery, but waking up more often to use the daytime charge.
+    Solar,
 }
 
+/// The behaviors bundled by a [`PowerProfile`].
+pub struct PowerPreset {
+    /// 80 MHz instead of the chip's maximum.
+    pub low_cpu_clock: bool,
+    /// The WiFi radio sleeps between the access point's beacons.
+    pub wifi_power_save: bool,
+    /// Deep sleep between the samples in minutes, 0 stays awake.
+    pub sleep_minutes: u8,
+    /// Upper limit of the LED brightness in percent.
+    pub led_max_brightness: u8,
+    /// Upper limit of the display contrast.
+    pub display_max_contrast: u8,
+}
+
+impl PowerProfile {
+    pub fn preset(&self) -> PowerPreset {
+        match self {
+            PowerProfile::Mains => PowerPreset {
+                low_cpu_clock: false,
+                wifi_power_save: false,
+                sleep_minutes: 0,
+                led_max_brightness: 100,
+                display_max_contrast: u8::MAX,
+            },
+            PowerProfile::Frugal => PowerPreset {
+                low_cpu_clock: true,
+                wifi_power_save: true,
+                sleep_minutes: 0,
+                led_max_brightness: 100,
+                display_max_contrast: u8::MAX,
+            },
+            PowerProfile::Battery => PowerPreset {
+                low_cpu_clock: true,
+                wifi_power_save: true,
+                sleep_minutes: 15,
+                led_max_brightness: 0,
+                display_max_contrast: 0x10,
+            },
+            PowerProfile::Solar => PowerPreset {
+                low_cpu_clock: true,
+                wifi_power_save: true,
+                sleep_minutes: 5,
+                led_max_brightness: 10,
+                display_max_contrast: 0x20,
+            },
+        }
+    }
+}
+
+impl From<u8> for PowerProfile {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => PowerProfile::Frugal,
+            2 => PowerProfile::Battery,
+            3 => PowerProfile::Solar,
+            _ => PowerProfile::Mains,
+        }
+    }
+}
+
+impl From<PowerProfile> for u8 {
+    fn from(value: PowerProfile) -> Self {
+        match value {
+            PowerProfile::Mains => 0,
+            PowerProfile::Frugal => 1,
+            PowerProfile::Battery => 2,
+            PowerProfile::Solar => 3,
+        }
+    }
+}
+
+/// What the raw register access of [`crate::i2c_debug`] may do.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, defmt::Format)]
+#[serde(rename_all = "lowercase")]
+pub enum I2cDebug {
+    #[default]
+    Off,
+    Read,
+    /// Reads and writes.
+    Write,
+}
+
+impl From<u8> for I2cDebug {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => I2cDebug::Read,
+            2 => I2cDebug::Write,
+            _ => I2cDebug::Off,
+        }
+    }
+}
+
+impl From<I2cDebug> for u8 {
+    fn from(value: I2cDebug) -> Self {
+        match value {
+            I2cDebug::Off => 0,
+            I2cDebug::Read => 1,
+            I2cDebug::Write => 2,
+        }
+    }
+}
+
+/// How the samples get to the broker.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, defmt::Format)]
+#[serde(rename_all = "lowercase")]
+pub enum MqttTransport {
+    #[default]
+    Tcp,
+    /// MQTT-SN over UDP to a gateway at the broker address, no connection to
+    /// keep up.
+    Sn,
+    /// Raw LoRa through an SX1262 instead of WiFi, see [`crate::lora`].
+    Lora,
+}
+
+impl From<u8> for MqttTransport {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => MqttTransport::Sn,
+            2 => MqttTransport::Lora,
+            _ => MqttTransport::Tcp,
+        }
+    }
+}
+
+impl From<MqttTransport> for u8 {
+    fn from(value: MqttTransport) -> Self {
+        match value {
+            MqttTransport::Tcp => 0,
+            MqttTransport::Sn => 1,
+            MqttTransport::Lora => 2,
+        }
+    }
+}
+
+/// How a sample is encoded, on MQTT as well as on `/api/latest`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, defmt::Format)]
+#[serde(rename_all = "lowercase")]
+pub enum PayloadFormat {
+    /// One flat object, the field names are the sensor readings.
+    #[default]
+    Json,
+    /// SenML (RFC 8428) records, see [`crate::senml`].
+    Senml,
+}
+
+impl From<u8> for PayloadFormat {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => PayloadFormat::Senml,
+            _ => PayloadFormat::Json,
+        }
+    }
+}
+
+impl From<PayloadFormat> for u8 {
+    fn from(value: PayloadFormat) -> Self {
+        match value {
+            PayloadFormat::Json => 0,
+            PayloadFormat::Senml => 1,
+        }
+    }
+}
+
+/// What the samples on the SD card are written as.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, defmt::Format)]
+#[serde(rename_all = "lowercase")]
+pub enum SdFormat {
+    /// One line per sample, for a spreadsheet.
+    #[default]
+    Csv,
+    /// COBS framed postcard of the sample, a third of the size. Records of
+    /// older firmware read back with [`crate::sensors::schema::decode`].
+    Postcard,
+}
+
+impl From<u8> for SdFormat {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => SdFormat::Postcard,
+            _ => SdFormat::Csv,
+        }
+    }
+}
+
+impl From<SdFormat> for u8 {
+    fn from(value: SdFormat) -> Self {
+        match value {
+            SdFormat::Csv => 0,
+            SdFormat::Postcard => 1,
+        }
+    }
+}
+
+/// What the BME680's gas heater does on a low supply voltage.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, defmt::Format)]
+#[serde(rename_all = "lowercase")]
+pub enum GasHeater {
+    #[default]
+    Always,
+    /// Off while the supply voltage is low, the heater draws the most of all
+    /// the sensors.
+    Saving,
+}
+
+impl From<u8> for GasHeater {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => GasHeater::Saving,
+            _ => GasHeater::Always,
+        }
+    }
+}
+
+impl From<GasHeater> for u8 {
+    fn from(value: GasHeater) -> Self {
+        match value {
+            GasHeater::Always => 0,
+            GasHeater::Saving => 1,
+        }
+    }
+}
+
+/// How the MH-Z19B keeps its zero point.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, defmt::Format)]
+#[serde(rename_all = "lowercase")]
+pub enum Co2Calibration {
+    /// The lowest reading of every day is taken as the outdoor 400 ppm, for
+    /// rooms that get aired daily.
+    #[default]
+    Automatic,
+    /// For greenhouses, cellars and rooms that never get down to the outdoor
+    /// level.
+    Off,
+}
+
+impl From<u8> for Co2Calibration {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => Co2Calibration::Off,
+            _ => Co2Calibration::Automatic,
+        }
+    }
+}
+
+impl From<Co2Calibration> for u8 {
+    fn from(value: Co2Calibration) -> Self {
+        match value {
+            Co2Calibration::Automatic => 0,
+            Co2Calibration::Off => 1,
+        }
+    }
+}
+
+/// What a strip of several LEDs shows.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, defmt::Format)]
+#[serde(rename_all = "lowercase")]
+pub enum StripMode {
+    /// Every LED shows the status, same as a single LED.
+    #[default]
+    Status,
+    /// One LED per subsystem: WiFi, MQTT, sensors and time.
+    Subsystems,
+    Temperature,
+    Humidity,
+    Light,
+    Pressure,
+    Air,
+}
+
+impl From<u8> for StripMode {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => StripMode::Subsystems,
+            2 => StripMode::Temperature,
+            3 => StripMode::Humidity,
+            4 => StripMode::Light,
+            5 => StripMode::Pressure,
+            6 => StripMode::Air,
+            _ => StripMode::Status,
+        }
+    }
+}
+
+impl From<StripMode> for u8 {
+    fn from(value: StripMode) -> Self {
+        match value {
+            StripMode::Status => 0,
+            StripMode::Subsystems => 1,
+            StripMode::Temperature => 2,
+            StripMode::Humidity => 3,
+            StripMode::Light => 4,
+            StripMode::Pressure => 5,
+            StripMode::Air => 6,
+        }
+    }
+}
+
+fn default_led_count() -> u8 {
+    DEFAULT_LED_COUNT
+}
+
+fn default_led_brightness() -> u8 {
+    DEFAULT_LED_BRIGHTNESS
+}
+
+fn default_display_contrast() -> u8 {
+    DEFAULT_DISPLAY_CONTRAST
+}
+
+fn default_night_start_hour() -> u8 {
+    DEFAULT_NIGHT_START_HOUR
+}
+
+fn default_night_end_hour() -> u8 {
+    DEFAULT_NIGHT_END_HOUR
+}
+
+fn default_lora_frequency_khz() -> u32 {
+    DEFAULT_LORA_FREQUENCY_KHZ
+}
+
+fn default_mqtt_keep_alive_secs() -> u16 {
+    DEFAULT_MQTT_KEEP_ALIVE_SECS
+}
+
+fn default_motion_hold_secs() -> u16 {
+    DEFAULT_MOTION_HOLD_SECS
+}
+
+fn default_setup_channel() -> u8 {
+    DEFAULT_SETUP_CHANNEL
+}
+
+fn default_web_tasks() -> u8 {
+    DEFAULT_WEB_TASKS
+}
+
+fn default_web_http_buffer() -> u16 {
+    DEFAULT_WEB_HTTP_BUFFER
+}
+
+fn default_web_tcp_buffer() -> u16 {
+    DEFAULT_WEB_TCP_BUFFER
+}
+
+#[derive(Clone)]
 pub enum SettingsEnum {
     Optional(OptionalSettings),
     FilledIn(Settings),
@@ -69,6 +869,70 @@ impl SettingsEnum {
                         mqtt_client_id,
                         mqtt_topic,
                         reboot_to_reconfigure: settings.reboot_to_reconfigure.unwrap_or(false),
+                        units: settings.units.unwrap_or_default(),
+                        utc_offset_min: settings.utc_offset_min.unwrap_or_default(),
+                        night_start_hour: settings
+                            .night_start_hour
+                            .unwrap_or(DEFAULT_NIGHT_START_HOUR),
+                        night_end_hour: settings.night_end_hour.unwrap_or(DEFAULT_NIGHT_END_HOUR),
+                        display_contrast: settings
+                            .display_contrast
+                            .unwrap_or(DEFAULT_DISPLAY_CONTRAST),
+                        display_night: settings.display_night.unwrap_or_default(),
+                        display_large: settings.display_large.unwrap_or_default(),
+                        display_rotation: settings.display_rotation.unwrap_or_default(),
+                        led_brightness: settings.led_brightness.unwrap_or(DEFAULT_LED_BRIGHTNESS),
+                        led_mode: settings.led_mode.unwrap_or_default(),
+                        led_count: settings.led_count.unwrap_or(DEFAULT_LED_COUNT),
+                        led_strip: settings.led_strip.unwrap_or_default(),
+                        led_night: settings.led_night.unwrap_or_default(),
+                        sleep_minutes: settings.sleep_minutes.unwrap_or_default(),
+                        ota_url: settings.ota_url.unwrap_or_default(),
+                        ota_check_hours: settings.ota_check_hours.unwrap_or_default(),
+                        syslog_host: settings.syslog_host.unwrap_or_default(),
+                        power_profile: settings.power_profile.unwrap_or_default(),
+                        supply_low_mv: settings.supply_low_mv.unwrap_or_default(),
+                        gas_heater: settings.gas_heater.unwrap_or_default(),
+                        mqtt_transport: settings.mqtt_transport.unwrap_or_default(),
+                        payload_format: settings.payload_format.unwrap_or_default(),
+                        sd_format: settings.sd_format.unwrap_or_default(),
+                        relay_rule_1: settings.relay_rule_1.unwrap_or_default(),
+                        relay_rule_2: settings.relay_rule_2.unwrap_or_default(),
+                        lora_frequency_khz: settings
+                            .lora_frequency_khz
+                            .unwrap_or(DEFAULT_LORA_FREQUENCY_KHZ),
+                        mqtt_fields: settings.mqtt_fields.unwrap_or_default(),
+                        presence_beacons: settings.presence_beacons.unwrap_or_default(),
+                        co2_calibration: settings.co2_calibration.unwrap_or_default(),
+                        mqtt_keep_alive_secs: settings
+                            .mqtt_keep_alive_secs
+                            .unwrap_or(DEFAULT_MQTT_KEEP_ALIVE_SECS),
+                        adc0_map: settings.adc0_map.unwrap_or_default(),
+                        adc1_map: settings.adc1_map.unwrap_or_default(),
+                        alert_rules: settings.alert_rules.unwrap_or_default(),
+                        motion_hold_secs: settings
+                            .motion_hold_secs
+                            .unwrap_or(DEFAULT_MOTION_HOLD_SECS),
+                        sensor_warm_up: settings.sensor_warm_up.unwrap_or_default(),
+                        input_1: settings.input_1.unwrap_or_default(),
+                        input_2: settings.input_2.unwrap_or_default(),
+                        location: settings.location.unwrap_or_default(),
+                        room: settings.room.unwrap_or_default(),
+                        labels: settings.labels.unwrap_or_default(),
+                        ntp_servers: settings.ntp_servers.unwrap_or_default(),
+                        sensor_filter: settings.sensor_filter.unwrap_or_default(),
+                        i2c_debug: settings.i2c_debug.unwrap_or_default(),
+                        calibration: settings.calibration.unwrap_or_default(),
+                        setup_suffix: settings.setup_suffix.unwrap_or_default(),
+                        setup_password: settings.setup_password.unwrap_or_default(),
+                        setup_channel: settings.setup_channel.unwrap_or(DEFAULT_SETUP_CHANNEL),
+                        web_tasks: settings.web_tasks.unwrap_or(DEFAULT_WEB_TASKS),
+                        web_http_buffer: settings
+                            .web_http_buffer
+                            .unwrap_or(DEFAULT_WEB_HTTP_BUFFER),
+                        web_tcp_buffer: settings.web_tcp_buffer.unwrap_or(DEFAULT_WEB_TCP_BUFFER),
+                        wind_pulses: settings.wind_pulses.unwrap_or_default(),
+                        rain_pulses: settings.rain_pulses.unwrap_or_default(),
                     });
                 }
 
@@ -81,6 +945,58 @@ impl SettingsEnum {
                 mqtt_client_id: Some(settings.mqtt_client_id),
                 mqtt_topic: Some(settings.mqtt_topic),
                 reboot_to_reconfigure: Some(settings.reboot_to_reconfigure),
+                units: Some(settings.units),
+                utc_offset_min: Some(settings.utc_offset_min),
+                night_start_hour: Some(settings.night_start_hour),
+                night_end_hour: Some(settings.night_end_hour),
+                display_contrast: Some(settings.display_contrast),
+                display_night: Some(settings.display_night),
+                display_large: Some(settings.display_large),
+                display_rotation: Some(settings.display_rotation),
+                led_brightness: Some(settings.led_brightness),
+                led_mode: Some(settings.led_mode),
+                led_count: Some(settings.led_count),
+                led_strip: Some(settings.led_strip),
+                led_night: Some(settings.led_night),
+                sleep_minutes: Some(settings.sleep_minutes),
+                ota_url: Some(settings.ota_url),
+                ota_check_hours: Some(settings.ota_check_hours),
+                syslog_host: Some(settings.syslog_host),
+                power_profile: Some(settings.power_profile),
+                supply_low_mv: Some(settings.supply_low_mv),
+                gas_heater: Some(settings.gas_heater),
+                mqtt_transport: Some(settings.mqtt_transport),
+                payload_format: Some(settings.payload_format),
+                sd_format: Some(settings.sd_format),
+                relay_rule_1: Some(settings.relay_rule_1),
+                relay_rule_2: Some(settings.relay_rule_2),
+                lora_frequency_khz: Some(settings.lora_frequency_khz),
+                mqtt_fields: Some(settings.mqtt_fields),
+                presence_beacons: Some(settings.presence_beacons),
+                co2_calibration: Some(settings.co2_calibration),
+                mqtt_keep_alive_secs: Some(settings.mqtt_keep_alive_secs),
+                adc0_map: Some(settings.adc0_map),
+                adc1_map: Some(settings.adc1_map),
+                alert_rules: Some(settings.alert_rules),
+                motion_hold_secs: Some(settings.motion_hold_secs),
+                sensor_warm_up: Some(settings.sensor_warm_up),
+                input_1: Some(settings.input_1),
+                input_2: Some(settings.input_2),
+                location: Some(settings.location),
+                room: Some(settings.room),
+                labels: Some(settings.labels),
+                ntp_servers: Some(settings.ntp_servers),
+                sensor_filter: Some(settings.sensor_filter),
+                i2c_debug: Some(settings.i2c_debug),
+                calibration: Some(settings.calibration),
+                setup_suffix: Some(settings.setup_suffix),
+                setup_password: Some(settings.setup_password),
+                setup_channel: Some(settings.setup_channel),
+                web_tasks: Some(settings.web_tasks),
+                web_http_buffer: Some(settings.web_http_buffer),
+                web_tcp_buffer: Some(settings.web_tcp_buffer),
+                wind_pulses: Some(settings.wind_pulses),
+                rain_pulses: Some(settings.rain_pulses),
             }),
         }
     }
@@ -94,6 +1010,68 @@ impl SettingsEnum {
                 mqtt_client_id: settings.mqtt_client_id.unwrap_or_default(),
                 mqtt_topic: settings.mqtt_topic.unwrap_or_default(),
                 reboot_to_reconfigure: settings.reboot_to_reconfigure.unwrap_or_default(),
+                units: settings.units.unwrap_or_default(),
+                utc_offset_min: settings.utc_offset_min.unwrap_or_default(),
+                night_start_hour: settings
+                    .night_start_hour
+                    .unwrap_or(DEFAULT_NIGHT_START_HOUR),
+                night_end_hour: settings.night_end_hour.unwrap_or(DEFAULT_NIGHT_END_HOUR),
+                display_contrast: settings
+                    .display_contrast
+                    .unwrap_or(DEFAULT_DISPLAY_CONTRAST),
+                display_night: settings.display_night.unwrap_or_default(),
+                display_large: settings.display_large.unwrap_or_default(),
+                display_rotation: settings.display_rotation.unwrap_or_default(),
+                led_brightness: settings.led_brightness.unwrap_or(DEFAULT_LED_BRIGHTNESS),
+                led_mode: settings.led_mode.unwrap_or_default(),
+                led_count: settings.led_count.unwrap_or(DEFAULT_LED_COUNT),
+                led_strip: settings.led_strip.unwrap_or_default(),
+                led_night: settings.led_night.unwrap_or_default(),
+                sleep_minutes: settings.sleep_minutes.unwrap_or_default(),
+                ota_url: settings.ota_url.unwrap_or_default(),
+                ota_check_hours: settings.ota_check_hours.unwrap_or_default(),
+                syslog_host: settings.syslog_host.unwrap_or_default(),
+                power_profile: settings.power_profile.unwrap_or_default(),
+                supply_low_mv: settings.supply_low_mv.unwrap_or_default(),
+                gas_heater: settings.gas_heater.unwrap_or_default(),
+                mqtt_transport: settings.mqtt_transport.unwrap_or_default(),
+                payload_format: settings.payload_format.unwrap_or_default(),
+                sd_format: settings.sd_format.unwrap_or_default(),
+                relay_rule_1: settings.relay_rule_1.unwrap_or_default(),
+                relay_rule_2: settings.relay_rule_2.unwrap_or_default(),
+                lora_frequency_khz: settings
+                    .lora_frequency_khz
+                    .unwrap_or(DEFAULT_LORA_FREQUENCY_KHZ),
+                mqtt_fields: settings.mqtt_fields.unwrap_or_default(),
+                presence_beacons: settings.presence_beacons.unwrap_or_default(),
+                co2_calibration: settings.co2_calibration.unwrap_or_default(),
+                mqtt_keep_alive_secs: settings
+                    .mqtt_keep_alive_secs
+                    .unwrap_or(DEFAULT_MQTT_KEEP_ALIVE_SECS),
+                adc0_map: settings.adc0_map.unwrap_or_default(),
+                adc1_map: settings.adc1_map.unwrap_or_default(),
+                alert_rules: settings.alert_rules.unwrap_or_default(),
+                motion_hold_secs: settings
+                    .motion_hold_secs
+                    .unwrap_or(DEFAULT_MOTION_HOLD_SECS),
+                sensor_warm_up: settings.sensor_warm_up.unwrap_or_default(),
+                input_1: settings.input_1.unwrap_or_default(),
+                input_2: settings.input_2.unwrap_or_default(),
+                location: settings.location.unwrap_or_default(),
+                room: settings.room.unwrap_or_default(),
+                labels: settings.labels.unwrap_or_default(),
+                ntp_servers: settings.ntp_servers.unwrap_or_default(),
+                sensor_filter: settings.sensor_filter.unwrap_or_default(),
+                i2c_debug: settings.i2c_debug.unwrap_or_default(),
+                calibration: settings.calibration.unwrap_or_default(),
+                setup_suffix: settings.setup_suffix.unwrap_or_default(),
+                setup_password: settings.setup_password.unwrap_or_default(),
+                setup_channel: settings.setup_channel.unwrap_or(DEFAULT_SETUP_CHANNEL),
+                web_tasks: settings.web_tasks.unwrap_or(DEFAULT_WEB_TASKS),
+                web_http_buffer: settings.web_http_buffer.unwrap_or(DEFAULT_WEB_HTTP_BUFFER),
+                web_tcp_buffer: settings.web_tcp_buffer.unwrap_or(DEFAULT_WEB_TCP_BUFFER),
+                wind_pulses: settings.wind_pulses.unwrap_or_default(),
+                rain_pulses: settings.rain_pulses.unwrap_or_default(),
             },
             Self::FilledIn(settings) => settings,
         }
@@ -111,6 +1089,86 @@ pub async fn get_initial_settings<'a>(
         mqtt_client_id: kv_storage::read_string(&mut tx, MQTT_CLIENT_ID_KEY).await?,
         mqtt_topic: kv_storage::read_string(&mut tx, MQTT_TOPIC_KEY).await?,
         reboot_to_reconfigure: kv_storage::read_bool(&mut tx, SYSTEM_REBOOT_TO_RECONFIGURE).await?,
+        units: kv_storage::read_u8(&mut tx, DISPLAY_UNITS_KEY)
+            .await?
+            .map(Units::from),
+        utc_offset_min: kv_storage::read_i16(&mut tx, TIME_UTC_OFFSET_KEY).await?,
+        night_start_hour: kv_storage::read_u8(&mut tx, NIGHT_START_KEY).await?,
+        night_end_hour: kv_storage::read_u8(&mut tx, NIGHT_END_KEY).await?,
+        display_contrast: kv_storage::read_u8(&mut tx, DISPLAY_CONTRAST_KEY).await?,
+        display_night: kv_storage::read_u8(&mut tx, DISPLAY_NIGHT_KEY)
+            .await?
+            .map(NightMode::from),
+        display_large: kv_storage::read_u8(&mut tx, DISPLAY_LARGE_KEY)
+            .await?
+            .map(LargeMetric::from),
+        display_rotation: kv_storage::read_u8(&mut tx, DISPLAY_ROTATION_KEY)
+            .await?
+            .map(Rotation::from),
+        led_brightness: kv_storage::read_u8(&mut tx, LED_BRIGHTNESS_KEY).await?,
+        led_mode: kv_storage::read_u8(&mut tx, LED_MODE_KEY)
+            .await?
+            .map(LedMode::from),
+        led_count: kv_storage::read_u8(&mut tx, LED_COUNT_KEY).await?,
+        led_strip: kv_storage::read_u8(&mut tx, LED_STRIP_KEY)
+            .await?
+            .map(StripMode::from),
+        led_night: kv_storage::read_u8(&mut tx, LED_NIGHT_KEY)
+            .await?
+            .map(NightMode::from),
+        sleep_minutes: kv_storage::read_u8(&mut tx, POWER_SLEEP_KEY).await?,
+        ota_url: kv_storage::read_string(&mut tx, OTA_URL_KEY).await?,
+        ota_check_hours: kv_storage::read_u8(&mut tx, OTA_CHECK_KEY).await?,
+        syslog_host: kv_storage::read_string(&mut tx, SYSLOG_HOST_KEY).await?,
+        power_profile: kv_storage::read_u8(&mut tx, POWER_PROFILE_KEY)
+            .await?
+            .map(PowerProfile::from),
+        supply_low_mv: kv_storage::read_u16(&mut tx, SUPPLY_LOW_KEY).await?,
+        gas_heater: kv_storage::read_u8(&mut tx, GAS_HEATER_KEY)
+            .await?
+            .map(GasHeater::from),
+        mqtt_transport: kv_storage::read_u8(&mut tx, MQTT_TRANSPORT_KEY)
+            .await?
+            .map(MqttTransport::from),
+        payload_format: kv_storage::read_u8(&mut tx, PAYLOAD_FORMAT_KEY)
+            .await?
+            .map(PayloadFormat::from),
+        sd_format: kv_storage::read_u8(&mut tx, SD_FORMAT_KEY)
+            .await?
+            .map(SdFormat::from),
+        relay_rule_1: kv_storage::read_string(&mut tx, RELAY_RULE_1_KEY).await?,
+        relay_rule_2: kv_storage::read_string(&mut tx, RELAY_RULE_2_KEY).await?,
+        lora_frequency_khz: kv_storage::read_u32(&mut tx, LORA_FREQUENCY_KEY).await?,
+        mqtt_fields: kv_storage::read_string(&mut tx, MQTT_FIELDS_KEY).await?,
+        presence_beacons: kv_storage::read_string(&mut tx, PRESENCE_BEACONS_KEY).await?,
+        co2_calibration: kv_storage::read_u8(&mut tx, CO2_CALIBRATION_KEY)
+            .await?
+            .map(Co2Calibration::from),
+        mqtt_keep_alive_secs: kv_storage::read_u16(&mut tx, MQTT_KEEP_ALIVE_KEY).await?,
+        adc0_map: kv_storage::read_string(&mut tx, ADC0_MAP_KEY).await?,
+        adc1_map: kv_storage::read_string(&mut tx, ADC1_MAP_KEY).await?,
+        alert_rules: kv_storage::read_string(&mut tx, ALERT_RULES_KEY).await?,
+        motion_hold_secs: kv_storage::read_u16(&mut tx, MOTION_HOLD_KEY).await?,
+        sensor_warm_up: kv_storage::read_string(&mut tx, SENSOR_WARM_UP_KEY).await?,
+        input_1: kv_storage::read_string(&mut tx, INPUT_1_KEY).await?,
+        input_2: kv_storage::read_string(&mut tx, INPUT_2_KEY).await?,
+        location: kv_storage::read_string(&mut tx, LOCATION_KEY).await?,
+        room: kv_storage::read_string(&mut tx, ROOM_KEY).await?,
+        labels: kv_storage::read_string(&mut tx, LABELS_KEY).await?,
+        ntp_servers: kv_storage::read_string(&mut tx, NTP_SERVERS_KEY).await?,
+        sensor_filter: kv_storage::read_string(&mut tx, SENSOR_FILTER_KEY).await?,
+        i2c_debug: kv_storage::read_u8(&mut tx, I2C_DEBUG_KEY)
+            .await?
+            .map(I2cDebug::from),
+        calibration: kv_storage::read_string(&mut tx, CALIBRATION_KEY).await?,
+        setup_suffix: kv_storage::read_string(&mut tx, SETUP_SUFFIX_KEY).await?,
+        setup_password: kv_storage::read_string(&mut tx, SETUP_PASSWORD_KEY).await?,
+        setup_channel: kv_storage::read_u8(&mut tx, SETUP_CHANNEL_KEY).await?,
+        web_tasks: kv_storage::read_u8(&mut tx, WEB_TASKS_KEY).await?,
+        web_http_buffer: kv_storage::read_u16(&mut tx, WEB_HTTP_BUFFER_KEY).await?,
+        web_tcp_buffer: kv_storage::read_u16(&mut tx, WEB_TCP_BUFFER_KEY).await?,
+        wind_pulses: kv_storage::read_string(&mut tx, WIND_PULSES_KEY).await?,
+        rain_pulses: kv_storage::read_string(&mut tx, RAIN_PULSES_KEY).await?,
     })
     .transmute();
 
@@ -123,15 +1181,78 @@ pub async fn save_settings(
 ) -> kv_storage::DbResult<()> {
     let mut tx = db.write_transaction().await;
 
+    // ekv takes the keys of a transaction in ascending order only.
+    kv_storage::write_string(&mut tx, ADC0_MAP_KEY, &settings.adc0_map).await?;
+    kv_storage::write_string(&mut tx, ADC1_MAP_KEY, &settings.adc1_map).await?;
+    kv_storage::write_string(&mut tx, ALERT_RULES_KEY, &settings.alert_rules).await?;
+    kv_storage::write_u8(
+        &mut tx,
+        CO2_CALIBRATION_KEY,
+        settings.co2_calibration.into(),
+    )
+    .await?;
+    kv_storage::write_u8(&mut tx, I2C_DEBUG_KEY, settings.i2c_debug.into()).await?;
+    kv_storage::write_u8(&mut tx, DISPLAY_CONTRAST_KEY, settings.display_contrast).await?;
+    kv_storage::write_u8(&mut tx, DISPLAY_LARGE_KEY, settings.display_large.into()).await?;
+    kv_storage::write_u8(&mut tx, DISPLAY_NIGHT_KEY, settings.display_night.into()).await?;
+    kv_storage::write_u8(
+        &mut tx,
+        DISPLAY_ROTATION_KEY,
+        settings.display_rotation.into(),
+    )
+    .await?;
+    kv_storage::write_u8(&mut tx, DISPLAY_UNITS_KEY, settings.units.into()).await?;
+    kv_storage::write_string(&mut tx, INPUT_1_KEY, &settings.input_1).await?;
+    kv_storage::write_string(&mut tx, INPUT_2_KEY, &settings.input_2).await?;
+    kv_storage::write_u8(&mut tx, LED_BRIGHTNESS_KEY, settings.led_brightness).await?;
+    kv_storage::write_u8(&mut tx, LED_COUNT_KEY, settings.led_count).await?;
+    kv_storage::write_u8(&mut tx, LED_MODE_KEY, settings.led_mode.into()).await?;
+    kv_storage::write_u8(&mut tx, LED_NIGHT_KEY, settings.led_night.into()).await?;
+    kv_storage::write_u8(&mut tx, LED_STRIP_KEY, settings.led_strip.into()).await?;
+    kv_storage::write_u32(&mut tx, LORA_FREQUENCY_KEY, settings.lora_frequency_khz).await?;
+    kv_storage::write_string(&mut tx, LABELS_KEY, &settings.labels).await?;
+    kv_storage::write_string(&mut tx, LOCATION_KEY, &settings.location).await?;
+    kv_storage::write_string(&mut tx, ROOM_KEY, &settings.room).await?;
+    kv_storage::write_u16(&mut tx, MOTION_HOLD_KEY, settings.motion_hold_secs).await?;
     kv_storage::write_string(&mut tx, MQTT_BROKER_KEY, &settings.mqtt_broker).await?;
     kv_storage::write_string(&mut tx, MQTT_CLIENT_ID_KEY, &settings.mqtt_client_id).await?;
+    kv_storage::write_string(&mut tx, MQTT_FIELDS_KEY, &settings.mqtt_fields).await?;
+    kv_storage::write_u16(&mut tx, MQTT_KEEP_ALIVE_KEY, settings.mqtt_keep_alive_secs).await?;
     kv_storage::write_string(&mut tx, MQTT_TOPIC_KEY, &settings.mqtt_topic).await?;
+    kv_storage::write_u8(&mut tx, MQTT_TRANSPORT_KEY, settings.mqtt_transport.into()).await?;
+    kv_storage::write_u8(&mut tx, NIGHT_END_KEY, settings.night_end_hour).await?;
+    kv_storage::write_u8(&mut tx, NIGHT_START_KEY, settings.night_start_hour).await?;
+    kv_storage::write_string(&mut tx, NTP_SERVERS_KEY, &settings.ntp_servers).await?;
+    kv_storage::write_u8(&mut tx, OTA_CHECK_KEY, settings.ota_check_hours).await?;
+    kv_storage::write_string(&mut tx, OTA_URL_KEY, &settings.ota_url).await?;
+    kv_storage::write_u8(&mut tx, PAYLOAD_FORMAT_KEY, settings.payload_format.into()).await?;
+    kv_storage::write_u8(&mut tx, GAS_HEATER_KEY, settings.gas_heater.into()).await?;
+    kv_storage::write_u16(&mut tx, SUPPLY_LOW_KEY, settings.supply_low_mv).await?;
+    kv_storage::write_u8(&mut tx, POWER_PROFILE_KEY, settings.power_profile.into()).await?;
+    kv_storage::write_u8(&mut tx, POWER_SLEEP_KEY, settings.sleep_minutes).await?;
+    kv_storage::write_string(&mut tx, PRESENCE_BEACONS_KEY, &settings.presence_beacons).await?;
+    kv_storage::write_string(&mut tx, RELAY_RULE_1_KEY, &settings.relay_rule_1).await?;
+    kv_storage::write_string(&mut tx, RELAY_RULE_2_KEY, &settings.relay_rule_2).await?;
+    kv_storage::write_u8(&mut tx, SD_FORMAT_KEY, settings.sd_format.into()).await?;
+    kv_storage::write_string(&mut tx, CALIBRATION_KEY, &settings.calibration).await?;
+    kv_storage::write_string(&mut tx, SENSOR_FILTER_KEY, &settings.sensor_filter).await?;
+    kv_storage::write_string(&mut tx, SENSOR_WARM_UP_KEY, &settings.sensor_warm_up).await?;
+    kv_storage::write_u8(&mut tx, SETUP_CHANNEL_KEY, settings.setup_channel).await?;
+    kv_storage::write_string(&mut tx, SETUP_PASSWORD_KEY, &settings.setup_password).await?;
+    kv_storage::write_string(&mut tx, SETUP_SUFFIX_KEY, &settings.setup_suffix).await?;
+    kv_storage::write_string(&mut tx, SYSLOG_HOST_KEY, &settings.syslog_host).await?;
     kv_storage::write_bool(
         &mut tx,
         SYSTEM_REBOOT_TO_RECONFIGURE,
         settings.reboot_to_reconfigure,
     )
     .await?;
+    kv_storage::write_i16(&mut tx, TIME_UTC_OFFSET_KEY, settings.utc_offset_min).await?;
+    kv_storage::write_string(&mut tx, RAIN_PULSES_KEY, &settings.rain_pulses).await?;
+    kv_storage::write_string(&mut tx, WIND_PULSES_KEY, &settings.wind_pulses).await?;
+    kv_storage::write_u16(&mut tx, WEB_HTTP_BUFFER_KEY, settings.web_http_buffer).await?;
+    kv_storage::write_u8(&mut tx, WEB_TASKS_KEY, settings.web_tasks).await?;
+    kv_storage::write_u16(&mut tx, WEB_TCP_BUFFER_KEY, settings.web_tcp_buffer).await?;
     kv_storage::write_string(&mut tx, WIFI_PASSWORD_KEY, &settings.wifi_password).await?;
     kv_storage::write_string(&mut tx, WIFI_SSID_KEY, &settings.wifi_ssid).await?;
 
@@ -140,10 +1261,52 @@ pub async fn save_settings(
     Ok(())
 }
 
+/// Stores the brightness set by a command, without touching other settings.
+pub async fn save_led_brightness(
+    db: &'static kv_storage::Db,
+    brightness: u8,
+) -> kv_storage::DbResult<()> {
+    let mut tx = db.write_transaction().await;
+    kv_storage::write_u8(&mut tx, LED_BRIGHTNESS_KEY, brightness).await?;
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Saves the corrections the `calibrate` command changed, see
+/// [`crate::sensors::calibration`].
+pub async fn save_calibration(
+    db: &'static kv_storage::Db,
+    calibration: &String<128>,
+) -> kv_storage::DbResult<()> {
+    let mut tx = db.write_transaction().await;
+    kv_storage::write_string(&mut tx, CALIBRATION_KEY, calibration).await?;
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Forgets every setting and restarts into the provisioning.
+pub async fn factory_reset(db: &'static kv_storage::Db) -> kv_storage::DbResult<()> {
+    db.format().await?;
+
+    shutdown::restart(shutdown::Reason::FactoryReset).await
+}
+
+/// Takes back a requested reconfiguration, the node runs with its settings
+/// again after the next restart.
+pub async fn cancel_reconfigure(db: &'static kv_storage::Db) -> kv_storage::DbResult<()> {
+    let mut tx = db.write_transaction().await;
+    kv_storage::write_bool(&mut tx, SYSTEM_REBOOT_TO_RECONFIGURE, false).await?;
+    tx.commit().await?;
+
+    Ok(())
+}
+
 pub async fn set_reboot(db: &'static kv_storage::Db) -> kv_storage::DbResult<()> {
     let mut tx = db.write_transaction().await;
     kv_storage::write_bool(&mut tx, SYSTEM_REBOOT_TO_RECONFIGURE, true).await?;
     tx.commit().await?;
 
-    esp_hal::system::software_reset();
+    shutdown::restart(shutdown::Reason::Reconfigure).await
 }
@@ -22,7 +22,7 @@ pub async fn run<U: UdpBind>(socket: U) -> ! {
         };
     };
 
-    let server_ip = Ipv4Addr::new(192, 168, 1, 1);
+    let server_ip = crate::wifi::SETUP_ADDRESS;
 
     let mut server = edge_dhcp::server::Server::<_, 8>::new_with_et(server_ip);
     let mut gw_buf = [Ipv4Addr::UNSPECIFIED];
@@ -0,0 +1,294 @@
+//! Heap and stack usage, sampled now and then to catch slow leaks.
+//!
+//! The embassy tasks don't have stacks of their own, their futures live in
+//! static memory. So the only stack to watch is the main one, which is
+//! painted at boot and checked for how much of the paint is left.
+//!
+//! It also keeps how long the slow parts of the loops take, min, average and
+//! max since boot, and how many samples never made it out, by why.
+//!
+//! On modules with PSRAM the heap starts there, see [`use_psram`]. The radio
+//! asks esp-alloc for internal RAM by itself, so the web templates, the log
+//! and the rest of the `alloc` users leave the internal RAM to it.
+
+use core::alloc::Layout;
+use core::cell::Cell;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use defmt::{info, warn};
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_time::{Duration, Instant, Timer};
+use esp_alloc::{HeapRegion, MemoryCapability};
+use serde::Serialize;
+
+extern crate alloc;
+
+const SAMPLE_PERIOD: Duration = Duration::from_secs(60);
+const STACK_PAINT: u32 = 0xCCCC_CCCC;
+/// Keeps the painting off the stack guard esp-hal puts near the bottom.
+const STACK_GUARD_SKIP: usize = 256;
+/// Left untouched below the painter's own frame.
+const PAINT_MARGIN: usize = 1024;
+/// The `alloc::format!` calls of the display and MQTT need about that much
+/// at once.
+const LOW_BLOCK_WARNING: u32 = 4 * 1024;
+
+static LATEST: Mutex<CriticalSectionRawMutex, Cell<Diagnostics>> =
+    Mutex::new(Cell::new(Diagnostics {
+        heap_free: 0,
+        heap_max_block: 0,
+        heap_min_free: u32::MAX,
+        internal_free: 0,
+        stack_free: 0,
+    }));
+
+static PSRAM_SIZE: AtomicU32 = AtomicU32::new(0);
+
+static TIMINGS: Mutex<CriticalSectionRawMutex, Cell<[Timing; 3]>> =
+    Mutex::new(Cell::new([Timing::EMPTY; 3]));
+
+/// Per [`DropCause`].
+static DROPPED: [AtomicU32; 4] = [const { AtomicU32::new(0) }; 4];
+
+unsafe extern "C" {
+    /// Lowest address of the main stack, from the esp-hal linker script.
+    static _stack_end_cpu0: u32;
+}
+
+#[derive(Clone, Copy, Serialize, defmt::Format)]
+pub struct Diagnostics {
+    pub heap_free: u32,
+    /// Largest allocation that would succeed.
+    pub heap_max_block: u32,
+    /// Lowest `heap_free` since boot.
+    pub heap_min_free: u32,
+    /// Part of `heap_free` in internal RAM, what the radio can get.
+    pub internal_free: u32,
+    /// Bytes of the main stack that were never used.
+    pub stack_free: u32,
+}
+
+#[derive(Clone, Copy, defmt::Format)]
+pub enum Timed {
+    /// Reading all the sensors, from the start of the cycle.
+    SensorCycle,
+    /// From handing the sample over to the broker acknowledging it.
+    MqttPublish,
+    /// Drawing a screen and flushing it to the display.
+    DisplayRefresh,
+}
+
+/// Why a sample was lost.
+#[derive(Clone, Copy, defmt::Format)]
+pub enum DropCause {
+    /// The queue of the samples waiting for the connection was full.
+    QueueFull,
+    /// Publishing failed and the sample couldn't go back to the queue, or
+    /// the transport has no queue to put it back to.
+    PublishFailed,
+    /// The payload didn't fit its buffer.
+    Overflow,
+    /// The client sent it but the connection dropped before the broker
+    /// acknowledged it, the session starts clean after that.
+    ConnectionLost,
+}
+
+/// The samples lost since boot, by cause.
+#[derive(Clone, Copy, Serialize, defmt::Format)]
+pub struct Dropped {
+    pub queue_full: u32,
+    pub publish_failed: u32,
+    pub overflow: u32,
+    pub connection_lost: u32,
+}
+
+impl Dropped {
+    pub fn total(&self) -> u32 {
+        self.queue_full + self.publish_failed + self.overflow + self.connection_lost
+    }
+}
+
+#[derive(Clone, Copy, defmt::Format)]
+pub struct Timing {
+    pub min_ms: u32,
+    pub max_ms: u32,
+    total_ms: u64,
+    count: u32,
+}
+
+impl Timing {
+    const EMPTY: Self = Self {
+        min_ms: u32::MAX,
+        max_ms: 0,
+        total_ms: 0,
+        count: 0,
+    };
+
+    pub fn avg_ms(&self) -> u32 {
+        self.total_ms.checked_div(self.count as u64).unwrap_or(0) as u32
+    }
+
+    /// `[min, avg, max]`, all zeros before the first measurement.
+    pub fn summary(&self) -> [u32; 3] {
+        if self.count == 0 {
+            return [0; 3];
+        }
+
+        [self.min_ms, self.avg_ms(), self.max_ms]
+    }
+}
+
+/// Adds a measurement of `timed` that started at `start`.
+pub fn record(timed: Timed, start: Instant) {
+    let ms = start.elapsed().as_millis().min(u32::MAX as u64) as u32;
+
+    TIMINGS.lock(|timings| {
+        let mut all = timings.get();
+        let timing = &mut all[timed as usize];
+        timing.min_ms = timing.min_ms.min(ms);
+        timing.max_ms = timing.max_ms.max(ms);
+        timing.total_ms += ms as u64;
+        timing.count += 1;
+        timings.set(all);
+    });
+}
+
+pub fn timing(timed: Timed) -> Timing {
+    TIMINGS.lock(|timings| timings.get()[timed as usize])
+}
+
+/// Counts a sample lost to `cause`.
+pub fn dropped(cause: DropCause) {
+    dropped_many(cause, 1);
+}
+
+/// Counts `samples` lost to `cause` at once.
+pub fn dropped_many(cause: DropCause, samples: u32) {
+    DROPPED[cause as usize].fetch_add(samples, Ordering::Relaxed);
+}
+
+pub fn drops() -> Dropped {
+    let count = |cause: DropCause| DROPPED[cause as usize].load(Ordering::Relaxed);
+
+    Dropped {
+        queue_full: count(DropCause::QueueFull),
+        publish_failed: count(DropCause::PublishFailed),
+        overflow: count(DropCause::Overflow),
+        connection_lost: count(DropCause::ConnectionLost),
+    }
+}
+
+/// Adds the PSRAM at `start` to the heap, nothing when `size` is 0. Has to
+/// come before the internal regions, allocations take the first region with
+/// room.
+pub fn use_psram((start, size): (*mut u8, usize)) {
+    if size == 0 {
+        return;
+    }
+
+    unsafe {
+        esp_alloc::HEAP.add_region(HeapRegion::new(
+            start,
+            size,
+            MemoryCapability::External.into(),
+        ));
+    }
+    PSRAM_SIZE.store(size as u32, Ordering::Relaxed);
+    info!("Heap: {} bytes of PSRAM", size);
+}
+
+/// Whether the heap has PSRAM, room for the bigger buffers.
+pub fn has_psram() -> bool {
+    PSRAM_SIZE.load(Ordering::Relaxed) > 0
+}
+
+/// Fills the unused part of the main stack with a pattern. Meant to be called
+/// first thing in `main`.
+#[inline(never)]
+pub fn paint_stack() {
+    let marker = 0u8;
+    let top = core::ptr::addr_of!(marker) as usize - PAINT_MARGIN;
+
+    let mut word = stack_bottom();
+    while (word as usize) < top {
+        unsafe {
+            word.write_volatile(STACK_PAINT);
+            word = word.add(1);
+        }
+    }
+}
+
+/// The most recent sample, all zeros before the first one.
+pub fn latest() -> Diagnostics {
+    LATEST.lock(|latest| latest.get())
+}
+
+#[embassy_executor::task]
+pub async fn task() -> ! {
+    loop {
+        let free = esp_alloc::HEAP.free() as u32;
+        let max_block = largest_block(free as usize) as u32;
+        let stack_free = stack_free();
+
+        let diagnostics = LATEST.lock(|latest| {
+            let diagnostics = Diagnostics {
+                heap_free: free,
+                heap_max_block: max_block,
+                heap_min_free: latest.get().heap_min_free.min(free),
+                internal_free: esp_alloc::HEAP.free_caps(MemoryCapability::Internal.into()) as u32,
+                stack_free,
+            };
+            latest.set(diagnostics);
+            diagnostics
+        });
+
+        info!("Diagnostics: {}", diagnostics);
+        if diagnostics.heap_max_block < LOW_BLOCK_WARNING {
+            warn!(
+                "Heap is fragmented, largest block {} bytes",
+                diagnostics.heap_max_block
+            );
+        }
+
+        Timer::after(SAMPLE_PERIOD).await;
+    }
+}
+
+fn stack_bottom() -> *mut u32 {
+    unsafe { (core::ptr::addr_of!(_stack_end_cpu0) as *mut u8).add(STACK_GUARD_SKIP) as *mut u32 }
+}
+
+fn stack_free() -> u32 {
+    let mut word = stack_bottom();
+    let mut free = 0;
+    unsafe {
+        while word.read_volatile() == STACK_PAINT {
+            free += 4;
+            word = word.add(1);
+        }
+    }
+
+    free
+}
+
+/// Binary search with real allocations, the heap doesn't tell it otherwise.
+fn largest_block(free: usize) -> usize {
+    let (mut low, mut high) = (0, free);
+
+    while low < high {
+        let size = (low + high + 1) / 2;
+        let Ok(layout) = Layout::from_size_align(size, 4) else {
+            break;
+        };
+
+        let ptr = unsafe { alloc::alloc::alloc(layout) };
+        if ptr.is_null() {
+            high = size - 1;
+        } else {
+            unsafe { alloc::alloc::dealloc(ptr, layout) };
+            low = size;
+        }
+    }
+
+    low
+}
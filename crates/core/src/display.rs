@@ -1,134 +1,944 @@
-use core::cell::RefCell;
+use core::sync::atomic::Ordering;
 
-use alloc::format; 
-use alloc::string::String; 
-use alloc::vec::Vec; 
-use embedded_graphics::mono_font::{self, MonoTextStyleBuilder};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use embassy_futures::select;
+use embassy_time::{Duration, Instant, Timer};
+use embedded_graphics::mono_font::{self, MonoTextStyle, MonoTextStyleBuilder};
 use embedded_graphics::pixelcolor::BinaryColor;
-use embedded_graphics::prelude::Point;
+use embedded_graphics::prelude::{Dimensions, DrawTarget, Point, Primitive, Size};
+use embedded_graphics::primitives::{Circle, Line, PrimitiveStyle, Rectangle, Triangle};
 use embedded_graphics::{Drawable, text};
-use ssd1306::mode::{BufferedGraphicsMode, DisplayConfig};
-use ssd1306::prelude::{DisplayRotation, I2CInterface};
-use ssd1306::size::DisplaySize128x32;
+use qrcodegen_no_heap::{QrCode, QrCodeEcc, Version};
 
 extern crate alloc;
-use crate::sensors;
+use crate::air_quality::{self, AirQuality};
+use crate::config::{LargeMetric, Rotation, SettingsEnum};
+use crate::diagnostics::{self, Timed};
+use crate::schedule::{NightMode, Schedule};
+use crate::units::Units;
+use crate::{alerts, board, config, inputs, mqtt, net_time, sensors, shutdown, system, wifi};
 
-struct Display<'a> {
-    display: ssd1306::Ssd1306<
-        I2CInterface<sensors::RefCellDevI2C<'a>>,
-        DisplaySize128x32,
-        BufferedGraphicsMode<DisplaySize128x32>,
-    >,
-    text_style: mono_font::MonoTextStyle<'a, BinaryColor>,
+mod history;
+#[cfg(feature = "display-sh1106")]
+mod sh1106;
+
+pub const WIDTH: u32 = 128;
+#[cfg(not(feature = "display-128x64"))]
+pub const HEIGHT: u32 = 32;
+#[cfg(feature = "display-128x64")]
+pub const HEIGHT: u32 = 64;
+
+const HEADER_HEIGHT: u32 = 8;
+const ROW_HEIGHT: u32 = 12;
+const COLUMN_WIDTH: u32 = 64;
+const STATUS_REFRESH_SECS: u64 = 5;
+/// Every page stays on the screen for this many status refreshes.
+const PAGE_REFRESHES: u32 = 2;
+/// Large enough for the setup WiFi and URL payloads.
+const QR_MAX_VERSION: Version = Version::new(3);
+const QR_BUFFER_LEN: usize = QR_MAX_VERSION.buffer_len();
+const NIGHT_CONTRAST: u8 = 0;
+/// Samples come every minute, older ones mean the sensors task is stuck or
+/// restarting and the readings shouldn't be shown as current.
+const SAMPLE_STALE_AFTER: Duration = Duration::from_secs(5 * 60);
+
+/// Display related part of the settings.
+pub struct Config {
+    pub units: Units,
+    /// The node boots into the access point setup mode, with this access
+    /// point.
+    pub setup: Option<wifi::SetupAp>,
+    /// The setup was asked for with complete settings, it can be cancelled.
+    pub reconfigure: bool,
+    pub contrast: u8,
+    pub night_mode: NightMode,
+    pub night: Schedule,
+    pub large: LargeMetric,
+    pub rotation: Rotation,
+    /// The door or a generic input is wired, they get a page.
+    pub inputs: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            units: Units::default(),
+            setup: None,
+            reconfigure: false,
+            contrast: config::DEFAULT_DISPLAY_CONTRAST,
+            night_mode: NightMode::Disabled,
+            night: Schedule {
+                start_hour: config::DEFAULT_NIGHT_START_HOUR,
+                end_hour: config::DEFAULT_NIGHT_END_HOUR,
+                utc_offset_min: 0,
+            },
+            large: LargeMetric::Off,
+            rotation: Rotation::Rotate0,
+            inputs: false,
+        }
+    }
+}
+
+impl From<&SettingsEnum> for Config {
+    fn from(settings: &SettingsEnum) -> Self {
+        let setup = match settings {
+            SettingsEnum::Optional(settings) => settings.needs_reconfiguration(),
+            SettingsEnum::FilledIn(settings) => settings.reboot_to_reconfigure,
+        };
+        let reconfigure = matches!(settings, SettingsEnum::FilledIn(_)) && setup;
+        let setup = setup.then(|| wifi::SetupAp::from_settings(settings));
+        let settings = settings.clone().to_filled_in_with_default();
+        let rotation = match settings.display_rotation {
+            Rotation::Rotate90 | Rotation::Rotate270 if HEIGHT < 64 => {
+                defmt::warn!("Display: quarter turns need a 128x64 panel, ignoring");
+                Rotation::Rotate0
+            }
+            rotation => rotation,
+        };
+        let pins = board::pins();
+
+        Self {
+            units: settings.units,
+            setup,
+            reconfigure,
+            contrast: settings
+                .display_contrast
+                .min(settings.power_profile.preset().display_max_contrast),
+            night_mode: settings.display_night,
+            night: settings.night_schedule(),
+            large: settings.display_large,
+            rotation,
+            inputs: pins.door().is_some() || pins.inputs().iter().any(Option::is_some),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum Metric {
+    Temperature,
+    Humidity,
+    Light,
+    Pressure,
+}
+
+impl Metric {
+    pub const ALL: [Metric; 4] = [
+        Metric::Temperature,
+        Metric::Humidity,
+        Metric::Light,
+        Metric::Pressure,
+    ];
+
+    fn symbol(&self) -> &'static str {
+        match self {
+            Metric::Temperature => "T",
+            Metric::Humidity => "H",
+            Metric::Light => "L",
+            Metric::Pressure => "P",
+        }
+    }
+
+    /// Picks the value of the most precise sensor measuring the metric.
+    pub fn value(&self, sample: &sensors::Sample) -> Option<f32> {
+        match self {
+            Metric::Temperature => sample.temperature(),
+            Metric::Humidity => sample.humidity(),
+            Metric::Light => sample.light(),
+            Metric::Pressure => sample.pressure(),
+        }
+    }
+
+    fn unit(&self, units: Units) -> &'static str {
+        match self {
+            Metric::Temperature => units.temperature_symbol(),
+            Metric::Humidity => "%",
+            Metric::Light => "lx",
+            Metric::Pressure => units.pressure_symbol(),
+        }
+    }
+
+    /// Converts a value in metric units into the configured ones.
+    fn convert(&self, value: f32, units: Units) -> f32 {
+        match self {
+            Metric::Temperature => units.temperature(value),
+            Metric::Pressure => units.pressure(value),
+            Metric::Humidity | Metric::Light => value,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Page {
+    Values,
+    Graph(Metric),
+    Large(Metric),
+    AirQuality,
+    Mqtt,
+    Inputs,
+}
+
+fn pages(large: LargeMetric, inputs: bool) -> Vec<Page> {
+    match large {
+        LargeMetric::Off => {
+            let mut pages = alloc::vec![
+                Page::Values,
+                Page::Graph(Metric::Temperature),
+                Page::Graph(Metric::Humidity),
+                Page::Graph(Metric::Pressure),
+                Page::AirQuality,
+                Page::Mqtt,
+            ];
+            if inputs {
+                pages.push(Page::Inputs);
+            }
+            pages
+        }
+        LargeMetric::Cycle => Metric::ALL.iter().map(|m| Page::Large(*m)).collect(),
+        LargeMetric::Temperature => alloc::vec![Page::Large(Metric::Temperature)],
+        LargeMetric::Humidity => alloc::vec![Page::Large(Metric::Humidity)],
+        LargeMetric::Light => alloc::vec![Page::Large(Metric::Light)],
+        LargeMetric::Pressure => alloc::vec![Page::Large(Metric::Pressure)],
+    }
+}
+
+/// A monochrome panel with a frame buffer in RAM, sent over the shared bus.
+pub(crate) trait Panel: DrawTarget<Color = BinaryColor> {
+    async fn flush(&mut self);
+    fn clear_buffer(&mut self);
+    async fn set_contrast(&mut self, contrast: u8);
+    async fn set_on(&mut self, on: bool);
 }
 
-impl<'a> Display<'a> {
-    pub fn new(i2c: &'a RefCell<sensors::I2C<'a>>) -> Self {
-        let interface = ssd1306::I2CDisplayInterface::new(sensors::RefCellDevice::new(i2c));
+#[cfg(not(feature = "display-sh1106"))]
+mod panel {
+    use ssd1306::mode::{BufferedGraphicsModeAsync, DisplayConfigAsync};
+    use ssd1306::prelude::{Brightness, DisplayRotation, I2CInterface};
+
+    #[cfg(not(feature = "display-128x64"))]
+    use ssd1306::size::DisplaySize128x32 as Size;
+    #[cfg(feature = "display-128x64")]
+    use ssd1306::size::DisplaySize128x64 as Size;
+
+    use crate::{config::Rotation, sensors};
+
+    pub type Ssd1306 = ssd1306::Ssd1306Async<
+        I2CInterface<sensors::I2cDevice>,
+        Size,
+        BufferedGraphicsModeAsync<Size>,
+    >;
+
+    pub async fn new(i2c: &'static sensors::I2cBus, rotation: Rotation) -> Ssd1306 {
+        let rotation = match rotation {
+            Rotation::Rotate0 => DisplayRotation::Rotate0,
+            Rotation::Rotate90 => DisplayRotation::Rotate90,
+            Rotation::Rotate180 => DisplayRotation::Rotate180,
+            Rotation::Rotate270 => DisplayRotation::Rotate270,
+        };
+        let interface = ssd1306::I2CDisplayInterface::new(sensors::I2cDevice::new(i2c));
         let mut display =
-            ssd1306::Ssd1306::new(interface, DisplaySize128x32, DisplayRotation::Rotate0)
-                .into_buffered_graphics_mode();
+            ssd1306::Ssd1306Async::new(interface, Size, rotation).into_buffered_graphics_mode();
+
+        display.init().await.unwrap();
+
+        display
+    }
+
+    impl super::Panel for Ssd1306 {
+        async fn flush(&mut self) {
+            ssd1306::Ssd1306Async::flush(self).await.ok();
+        }
+
+        fn clear_buffer(&mut self) {
+            ssd1306::Ssd1306Async::clear_buffer(self);
+        }
+
+        async fn set_contrast(&mut self, contrast: u8) {
+            self.set_brightness(Brightness::custom(0x2, contrast))
+                .await
+                .ok();
+        }
+
+        async fn set_on(&mut self, on: bool) {
+            self.set_display_on(on).await.ok();
+        }
+    }
+}
+
+#[cfg(feature = "display-sh1106")]
+mod panel {
+    use crate::{config::Rotation, sensors};
+
+    pub type Sh1106 = super::sh1106::Sh1106<sensors::I2cDevice>;
+
+    pub async fn new(i2c: &'static sensors::I2cBus, rotation: Rotation) -> Sh1106 {
+        let mut display = super::sh1106::Sh1106::new(sensors::I2cDevice::new(i2c), rotation);
+
+        if display.init().await.is_err() {
+            defmt::warn!("Display: could not initialize SH1106");
+        }
+
+        display
+    }
+
+    impl super::Panel for Sh1106 {
+        async fn flush(&mut self) {
+            super::sh1106::Sh1106::flush(self).await.ok();
+        }
+
+        fn clear_buffer(&mut self) {
+            super::sh1106::Sh1106::clear_buffer(self);
+        }
+
+        async fn set_contrast(&mut self, contrast: u8) {
+            super::sh1106::Sh1106::set_contrast(self, contrast)
+                .await
+                .ok();
+        }
+
+        async fn set_on(&mut self, on: bool) {
+            super::sh1106::Sh1106::set_on(self, on).await.ok();
+        }
+    }
+}
+
+/// Connectivity snapshot rendered in the header row.
+struct Status {
+    /// RSSI in dBm when the WiFi is connected.
+    wifi: Option<i32>,
+    mqtt: bool,
+    time_synced: bool,
+}
+
+impl Status {
+    async fn current() -> Self {
+        let wifi = wifi::CONNECTED
+            .load(Ordering::Relaxed)
+            .then(|| wifi::RSSI.load(Ordering::Relaxed));
+        let time_synced = { net_time::TIME_STATE.lock().await.now().is_some() };
 
-        display.init().unwrap();
+        Self {
+            wifi,
+            mqtt: mqtt::CONNECTED.load(Ordering::Relaxed),
+            time_synced,
+        }
+    }
+}
+
+/// Maps RSSI to the number of WiFi bars, 0 to 4.
+fn wifi_bars(rssi: i32) -> u32 {
+    match rssi {
+        -55.. => 4,
+        -67..-55 => 3,
+        -75..-67 => 2,
+        -85..-75 => 1,
+        _ => 0,
+    }
+}
 
+struct Display<P: Panel> {
+    panel: P,
+    units: Units,
+    contrast: u8,
+    night_mode: NightMode,
+    /// Power state and contrast last sent to the panel.
+    applied: Option<(bool, u8)>,
+    text_style: MonoTextStyle<'static, BinaryColor>,
+    label_style: MonoTextStyle<'static, BinaryColor>,
+    large_style: MonoTextStyle<'static, BinaryColor>,
+}
+
+impl<P: Panel> Display<P> {
+    pub fn new(panel: P, config: &Config) -> Self {
         let text_style = MonoTextStyleBuilder::new()
-            .font(&mono_font::ascii::FONT_8X13)
+            .font(&mono_font::ascii::FONT_6X10)
+            .text_color(BinaryColor::On)
+            .build();
+
+        let label_style = MonoTextStyleBuilder::new()
+            .font(&mono_font::ascii::FONT_4X6)
+            .text_color(BinaryColor::On)
+            .build();
+
+        let large_style = MonoTextStyleBuilder::new()
+            .font(&mono_font::ascii::FONT_10X20)
             .text_color(BinaryColor::On)
             .build();
 
         Self {
-            display,
+            panel,
+            units: config.units,
+            contrast: config.contrast,
+            night_mode: config.night_mode,
+            applied: None,
             text_style,
+            label_style,
+            large_style,
         }
     }
 
-    pub fn line_one(&mut self, val1: &str, val2: Option<&str>) {
-        text::Text::with_baseline(val1, Point::zero(), self.text_style, text::Baseline::Top)
-            .draw(&mut self.display)
+    fn rows(&self) -> u32 {
+        (self.panel.bounding_box().size.height - HEADER_HEIGHT) / ROW_HEIGHT
+    }
+
+    fn columns(&self) -> u32 {
+        self.panel.bounding_box().size.width / COLUMN_WIDTH
+    }
+
+    pub fn text(&mut self, row: u32, column: u32, value: &str) {
+        let position = Point::new(
+            (column * COLUMN_WIDTH) as i32,
+            (HEADER_HEIGHT + row * ROW_HEIGHT) as i32,
+        );
+
+        text::Text::with_baseline(value, position, self.text_style, text::Baseline::Top)
+            .draw(&mut self.panel)
             .ok();
+    }
 
-        val2.map(|val| {
-            text::Text::with_baseline(val, Point::new(64, 0), self.text_style, text::Baseline::Top)
-                .draw(&mut self.display)
-                .ok()
-        });
+    /// Draws free-standing text with its top left corner at `position`.
+    fn text_at(&mut self, position: Point, value: &str) {
+        text::Text::with_baseline(value, position, self.text_style, text::Baseline::Top)
+            .draw(&mut self.panel)
+            .ok();
+    }
+
+    /// Draws the QR code of `data` at the left edge, as big as the panel
+    /// height allows, with the caption lines to the right of it.
+    ///
+    /// The code is drawn inverted (lit quiet zone, dark modules) so that it
+    /// looks like a printed one to phone cameras.
+    pub fn qr_code(&mut self, data: &str, caption: &[&str]) {
+        let mut temp = [0u8; QR_BUFFER_LEN];
+        let mut out = [0u8; QR_BUFFER_LEN];
+
+        let Ok(qr) = QrCode::encode_text(
+            data,
+            &mut temp,
+            &mut out,
+            QrCodeEcc::Low,
+            Version::MIN,
+            QR_MAX_VERSION,
+            None,
+            true,
+        ) else {
+            self.text_at(Point::zero(), "QR too long");
+            return;
+        };
+
+        let height = self.panel.bounding_box().size.height as i32;
+        let modules = qr.size() + 2;
+        let scale = (height / modules).max(1);
+        let top = (height - modules * scale) / 2;
+
+        Rectangle::new(
+            Point::new(0, top),
+            Size::new((modules * scale) as u32, (modules * scale) as u32),
+        )
+        .into_styled(PrimitiveStyle::with_fill(BinaryColor::On))
+        .draw(&mut self.panel)
+        .ok();
+
+        let dark = PrimitiveStyle::with_fill(BinaryColor::Off);
+        for y in 0..qr.size() {
+            for x in 0..qr.size() {
+                if qr.get_module(x, y) {
+                    Rectangle::new(
+                        Point::new((x + 1) * scale, top + (y + 1) * scale),
+                        Size::new(scale as u32, scale as u32),
+                    )
+                    .into_styled(dark)
+                    .draw(&mut self.panel)
+                    .ok();
+                }
+            }
+        }
+
+        let left = modules * scale + 4;
+        for (line, text) in caption.iter().enumerate() {
+            self.text_at(Point::new(left, line as i32 * ROW_HEIGHT as i32), text);
+        }
+    }
+
+    /// Shows the setup access point SSID, address and the number of stations
+    /// connected to it.
+    pub fn setup_status(&mut self, ssid: &str) {
+        let clients = wifi::SETUP_CLIENTS.load(Ordering::Relaxed);
+
+        self.text_at(Point::new(0, 0), &format!("AP {}", ssid));
+        self.text_at(
+            Point::new(0, ROW_HEIGHT as i32),
+            &format!("IP {}", wifi::SETUP_ADDRESS),
+        );
+        self.text_at(
+            Point::new(0, 2 * ROW_HEIGHT as i32),
+            &format!("Clients {}", clients),
+        );
     }
 
-    pub fn line_two(&mut self, val1: &str, val2: Option<&str>) {
+    /// Says the setup was asked for and can be cancelled on the settings
+    /// page.
+    pub fn reconfigure_notice(&mut self) {
+        self.text_at(Point::new(0, 0), "Reconfiguring");
+        self.text_at(Point::new(0, ROW_HEIGHT as i32), "Cancel on the");
+        self.text_at(Point::new(0, 2 * ROW_HEIGHT as i32), "settings page");
+    }
+
+    /// Shows the broker, whether it's connected, how long ago a sample last
+    /// got out and how many are waiting.
+    pub fn mqtt(&mut self, link: &mqtt::Link) {
+        let broker = link
+            .broker
+            .map(|broker| format!("MQTT {}", broker))
+            .unwrap_or_else(|| String::from("MQTT off"));
+        let state = if link.connected {
+            "Connected"
+        } else {
+            "Disconnected"
+        };
+        let last = link
+            .last_publish
+            .map(|at| age(at.elapsed()))
+            .unwrap_or_else(|| String::from("never"));
+
+        self.text_at(Point::new(0, 0), &broker);
+        self.text_at(Point::new(0, ROW_HEIGHT as i32), state);
+        self.text_at(
+            Point::new(0, 2 * ROW_HEIGHT as i32),
+            &format!("Last {} Queue {}", last, link.queued),
+        );
+    }
+
+    /// Lists the watched inputs with their state, a row each.
+    pub fn inputs(&mut self, inputs: &[inputs::Watched]) {
+        for (row, input) in inputs.iter().enumerate() {
+            let state = if input.active { "active" } else { "idle" };
+            self.text_at(
+                Point::new(0, row as i32 * ROW_HEIGHT as i32),
+                &format!("{} {}", input.name, state),
+            );
+        }
+    }
+
+    /// Shows a single metric in the large font, centered below its symbol
+    /// and unit.
+    pub fn large(&mut self, metric: Metric, value: Option<f32>) {
+        let size = self.panel.bounding_box().size;
+        let value = value
+            .map(|v| format!("{:.1}", metric.convert(v, self.units)))
+            .unwrap_or_else(|| String::from("---"));
+
         text::Text::with_baseline(
-            val1,
-            Point::new(0, 16),
-            self.text_style,
+            &format!("{} {}", metric.symbol(), metric.unit(self.units)),
+            Point::new(0, 0),
+            self.label_style,
             text::Baseline::Top,
         )
-        .draw(&mut self.display)
+        .draw(&mut self.panel)
+        .ok();
+
+        text::Text::with_text_style(
+            &value,
+            Point::new(
+                size.width as i32 / 2,
+                (HEADER_HEIGHT + (size.height - HEADER_HEIGHT) / 2) as i32,
+            ),
+            self.large_style,
+            text::TextStyleBuilder::new()
+                .alignment(text::Alignment::Center)
+                .baseline(text::Baseline::Middle)
+                .build(),
+        )
+        .draw(&mut self.panel)
         .ok();
+    }
+
+    /// Replaces the readings with the fault's code and hint.
+    pub fn fault(&mut self, fault: system::Fault) {
+        self.text(0, 0, &format!("ERROR {}", fault.code()));
+        self.text_at(
+            Point::new(0, (HEADER_HEIGHT + ROW_HEIGHT) as i32),
+            fault.hint(),
+        );
+    }
+
+    /// Replaces the readings with the alert's metric and how its reading
+    /// compares to the level that raised it.
+    pub fn alert(&mut self, alert: &alerts::State) {
+        let value = alert
+            .value
+            .map(|value| format!("{:.1}", value))
+            .unwrap_or_else(|| String::from("---"));
+        let comparison = if alert.above { ">" } else { "<" };
+
+        self.text(0, 0, &format!("ALERT {}", alert.metric.name()));
+        self.text_at(
+            Point::new(0, (HEADER_HEIGHT + ROW_HEIGHT) as i32),
+            &format!("{} {} {:.1}", value, comparison, alert.threshold),
+        );
+    }
+
+    /// Lays the values out in a grid, row by row, filling the cells without a
+    /// value with dashes.
+    pub fn values(&mut self, values: &[String]) {
+        let columns = self.columns();
 
-        val2.map(|val| {
-            text::Text::with_baseline(
-                val,
-                Point::new(64, 16),
-                self.text_style,
-                text::Baseline::Top,
+        for row in 0..self.rows() {
+            for column in 0..columns {
+                let value = values
+                    .get((row * columns + column) as usize)
+                    .map(|v| v.as_str())
+                    .unwrap_or("---");
+                self.text(row, column, value);
+            }
+        }
+    }
+
+    /// Shows the air quality category with an arrow pointing where the score
+    /// has been heading.
+    pub fn air_quality(&mut self, score: u32, quality: AirQuality, trend: Option<history::Trend>) {
+        self.text(0, 0, &format!("AIQ {}", score));
+        self.text(0, 1, quality.label());
+
+        let size = self.panel.bounding_box().size;
+        let x = size.width as i32 - 10;
+        let y = (HEADER_HEIGHT + 2) as i32;
+        let arrow = match trend {
+            Some(history::Trend::Rising) => Triangle::new(
+                Point::new(x + 4, y),
+                Point::new(x, y + 8),
+                Point::new(x + 8, y + 8),
+            ),
+            Some(history::Trend::Falling) => Triangle::new(
+                Point::new(x, y),
+                Point::new(x + 8, y),
+                Point::new(x + 4, y + 8),
+            ),
+            Some(history::Trend::Steady) | None => return,
+        };
+
+        arrow
+            .into_styled(PrimitiveStyle::with_fill(BinaryColor::On))
+            .draw(&mut self.panel)
+            .ok();
+    }
+
+    /// Draws the metric's history as a line graph below its current value
+    /// and range.
+    pub fn sparkline(&mut self, metric: Metric, series: &history::Series) {
+        let Some(last) = series.recent() else {
+            self.text(0, 0, metric.symbol());
+            self.text(0, 1, "no data");
+            return;
+        };
+
+        let units = self.units;
+        let last = metric.convert(*last, units);
+        let (min, max) = series
+            .oldest_ordered()
+            .map(|v| metric.convert(*v, units))
+            .fold((f32::MAX, f32::MIN), |(min, max), v| {
+                (min.min(v), max.max(v))
+            });
+
+        self.text(0, 0, &format!("{} {:4.1}", metric.symbol(), last));
+        self.text(0, 1, &format!("{:.0}..{:.0}", min, max));
+
+        let size = self.panel.bounding_box().size;
+        let top = HEADER_HEIGHT + ROW_HEIGHT;
+        if series.len() < 2 || size.height <= top + 1 {
+            return;
+        }
+
+        let height = (size.height - top - 1) as f32;
+        let range = if max - min > f32::EPSILON {
+            max - min
+        } else {
+            1.0
+        };
+        let step = (size.width - 1) as f32 / (history::LENGTH - 1) as f32;
+
+        let points = series.oldest_ordered().enumerate().map(|(i, v)| {
+            let v = metric.convert(*v, units);
+            Point::new(
+                (i as f32 * step) as i32,
+                (top as f32 + height - (v - min) / range * height) as i32,
             )
-            .draw(&mut self.display)
-            .ok()
         });
+
+        let style = PrimitiveStyle::with_stroke(BinaryColor::On, 1);
+        let mut previous: Option<Point> = None;
+        for point in points {
+            if let Some(previous) = previous {
+                Line::new(previous, point)
+                    .into_styled(style)
+                    .draw(&mut self.panel)
+                    .ok();
+            }
+            previous = Some(point);
+        }
+    }
+
+    /// Draws the WiFi bars, the MQTT and the time sync indicators into the
+    /// header row.
+    pub fn header(&mut self, status: &Status) {
+        let on = PrimitiveStyle::with_fill(BinaryColor::On);
+        let outline = PrimitiveStyle::with_stroke(BinaryColor::On, 1);
+
+        match status.wifi {
+            Some(rssi) => {
+                let bars = wifi_bars(rssi);
+                for bar in 0..4 {
+                    let height = 2 * (bar + 1) - 1;
+                    let rect = Rectangle::new(
+                        Point::new(3 * bar as i32, (HEADER_HEIGHT - 1 - height) as i32),
+                        Size::new(2, height),
+                    );
+                    let style = if bar < bars { on } else { outline };
+                    rect.into_styled(style).draw(&mut self.panel).ok();
+                }
+            }
+            None => {
+                Line::new(Point::new(0, 0), Point::new(6, 6))
+                    .into_styled(outline)
+                    .draw(&mut self.panel)
+                    .ok();
+                Line::new(Point::new(0, 6), Point::new(6, 0))
+                    .into_styled(outline)
+                    .draw(&mut self.panel)
+                    .ok();
+            }
+        }
+
+        self.indicator(20, "MQ", status.mqtt);
+        self.indicator(44, "NTP", status.time_synced);
     }
 
-    pub fn flush(&mut self) {
-        self.display.flush().ok();
+    fn indicator(&mut self, x: i32, label: &str, active: bool) {
+        let label_end = text::Text::with_baseline(
+            label,
+            Point::new(x, 1),
+            self.label_style,
+            text::Baseline::Top,
+        )
+        .draw(&mut self.panel)
+        .unwrap_or(Point::new(x, 0));
+
+        let style = if active {
+            PrimitiveStyle::with_fill(BinaryColor::On)
+        } else {
+            PrimitiveStyle::with_stroke(BinaryColor::On, 1)
+        };
+
+        Circle::new(Point::new(label_end.x + 2, 1), 6)
+            .into_styled(style)
+            .draw(&mut self.panel)
+            .ok();
+    }
+
+    /// Dims or turns the panel off during the night, according to the night
+    /// mode. Only talks to the panel when something changes.
+    pub async fn apply_night(&mut self, night: bool) {
+        let (on, contrast) = match (night, self.night_mode) {
+            (true, NightMode::Off) => (false, NIGHT_CONTRAST),
+            (true, NightMode::Dim) => (true, NIGHT_CONTRAST),
+            _ => (true, self.contrast),
+        };
+
+        if self.applied != Some((on, contrast)) {
+            self.panel.set_contrast(contrast).await;
+            self.panel.set_on(on).await;
+            self.applied = Some((on, contrast));
+        }
+    }
+
+    pub async fn flush(&mut self) {
+        self.panel.flush().await;
     }
 
     pub fn clear_buffer(&mut self) {
-        self.display.clear_buffer();
+        self.panel.clear_buffer();
     }
 }
 
-pub async fn run(i2c: &'static RefCell<sensors::I2C<'static>>) {
-    let mut display = Display::new(i2c);
+#[embassy_executor::task]
+pub async fn task(i2c: &'static sensors::I2cBus, config: Config) {
+    run(i2c, config).await;
+}
+
+pub async fn run(i2c: &'static sensors::I2cBus, config: Config) {
+    let mut display = Display::new(panel::new(i2c, config.rotation).await, &config);
+    display.apply_night(false).await;
+
+    let mut stop = shutdown::STOP.receiver().unwrap();
+    shutdown::register(shutdown::Participant::Display);
+
+    let showing = async {
+        if let Some(setup_ap) = &config.setup {
+            run_setup(&mut display, setup_ap, config.reconfigure).await;
+        }
+
+        run_values(&mut display, &config).await
+    };
+    let reason = match select::select(showing, stop.changed()).await {
+        select::Either::First(never) => never,
+        select::Either::Second(reason) => reason,
+    };
+
+    display.apply_night(false).await;
+    display.clear_buffer();
+    display.text(0, 0, "Restarting");
+    display.text(0, 1, reason.label());
+    display.flush().await;
+    shutdown::finished(shutdown::Participant::Display);
+}
+
+/// Pages through the values, graphs and the faults, or shows the first active
+/// alert.
+async fn run_values<P: Panel>(display: &mut Display<P>, config: &Config) -> ! {
+    display.text(0, 0, "Loading");
+    display.flush().await;
 
-    display.line_one("Loading", None);
-    display.flush();
+    let pages = pages(config.large, config.inputs);
+    let mut samples = sensors::LATEST.receiver().unwrap();
+    let mut latest: Option<sensors::Sample> = samples.try_get();
+    let mut received = Instant::now();
+    let mut history = history::History::new();
+    let mut refreshes: u32 = 0;
+    let mut faults = system::FAULT.receiver().unwrap();
+    let mut fault: Option<(system::Fault, Instant)> = None;
 
     loop {
-        let sample = sensors::LATEST_SAMPLE.wait().await;
-        let mut values: Vec<String> = Vec::new();
-
-        sample
-            .temp_sht40
-            .or_else(|| sample.temp_bmp390)
-            .or_else(|| sample.temperature)
-            .inspect(|val| values.push(format!("T {:4.2}", val)));
-        sample
-            .hum_sht40
-            .or_else(|| sample.humidity)
-            .inspect(|val| values.push(format!("H {:4.2}", val)));
-        sample
-            .lux_veml7700
-            .or_else(|| sample.lux_bh1750)
-            .inspect(|val| values.push(format!("L {:4.2}", val)));
-        sample
-            .press_bmp390
-            .or_else(|| sample.pressure)
-            .inspect(|val| values.push(format!("P {:4.2}", val)));
+        let refresh = Timer::after_secs(STATUS_REFRESH_SECS);
 
-        display.clear_buffer();
-        display.flush();
+        match select::select3(samples.changed(), faults.changed(), refresh).await {
+            select::Either3::First(sample) => {
+                history.record(&sample);
+                latest = Some(sample);
+                received = Instant::now();
+            }
+            select::Either3::Second(new_fault) => fault = Some((new_fault, Instant::now())),
+            select::Either3::Third(_) => refreshes = refreshes.wrapping_add(1),
+        }
 
-        if !values.is_empty() {
-            display.line_one(values[0].as_str(), values.get(1).map(|v| v.as_str()));
-        } else {
-            display.line_one("---", Some("---"));
+        if latest.is_some() && received.elapsed() > SAMPLE_STALE_AFTER {
+            defmt::warn!("Display: no new samples, hiding stale values");
+            latest = None;
         }
 
-        if values.len() > 2 {
-            display.line_two(values[2].as_str(), values.get(3).map(|v| v.as_str()));
-        } else {
-            display.line_two("---", Some("---"));
+        if let Some((current, since)) = fault
+            && current.is_cleared(since)
+        {
+            fault = None;
         }
 
-        display.flush();
+        let started = Instant::now();
+        let night = config.night_mode != NightMode::Disabled && config.night.is_active().await;
+        display.apply_night(night).await;
+
+        let page = pages[(refreshes / PAGE_REFRESHES) as usize % pages.len()];
+        let status = Status::current().await;
+        let alert = alerts::active();
+
+        display.clear_buffer();
+        match (fault, alert, page) {
+            (Some((fault, _)), _, _) => {
+                display.header(&status);
+                display.fault(fault);
+            }
+            (None, Some(alert), _) => {
+                display.header(&status);
+                display.alert(&alert);
+            }
+            (None, None, Page::Values) => {
+                display.header(&status);
+                let values = latest
+                    .as_ref()
+                    .map(|sample| sample_values(sample, config.units))
+                    .unwrap_or_default();
+                display.values(&values);
+            }
+            (None, None, Page::Graph(metric)) => {
+                display.header(&status);
+                display.sparkline(metric, history.series(metric));
+            }
+            (None, None, Page::AirQuality) => {
+                display.header(&status);
+                match latest.as_ref().and_then(air_quality::from_sample) {
+                    Some((score, quality)) => {
+                        display.air_quality(score, quality, history.score_trend())
+                    }
+                    None => {
+                        display.text(0, 0, "AIQ");
+                        display.text(0, 1, "no data");
+                    }
+                }
+            }
+            (None, None, Page::Mqtt) => display.mqtt(&mqtt::link().await),
+            (None, None, Page::Inputs) => display.inputs(&inputs::watched()),
+            (None, None, Page::Large(metric)) => {
+                let value = latest.as_ref().and_then(|sample| metric.value(sample));
+                display.large(metric, value);
+            }
+        }
+        display.flush().await;
+        diagnostics::record(Timed::DisplayRefresh, started);
     }
 }
+
+/// Cycles through the access point status, the QR code to join the setup
+/// access point and the one to open the configuration page. A requested
+/// `reconfigure` gets a page of its own, saying how to get out of it.
+async fn run_setup<P: Panel>(
+    display: &mut Display<P>,
+    setup_ap: &wifi::SetupAp,
+    reconfigure: bool,
+) -> ! {
+    let wifi_qr = setup_ap.qr_code();
+    let url = format!("http://{}/", wifi::SETUP_ADDRESS);
+
+    loop {
+        display.clear_buffer();
+        display.setup_status(&setup_ap.ssid);
+        display.flush().await;
+        Timer::after_secs(STATUS_REFRESH_SECS * PAGE_REFRESHES as u64).await;
+
+        display.clear_buffer();
+        display.qr_code(&wifi_qr, &["1. Join", "WiFi", setup_ap.ssid.as_str()]);
+        display.flush().await;
+        Timer::after_secs(STATUS_REFRESH_SECS * PAGE_REFRESHES as u64).await;
+
+        display.clear_buffer();
+        display.qr_code(&url, &["2. Open", "settings"]);
+        display.flush().await;
+        Timer::after_secs(STATUS_REFRESH_SECS * PAGE_REFRESHES as u64).await;
+
+        if reconfigure {
+            display.clear_buffer();
+            display.reconfigure_notice();
+            display.flush().await;
+            Timer::after_secs(STATUS_REFRESH_SECS * PAGE_REFRESHES as u64).await;
+        }
+    }
+}
+
+/// Short enough for a line next to the queue length.
+fn age(elapsed: Duration) -> String {
+    match elapsed.as_secs() {
+        secs @ ..120 => format!("{}s", secs),
+        secs @ ..7200 => format!("{}m", secs / 60),
+        secs => format!("{}h", secs / 3600),
+    }
+}
+
+fn sample_values(sample: &sensors::Sample, units: Units) -> Vec<String> {
+    Metric::ALL
+        .iter()
+        .filter_map(|metric| {
+            metric
+                .value(sample)
+                .map(|val| format!("{} {:4.2}", metric.symbol(), metric.convert(val, units)))
+        })
+        .collect()
+}
@@ -0,0 +1,85 @@
+//! Short in-RAM history of the displayed metrics, used for the sparklines and
+//! the air quality trend.
+
+use embassy_time::{Duration, Instant};
+use heapless::HistoryBuf;
+
+use super::Metric;
+use crate::{air_quality, sensors};
+
+/// One point every 5 minutes...
+pub const RESOLUTION: Duration = Duration::from_secs(5 * 60);
+/// ...for the last 2 hours.
+pub const LENGTH: usize = 24;
+
+pub type Series = HistoryBuf<f32, LENGTH>;
+
+/// Points compared to tell the air quality trend, 30 minutes.
+const TREND_POINTS: usize = 6;
+/// Score change below this is not a trend.
+const TREND_THRESHOLD: i32 = 10;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Trend {
+    Rising,
+    Steady,
+    Falling,
+}
+
+pub struct History {
+    series: [Series; Metric::ALL.len()],
+    scores: HistoryBuf<u32, LENGTH>,
+    last_write: Option<Instant>,
+}
+
+impl History {
+    pub const fn new() -> Self {
+        Self {
+            series: [const { HistoryBuf::new() }; Metric::ALL.len()],
+            scores: HistoryBuf::new(),
+            last_write: None,
+        }
+    }
+
+    /// Stores the sample's values, unless the last point was recorded less
+    /// than [`RESOLUTION`] ago.
+    pub fn record(&mut self, sample: &sensors::Sample) {
+        if let Some(last_write) = self.last_write
+            && last_write.elapsed() < RESOLUTION
+        {
+            return;
+        }
+
+        self.last_write = Some(Instant::now());
+
+        for metric in Metric::ALL {
+            if let Some(value) = metric.value(sample) {
+                self.series[metric as usize].write(value);
+            }
+        }
+
+        if let Some((score, _)) = air_quality::from_sample(sample) {
+            self.scores.write(score);
+        }
+    }
+
+    pub fn series(&self, metric: Metric) -> &Series {
+        &self.series[metric as usize]
+    }
+
+    /// Direction of the air quality score over the last [`TREND_POINTS`].
+    /// Higher scores are worse.
+    pub fn score_trend(&self) -> Option<Trend> {
+        let recent = *self.scores.recent()? as i32;
+        let earlier = *self
+            .scores
+            .oldest_ordered()
+            .nth(self.scores.len().saturating_sub(TREND_POINTS))? as i32;
+
+        Some(match recent - earlier {
+            change if change >= TREND_THRESHOLD => Trend::Rising,
+            change if change <= -TREND_THRESHOLD => Trend::Falling,
+            _ => Trend::Steady,
+        })
+    }
+}
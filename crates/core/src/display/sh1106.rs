@@ -0,0 +1,157 @@
+//! Minimal buffered driver for SH1106 based panels.
+//!
+//! The SH1106 accepts the same commands as the SSD1306 for everything we need,
+//! but its RAM is 132 columns wide (so the 128 pixel panel sits at a column
+//! offset) and it only supports page addressing.
+//!
+//! Half turns are done by the controller itself, quarter turns are done while
+//! drawing into the buffer.
+
+use embedded_graphics::Pixel;
+use embedded_graphics::pixelcolor::BinaryColor;
+use embedded_graphics::prelude::{DrawTarget, OriginDimensions, Size};
+use embedded_hal_async::i2c::I2c;
+
+use super::{HEIGHT, WIDTH};
+use crate::config::Rotation;
+
+const ADDRESS: u8 = 0x3C;
+const COLUMN_OFFSET: u8 = 2;
+const PAGES: usize = HEIGHT as usize / 8;
+
+const CONTROL_COMMAND: u8 = 0x00;
+const CONTROL_DATA: u8 = 0x40;
+
+pub struct Sh1106<I> {
+    i2c: I,
+    rotation: Rotation,
+    buffer: [u8; WIDTH as usize * PAGES],
+}
+
+impl<I: I2c> Sh1106<I> {
+    pub fn new(i2c: I, rotation: Rotation) -> Self {
+        Self {
+            i2c,
+            rotation,
+            buffer: [0; WIDTH as usize * PAGES],
+        }
+    }
+
+    pub async fn init(&mut self) -> Result<(), I::Error> {
+        let com_pins = if HEIGHT == 64 { 0x12 } else { 0x02 };
+        let flipped = matches!(self.rotation, Rotation::Rotate180 | Rotation::Rotate270);
+
+        self.commands(&[
+            0xAE, // display off
+            0xD5,
+            0x80, // clock divide ratio / oscillator frequency
+            0xA8,
+            (HEIGHT - 1) as u8, // multiplex ratio
+            0xD3,
+            0x00, // display offset
+            0x40, // display start line
+            0xAD,
+            0x8B,                              // DC-DC converter on
+            if flipped { 0xA0 } else { 0xA1 }, // segment remap
+            if flipped { 0xC0 } else { 0xC8 }, // COM output scan direction
+            0xDA,
+            com_pins, // COM pins hardware configuration
+            0x81,
+            0x80, // contrast
+            0xD9,
+            0x22, // pre-charge period
+            0xDB,
+            0x35, // VCOM deselect level
+            0xA4, // display follows RAM content
+            0xA6, // normal, not inverted
+            0xAF, // display on
+        ])
+        .await
+    }
+
+    pub async fn flush(&mut self) -> Result<(), I::Error> {
+        let mut data = [0u8; WIDTH as usize + 1];
+        data[0] = CONTROL_DATA;
+
+        for page in 0..PAGES {
+            self.commands(&[
+                0xB0 | page as u8,
+                COLUMN_OFFSET & 0x0F,
+                0x10 | (COLUMN_OFFSET >> 4),
+            ])
+            .await?;
+
+            let start = page * WIDTH as usize;
+            data[1..].copy_from_slice(&self.buffer[start..start + WIDTH as usize]);
+            self.i2c.write(ADDRESS, &data).await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn set_contrast(&mut self, contrast: u8) -> Result<(), I::Error> {
+        self.commands(&[0x81, contrast]).await
+    }
+
+    pub async fn set_on(&mut self, on: bool) -> Result<(), I::Error> {
+        self.commands(&[if on { 0xAF } else { 0xAE }]).await
+    }
+
+    pub fn clear_buffer(&mut self) {
+        self.buffer.fill(0);
+    }
+
+    async fn commands(&mut self, commands: &[u8]) -> Result<(), I::Error> {
+        for command in commands {
+            self.i2c
+                .write(ADDRESS, &[CONTROL_COMMAND, *command])
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<I> OriginDimensions for Sh1106<I> {
+    fn size(&self) -> Size {
+        match self.rotation {
+            Rotation::Rotate0 | Rotation::Rotate180 => Size::new(WIDTH, HEIGHT),
+            Rotation::Rotate90 | Rotation::Rotate270 => Size::new(HEIGHT, WIDTH),
+        }
+    }
+}
+
+impl<I> DrawTarget for Sh1106<I> {
+    type Color = BinaryColor;
+    type Error = core::convert::Infallible;
+
+    fn draw_iter<P>(&mut self, pixels: P) -> Result<(), Self::Error>
+    where
+        P: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(point, color) in pixels {
+            let Ok((x, y)) = <(u32, u32)>::try_from(point) else {
+                continue;
+            };
+            let (x, y) = match self.rotation {
+                Rotation::Rotate0 | Rotation::Rotate180 => (x, y),
+                Rotation::Rotate90 | Rotation::Rotate270 if x < HEIGHT => (y, HEIGHT - 1 - x),
+                Rotation::Rotate90 | Rotation::Rotate270 => continue,
+            };
+
+            if x >= WIDTH || y >= HEIGHT {
+                continue;
+            }
+
+            let index = (x + (y / 8) * WIDTH) as usize;
+            let bit = 1 << (y % 8);
+
+            match color {
+                BinaryColor::On => self.buffer[index] |= bit,
+                BinaryColor::Off => self.buffer[index] &= !bit,
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,225 @@
+//! What happened to the node lately, kept in the key-value storage across
+//! reboots for when a node in the field misbehaves and nobody was watching
+//! the syslog: the boots, the panics and brownouts, the WiFi and MQTT going
+//! up and down, the updates and the settings changes.
+//!
+//! The entries are small and fixed size, a slot key each. The slots form a
+//! ring of [`CAPACITY`], the next entry overwrites the oldest one. Readable on
+//! `/api/events` and published on `<topic>/eventlog` when the `eventlog`
+//! command asks for it.
+
+use core::fmt::Write;
+
+use defmt::{info, warn};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::mutex::Mutex;
+use embassy_time::Instant;
+use heapless::{Deque, String, Vec};
+use serde::Serialize;
+use serde::ser::{SerializeTuple, Serializer};
+
+use crate::kv_storage::{self, Db, DbResult};
+use crate::net_time;
+
+pub const CAPACITY: usize = 32;
+const QUEUE_LEN: usize = 8;
+/// The time as little endian seconds and the kind.
+const ENTRY_LEN: usize = 5;
+
+/// Entries written so far, the next slot is this modulo [`CAPACITY`]. Sorts
+/// after the slots `evlog.00` to `evlog.31`, they are written together.
+static NEXT_KEY: &str = "evlog.next";
+
+static PENDING: Channel<CriticalSectionRawMutex, (Kind, Instant), QUEUE_LEN> = Channel::new();
+/// Loaded by the first one to get to it, [`task`] or [`append`].
+static RING: Mutex<CriticalSectionRawMutex, Option<Ring>> = Mutex::new(None);
+
+#[derive(Clone, Copy, PartialEq, Serialize, defmt::Format)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    Boot = 0,
+    /// The previous run ended in a panic.
+    Panic = 1,
+    /// The previous run ended in a brownout.
+    Brownout = 2,
+    WifiUp = 3,
+    WifiDown = 4,
+    MqttUp = 5,
+    MqttDown = 6,
+    /// A new image runs for the first time.
+    OtaInstalled = 7,
+    /// The new image connected and is kept.
+    OtaConfirmed = 8,
+    ConfigChanged = 9,
+}
+
+impl TryFrom<u8> for Kind {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0 => Self::Boot,
+            1 => Self::Panic,
+            2 => Self::Brownout,
+            3 => Self::WifiUp,
+            4 => Self::WifiDown,
+            5 => Self::MqttUp,
+            6 => Self::MqttDown,
+            7 => Self::OtaInstalled,
+            8 => Self::OtaConfirmed,
+            9 => Self::ConfigChanged,
+            _ => return Err(()),
+        })
+    }
+}
+
+/// Serialized as `[timestamp, kind]`, so the whole ring fits an MQTT
+/// payload.
+#[derive(Clone, Copy, defmt::Format)]
+pub struct Entry {
+    /// Unix time, or the uptime in seconds when the clock wasn't synced.
+    pub timestamp: u32,
+    pub kind: Kind,
+}
+
+impl Entry {
+    fn to_bytes(self) -> [u8; ENTRY_LEN] {
+        let mut bytes = [0u8; ENTRY_LEN];
+        bytes[..4].copy_from_slice(&self.timestamp.to_le_bytes());
+        bytes[4] = self.kind as u8;
+
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let bytes: &[u8; ENTRY_LEN] = bytes.try_into().ok()?;
+
+        Some(Self {
+            timestamp: u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            kind: Kind::try_from(bytes[4]).ok()?,
+        })
+    }
+}
+
+impl Serialize for Entry {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut tuple = serializer.serialize_tuple(2)?;
+        tuple.serialize_element(&self.timestamp)?;
+        tuple.serialize_element(&self.kind)?;
+
+        tuple.end()
+    }
+}
+
+struct Ring {
+    entries: Deque<Entry, CAPACITY>,
+    next: u32,
+}
+
+/// Queues `kind` for [`task`] to write, stamped with the time of the call.
+/// Some get lost when many come at once.
+pub fn record(kind: Kind) {
+    if PENDING.try_send((kind, Instant::now())).is_err() {
+        warn!("Event log: queue full, {} dropped", kind);
+    }
+}
+
+/// Writes `kind` right away, for the changes a reboot follows.
+pub async fn append(db: &'static Db, kind: Kind) {
+    append_at(db, kind, Instant::now()).await;
+}
+
+/// The entries, oldest first.
+pub async fn entries() -> Vec<Entry, CAPACITY> {
+    match RING.lock().await.as_ref() {
+        Some(ring) => ring.entries.iter().copied().collect(),
+        None => Vec::new(),
+    }
+}
+
+/// Loads the ring and writes the recorded entries to it.
+#[embassy_executor::task]
+pub async fn task(db: &'static Db) -> ! {
+    load_once(db, &mut *RING.lock().await).await;
+
+    loop {
+        let (kind, at) = PENDING.receive().await;
+        append_at(db, kind, at).await;
+    }
+}
+
+async fn append_at(db: &'static Db, kind: Kind, at: Instant) {
+    let now = { net_time::TIME_STATE.lock().await.now_or_uptime() };
+    let entry = Entry {
+        timestamp: now.saturating_sub(at.elapsed().as_secs() as u32),
+        kind,
+    };
+
+    let mut ring = RING.lock().await;
+    let ring = load_once(db, &mut ring).await;
+    if let Err(err) = save(db, ring.next, entry).await {
+        warn!("Event log: could not write {}: {:?}", kind, err);
+        return;
+    }
+
+    if ring.entries.is_full() {
+        ring.entries.pop_front();
+    }
+    ring.entries.push_back(entry).ok();
+    ring.next = ring.next.wrapping_add(1);
+}
+
+fn slot_key(slot: u32) -> String<12> {
+    let mut key = String::new();
+    write!(key, "evlog.{:02}", slot % CAPACITY as u32).ok();
+
+    key
+}
+
+async fn load_once<'a>(db: &'static Db, ring: &'a mut Option<Ring>) -> &'a mut Ring {
+    let loaded = match ring.take() {
+        Some(loaded) => loaded,
+        None => {
+            let loaded = load(db).await.unwrap_or_else(|err| {
+                warn!("Event log: could not load: {:?}", err);
+                Ring {
+                    entries: Deque::new(),
+                    next: 0,
+                }
+            });
+            info!("Event log: {} entries", loaded.entries.len());
+            loaded
+        }
+    };
+
+    ring.insert(loaded)
+}
+
+async fn load(db: &'static Db) -> DbResult<Ring> {
+    let mut tx = db.read_transaction().await;
+    let next = kv_storage::read_u32(&mut tx, NEXT_KEY)
+        .await?
+        .unwrap_or_default();
+
+    let mut entries = Deque::new();
+    let oldest = next.saturating_sub(CAPACITY as u32);
+    for slot in oldest..next {
+        let mut buf = [0u8; ENTRY_LEN];
+        let len = kv_storage::read_bytes(&mut tx, &slot_key(slot), &mut buf).await?;
+        if let Some(entry) = len.and_then(|len| Entry::from_bytes(&buf[..len])) {
+            entries.push_back(entry).ok();
+        }
+    }
+
+    Ok(Ring { entries, next })
+}
+
+async fn save(db: &'static Db, slot: u32, entry: Entry) -> DbResult<()> {
+    let mut tx = db.write_transaction().await;
+    kv_storage::write_bytes(&mut tx, &slot_key(slot), &entry.to_bytes()).await?;
+    kv_storage::write_u32(&mut tx, NEXT_KEY, slot.wrapping_add(1)).await?;
+    tx.commit().await?;
+
+    Ok(())
+}
@@ -0,0 +1,227 @@
+//! Changes worth publishing right away instead of with the next sample: a
+//! door opened, motion, the CO2 crossing [`CO2_HIGH_PPM`] and the air
+//! quality moving to another band.
+//!
+//! Events go to `<topic>/event` as soon as the MQTT task picks them up. A
+//! token bucket keeps a flapping contact from flooding the broker: up to
+//! [`BURST`] events at once, then one per [`REFILL`]. The ones over the limit
+//! are dropped and the next event published tells how many.
+//!
+//! Needs MQTT over TCP, nothing is raised before [`task`] runs.
+
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use defmt::warn;
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_sync::channel::Channel;
+use embassy_time::{Duration, Instant};
+use serde::Serialize;
+use serde::ser::{SerializeMap, Serializer};
+
+use crate::air_quality::{self, AirQuality};
+use crate::sensors::Sample;
+use crate::{inputs, net_time};
+
+const BURST: u32 = 5;
+const REFILL: Duration = Duration::from_secs(10);
+const QUEUE_LEN: usize = 4;
+
+/// Above that the room needs airing, [`CO2_LOW_PPM`] is where it's back
+/// to fine, so a reading around the threshold doesn't raise it every sample.
+const CO2_HIGH_PPM: u16 = 1000;
+const CO2_LOW_PPM: u16 = 900;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static RAISED: Channel<CriticalSectionRawMutex, Raised, QUEUE_LEN> = Channel::new();
+static LIMITER: Mutex<CriticalSectionRawMutex, Cell<Limiter>> = Mutex::new(Cell::new(Limiter {
+    tokens: BURST,
+    refilled: Instant::from_ticks(0),
+    dropped: 0,
+}));
+static LEVELS: Mutex<CriticalSectionRawMutex, Cell<Levels>> = Mutex::new(Cell::new(Levels {
+    co2_high: false,
+    quality: None,
+}));
+
+#[derive(Clone, Copy, defmt::Format)]
+pub enum Event {
+    /// The door or the motion input changed.
+    Input(inputs::Event),
+    Co2 {
+        ppm: u16,
+        high: bool,
+    },
+    AirQuality {
+        score: u32,
+        quality: AirQuality,
+    },
+}
+
+impl Event {
+    fn name(&self) -> &'static str {
+        match self {
+            Event::Input(input) if input.id == inputs::DOOR => "door",
+            Event::Input(input) if input.id == inputs::MOTION => "motion",
+            Event::Input(_) => "input",
+            Event::Co2 { .. } => "co2",
+            Event::AirQuality { .. } => "aiq",
+        }
+    }
+}
+
+/// An event as it's published, e.g. `{"ts":1700000000,"event":"door",
+/// "active":true}`.
+pub struct Raised {
+    event: Event,
+    timestamp: u32,
+    /// Events the limit dropped before this one.
+    dropped: u16,
+}
+
+impl Serialize for Raised {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+
+        map.serialize_entry("ts", &self.timestamp)?;
+        map.serialize_entry("event", self.event.name())?;
+        match self.event {
+            Event::Input(input) => map.serialize_entry("active", &input.active)?,
+            Event::Co2 { ppm, high } => {
+                map.serialize_entry("ppm", &ppm)?;
+                map.serialize_entry("high", &high)?;
+            }
+            Event::AirQuality { score, quality } => {
+                map.serialize_entry("score", &score)?;
+                map.serialize_entry("quality", quality.label())?;
+            }
+        }
+        if self.dropped > 0 {
+            map.serialize_entry("dropped", &self.dropped)?;
+        }
+
+        map.end()
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Limiter {
+    tokens: u32,
+    refilled: Instant,
+    /// Since the last event let through.
+    dropped: u16,
+}
+
+impl Limiter {
+    /// Takes a token and gives the events dropped before it, none when the
+    /// bucket is empty.
+    fn take(&mut self, now: Instant) -> Option<u16> {
+        let refills = ((now - self.refilled).as_ticks() / REFILL.as_ticks()) as u32;
+        self.tokens = (self.tokens + refills).min(BURST);
+        self.refilled = if self.tokens == BURST {
+            now
+        } else {
+            self.refilled + REFILL * refills
+        };
+
+        if self.tokens == 0 {
+            self.dropped = self.dropped.saturating_add(1);
+            return None;
+        }
+
+        self.tokens -= 1;
+        Some(core::mem::take(&mut self.dropped))
+    }
+}
+
+/// What the last sample was, to tell the changes.
+#[derive(Clone, Copy)]
+struct Levels {
+    co2_high: bool,
+    quality: Option<AirQuality>,
+}
+
+/// Queues `event` for the MQTT task, unless it's over the limit.
+pub async fn raise(event: Event) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let now = Instant::now();
+    let taken = LIMITER.lock(|limiter| {
+        let mut current = limiter.get();
+        let taken = current.take(now);
+        limiter.set(current);
+        taken
+    });
+    let Some(dropped) = taken else {
+        warn!("Events: over the limit, {} dropped", event);
+        return;
+    };
+
+    let timestamp = { net_time::TIME_STATE.lock().await.now_or_uptime() };
+    let raised = Raised {
+        event,
+        timestamp,
+        dropped,
+    };
+    if RAISED.try_send(raised).is_err() {
+        warn!("Events: queue full, {} dropped", event);
+    }
+}
+
+/// Raises the CO2 and the air quality changes of a new sample.
+pub async fn sample(sample: &Sample) {
+    let co2 = sample.co2();
+    let quality = air_quality::from_sample(sample);
+
+    let (levels, previous) = LEVELS.lock(|levels| {
+        let previous = levels.get();
+        let mut current = previous;
+        match co2 {
+            Some(ppm) if ppm >= CO2_HIGH_PPM => current.co2_high = true,
+            Some(ppm) if ppm <= CO2_LOW_PPM => current.co2_high = false,
+            _ => {}
+        }
+        current.quality = quality.map(|(_, quality)| quality).or(previous.quality);
+        levels.set(current);
+
+        (current, previous)
+    });
+
+    if let Some(ppm) = co2
+        && levels.co2_high != previous.co2_high
+    {
+        raise(Event::Co2 {
+            ppm,
+            high: levels.co2_high,
+        })
+        .await;
+    }
+
+    // The first band is where the node starts, not a change.
+    if let (Some((score, quality)), Some(before)) = (quality, previous.quality)
+        && quality != before
+    {
+        raise(Event::AirQuality { score, quality }).await;
+    }
+}
+
+/// The next event to publish.
+pub async fn next() -> Raised {
+    RAISED.receive().await
+}
+
+/// Raises the changes of the door and the motion inputs.
+#[embassy_executor::task]
+pub async fn task() -> ! {
+    ENABLED.store(true, Ordering::Relaxed);
+    let mut events = inputs::subscribe();
+
+    loop {
+        let event = events.next_message_pure().await;
+        if [inputs::DOOR, inputs::MOTION].contains(&event.id) {
+            raise(Event::Input(event)).await;
+        }
+    }
+}
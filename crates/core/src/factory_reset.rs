@@ -0,0 +1,58 @@
+//! Holding the BOOT button wipes the settings and restarts into the
+//! provisioning, for when neither the web page nor MQTT can be reached.
+//! Letting go of it halfway through the countdown restarts into the safe mode
+//! instead.
+
+use defmt::{error, info, warn};
+use embassy_time::{Duration, with_timeout};
+
+use crate::inputs::{self, BUTTON};
+use crate::{config, kv_storage, led, system};
+
+/// How long the button has to be held.
+const HOLD_SECS: u8 = 10;
+/// Released after at least that long, the node restarts into the safe mode.
+const SAFE_MODE_SECS: u8 = 3;
+
+#[embassy_executor::task]
+pub async fn task(db: &'static kv_storage::Db) -> ! {
+    let mut events = inputs::subscribe();
+
+    loop {
+        inputs::wait_for(&mut events, BUTTON, true).await;
+        info!("Factory reset: button held");
+
+        let mut seconds_left = HOLD_SECS;
+        while seconds_left > 0 {
+            led::countdown(Some(seconds_left));
+
+            if with_timeout(
+                Duration::from_secs(1),
+                inputs::wait_for(&mut events, BUTTON, false),
+            )
+            .await
+            .is_ok()
+            {
+                break;
+            }
+
+            seconds_left -= 1;
+        }
+
+        led::countdown(None);
+
+        if seconds_left > 0 {
+            if HOLD_SECS - seconds_left >= SAFE_MODE_SECS {
+                system::reboot_to_safe_mode().await;
+            }
+
+            info!("Factory reset: button released, cancelled");
+            continue;
+        }
+
+        warn!("Factory reset: wiping the settings");
+        if let Err(err) = config::factory_reset(db).await {
+            error!("Factory reset failed: {:?}", err);
+        }
+    }
+}
@@ -0,0 +1,174 @@
+//! Raw register access on the I2C bus, to see what a misbehaving sensor on a
+//! node in the field says without a special firmware build.
+//!
+//! Off unless the settings allow it, and writes need their own permission.
+//! The `i2cget <address> <register> [length]` and `i2cset <address>
+//! <register> <value>` commands answer on `<topic>/i2c`, `/api/i2c` takes
+//! the same as a form. Every access goes to the syslog.
+
+use core::cell::Cell;
+
+use defmt::warn;
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_sync::signal::Signal;
+use heapless::Vec;
+use serde::{Deserialize, Serialize};
+
+use crate::config::I2cDebug;
+use crate::sensors::I2cBus;
+use crate::syslog::{self, Severity};
+
+/// Bytes a read returns at most.
+pub const MAX_LEN: usize = 16;
+
+static ACCESS: Mutex<CriticalSectionRawMutex, Cell<I2cDebug>> =
+    Mutex::new(Cell::new(I2cDebug::Off));
+static BUS: Mutex<CriticalSectionRawMutex, Cell<Option<&'static I2cBus>>> =
+    Mutex::new(Cell::new(None));
+static REPLY: Signal<CriticalSectionRawMutex, Reply> = Signal::new();
+
+/// A read of `len` bytes from `register`, or a write of `value` to it.
+#[derive(Clone, Copy, Deserialize, defmt::Format)]
+pub struct Request {
+    pub address: u8,
+    pub register: u8,
+    #[serde(default = "one")]
+    pub len: u8,
+    #[serde(default)]
+    pub value: Option<u8>,
+}
+
+fn one() -> u8 {
+    1
+}
+
+impl Request {
+    /// The arguments of `i2cget`, `<address> <register> [length]`, in hex
+    /// with `0x` or decimal.
+    pub fn parse_get(args: &str) -> Option<Self> {
+        let mut args = args.split_whitespace().map(parse_number);
+        let (address, register) = (args.next()??, args.next()??);
+        let len = args.next().unwrap_or(Some(1))?;
+
+        args.next().is_none().then_some(Self {
+            address,
+            register,
+            len,
+            value: None,
+        })
+    }
+
+    /// The arguments of `i2cset`, `<address> <register> <value>`.
+    pub fn parse_set(args: &str) -> Option<Self> {
+        let mut args = args.split_whitespace().map(parse_number);
+        let (address, register, value) = (args.next()??, args.next()??, args.next()??);
+
+        args.next().is_none().then_some(Self {
+            address,
+            register,
+            len: 0,
+            value: Some(value),
+        })
+    }
+}
+
+fn parse_number(text: &str) -> Option<u8> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+/// What came of a [`Request`], the bytes read or why there are none.
+#[derive(Clone, Serialize)]
+pub struct Reply {
+    pub address: u8,
+    pub register: u8,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub data: Vec<u8, MAX_LEN>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<&'static str>,
+}
+
+/// Sets what the settings allow, at startup.
+pub fn set_access(access: I2cDebug) {
+    ACCESS.lock(|current| current.set(access));
+}
+
+/// Hands the sensor bus over, once it is up.
+pub fn set_bus(bus: &'static I2cBus) {
+    BUS.lock(|current| current.set(Some(bus)));
+}
+
+/// Runs `request` for the MQTT command, the reply comes from [`reply`].
+pub async fn request(request: Request) {
+    REPLY.signal(run(request).await);
+}
+
+/// Waits for the reply to the last [`request`].
+pub async fn reply() -> Reply {
+    REPLY.wait().await
+}
+
+/// Runs `request` once the sensors and the display are done with the bus.
+pub async fn run(request: Request) -> Reply {
+    let mut reply = Reply {
+        address: request.address,
+        register: request.register,
+        data: Vec::new(),
+        error: None,
+    };
+    reply.error = access(&request, &mut reply.data).await.err();
+    if let Some(error) = reply.error {
+        warn!(
+            "I2C debug: 0x{:02x} register 0x{:02x}: {}",
+            request.address, request.register, error
+        );
+    }
+
+    reply
+}
+
+async fn access(request: &Request, data: &mut Vec<u8, MAX_LEN>) -> Result<(), &'static str> {
+    let allowed = ACCESS.lock(|access| access.get());
+    match (allowed, request.value) {
+        (I2cDebug::Off, _) => return Err("disabled"),
+        (I2cDebug::Read, Some(_)) => return Err("writes disabled"),
+        _ => {}
+    }
+
+    let bus = BUS.lock(|bus| bus.get()).ok_or("no bus")?;
+    let mut bus = bus.lock().await;
+
+    match request.value {
+        Some(value) => {
+            syslog::log(
+                Severity::Warning,
+                format_args!(
+                    "I2C debug: writing 0x{:02x} to 0x{:02x} register 0x{:02x}",
+                    value, request.address, request.register
+                ),
+            );
+            bus.write_async(request.address, &[request.register, value])
+                .await
+                .map_err(|_| "no acknowledgement")
+        }
+        None => {
+            let len = request.len as usize;
+            if len == 0 || len > MAX_LEN {
+                return Err("bad length");
+            }
+            syslog::log(
+                Severity::Notice,
+                format_args!(
+                    "I2C debug: reading {} bytes from 0x{:02x} register 0x{:02x}",
+                    len, request.address, request.register
+                ),
+            );
+            data.resize_default(len).ok();
+            bus.write_read_async(request.address, &[request.register], data)
+                .await
+                .map_err(|_| "no acknowledgement")
+        }
+    }
+}
@@ -0,0 +1,245 @@
+//! Debounced digital inputs: the button, and the motion sensors, reed
+//! contacts and pulse counters that get wired to the free pins.
+//!
+//! Each input has a task that sleeps on the pin interrupt and reads the pin
+//! again once it settled. Inputs in [`Mode::Events`] publish an [`Event`] to
+//! every subscriber when they turn active or inactive, inputs in
+//! [`Mode::Pulses`] only count the times they turned active.
+//!
+//! The state of the door and of the [`GENERIC`] inputs is also kept, for the
+//! MQTT task to publish and the display to show, see [`watch`].
+
+use core::cell::Cell;
+use core::sync::atomic::{AtomicU8, AtomicU32, Ordering};
+
+use defmt::info;
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_sync::pubsub::{PubSubChannel, Subscriber};
+use embassy_time::{Duration, Timer};
+use esp_hal::gpio::{Input, InputConfig, Level, Pull};
+
+use crate::board;
+
+pub const MAX_INPUTS: usize = 7;
+/// The factory reset, the events and the occupancy, plus whatever watches the
+/// other inputs.
+const SUBSCRIBERS: usize = 4;
+const QUEUE_LEN: usize = 8;
+
+/// Index of an input, below [`MAX_INPUTS`].
+pub type Id = usize;
+
+pub const BUTTON: Id = 0;
+pub const DOOR: Id = 1;
+pub const MOTION: Id = 2;
+/// `input1` and `input2` of the board pins.
+pub const GENERIC: [Id; 2] = [3, 4];
+/// The anemometer and the rain gauge, see [`crate::sensors::weather`].
+pub const WIND: Id = 5;
+pub const RAIN: Id = 6;
+
+static EVENTS: PubSubChannel<CriticalSectionRawMutex, Event, QUEUE_LEN, SUBSCRIBERS, 1> =
+    PubSubChannel::new();
+static PULSES: [AtomicU32; MAX_INPUTS] = [const { AtomicU32::new(0) }; MAX_INPUTS];
+
+/// A bit per input, see [`watch`].
+static WATCHED: AtomicU8 = AtomicU8::new(0);
+static ACTIVE: AtomicU8 = AtomicU8::new(0);
+static CHANGED: AtomicU8 = AtomicU8::new(0);
+static NAMES: Mutex<CriticalSectionRawMutex, Cell<[&'static str; MAX_INPUTS]>> =
+    Mutex::new(Cell::new([""; MAX_INPUTS]));
+
+pub type Events = Subscriber<'static, CriticalSectionRawMutex, Event, QUEUE_LEN, SUBSCRIBERS, 1>;
+
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub struct Event {
+    pub id: Id,
+    pub active: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub enum Mode {
+    Events,
+    Pulses,
+}
+
+#[derive(Clone, Copy)]
+pub struct Config {
+    pub pull: Pull,
+    /// The level the input is active at.
+    pub active: Level,
+    /// How long the pin has to settle after an edge.
+    pub debounce: Duration,
+    pub mode: Mode,
+}
+
+impl Config {
+    /// A button to ground, like BOOT.
+    pub const BUTTON: Self = Self {
+        pull: Pull::Up,
+        active: Level::Low,
+        debounce: Duration::from_millis(30),
+        mode: Mode::Events,
+    };
+
+    /// A reed contact to ground, active while the door is open and the
+    /// contact with it.
+    pub const DOOR: Self = Self {
+        pull: Pull::Up,
+        active: Level::High,
+        debounce: Duration::from_millis(50),
+        mode: Mode::Events,
+    };
+
+    /// A PIR sensor, its output goes high for a few seconds on motion.
+    pub const MOTION: Self = Self {
+        pull: Pull::Down,
+        active: Level::High,
+        debounce: Duration::from_millis(50),
+        mode: Mode::Events,
+    };
+
+    /// The reed contact of an anemometer, to ground. A short debounce, the
+    /// cups turn a few times a second in a strong wind.
+    pub const WIND: Self = Self {
+        pull: Pull::Up,
+        active: Level::Low,
+        debounce: Duration::from_millis(2),
+        mode: Mode::Pulses,
+    };
+
+    /// The reed contact of a tipping-bucket rain gauge, to ground. It
+    /// bounces as the bucket tips over.
+    pub const RAIN: Self = Self {
+        pull: Pull::Up,
+        active: Level::Low,
+        debounce: Duration::from_millis(50),
+        mode: Mode::Pulses,
+    };
+
+    /// A contact or a binary sensor on one of the [`GENERIC`] inputs, active
+    /// at the high level.
+    pub const GENERIC: Self = Self {
+        pull: Pull::Up,
+        active: Level::High,
+        debounce: Duration::from_millis(50),
+        mode: Mode::Events,
+    };
+
+    /// The setting of a generic input: its name, then optionally the pull,
+    /// `up`, `down` or `none`, and the debounce in milliseconds, like
+    /// `window up 100`. They default to the ones of [`Self::GENERIC`].
+    pub fn parse(text: &str) -> Option<(Self, &str)> {
+        let mut words = text.split_whitespace();
+        let mut config = Self::GENERIC;
+
+        let name = words.next()?;
+        if let Some(pull) = words.next() {
+            config.pull = match pull {
+                "up" => Pull::Up,
+                "down" => Pull::Down,
+                "none" => Pull::None,
+                _ => return None,
+            };
+        }
+        if let Some(debounce) = words.next() {
+            config.debounce = Duration::from_millis(debounce.parse().ok()?);
+        }
+
+        words.next().is_none().then_some((config, name))
+    }
+}
+
+/// An input [`watch`] keeps the state of.
+#[derive(Clone, Copy, defmt::Format)]
+pub struct Watched {
+    pub id: Id,
+    pub name: &'static str,
+    pub active: bool,
+}
+
+/// Every [`Event`] from now on. Events published while the subscriber lags
+/// behind by more than a few are lost.
+pub fn subscribe() -> Events {
+    EVENTS.subscriber().unwrap()
+}
+
+/// Waits for input `id` to turn `active`, or inactive.
+pub async fn wait_for(events: &mut Events, id: Id, active: bool) {
+    while events.next_message_pure().await != (Event { id, active }) {}
+}
+
+/// Keeps the state of input `id` under `name`, before its [`task`] starts.
+pub fn watch(id: Id, name: &'static str) {
+    NAMES.lock(|names| {
+        let mut current = names.get();
+        current[id] = name;
+        names.set(current);
+    });
+    WATCHED.fetch_or(1 << id, Ordering::Relaxed);
+}
+
+/// The watched inputs, in the order of their ids.
+pub fn watched() -> heapless::Vec<Watched, MAX_INPUTS> {
+    let watched = WATCHED.load(Ordering::Relaxed);
+    let active = ACTIVE.load(Ordering::Relaxed);
+    let names = NAMES.lock(|names| names.get());
+
+    (0..MAX_INPUTS)
+        .filter(|id| watched & (1 << id) != 0)
+        .map(|id| Watched {
+            id,
+            name: names[id],
+            active: active & (1 << id) != 0,
+        })
+        .collect()
+}
+
+/// A bit per watched input, by its id, that changed since the last call.
+pub fn take_changed() -> u8 {
+    CHANGED.swap(0, Ordering::Relaxed) & WATCHED.load(Ordering::Relaxed)
+}
+
+/// Active edges of input `id` since the last call.
+pub fn take_pulses(id: Id) -> u32 {
+    PULSES[id].swap(0, Ordering::Relaxed)
+}
+
+#[embassy_executor::task(pool_size = MAX_INPUTS)]
+pub async fn task(id: Id, pin: u8, config: Config) -> ! {
+    let mut input = Input::new(
+        unsafe { board::pin(pin) },
+        InputConfig::default().with_pull(config.pull),
+    );
+    let mut active = input.level() == config.active;
+    set_active(id, active);
+    info!("Input {}: GPIO{}, {}", id, pin, config.mode);
+
+    loop {
+        input.wait_for_any_edge().await;
+        Timer::after(config.debounce).await;
+
+        if (input.level() == config.active) == active {
+            continue;
+        }
+        active = !active;
+        set_active(id, active);
+
+        match config.mode {
+            Mode::Events => EVENTS.publish_immediate(Event { id, active }),
+            Mode::Pulses if active => {
+                PULSES[id].fetch_add(1, Ordering::Relaxed);
+            }
+            Mode::Pulses => {}
+        }
+    }
+}
+
+fn set_active(id: Id, active: bool) {
+    if active {
+        ACTIVE.fetch_or(1 << id, Ordering::Relaxed);
+    } else {
+        ACTIVE.fetch_and(!(1 << id), Ordering::Relaxed);
+    }
+    CHANGED.fetch_or(1 << id, Ordering::Relaxed);
+}
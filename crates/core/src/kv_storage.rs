@@ -6,6 +6,8 @@ use esp_storage::{FlashStorage, FlashStorageError};
 use heapless::String;
 use static_cell::StaticCell;
 
+use crate::self_test::{self, Check, Outcome};
+
 pub type Mutex = embassy_sync_06::blocking_mutex::raw::CriticalSectionRawMutex;
 pub type Flash = EspFlash<FlashStorage<'static>>;
 pub type Db = Database<Flash, Mutex>;
@@ -204,6 +206,8 @@ impl From<heapless::CapacityError> for DbError {
 //     }
 // }
 
+/// The storage sits outside the partition table and is accessed raw, so it
+/// stays readable with flash encryption on, and unencrypted too.
 pub async fn init(
     flash: esp_hal::peripherals::FLASH<'static>,
     flash_start: usize,
@@ -217,8 +221,13 @@ pub async fn init(
 
     let db = DB.init(Database::new(flash, ekv::Config::default()));
 
-    if db.mount().await.is_err() {
-        db.format().await?;
+    if db.mount().await.is_ok() {
+        self_test::record(Check::Flash, Outcome::Pass);
+    } else if let Err(err) = db.format().await {
+        self_test::record(Check::Flash, Outcome::Fail);
+        return Err(err.into());
+    } else {
+        self_test::record(Check::Flash, Outcome::Recovered);
     }
 
     Ok(db)
@@ -236,11 +245,46 @@ async fn read_from_db<'a>(
     }
 }
 
+/// The value as it is stored, whatever its type. Returns its length.
+pub async fn read_bytes<'a>(
+    tx: &'a mut ReadTx,
+    key: &str,
+    buf: &mut [u8],
+) -> DbResult<Option<usize>> {
+    read_from_db(tx, key, buf).await
+}
+
 pub async fn read_bool<'a>(tx: &'a mut ReadTx, key: &str) -> DbResult<Option<bool>> {
     let mut buf = [0u8; 1];
     Ok(read_from_db(tx, key, &mut buf).await?.map(|_| buf[0] != 0))
 }
 
+pub async fn read_u8<'a>(tx: &'a mut ReadTx, key: &str) -> DbResult<Option<u8>> {
+    let mut buf = [0u8; 1];
+    Ok(read_from_db(tx, key, &mut buf).await?.map(|_| buf[0]))
+}
+
+pub async fn read_u16<'a>(tx: &'a mut ReadTx, key: &str) -> DbResult<Option<u16>> {
+    let mut buf = [0u8; 2];
+    Ok(read_from_db(tx, key, &mut buf)
+        .await?
+        .map(|_| u16::from_le_bytes(buf)))
+}
+
+pub async fn read_u32<'a>(tx: &'a mut ReadTx, key: &str) -> DbResult<Option<u32>> {
+    let mut buf = [0u8; 4];
+    Ok(read_from_db(tx, key, &mut buf)
+        .await?
+        .map(|_| u32::from_le_bytes(buf)))
+}
+
+pub async fn read_i16<'a>(tx: &'a mut ReadTx, key: &str) -> DbResult<Option<i16>> {
+    let mut buf = [0u8; 2];
+    Ok(read_from_db(tx, key, &mut buf)
+        .await?
+        .map(|_| i16::from_le_bytes(buf)))
+}
+
 pub async fn read_string<'a, const N: usize>(
     tx: &'a mut ReadTx,
     key: &str,
@@ -255,12 +299,37 @@ pub async fn read_string<'a, const N: usize>(
     })
 }
 
+pub async fn write_bytes(tx: &mut WriteTx, key: &str, value: &[u8]) -> DbResult<()> {
+    tx.write(key.as_bytes(), value).await?;
+    Ok(())
+}
+
 pub async fn write_bool(tx: &mut WriteTx, key: &str, value: bool) -> DbResult<()> {
     let value = if value { [1u8] } else { [0u8] };
     tx.write(key.as_bytes(), &value).await?;
     Ok(())
 }
 
+pub async fn write_u8(tx: &mut WriteTx, key: &str, value: u8) -> DbResult<()> {
+    tx.write(key.as_bytes(), &[value]).await?;
+    Ok(())
+}
+
+pub async fn write_u16(tx: &mut WriteTx, key: &str, value: u16) -> DbResult<()> {
+    tx.write(key.as_bytes(), &value.to_le_bytes()).await?;
+    Ok(())
+}
+
+pub async fn write_u32(tx: &mut WriteTx, key: &str, value: u32) -> DbResult<()> {
+    tx.write(key.as_bytes(), &value.to_le_bytes()).await?;
+    Ok(())
+}
+
+pub async fn write_i16(tx: &mut WriteTx, key: &str, value: i16) -> DbResult<()> {
+    tx.write(key.as_bytes(), &value.to_le_bytes()).await?;
+    Ok(())
+}
+
 pub async fn write_string<const N: usize>(
     tx: &mut WriteTx,
     key: &str,
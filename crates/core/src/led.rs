@@ -1,27 +1,114 @@
+use core::cell::Cell;
+use core::sync::atomic::{AtomicU8, Ordering};
+
 use embassy_futures::select;
-use embassy_time::Timer;
-use esp_hal_smartled::SmartLedsAdapter;
-use rgb::Grb;
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
 use smart_leds::{
     RGB8, brightness, gamma,
     hsv::{Hsv, hsv2rgb},
 };
 
-use crate::system;
+use crate::air_quality::{self, AirQuality};
+use crate::config::{self, LedMode, StripMode};
+use crate::schedule::{NightMode, Schedule};
+use crate::{mqtt, net_time, sensors, system, wifi};
+
+mod backend;
+pub use backend::{Backend, GpioLed, PwmLed, SmartLed};
+
+/// User scaling of the LED output in percent, 0 turns the LED off.
+static BRIGHTNESS: AtomicU8 = AtomicU8::new(config::DEFAULT_LED_BRIGHTNESS);
+/// [`LedMode`] as `u8`.
+static MODE: AtomicU8 = AtomicU8::new(0);
+/// Number of LEDs on the strip.
+static COUNT: AtomicU8 = AtomicU8::new(config::DEFAULT_LED_COUNT);
+/// [`StripMode`] as `u8`.
+static STRIP: AtomicU8 = AtomicU8::new(0);
+
+/// [`NightMode`] as `u8`.
+static NIGHT_MODE: AtomicU8 = AtomicU8::new(0);
+static NIGHT: Mutex<CriticalSectionRawMutex, Cell<Option<Schedule>>> = Mutex::new(Cell::new(None));
+/// Upper limit of [`BRIGHTNESS`] while the night schedule is active.
+static NIGHT_LIMIT: AtomicU8 = AtomicU8::new(100);
+
+/// Brightness in percent used by [`NightMode::Dim`].
+const NIGHT_BRIGHTNESS: u8 = 5;
+/// How often the night schedule is checked.
+const NIGHT_CHECK_SECS: u64 = 60;
+
+static IDENTIFY: Signal<CriticalSectionRawMutex, Duration> = Signal::new();
+static COUNTDOWN: Signal<CriticalSectionRawMutex, Option<u8>> = Signal::new();
+static ALERT: Signal<CriticalSectionRawMutex, bool> = Signal::new();
+
+/// How long the identify strobe runs when the request doesn't say.
+pub const IDENTIFY_DURATION: Duration = Duration::from_secs(10);
+
+/// Longest supported strip, sizes the LED buffers.
+pub const MAX_LEDS: usize = 16;
+/// How often the strip follows the subsystems and the latest sample.
+const STRIP_REFRESH_SECS: u64 = 2;
 
-pub struct Status<L>
-where
-    L: smart_leds::SmartLedsWrite<Color = Grb<u8>>,
-{
-    led: L,
+const GREEN: RGB8 = RGB8 { r: 0, g: 64, b: 0 };
+const RED: RGB8 = RGB8 { r: 64, g: 0, b: 0 };
+
+/// How often the air quality color follows the latest sample.
+const AIR_QUALITY_REFRESH_SECS: u64 = 5;
+
+pub fn set_brightness(percent: u8) {
+    BRIGHTNESS.store(percent.min(100), Ordering::Relaxed);
+}
+
+pub fn set_mode(mode: LedMode) {
+    MODE.store(mode.into(), Ordering::Relaxed);
+}
+
+pub fn set_strip(count: u8, mode: StripMode) {
+    COUNT.store(count.clamp(1, MAX_LEDS as u8), Ordering::Relaxed);
+    STRIP.store(mode.into(), Ordering::Relaxed);
+}
+
+/// Dims or turns the LED off during the night hours, the same ones the
+/// display uses.
+pub fn set_night(mode: NightMode, schedule: Schedule) {
+    NIGHT_MODE.store(mode.into(), Ordering::Relaxed);
+    NIGHT.lock(|night| night.set(Some(schedule)));
+}
+
+/// Strobes the LED white for `duration` to find the node among others, then
+/// goes back to what it showed before.
+pub fn identify(duration: Duration) {
+    IDENTIFY.signal(duration);
+}
+
+/// Counts down the seconds a held button still needs, `None` ends it.
+pub fn countdown(seconds_left: Option<u8>) {
+    COUNTDOWN.signal(seconds_left);
+}
+
+/// Whether one of the [`crate::alerts`] is active.
+pub fn alert(active: bool) {
+    ALERT.signal(active);
+}
+
+/// What the other tasks asked of the LED.
+enum Request {
+    Countdown(Option<u8>),
+    Alert(bool),
+}
+
+fn count() -> usize {
+    COUNT.load(Ordering::Relaxed) as usize
+}
+
+pub struct Status<B: Backend> {
+    led: B,
     brightness: u8,
 }
 
-impl<L> Status<L>
-where
-    L: smart_leds::SmartLedsWrite<Color = Grb<u8>>,
-{
-    pub fn new(led: L) -> Self {
+impl<B: Backend> Status<B> {
+    pub fn new(led: B) -> Self {
         Self {
             led,
             brightness: 10,
@@ -32,51 +119,343 @@ where
         self.set(0, 0, 0);
     }
 
+    /// Shows the color on every LED.
     pub fn set(&mut self, r: u8, g: u8, b: u8) {
-        let rgb = RGB8 { r, g, b };
-        let _ = self.led.write([rgb].into_iter());
+        self.set_pixels(&[RGB8 { r, g, b }; MAX_LEDS][..count()]);
+    }
+
+    pub fn set_pixels(&mut self, colors: &[RGB8]) {
+        let percent = BRIGHTNESS
+            .load(Ordering::Relaxed)
+            .min(NIGHT_LIMIT.load(Ordering::Relaxed)) as u16;
+        let scale = |c: u8| (c as u16 * percent / 100) as u8;
+
+        let mut scaled = [RGB8::default(); MAX_LEDS];
+        for (scaled, color) in scaled.iter_mut().zip(colors) {
+            *scaled = RGB8 {
+                r: scale(color.r),
+                g: scale(color.g),
+                b: scale(color.b),
+            };
+        }
+
+        self.led.write(&scaled[..colors.len().min(MAX_LEDS)]);
     }
 
     pub fn set_hsv(&mut self, hsv: Hsv) {
         let rgb = hsv2rgb(hsv);
-        let rgb = brightness(gamma([rgb].into_iter()), self.brightness);
-        self.led.write(rgb).ok();
+        for rgb in brightness(gamma([rgb].into_iter()), self.brightness) {
+            self.set(rgb.r, rgb.g, rgb.b);
+        }
     }
 }
 
-pub async fn run<'ch, const BUFFER_SIZE: usize>(led: SmartLedsAdapter<'ch, BUFFER_SIZE>) -> ! {
+pub async fn run<B: Backend>(led: B) -> ! {
     let mut led = Status::new(led);
 
+    let mut states = system::STATE.receiver().unwrap();
     let mut state = system::State::default();
+    let mut faults = system::FAULT.receiver().unwrap();
+    let mut fault: Option<(system::Fault, Instant)> = None;
+    let mut identify_until: Option<Instant> = None;
+    let mut countdown: Option<u8> = None;
+    let mut alert = false;
 
     loop {
-        match select::select(system::STATE.wait(), pattern(&mut led, &state)).await {
-            select::Either::First(new_state) => state = new_state,
-            select::Either::Second(_) => {}
+        if let Some((current, since)) = fault
+            && current.is_cleared(since)
+        {
+            fault = None;
+        }
+
+        match select::select4(
+            states.changed(),
+            faults.changed(),
+            show(
+                &mut led,
+                state,
+                fault.map(|(fault, _)| fault),
+                identify_until,
+                countdown,
+                alert,
+            ),
+            async {
+                match select::select3(watch_night(), COUNTDOWN.wait(), ALERT.wait()).await {
+                    select::Either3::First(never) => never,
+                    select::Either3::Second(seconds_left) => Request::Countdown(seconds_left),
+                    select::Either3::Third(active) => Request::Alert(active),
+                }
+            },
+        )
+        .await
+        {
+            select::Either4::First(new_state) => {
+                defmt::debug!("LED: state {} -> {}", state, new_state);
+                state = new_state;
+            }
+            select::Either4::Second(new_fault) => fault = Some((new_fault, Instant::now())),
+            select::Either4::Third(until) => identify_until = until,
+            select::Either4::Fourth(Request::Countdown(seconds_left)) => countdown = seconds_left,
+            select::Either4::Fourth(Request::Alert(active)) => alert = active,
         }
     }
 }
 
-pub async fn pattern<const BUFFER_SIZE: usize>(
-    led: &mut Status<SmartLedsAdapter<'_, BUFFER_SIZE>>,
-    state: &system::State,
-) -> ! {
+/// Counts down while a button is held, or strobes until `identify_until`, or
+/// blinks the fault's code once, or flashes while an alert is active, or runs
+/// the state's pattern when there is neither. Returns when the strobe is over,
+/// or with its end when one is requested.
+async fn show<B: Backend>(
+    led: &mut Status<B>,
+    state: system::State,
+    fault: Option<system::Fault>,
+    identify_until: Option<Instant>,
+    countdown: Option<u8>,
+    alert: bool,
+) -> Option<Instant> {
+    if let Some(seconds_left) = countdown {
+        pattern_countdown(led, seconds_left).await;
+    }
+
+    if let Some(until) = identify_until
+        && until > Instant::now()
+    {
+        strobe(led, until).await;
+        return None;
+    }
+
+    let current = async {
+        match fault {
+            Some(fault) => blink_code(led, fault).await,
+            None if alert => pattern_alert(led).await,
+            None => pattern(led, state).await,
+        }
+    };
+
+    match select::select(IDENTIFY.wait(), current).await {
+        select::Either::First(duration) => Some(Instant::now() + duration),
+        select::Either::Second(_) => None,
+    }
+}
+
+async fn strobe<B: Backend>(led: &mut Status<B>, until: Instant) {
+    while Instant::now() < until {
+        led.set(64, 64, 64);
+        Timer::after_millis(50).await;
+        led.off();
+        Timer::after_millis(50).await;
+    }
+}
+
+/// Blinks amber once a second, on a strip with one LED per second left.
+async fn pattern_countdown<B: Backend>(led: &mut Status<B>, seconds_left: u8) -> ! {
+    const AMBER: RGB8 = RGB8 { r: 64, g: 24, b: 0 };
+
+    let mut pixels = [RGB8::default(); MAX_LEDS];
+    let lit = if count() > 1 {
+        (seconds_left as usize).min(count())
+    } else {
+        1
+    };
+    pixels[..lit].fill(AMBER);
+
+    loop {
+        led.set_pixels(&pixels[..count()]);
+        Timer::after_millis(500).await;
+        led.off();
+        Timer::after_millis(500).await;
+    }
+}
+
+/// Two short orange flashes a second, on every LED of a strip.
+async fn pattern_alert<B: Backend>(led: &mut Status<B>) -> ! {
+    const ORANGE: RGB8 = RGB8 { r: 64, g: 16, b: 0 };
+
+    let pixels = [ORANGE; MAX_LEDS];
+    loop {
+        for _ in 0..2 {
+            led.set_pixels(&pixels[..count()]);
+            Timer::after_millis(100).await;
+            led.off();
+            Timer::after_millis(150).await;
+        }
+        Timer::after_millis(500).await;
+    }
+}
+
+/// Keeps [`NIGHT_LIMIT`] in line with the night schedule.
+async fn watch_night() -> ! {
+    loop {
+        let mode = NightMode::from(NIGHT_MODE.load(Ordering::Relaxed));
+        let schedule = NIGHT.lock(|night| night.get());
+
+        let active = match schedule {
+            Some(schedule) if mode != NightMode::Disabled => schedule.is_active().await,
+            _ => false,
+        };
+
+        let limit = match (active, mode) {
+            (true, NightMode::Dim) => NIGHT_BRIGHTNESS,
+            (true, NightMode::Off) => 0,
+            _ => 100,
+        };
+        NIGHT_LIMIT.store(limit, Ordering::Relaxed);
+
+        Timer::after_secs(NIGHT_CHECK_SECS).await;
+    }
+}
+
+pub async fn pattern<B: Backend>(led: &mut Status<B>, state: system::State) -> ! {
+    let strip = StripMode::from(STRIP.load(Ordering::Relaxed));
+    if strip != StripMode::Status && count() > 1 {
+        pattern_strip(led, strip).await;
+    }
+
     match state {
         system::State::Booting => pattern_connecting(led).await,
         system::State::WifiConnecting => pattern_connecting(led).await,
         system::State::Dhcp => pattern_connecting(led).await,
         system::State::NtpSync => pattern_ok(led).await,
         system::State::MqttConnecting => pattern_connecting(led).await,
-        system::State::Ok => pattern_ok(led).await,
+        system::State::Ok => match LedMode::from(MODE.load(Ordering::Relaxed)) {
+            LedMode::Pattern => pattern_ok(led).await,
+            LedMode::Air => pattern_air_quality(led).await,
+        },
         system::State::Panic => pattern_connecting(led).await,
         system::State::Ble => pattern_ok(led).await,
         system::State::Sensors => pattern_connecting(led).await,
+        system::State::Setup => pattern_setup(led).await,
     }
 }
 
-async fn pattern_ok<const BUFFER_SIZE: usize>(
-    led: &mut Status<SmartLedsAdapter<'_, BUFFER_SIZE>>,
-) -> ! {
+/// Color and number of blinks telling the faults apart: blue for the WiFi
+/// and network, cyan for MQTT, magenta for the sensors and red for the node
+/// itself.
+fn fault_code(fault: system::Fault) -> (RGB8, u8) {
+    const BLUE: RGB8 = RGB8 { r: 0, g: 0, b: 64 };
+    const CYAN: RGB8 = RGB8 { r: 0, g: 64, b: 64 };
+    const MAGENTA: RGB8 = RGB8 { r: 64, g: 0, b: 64 };
+
+    match fault {
+        system::Fault::WifiDown => (BLUE, 1),
+        system::Fault::WifiAuth => (BLUE, 2),
+        system::Fault::DhcpTimeout => (BLUE, 3),
+        system::Fault::MqttRefused => (CYAN, 2),
+        system::Fault::SensorBus => (MAGENTA, 3),
+        system::Fault::Storage => (RED, 2),
+        system::Fault::Panic => (RED, 4),
+        system::Fault::CrashLoop => (RED, 5),
+        system::Fault::SafeMode => (RED, 6),
+    }
+}
+
+async fn blink_code<B: Backend>(led: &mut Status<B>, fault: system::Fault) {
+    let (color, blinks) = fault_code(fault);
+
+    for _ in 0..blinks {
+        led.set(color.r, color.g, color.b);
+        Timer::after_millis(250).await;
+        led.off();
+        Timer::after_millis(250).await;
+    }
+
+    Timer::after_millis(1500).await;
+}
+
+/// Renders the subsystems or the bar graph of the strip mode.
+async fn pattern_strip<B: Backend>(led: &mut Status<B>, mode: StripMode) -> ! {
+    loop {
+        let mut pixels = [RGB8::default(); MAX_LEDS];
+        let pixels = &mut pixels[..count()];
+
+        match mode {
+            StripMode::Status => {}
+            StripMode::Subsystems => {
+                let time_synced = { net_time::TIME_STATE.lock().await.now().is_some() };
+                let subsystems = [
+                    wifi::CONNECTED.load(Ordering::Relaxed),
+                    mqtt::CONNECTED.load(Ordering::Relaxed),
+                    sensors::latest().is_some(),
+                    time_synced,
+                ];
+
+                for (pixel, up) in pixels.iter_mut().zip(subsystems) {
+                    *pixel = if up { GREEN } else { RED };
+                }
+            }
+            _ => {
+                if let Some(fraction) =
+                    sensors::latest().and_then(|sample| bar_fraction(mode, &sample))
+                {
+                    bar(pixels, fraction);
+                }
+            }
+        }
+
+        led.set_pixels(pixels);
+        Timer::after_secs(STRIP_REFRESH_SECS).await;
+    }
+}
+
+/// Where the metric of the strip mode sits in its usual indoor range, 0 to 1.
+fn bar_fraction(mode: StripMode, sample: &sensors::Sample) -> Option<f32> {
+    let (value, min, max) = match mode {
+        StripMode::Temperature => (sample.temperature()?, 10.0, 35.0),
+        StripMode::Humidity => (sample.humidity()?, 0.0, 100.0),
+        StripMode::Light => (sample.light()?, 0.0, 1000.0),
+        StripMode::Pressure => (sample.pressure()?, 970.0, 1040.0),
+        StripMode::Air => (air_quality::from_sample(sample)?.0 as f32, 0.0, 300.0),
+        StripMode::Status | StripMode::Subsystems => return None,
+    };
+
+    Some(((value - min) / (max - min)).clamp(0.0, 1.0))
+}
+
+/// Lights the first `fraction` of the pixels, from green to red.
+fn bar(pixels: &mut [RGB8], fraction: f32) {
+    let len = pixels.len();
+    let lit = (fraction * len as f32 + 0.5) as usize;
+
+    for (i, pixel) in pixels.iter_mut().take(lit).enumerate() {
+        let red = (64 * i / (len - 1).max(1)) as u8;
+        *pixel = RGB8 {
+            r: red,
+            g: 64 - red,
+            b: 0,
+        };
+    }
+}
+
+fn air_quality_color(quality: AirQuality) -> RGB8 {
+    match quality {
+        AirQuality::Good => GREEN,
+        AirQuality::Moderate => RGB8 { r: 64, g: 48, b: 0 },
+        AirQuality::UnhealthyForSensitiveGroups => RGB8 { r: 64, g: 20, b: 0 },
+        AirQuality::Unhealthy => RED,
+        AirQuality::VeryUnhealthy | AirQuality::Hazardous => RGB8 { r: 40, g: 0, b: 64 },
+    }
+}
+
+/// Holds the color of the current air quality band, the LED stays off until
+/// there is a score.
+async fn pattern_air_quality<B: Backend>(led: &mut Status<B>) -> ! {
+    loop {
+        match sensors::latest()
+            .as_ref()
+            .and_then(air_quality::from_sample)
+        {
+            Some((_, quality)) => {
+                let color = air_quality_color(quality);
+                led.set(color.r, color.g, color.b);
+            }
+            None => led.off(),
+        }
+
+        Timer::after_secs(AIR_QUALITY_REFRESH_SECS).await;
+    }
+}
+
+async fn pattern_ok<B: Backend>(led: &mut Status<B>) -> ! {
     let rnd = esp_hal::rng::Rng::new();
     loop {
         let c1 = rnd.random();
@@ -100,12 +479,7 @@ async fn pattern_ok<const BUFFER_SIZE: usize>(
     }
 }
 
-async fn blink_with_blue<const BUFFER_SIZE: usize>(
-    led: &mut Status<SmartLedsAdapter<'_, BUFFER_SIZE>>,
-    b: f32,
-    r: f32,
-    c: f32,
-) {
+async fn blink_with_blue<B: Backend>(led: &mut Status<B>, b: f32, r: f32, c: f32) {
     for g in 0..16 {
         set_led(led, r, g, b, c);
         Timer::after_millis(80).await;
@@ -117,13 +491,7 @@ async fn blink_with_blue<const BUFFER_SIZE: usize>(
     }
 }
 
-fn set_led<const BUFFER_SIZE: usize>(
-    led: &mut Status<SmartLedsAdapter<'_, BUFFER_SIZE>>,
-    r: f32,
-    g: u8,
-    b: f32,
-    c: f32,
-) {
+fn set_led<B: Backend>(led: &mut Status<B>, r: f32, g: u8, b: f32, c: f32) {
     let i = g as f32;
     let g = (i * c) as u8;
     let r = (r * i / 2.0) as u8;
@@ -131,9 +499,24 @@ fn set_led<const BUFFER_SIZE: usize>(
     led.set(r, g, b);
 }
 
-async fn pattern_connecting<const BUFFER_SIZE: usize>(
-    led: &mut Status<SmartLedsAdapter<'_, BUFFER_SIZE>>,
-) -> ! {
+/// Slow white breathing, unlike anything the node shows while it runs.
+async fn pattern_setup<B: Backend>(led: &mut Status<B>) -> ! {
+    let mut color = Hsv {
+        hue: 0,
+        sat: 0,
+        val: 0,
+    };
+
+    loop {
+        for val in (0u8..=255).chain((0u8..=255).rev()) {
+            color.val = val;
+            led.set_hsv(color);
+            Timer::after_millis(8).await;
+        }
+    }
+}
+
+async fn pattern_connecting<B: Backend>(led: &mut Status<B>) -> ! {
     let mut color = Hsv {
         hue: 0,
         sat: 255,
@@ -0,0 +1,76 @@
+//! Hardware the status LED can be driven with.
+
+use esp_hal::gpio::Output;
+use esp_hal::ledc::{LowSpeed, channel::Channel, channel::ChannelIFace};
+use rgb::Grb;
+use smart_leds::{RGB8, SmartLedsWrite};
+
+/// Something that can show colors, as good as it can. Backends with a
+/// single LED show the first color.
+pub trait Backend {
+    fn write(&mut self, colors: &[RGB8]);
+}
+
+/// Addressable LED or strip of them, such as the WS2812 on the dev boards.
+pub struct SmartLed<L>(L);
+
+impl<L: SmartLedsWrite<Color = Grb<u8>>> SmartLed<L> {
+    pub fn new(led: L) -> Self {
+        Self(led)
+    }
+}
+
+impl<L: SmartLedsWrite<Color = Grb<u8>>> Backend for SmartLed<L> {
+    fn write(&mut self, colors: &[RGB8]) {
+        let _ = self.0.write(colors.iter().copied());
+    }
+}
+
+/// Single color LED on a plain GPIO, lit for any non-black color.
+pub struct GpioLed<'a> {
+    pin: Output<'a>,
+    active_low: bool,
+}
+
+impl<'a> GpioLed<'a> {
+    pub fn new(pin: Output<'a>, active_low: bool) -> Self {
+        Self { pin, active_low }
+    }
+}
+
+impl Backend for GpioLed<'_> {
+    fn write(&mut self, colors: &[RGB8]) {
+        let on = colors
+            .first()
+            .is_some_and(|color| *color != RGB8::default());
+        self.pin.set_level((on != self.active_low).into());
+    }
+}
+
+/// RGB LED with every channel on its own LEDC PWM channel.
+pub struct PwmLed<'a> {
+    red: Channel<'a, LowSpeed>,
+    green: Channel<'a, LowSpeed>,
+    blue: Channel<'a, LowSpeed>,
+}
+
+impl<'a> PwmLed<'a> {
+    pub fn new(
+        red: Channel<'a, LowSpeed>,
+        green: Channel<'a, LowSpeed>,
+        blue: Channel<'a, LowSpeed>,
+    ) -> Self {
+        Self { red, green, blue }
+    }
+}
+
+impl Backend for PwmLed<'_> {
+    fn write(&mut self, colors: &[RGB8]) {
+        let color = colors.first().copied().unwrap_or_default();
+        let duty = |c: u8| (c as u16 * 100 / 255) as u8;
+
+        self.red.set_duty(duty(color.r)).ok();
+        self.green.set_duty(duty(color.g)).ok();
+        self.blue.set_duty(duty(color.b)).ok();
+    }
+}
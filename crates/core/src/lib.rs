@@ -1,45 +1,153 @@
 #![no_std]
+#![feature(addr_parse_ascii)]
 #![feature(impl_trait_in_assoc_type)]
 
 use mqtt_client::packet::publish;
 
+pub mod adc;
 pub mod air_quality;
+pub mod alerts;
+pub mod app;
+pub mod availability;
 pub mod ble;
+pub mod board;
+pub mod cli;
 pub mod config;
 pub mod dhcp;
+pub mod diagnostics;
 #[cfg(feature = "display")]
 pub mod display;
+pub mod event_log;
+pub mod events;
+pub mod factory_reset;
+pub mod i2c_debug;
+pub mod inputs;
 pub mod kv_storage;
 pub mod led;
+#[cfg(feature = "lora")]
+pub mod lora;
 pub mod mqtt;
+pub mod mqtt_sn;
 pub mod net_time;
+pub mod noise;
+pub mod occupancy;
+pub mod ota;
+pub mod payload;
+pub mod power;
+pub mod presence;
+pub mod provisioning;
+pub mod relay;
+pub mod schedule;
+#[cfg(feature = "sd-log")]
+pub mod sd_log;
+pub mod self_test;
+pub mod senml;
 pub mod sensors;
+pub mod shutdown;
+pub mod syslog;
 pub mod system;
+pub mod uart_bus;
+pub mod units;
+pub mod version;
+pub mod watchdog;
 pub mod web;
 pub mod wifi;
 
 #[derive(defmt::Format)]
-enum Error {
+pub enum Error {
     CannotConvertPayload,
 }
 
+/// What the node is told to do over MQTT or MQTT-SN.
 #[derive(defmt::Format)]
-pub(crate) enum Command {
+pub enum Command {
     RebootToReconfigure,
+    /// LED brightness in percent.
+    LedBrightness(u8),
+    /// Strobe the LED for the given number of seconds.
+    Identify(Option<u8>),
+    /// Check the OTA manifest right away.
+    Update,
+    /// Publish the firmware version and build details.
+    Version,
+    /// Restart with only the WiFi, the web server and the log page.
+    SafeMode,
+    /// Switch a relay by its number from 1, toggle it without a state.
+    Relay(u8, Option<bool>),
+    /// Publish the persistent event log.
+    EventLog,
+    /// Read or write raw I2C registers, when the settings allow it.
+    I2c(i2c_debug::Request),
+    /// Correct a field of the samples and save the correction.
+    Calibrate(sensors::calibration::Change),
+    /// Take the current CO2 reading as this many ppm.
+    Co2Calibrate(u16),
 }
 
 impl<'a> TryFrom<publish::Publish<'a>> for Command {
     type Error = Error;
 
     fn try_from(msg: publish::Publish<'a>) -> Result<Self, Self::Error> {
-        if msg.payload.len() != 1 {
-            return Err(Error::CannotConvertPayload);
-        }
+        Self::try_from(msg.payload.as_bytes())
+    }
+}
+
+impl TryFrom<&[u8]> for Command {
+    type Error = Error;
 
-        let value = msg.payload.as_bytes()[0];
+    fn try_from(payload: &[u8]) -> Result<Self, Self::Error> {
+        let payload = core::str::from_utf8(payload)
+            .map_err(|_| Error::CannotConvertPayload)?
+            .trim();
 
-        match value {
-            48 => Ok(Self::RebootToReconfigure), // ASCII zero
+        match payload.split_once(' ').unwrap_or((payload, "")) {
+            ("0", "") => Ok(Self::RebootToReconfigure),
+            ("update", "") => Ok(Self::Update),
+            ("version", "") => Ok(Self::Version),
+            ("safemode", "") => Ok(Self::SafeMode),
+            ("eventlog", "") => Ok(Self::EventLog),
+            ("identify", "") => Ok(Self::Identify(None)),
+            ("identify", secs) => secs
+                .parse()
+                .map(|secs| Self::Identify(Some(secs)))
+                .map_err(|_| Error::CannotConvertPayload),
+            ("i2cget", args) => i2c_debug::Request::parse_get(args)
+                .map(Self::I2c)
+                .ok_or(Error::CannotConvertPayload),
+            ("i2cset", args) => i2c_debug::Request::parse_set(args)
+                .map(Self::I2c)
+                .ok_or(Error::CannotConvertPayload),
+            ("calibrate", args) => sensors::calibration::Change::parse(args)
+                .map(Self::Calibrate)
+                .ok_or(Error::CannotConvertPayload),
+            ("co2calibrate", "") => Ok(Self::Co2Calibrate(sensors::co2::DEFAULT_PPM)),
+            ("co2calibrate", ppm) => ppm
+                .parse()
+                .ok()
+                .filter(|ppm| sensors::co2::PPM_RANGE.contains(ppm))
+                .map(Self::Co2Calibrate)
+                .ok_or(Error::CannotConvertPayload),
+            ("relay", args) => {
+                let (number, state) = args.split_once(' ').unwrap_or((args, "toggle"));
+                let state = match state.trim() {
+                    "on" => Some(Some(true)),
+                    "off" => Some(Some(false)),
+                    "toggle" => Some(None),
+                    _ => None,
+                };
+                number
+                    .parse()
+                    .ok()
+                    .zip(state)
+                    .map(|(number, state)| Self::Relay(number, state))
+                    .ok_or(Error::CannotConvertPayload)
+            }
+            ("led", value) => value
+                .parse()
+                .ok()
+                .filter(|percent| *percent <= 100)
+                .map(Self::LedBrightness)
+                .ok_or(Error::CannotConvertPayload),
             _ => Err(Error::CannotConvertPayload),
         }
     }
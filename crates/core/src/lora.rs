@@ -0,0 +1,169 @@
+//! Raw LoRa uplink through an SX1262, for sites no WiFi reaches. The node
+//! sends each sample and never listens, a gateway on the same frequency and
+//! modulation picks it up.
+//!
+//! A frame is the postcard of [`Frame`]: the client id, a sequence number
+//! and the readings scaled to integers, so a full sample stays around 30
+//! bytes and fits the airtime of SF10 even in the EU duty cycle.
+
+use defmt::{info, warn};
+use embassy_time::Delay;
+use embedded_hal_bus::spi::ExclusiveDevice;
+use esp_hal::gpio::{Input, InputConfig, Level, Output, OutputConfig};
+use esp_hal::peripherals::SPI2;
+use esp_hal::spi::master::{Config, Spi};
+use esp_hal::time::Rate;
+use lora_phy::LoRa;
+use lora_phy::iv::GenericSx126xInterfaceVariant;
+use lora_phy::mod_params::{Bandwidth, CodingRate, SpreadingFactor};
+use lora_phy::sx126x::{self, Sx126x, Sx1262, TcxoCtrlVoltage};
+use serde::Serialize;
+
+use crate::board::{self, Pins};
+use crate::diagnostics::{self, DropCause};
+use crate::sensors::{self, Sample};
+use crate::{mqtt, system};
+
+/// 14 dBm is the limit of the 868 MHz band in the EU.
+const TX_POWER_DBM: i32 = 14;
+const PREAMBLE_LEN: u16 = 8;
+const MAX_FRAME: usize = 64;
+
+#[derive(Serialize)]
+struct Frame<'a> {
+    node: &'a str,
+    seq: u16,
+    /// Centidegrees Celsius.
+    temperature: Option<i16>,
+    /// Tenths of a percent.
+    humidity: Option<u16>,
+    /// Tenths of a hPa.
+    pressure: Option<u16>,
+    lux: Option<u32>,
+    /// Tenths of a dBA.
+    noise: Option<u16>,
+    supply_mv: Option<u16>,
+}
+
+impl<'a> Frame<'a> {
+    fn new(node: &'a str, seq: u16, sample: &Sample) -> Self {
+        Self {
+            node,
+            seq,
+            temperature: sample.temperature().map(|value| (value * 100.0) as i16),
+            humidity: sample.humidity().map(|value| (value * 10.0) as u16),
+            pressure: sample.pressure().map(|value| (value * 10.0) as u16),
+            lux: sample.light().map(|value| value as u32),
+            noise: sample.noise_dba.map(|value| (value * 10.0) as u16),
+            supply_mv: sample.supply_mv,
+        }
+    }
+}
+
+/// Needs [`Pins::lora`]. Sends every sample on `frequency_khz` and then puts
+/// the radio to sleep.
+#[embassy_executor::task]
+pub async fn task(
+    spi2: SPI2<'static>,
+    pins: Pins,
+    client_id: &'static str,
+    frequency_khz: u32,
+) -> ! {
+    info!("LoRa: started on {} kHz", frequency_khz);
+
+    let spi = Spi::new(spi2, Config::default().with_frequency(Rate::from_mhz(8)))
+        .unwrap()
+        .with_sck(unsafe { board::pin(pins.sd_sck) })
+        .with_mosi(unsafe { board::pin(pins.sd_mosi) })
+        .with_miso(unsafe { board::pin(pins.sd_miso) })
+        .into_async();
+    let nss = Output::new(
+        unsafe { board::pin(pins.lora_nss) },
+        Level::High,
+        OutputConfig::default(),
+    );
+    let device = ExclusiveDevice::new(spi, nss, Delay).unwrap();
+
+    let reset = Output::new(
+        unsafe { board::pin(pins.lora_reset) },
+        Level::High,
+        OutputConfig::default(),
+    );
+    let busy = Input::new(
+        unsafe { board::pin(pins.lora_busy) },
+        InputConfig::default(),
+    );
+    let dio1 = Input::new(
+        unsafe { board::pin(pins.lora_dio1) },
+        InputConfig::default(),
+    );
+    let variant = GenericSx126xInterfaceVariant::new(reset, dio1, busy, None, None).unwrap();
+
+    // The common modules run the SX1262 off a TCXO on DIO3.
+    let config = sx126x::Config {
+        chip: Sx1262,
+        tcxo_ctrl: Some(TcxoCtrlVoltage::Ctrl1V8),
+        use_dcdc: true,
+        rx_boost: false,
+    };
+    let mut radio = match LoRa::new(Sx126x::new(device, variant, config), false, Delay).await {
+        Ok(radio) => radio,
+        Err(err) => {
+            warn!("LoRa: the radio doesn't answer: {:?}", err);
+            loop {
+                core::future::pending::<()>().await;
+            }
+        }
+    };
+
+    let modulation = radio
+        .create_modulation_params(
+            SpreadingFactor::_10,
+            Bandwidth::_125KHz,
+            CodingRate::_4_5,
+            frequency_khz * 1000,
+        )
+        .unwrap();
+    let mut packet = radio
+        .create_tx_packet_params(PREAMBLE_LEN, false, true, false, &modulation)
+        .unwrap();
+
+    let mut seq = 0u16;
+
+    loop {
+        sensors::HAS_DATA.wait().await;
+
+        while let Some(sample) = { sensors::QUEUE.lock().await.dequeue() } {
+            let mut buf = [0u8; MAX_FRAME];
+            let Ok(frame) = postcard::to_slice(&Frame::new(client_id, seq, &sample), &mut buf)
+            else {
+                warn!("LoRa: frame doesn't fit");
+                diagnostics::dropped(DropCause::Overflow);
+                continue;
+            };
+            seq = seq.wrapping_add(1);
+
+            let sent = async {
+                radio
+                    .prepare_for_tx(&modulation, &mut packet, TX_POWER_DBM, frame)
+                    .await?;
+                radio.tx().await
+            }
+            .await;
+
+            match sent {
+                Ok(()) => {
+                    info!("LoRa: sent {} bytes", frame.len());
+                    system::transition(&[system::State::Booting], system::State::Ok);
+                    mqtt::published();
+                }
+                Err(err) => {
+                    warn!("LoRa: sending failed: {:?}", err);
+                    diagnostics::dropped(DropCause::PublishFailed);
+                }
+            }
+        }
+
+        radio.sleep(false).await.ok();
+    }
+}
@@ -1,49 +1,128 @@
+use core::cell::{Cell, RefCell};
 use core::fmt::Write;
 use core::net::Ipv4Addr;
+use core::sync::atomic::{AtomicBool, AtomicU16, Ordering};
 use defmt::{Debug2Format, info, warn};
 use embassy_futures::join::join3;
 use embassy_futures::select;
 use embassy_net::tcp::TcpSocket;
 use embassy_net::{Stack, tcp};
+use embassy_sync::blocking_mutex::Mutex;
 use embassy_sync::channel::{Channel, Receiver, Sender, TryReceiveError, TrySendError};
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
-use embassy_time::{Duration, Instant, Timer};
-use heapless::String;
+use embassy_time::{Duration, Instant, Timer, with_timeout};
+use heapless::{Deque, String};
 
 use mqtt_client::packet::QoS;
 use mqtt_client::time::EmbassyClock;
 use mqtt_client::{ConnectOptions, Event, PublishMsg, SubscribeOptions};
-use static_cell::StaticCell;
-
-use crate::{Command, config, kv_storage, sensors, wifi};
+use serde::Serialize;
+use serde::ser::{SerializeMap, Serializer};
+
+use crate::config::{MqttTransport, PayloadFormat};
+use crate::diagnostics::{self, DropCause, Timed};
+use crate::event_log::{self, Kind};
+use crate::syslog::{self, Severity};
+use crate::{
+    Command, alerts, config, events, i2c_debug, inputs, kv_storage, led, mqtt_sn, net_time,
+    occupancy, ota, payload, presence, provisioning, relay, self_test, sensors, shutdown, system,
+    version, watchdog,
+};
 
 extern crate alloc;
 
 type MqttClient<'c, 't> = mqtt_client::Client<'c, EmbassyClock, TcpSocket<'t>, 1, 4, 1, 4>;
 
-type SampleSender = Sender<'static, CriticalSectionRawMutex, sensors::Sample, PUBLISH_QUEUE_SIZE>;
-type SampleReceiver =
+pub(crate) type SampleSender =
+    Sender<'static, CriticalSectionRawMutex, sensors::Sample, PUBLISH_QUEUE_SIZE>;
+pub(crate) type SampleReceiver =
     Receiver<'static, CriticalSectionRawMutex, sensors::Sample, PUBLISH_QUEUE_SIZE>;
 
-type CommandSender = Sender<'static, CriticalSectionRawMutex, Command, SUBSCRIBE_QUEUE_SIZE>;
-type CommandReceiver = Receiver<'static, CriticalSectionRawMutex, Command, SUBSCRIBE_QUEUE_SIZE>;
+pub(crate) type CommandSender =
+    Sender<'static, CriticalSectionRawMutex, Command, SUBSCRIBE_QUEUE_SIZE>;
+pub(crate) type CommandReceiver =
+    Receiver<'static, CriticalSectionRawMutex, Command, SUBSCRIBE_QUEUE_SIZE>;
 
-pub static READY: Signal<CriticalSectionRawMutex, ()> = Signal::new();
-pub static DOWN: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+pub static CONNECTED: AtomicBool = AtomicBool::new(false);
+/// The broker acknowledged a published sample.
+pub static PUBLISHED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
 
 const PUBLISH_QUEUE_SIZE: usize = 8;
 const SUBSCRIBE_QUEUE_SIZE: usize = 8;
 const PUBLISH_BURST: usize = 4;
+/// QoS 1 publishes awaiting their PUBACK, more than the client lets out.
+const IN_FLIGHT_LEN: usize = 16;
+/// Room next to the payload in the transmit buffer, for the fixed header,
+/// the topic with its length and the packet id of a PUBLISH.
+const PUBLISH_HEADER_LEN: usize = 128;
+const _: () = assert!(
+    5 + 2 + config::MQTT_TOPIC_LEN + 2 <= PUBLISH_HEADER_LEN,
+    "the sample topic may not fit the transmit buffer"
+);
 const IO_POLL_TIMEOUT_MS: u64 = 6_000;
 const CONNECT_TIMEOUT_SECS: u64 = 10;
+/// Consecutive rejected connects after which the broker is reported as
+/// refusing us.
+const REFUSED_ATTEMPTS: u32 = 3;
+/// How often the wait for the WiFi checks in with the watchdog.
+const WIFI_WAIT_SECS: u64 = 10;
 
 static PUBLISH_QUEUE: Channel<CriticalSectionRawMutex, sensors::Sample, PUBLISH_QUEUE_SIZE> =
     Channel::new();
 static SUBSCRIBE_QUEUE: Channel<CriticalSectionRawMutex, Command, SUBSCRIBE_QUEUE_SIZE> =
     Channel::new();
 
+static VERSION_REQUEST: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static EVENT_LOG_REQUEST: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+static KEEP_ALIVE_SECS: AtomicU16 = AtomicU16::new(config::DEFAULT_MQTT_KEEP_ALIVE_SECS);
+
+static BROKER: Mutex<CriticalSectionRawMutex, Cell<Option<Ipv4Addr>>> = Mutex::new(Cell::new(None));
+static LAST_PUBLISH: Mutex<CriticalSectionRawMutex, Cell<Option<Instant>>> =
+    Mutex::new(Cell::new(None));
+/// The QoS 1 publishes of the connection the broker didn't acknowledge yet,
+/// oldest first, with the time the samples among them went out. The client
+/// reports a PUBACK without its packet id, but the broker acknowledges the
+/// publishes in the order it got them (MQTT 3.1.1, 4.6.0), so a PUBACK is
+/// for the front one.
+static IN_FLIGHT: Mutex<CriticalSectionRawMutex, RefCell<Deque<Option<Instant>, IN_FLIGHT_LEN>>> =
+    Mutex::new(RefCell::new(Deque::new()));
+
 static COMMANDS_TOPIC_BASE: &'static str = "broker/command";
 
+/// Whether the samples actually leave the node, for the display.
+pub struct Link {
+    /// None until the task starts, and on LoRa nodes.
+    pub broker: Option<Ipv4Addr>,
+    pub connected: bool,
+    pub last_publish: Option<Instant>,
+    /// Samples waiting for the broker, in the sensors queue and ours.
+    pub queued: usize,
+}
+
+pub async fn link() -> Link {
+    let queued = sensors::QUEUE.lock().await.len() + PUBLISH_QUEUE.len();
+
+    Link {
+        broker: BROKER.lock(|broker| broker.get()),
+        connected: CONNECTED.load(Ordering::Relaxed),
+        last_publish: LAST_PUBLISH.lock(|at| at.get()),
+        queued,
+    }
+}
+
+/// The MQTT keep alive, the TCP keep alive and the socket timeout follow it.
+/// Below 10 s the pings would be most of the traffic.
+pub fn set_keep_alive(secs: u16) {
+    KEEP_ALIVE_SECS.store(secs.max(10), Ordering::Relaxed);
+}
+
+/// A sample got to the broker, or out over the radio.
+pub(crate) fn published() {
+    LAST_PUBLISH.lock(|at| at.set(Some(Instant::now())));
+    PUBLISHED.signal(());
+}
+
 #[embassy_executor::task]
 pub async fn task(
     db: &'static kv_storage::Db,
@@ -51,9 +130,14 @@ pub async fn task(
     broker_addr: Ipv4Addr,
     client_id: &'static str,
     topic: &'static str,
+    firmware_version: &'static str,
+    transport: MqttTransport,
+    format: PayloadFormat,
 ) -> ! {
     info!("MQTT task started");
 
+    BROKER.lock(|broker| broker.set(Some(broker_addr)));
+
     let publish_sender = PUBLISH_QUEUE.sender();
     let publish_receiver = PUBLISH_QUEUE.receiver();
 
@@ -63,7 +147,37 @@ pub async fn task(
     join3(
         publisher_loop(publish_sender),
         command_execution_loop(db, subscribe_receiver),
-        mqtt_loop(stack, broker_addr, client_id, topic, publish_receiver, subscribe_sender),
+        async {
+            match transport {
+                // Only without the lora feature, app::run keeps LoRa nodes
+                // away from MQTT otherwise.
+                MqttTransport::Tcp | MqttTransport::Lora => {
+                    mqtt_loop(
+                        stack,
+                        broker_addr,
+                        client_id,
+                        topic,
+                        firmware_version,
+                        format,
+                        publish_receiver,
+                        subscribe_sender,
+                    )
+                    .await
+                }
+                MqttTransport::Sn => {
+                    mqtt_sn::run(
+                        stack,
+                        broker_addr,
+                        client_id,
+                        topic,
+                        format,
+                        publish_receiver,
+                        subscribe_sender,
+                    )
+                    .await
+                }
+            }
+        },
     )
     .await;
 
@@ -78,6 +192,72 @@ async fn command_execution_loop(db: &'static kv_storage::Db, receiver: CommandRe
                     warn!("Could not set settings to reboot: {:?}", err);
                 };
             }
+            Command::Identify(secs) => {
+                info!("Identify requested");
+                led::identify(secs.map_or(led::IDENTIFY_DURATION, |secs| {
+                    Duration::from_secs(secs as u64)
+                }));
+            }
+            Command::LedBrightness(percent) => {
+                if let Err(busy) = provisioning::claim(provisioning::Channel::Mqtt) {
+                    warn!("LED brightness not set, {} has the settings", busy.0);
+                    continue;
+                }
+                info!("LED brightness set to {}%", percent);
+                led::set_brightness(percent);
+                if let Err(err) = config::save_led_brightness(db, percent).await {
+                    warn!("Could not save LED brightness: {:?}", err);
+                }
+                provisioning::release(provisioning::Channel::Mqtt);
+            }
+            Command::Update => {
+                info!("Update check requested");
+                ota::request_update();
+            }
+            Command::Version => {
+                info!("Version requested");
+                VERSION_REQUEST.signal(());
+            }
+            Command::EventLog => {
+                info!("Event log requested");
+                EVENT_LOG_REQUEST.signal(());
+            }
+            Command::SafeMode => {
+                info!("Safe mode requested");
+                system::reboot_to_safe_mode().await;
+            }
+            Command::Relay(number, state) => {
+                info!("Relay {} requested", number);
+                relay::request(number, state);
+            }
+            Command::Calibrate(change) => {
+                if let Err(busy) = provisioning::claim(provisioning::Channel::Mqtt) {
+                    warn!("Calibration not changed, {} has the settings", busy.0);
+                    continue;
+                }
+                info!("Calibration requested: {}", change);
+                let Some(calibration) = sensors::calibration::change(change) else {
+                    warn!("The calibration doesn't fit the setting, not saved");
+                    provisioning::release(provisioning::Channel::Mqtt);
+                    continue;
+                };
+                if let Err(err) = config::save_calibration(db, &calibration).await {
+                    warn!("Could not save the calibration: {:?}", err);
+                }
+                provisioning::release(provisioning::Channel::Mqtt);
+            }
+            Command::Co2Calibrate(ppm) => {
+                // Released by the CO2 task once the calibration is saved.
+                if let Err(busy) = provisioning::claim(provisioning::Channel::Mqtt) {
+                    warn!("CO2 calibration not started, {} has the settings", busy.0);
+                    continue;
+                }
+                sensors::co2::force(ppm);
+            }
+            Command::I2c(request) => {
+                info!("I2C access requested: {}", request);
+                i2c_debug::request(request).await;
+            }
         }
     }
 }
@@ -98,8 +278,51 @@ async fn publisher_loop(sender: SampleSender) -> ! {
     }
 }
 
-fn command_topic(client_id: &str) -> alloc::string::String {
-    alloc::format!("{COMMANDS_TOPIC_BASE}/{client_id}")
+pub(crate) fn set_ready() {
+    CONNECTED.store(true, Ordering::Relaxed);
+    syslog::log(Severity::Info, format_args!("MQTT connected"));
+    event_log::record(Kind::MqttUp);
+    system::transition(
+        &[
+            system::State::Dhcp,
+            system::State::NtpSync,
+            system::State::MqttConnecting,
+            system::State::Sensors,
+        ],
+        system::State::Ok,
+    );
+}
+
+pub(crate) fn set_down() {
+    if CONNECTED.swap(false, Ordering::Relaxed) {
+        syslog::log(Severity::Warning, format_args!("MQTT disconnected"));
+        event_log::record(Kind::MqttDown);
+    }
+    system::transition(&[system::State::Ok], system::State::MqttConnecting);
+}
+
+pub(crate) fn command_topic(client_id: &str) -> alloc::string::String {
+    subtopic(COMMANDS_TOPIC_BASE, client_id)
+}
+
+/// `<topic>/<suffix>`, e.g. the `health` of a sample topic.
+fn subtopic(topic: &str, suffix: impl core::fmt::Display) -> alloc::string::String {
+    alloc::format!("{topic}/{suffix}")
+}
+
+/// Waits for the WiFi, checking in with the watchdog meanwhile.
+pub(crate) async fn wait_for_wifi() {
+    info!("MQTT: waiting for WiFi...");
+    while with_timeout(
+        Duration::from_secs(WIFI_WAIT_SECS),
+        system::wait_for_state(|state| state.wifi_up()),
+    )
+    .await
+    .is_err()
+    {
+        watchdog::check_in(watchdog::Task::Mqtt);
+    }
+    info!("MQTT: WiFi is up");
 }
 
 async fn mqtt_loop(
@@ -107,29 +330,52 @@ async fn mqtt_loop(
     broker_addr: Ipv4Addr,
     client_id: &'static str,
     topic: &'static str,
+    firmware_version: &'static str,
+    format: PayloadFormat,
     publish_receiver: SampleReceiver,
     command_sender: CommandSender,
 ) -> ! {
     let broker_port = 1883;
-    let keep_alive_secs: u16 = 120;
+    let keep_alive_secs = KEEP_ALIVE_SECS.load(Ordering::Relaxed);
+    let keep_alive_timeout = Duration::from_secs(keep_alive_secs as u64);
 
     let mut backoff = 1u64;
-
-    let cmd_topic: &'static alloc::string::String = {
-        static CMD_TOPIC: StaticCell<alloc::string::String> = StaticCell::new();
-        CMD_TOPIC.init(command_topic(client_id))
-    };
+    let mut refusals = 0u32;
+
+    // Built once, the task runs for good.
+    let cmd_topic: &'static str = command_topic(client_id).leak();
+    let boot_topic: &'static str = subtopic(topic, "boot").leak();
+    let version_topic: &'static str = subtopic(topic, "version").leak();
+    let self_test_topic: &'static str = subtopic(topic, "selftest").leak();
+    let relays_topic: &'static str = subtopic(topic, "relays").leak();
+    let setup_topic: &'static str = subtopic(topic, "setup").leak();
+    let event_topic: &'static str = subtopic(topic, "event").leak();
+    let event_log_topic: &'static str = subtopic(topic, "eventlog").leak();
+    let health_topic: &'static str = subtopic(topic, "health").leak();
+    let i2c_topic: &'static str = subtopic(topic, "i2c").leak();
+    let occupancy_topic: &'static str = subtopic(topic, "occupancy").leak();
+    let i2c_scan_topic: &'static str = subtopic(topic, "i2cscan").leak();
+    let mut boot_report = system::take_boot_report();
+    let mut self_test_published = false;
+    let mut i2c_scan_published = false;
+
+    let mut stop = shutdown::STOP.receiver().unwrap();
+    shutdown::register(shutdown::Participant::Mqtt);
 
     loop {
-        info!("MQTT: waiting for WiFi...");
-        wifi::UP.wait().await;
-        info!("MQTT: WiFi is up");
+        wait_for_wifi().await;
 
         let mut rx_buf = [0u8; 1024];
         let mut tx_buf = [0u8; 1024];
         let mut tcp_socket = tcp::TcpSocket::new(stack, &mut rx_buf, &mut tx_buf);
 
-        tcp_socket.set_timeout(None);
+        // The TCP keep alive probes an idle connection a few times within
+        // the MQTT one, and the timeout drops it when none of them is
+        // answered, or the data sent isn't acknowledged. A broker gone
+        // without a FIN fails the next poll then, instead of the connection
+        // looking up until the first publish.
+        tcp_socket.set_keep_alive(Some(keep_alive_timeout / 3));
+        tcp_socket.set_timeout(Some(keep_alive_timeout));
 
         info!("MQTT: connecting to {}", broker_addr);
 
@@ -152,7 +398,7 @@ async fn mqtt_loop(
         };
 
         let rx_buf = &mut [0u8; 1024];
-        let tx_buf = &mut [0u8; 1024];
+        let tx_buf = &mut [0u8; payload::MAX_LEN + PUBLISH_HEADER_LEN];
 
         let clock = mqtt_client::time::EmbassyClock::default();
         let keep_alive = mqtt_client::time::KeepAlive::from_sec(keep_alive_secs as u64);
@@ -170,6 +416,14 @@ async fn mqtt_loop(
 
         if let Err(err) = wait_for_connect(&mut client).await {
             warn!("MQTT: connect poll error: {:?}", Debug2Format(&err));
+
+            // The TCP connection is up at this point, so it's the broker
+            // that doesn't let us in.
+            refusals += 1;
+            if refusals == REFUSED_ATTEMPTS {
+                system::report_fault(system::Fault::MqttRefused);
+            }
+
             Timer::after_secs(backoff).await;
             backoff = (backoff * 2).min(30);
 
@@ -177,44 +431,148 @@ async fn mqtt_loop(
         };
 
         info!("MQTT: connected");
-        READY.signal(());
+        // A clean session, nothing of the last connection gets acknowledged.
+        IN_FLIGHT.lock(|in_flight| in_flight.borrow_mut().clear());
+        set_ready();
         backoff = 1;
+        refusals = 0;
 
         let subscribe_options = SubscribeOptions {
             qos: Some(QoS::AtMostOnce),
-            topic: &cmd_topic,
+            topic: cmd_topic,
         };
 
         if let Err(err) = client.schedule_subscribe(subscribe_options) {
             warn!("Error when subscribe scheduled: {:?}", err);
         }
 
+        if let Some(report) = &boot_report {
+            if publish_json(&mut client, boot_topic, report, true) {
+                boot_report = None;
+            }
+        }
+
+        let mut relays_published = false;
+        let mut health_published = false;
+        let mut setup_published = false;
+        let mut alerts_published = false;
+        let mut inputs_published = false;
+        let mut occupancy_published = !occupancy::enabled();
+        let mut presence_subscribed = !presence::enabled();
+        let mut presence_at = Instant::now() + presence::WINDOW;
+
         'connected: loop {
+            watchdog::check_in(watchdog::Task::Mqtt);
+
+            // The sensors may still be probed on the first connection.
+            if !self_test_published && self_test::complete() {
+                self_test_published =
+                    publish_json(&mut client, self_test_topic, &self_test::report(), true);
+            }
+            if !i2c_scan_published && let Some(table) = sensors::scan::table() {
+                i2c_scan_published = publish_json(&mut client, i2c_scan_topic, &table, true);
+            }
+
+            // A change while disconnected is only in the current state.
+            if !occupancy_published {
+                occupancy_published =
+                    publish_json(&mut client, occupancy_topic, &occupancy::current(), true);
+            }
+
+            // Clears a pending setup from before the restart.
+            if !setup_published {
+                setup_published = publish_setup(&mut client, setup_topic, false);
+            }
+
+            // Picked up within the IO poll timeout, that's soon enough.
+            if relay::take_changed() || !relays_published {
+                relays_published = publish_relays(&mut client, relays_topic);
+            }
+
+            if sensors::health::take_changed() || !health_published {
+                health_published =
+                    publish_json(&mut client, health_topic, &sensors::health::report(), true);
+            }
+
+            // All of them once connected, the changed ones after that.
+            let changed = if alerts_published {
+                alerts::take_changed()
+            } else {
+                u8::MAX
+            };
+            if changed != 0 {
+                alerts_published = publish_alerts(&mut client, topic, changed);
+            }
+
+            let changed = if inputs_published {
+                inputs::take_changed()
+            } else {
+                u8::MAX
+            };
+            if changed != 0 {
+                inputs_published = publish_inputs(&mut client, topic, changed);
+            }
+
+            // Retried until the client takes it, the commands one may still
+            // be waiting for its acknowledgement.
+            if !presence_subscribed {
+                presence_subscribed = client
+                    .schedule_subscribe(SubscribeOptions {
+                        qos: Some(QoS::AtMostOnce),
+                        topic: presence::OBSERVATIONS_TOPIC,
+                    })
+                    .is_ok();
+            }
+
+            if presence::enabled() && Instant::now() >= presence_at {
+                presence_at = Instant::now() + presence::WINDOW;
+                publish_presence(&mut client, client_id);
+            }
+
             if let Err(err) = client.poll_timers() {
                 warn!("MQTT poll timers error: {:?}", Debug2Format(&err));
-                DOWN.signal(());
+                set_down();
+                break;
+            }
+
+            // The TCP side may be fine while the broker is stuck, it still
+            // has to acknowledge the samples.
+            if oldest_unacked_sample().is_some_and(|sent| sent.elapsed() > keep_alive_timeout) {
+                warn!(
+                    "MQTT: no acknowledgement for {}s, reconnecting",
+                    keep_alive_secs
+                );
+                set_down();
                 break;
             }
 
-            match select::select(
+            match select::select4(
                 publish_receiver.receive(),
                 poll_io_with_timeout(&mut client),
+                select::select4(
+                    VERSION_REQUEST.wait(),
+                    EVENT_LOG_REQUEST.wait(),
+                    events::next(),
+                    select::select(occupancy::changed(), i2c_debug::reply()),
+                ),
+                stop.changed(),
             )
             .await
             {
-                select::Either::First(sample) => {
-                    if !publish_sample(&mut client, topic, sample).await {
-                        // @todo put sample back, or is it ok to drop it?
-                        DOWN.signal(());
+                select::Either4::First(sample) => {
+                    if !publish_sample(&mut client, topic, format, client_id, sample).await {
+                        // The sample is back in the queue.
+                        set_down();
                         break;
                     }
 
                     for _ in 0..PUBLISH_BURST {
                         match publish_receiver.try_receive() {
                             Ok(sample) => {
-                                if !publish_sample(&mut client, topic, sample).await {
-                                    // @todo put sample back, or is it ok to drop it?
-                                    DOWN.signal(());
+                                if !publish_sample(&mut client, topic, format, client_id, sample)
+                                    .await
+                                {
+                                    set_down();
                                     break 'connected;
                                 }
                             }
@@ -222,19 +580,83 @@ async fn mqtt_loop(
                         }
                     }
                 }
-                select::Either::Second(poll) => {
+                select::Either4::Second(poll) => {
+                    // Only a sample counts, not the boot report or the health
+                    // a sleeping node would otherwise go down after.
+                    if matches!(poll, Ok(Some(Event::Published)))
+                        && let Some(sent) = acknowledged()
+                    {
+                        diagnostics::record(Timed::MqttPublish, sent);
+                        published();
+                    }
+
                     if !handle_poll_result(client_id, poll, command_sender) {
-                        DOWN.signal(());
+                        set_down();
                         break;
                     }
                 }
+                select::Either4::Third(select::Either4::First(())) => {
+                    publish_version(&mut client, version_topic, firmware_version);
+                }
+                select::Either4::Third(select::Either4::Second(())) => {
+                    // Oldest first.
+                    let entries = event_log::entries().await;
+                    publish_json(&mut client, event_log_topic, &entries, false);
+                }
+                select::Either4::Third(select::Either4::Third(raised)) => {
+                    // The event is over by the time a new subscriber comes.
+                    publish_json(&mut client, event_topic, &raised, false);
+                }
+                select::Either4::Third(select::Either4::Fourth(select::Either::First(
+                    occupancy,
+                ))) => {
+                    occupancy_published =
+                        publish_json(&mut client, occupancy_topic, &occupancy, true);
+                }
+                select::Either4::Third(select::Either4::Fourth(select::Either::Second(reply))) => {
+                    // It answers the command that was just sent.
+                    publish_json(&mut client, i2c_topic, &reply, false);
+                }
+                select::Either4::Fourth(reason) => {
+                    if reason == shutdown::Reason::Reconfigure {
+                        publish_setup(&mut client, setup_topic, true);
+                    }
+                    disconnect(&mut client).await;
+                    shutdown::finished(shutdown::Participant::Mqtt);
+                    loop {
+                        core::future::pending::<()>().await;
+                    }
+                }
             }
         }
 
+        let unacked = unacked_samples();
+        if unacked > 0 {
+            warn!("MQTT: {} samples lost with the connection", unacked);
+            diagnostics::dropped_many(DropCause::ConnectionLost, unacked);
+        }
+
         info!("MQTT disconnected, retrying...");
     }
 }
 
+/// Sends the DISCONNECT, so the broker drops the session right away instead
+/// of waiting out the keep alive.
+async fn disconnect(client: &mut MqttClient<'_, '_>) {
+    info!("MQTT: disconnecting");
+    if let Err(err) = client.schedule_disconnect() {
+        warn!("MQTT: disconnect failed: {:?}", Debug2Format(&err));
+        return;
+    }
+
+    loop {
+        match poll_io_with_timeout(client).await {
+            Ok(Some(Event::Disconnected)) | Ok(None) | Err(_) => break,
+            Ok(Some(_)) => {}
+        }
+    }
+}
+
 async fn wait_for_connect(client: &mut MqttClient<'_, '_>) -> Result<(), mqtt_client::Error> {
     let deadline = Instant::now() + Duration::from_secs(CONNECT_TIMEOUT_SECS);
 
@@ -263,26 +685,36 @@ async fn poll_io_with_timeout<'a>(
 async fn publish_sample(
     client: &mut MqttClient<'_, '_>,
     topic: &'static str,
+    format: PayloadFormat,
+    client_id: &str,
     sample: sensors::Sample,
 ) -> bool {
-    let payload = build_payload(&sample);
+    let published_at = { net_time::TIME_STATE.lock().await.now_or_uptime() };
+    let mut buf = [0u8; payload::MAX_LEN];
+    let payload = match payload::build(&sample, format, client_id, published_at, &mut buf) {
+        Ok(payload) => payload,
+        Err(err) => {
+            warn!("MQTT: sample dropped: {}", err);
+            diagnostics::dropped(DropCause::Overflow);
+            return true;
+        }
+    };
 
     let msg = PublishMsg {
         qos: QoS::AtLeastOnce,
         retain: false,
         topic,
-        payload: payload.as_bytes(),
+        payload,
     };
 
-    if let Err(err) = client.schedule_publish(msg) {
-        warn!("MQTT: publish failed: {:?}", Debug2Format(&err));
-
+    if !schedule_publish(client, msg, Some(Instant::now())) {
         let result = { sensors::QUEUE.lock().await.enqueue(sample) };
 
         match result {
             Ok(()) => {}
             Err(_sample) => {
                 warn!("Could not put sample back to the queue");
+                diagnostics::dropped(DropCause::PublishFailed);
             }
         }
 
@@ -292,6 +724,232 @@ async fn publish_sample(
     true
 }
 
+/// Hands `msg` to the client and keeps a QoS 1 one in [`IN_FLIGHT`], with
+/// `sample_sent` for a sample. False when the client didn't take it.
+fn schedule_publish(
+    client: &mut MqttClient<'_, '_>,
+    msg: PublishMsg,
+    sample_sent: Option<Instant>,
+) -> bool {
+    let topic = msg.topic;
+    let acknowledged = matches!(msg.qos, QoS::AtLeastOnce);
+    if let Err(err) = client.schedule_publish(msg) {
+        warn!("MQTT: {} publish failed: {:?}", topic, Debug2Format(&err));
+        return false;
+    }
+
+    if acknowledged
+        && IN_FLIGHT
+            .lock(|in_flight| in_flight.borrow_mut().push_back(sample_sent))
+            .is_err()
+    {
+        warn!("MQTT: too many publishes in flight to tell the samples apart");
+    }
+
+    true
+}
+
+/// Takes the publish a PUBACK is for, the time it went out if it was a
+/// sample.
+fn acknowledged() -> Option<Instant> {
+    IN_FLIGHT.lock(|in_flight| in_flight.borrow_mut().pop_front().flatten())
+}
+
+fn oldest_unacked_sample() -> Option<Instant> {
+    IN_FLIGHT.lock(|in_flight| in_flight.borrow().iter().flatten().next().copied())
+}
+
+/// Samples sent and not acknowledged yet, lost when the connection drops.
+fn unacked_samples() -> u32 {
+    IN_FLIGHT.lock(|in_flight| in_flight.borrow().iter().flatten().count() as u32)
+}
+
+/// Serializes `value` and hands it to the client. False when the client
+/// didn't take it, one that doesn't fit is given up on.
+fn publish_json<T: Serialize + ?Sized>(
+    client: &mut MqttClient<'_, '_>,
+    topic: &str,
+    value: &T,
+    retain: bool,
+) -> bool {
+    let mut buf = [0u8; payload::MAX_LEN];
+    let Ok(len) = serde_json_core::to_slice(value, &mut buf) else {
+        warn!("MQTT: {} does not fit", topic);
+        return true;
+    };
+
+    let msg = PublishMsg {
+        qos: QoS::AtLeastOnce,
+        retain,
+        topic,
+        payload: &buf[..len],
+    };
+
+    schedule_publish(client, msg, None)
+}
+
+/// `{"1":"on","2":"off"}`, by the numbers of the relays from 1.
+struct Relays([Option<bool>; relay::RELAYS]);
+
+impl Serialize for Relays {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        for (i, state) in self.0.iter().enumerate() {
+            if let Some(state) = state {
+                let mut number = String::<3>::new();
+                write!(number, "{}", i + 1).ok();
+                map.serialize_entry(number.as_str(), if *state { "on" } else { "off" })?;
+            }
+        }
+
+        map.end()
+    }
+}
+
+/// Retained, nothing without relays.
+fn publish_relays(client: &mut MqttClient<'_, '_>, topic: &str) -> bool {
+    let states = relay::states();
+    if states.iter().all(Option::is_none) {
+        return true;
+    }
+
+    publish_json(client, topic, &Relays(states), true)
+}
+
+#[derive(Serialize)]
+struct Input {
+    active: bool,
+}
+
+/// Retained on `<topic>/inputs/<name>` for each watched input with its bit
+/// set in `changed`, e.g. `{"active":true}`.
+fn publish_inputs(client: &mut MqttClient<'_, '_>, topic: &str, changed: u8) -> bool {
+    let mut published = true;
+    for input in inputs::watched() {
+        if changed & (1 << input.id) == 0 {
+            continue;
+        }
+
+        let input_topic = subtopic(topic, format_args!("inputs/{}", input.name));
+        let state = Input {
+            active: input.active,
+        };
+        published &= publish_json(client, &input_topic, &state, true);
+    }
+
+    published
+}
+
+/// Retained on `<topic>/alerts/<metric>` for each alert with its bit set in
+/// `changed`, e.g. `{"active":true,"value":1250.0,"threshold":1200.0}`.
+fn publish_alerts(client: &mut MqttClient<'_, '_>, topic: &str, changed: u8) -> bool {
+    let mut published = true;
+    for (i, state) in alerts::states().iter().enumerate() {
+        if changed & (1 << i) == 0 {
+            continue;
+        }
+
+        let alert_topic = subtopic(topic, format_args!("alerts/{}", state.metric.name()));
+        published &= publish_json(client, &alert_topic, state, true);
+    }
+
+    published
+}
+
+/// What we heard of the beacons, and the beacons we are the nearest node to
+/// now, see [`presence`].
+fn publish_presence(client: &mut MqttClient<'_, '_>, client_id: &str) {
+    for (beacon, rssi) in presence::take_heard().into_iter().enumerate() {
+        let Some(rssi) = rssi else {
+            continue;
+        };
+
+        let name = presence::beacon_name(beacon);
+        let observation = presence::Observation {
+            node: client_id,
+            beacon: &name,
+            rssi,
+        };
+        let mut buf = [0u8; 96];
+        let Ok(len) = serde_json_core::to_slice(&observation, &mut buf) else {
+            continue;
+        };
+
+        let msg = PublishMsg {
+            qos: QoS::AtMostOnce,
+            retain: false,
+            topic: presence::OBSERVATIONS_TOPIC,
+            payload: &buf[..len],
+        };
+        if let Err(err) = client.schedule_publish(msg) {
+            warn!("MQTT: presence publish failed: {:?}", Debug2Format(&err));
+        }
+    }
+
+    for change in presence::changes(client_id) {
+        let (beacon, estimate) = match change {
+            presence::Change::Nearest { beacon, rssi } => (
+                beacon,
+                presence::Estimate {
+                    node: Some(client_id),
+                    rssi: Some(rssi),
+                },
+            ),
+            presence::Change::Gone { beacon } => (
+                beacon,
+                presence::Estimate {
+                    node: None,
+                    rssi: None,
+                },
+            ),
+        };
+        let mut buf = [0u8; 64];
+        let Ok(len) = serde_json_core::to_slice(&estimate, &mut buf) else {
+            continue;
+        };
+
+        let topic = presence::estimate_topic(beacon);
+        let msg = PublishMsg {
+            qos: QoS::AtMostOnce,
+            retain: true,
+            topic: &topic,
+            payload: &buf[..len],
+        };
+        if let Err(err) = client.schedule_publish(msg) {
+            warn!("MQTT: presence publish failed: {:?}", Debug2Format(&err));
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Setup {
+    pending: bool,
+}
+
+/// Retained, so it's still there while the node serves the setup access
+/// point instead of the broker.
+fn publish_setup(client: &mut MqttClient<'_, '_>, topic: &str, pending: bool) -> bool {
+    publish_json(client, topic, &Setup { pending }, true)
+}
+
+#[derive(Serialize)]
+struct Version<'a> {
+    version: &'a str,
+    git: &'static str,
+    chip: &'static str,
+    features: &'static [&'static str],
+}
+
+fn publish_version(client: &mut MqttClient<'_, '_>, topic: &str, firmware_version: &str) {
+    let info = Version {
+        version: firmware_version,
+        git: version::GIT_HASH,
+        chip: version::CHIP,
+        features: version::FEATURES,
+    };
+    publish_json(client, topic, &info, false);
+}
+
 fn handle_poll_result(
     client_id: &str,
     poll_result: Result<Option<Event<'_>>, mqtt_client::Error>,
@@ -313,6 +971,11 @@ fn handle_poll_result(
                         }
                         Err(err) => warn!("Error while converting payload to Command: {:?}", err),
                     }
+                } else if msg.topic.as_bytes() == presence::OBSERVATIONS_TOPIC.as_bytes() {
+                    match serde_json_core::from_slice(msg.payload.as_bytes()) {
+                        Ok((observation, _)) => presence::observed(&observation),
+                        Err(_) => warn!("MQTT: can't make sense of a presence observation"),
+                    }
                 } else {
                     warn!("Unknown packet arrived: {:?}", msg);
                 }
@@ -335,39 +998,3 @@ fn handle_poll_result(
 
     true
 }
-
-fn build_payload(sample: &sensors::Sample) -> String<256> {
-    let mut payload = String::<256>::new();
-
-    write!(payload, "{{\"ts\":{}", sample.timestamp).ok();
-    sample.temp_bme680.inspect(|value| {
-        write!(payload, ",\"temp_bme680\":{}", value).ok();
-    });
-    sample.press_bme680.inspect(|value| {
-        write!(payload, ",\"press_bme680\":{}", value).ok();
-    });
-    sample.hum_bme680.inspect(|value| {
-        write!(payload, ",\"hum_bme680\":{}", value).ok();
-    });
-    sample.lux_bh1750.inspect(|value| {
-        write!(payload, ",\"lux_bh1750\":{}", value).ok();
-    });
-    sample.lux_veml7700.inspect(|value| {
-        write!(payload, ",\"lux_veml7700\":{}", value).ok();
-    });
-    sample.temp_bmp390.inspect(|value| {
-        write!(payload, ",\"temp_bmp390\":{}", value).ok();
-    });
-    sample.press_bmp390.inspect(|value| {
-        write!(payload, ",\"press_bmp390\":{}", value).ok();
-    });
-    sample.hum_sht40.inspect(|value| {
-        write!(payload, ",\"hum_sht40\":{}", value).ok();
-    });
-    sample.temp_sht40.inspect(|value| {
-        write!(payload, ",\"temp_sht40\":{}", value).ok();
-    });
-    write!(payload, "}}").ok();
-
-    payload
-}
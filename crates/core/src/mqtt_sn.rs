@@ -0,0 +1,505 @@
+//! MQTT-SN over UDP (MQTT-SN 1.2), for nodes where keeping a TCP connection
+//! to the broker up costs too much power. A gateway such as the Eclipse Paho
+//! one forwards to the broker.
+//!
+//! Takes the samples from the same queue as MQTT over TCP and publishes them
+//! with QoS 1 to the configured topic, and subscribes to the command topic.
+//! The boot, version and self test reports are only sent over TCP.
+
+use core::net::Ipv4Addr;
+
+use defmt::{Debug2Format, info, warn};
+use embassy_futures::select;
+use embassy_net::Stack;
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_time::{Duration, Instant, Timer, with_timeout};
+use heapless::Vec;
+
+use crate::config::PayloadFormat;
+use crate::diagnostics::{self, DropCause, Timed};
+use crate::mqtt::{self, CommandSender, SampleReceiver};
+use crate::{Command, net_time, payload, sensors, shutdown, watchdog};
+
+/// Port of the Paho gateway.
+pub const GATEWAY_PORT: u16 = 10000;
+
+/// A full payload plus the PUBLISH header.
+const MAX_PACKET: usize = payload::MAX_LEN + 16;
+/// Long, as there is no connection to keep up, only the gateway's session.
+const KEEP_ALIVE_SECS: u16 = 15 * 60;
+const RETRY_TIMEOUT: Duration = Duration::from_secs(5);
+const RETRIES: u8 = 3;
+
+const PROTOCOL_ID: u8 = 0x01;
+const FLAG_CLEAN_SESSION: u8 = 0x04;
+const FLAG_QOS_1: u8 = 0x20;
+const RC_ACCEPTED: u8 = 0x00;
+
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+#[repr(u8)]
+enum MsgType {
+    Connect = 0x04,
+    ConnAck = 0x05,
+    Register = 0x0A,
+    RegAck = 0x0B,
+    Publish = 0x0C,
+    PubAck = 0x0D,
+    Subscribe = 0x12,
+    SubAck = 0x13,
+    PingReq = 0x16,
+    PingResp = 0x17,
+    Disconnect = 0x18,
+}
+
+impl MsgType {
+    fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            0x04 => Self::Connect,
+            0x05 => Self::ConnAck,
+            0x0A => Self::Register,
+            0x0B => Self::RegAck,
+            0x0C => Self::Publish,
+            0x0D => Self::PubAck,
+            0x12 => Self::Subscribe,
+            0x13 => Self::SubAck,
+            0x16 => Self::PingReq,
+            0x17 => Self::PingResp,
+            0x18 => Self::Disconnect,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, defmt::Format)]
+enum Error {
+    Socket,
+    Timeout,
+    /// The gateway answered with this return code.
+    Rejected(u8),
+    TooLong,
+}
+
+/// A packet to send: the length, the type and the body.
+struct Packet(Vec<u8, MAX_PACKET>);
+
+impl Packet {
+    fn new(msg_type: MsgType, body: &[&[u8]]) -> Result<Self, Error> {
+        let body_len: usize = body.iter().map(|part| part.len()).sum();
+        let mut packet = Vec::new();
+
+        // One length byte up to 255, otherwise 0x01 and two of them.
+        if body_len + 2 <= 255 {
+            packet.push((body_len + 2) as u8).ok();
+        } else {
+            let len = (body_len + 4) as u16;
+            packet
+                .extend_from_slice(&[0x01, (len >> 8) as u8, len as u8])
+                .map_err(|_| Error::TooLong)?;
+        }
+        packet.push(msg_type as u8).map_err(|_| Error::TooLong)?;
+        for part in body {
+            packet.extend_from_slice(part).map_err(|_| Error::TooLong)?;
+        }
+
+        Ok(Self(packet))
+    }
+}
+
+/// Splits a received datagram into its type and body.
+fn parse(datagram: &[u8]) -> Option<(MsgType, &[u8])> {
+    let (len, rest) = match datagram {
+        [0x01, high, low, rest @ ..] => (u16::from_be_bytes([*high, *low]) as usize, rest),
+        [len, rest @ ..] => (*len as usize, rest),
+        [] => return None,
+    };
+    let header = datagram.len() - rest.len();
+    let (msg_type, body) = rest.split_first()?;
+
+    (len == datagram.len() && len > header)
+        .then(|| MsgType::from_u8(*msg_type).map(|msg_type| (msg_type, body)))
+        .flatten()
+}
+
+fn u16_at(body: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([*body.get(at)?, *body.get(at + 1)?]))
+}
+
+struct Session<'a> {
+    socket: UdpSocket<'a>,
+    gateway: (Ipv4Addr, u16),
+    msg_id: u16,
+    command_topic_id: Option<u16>,
+    commands: CommandSender,
+    last_sent: Instant,
+}
+
+impl Session<'_> {
+    fn next_msg_id(&mut self) -> u16 {
+        self.msg_id = self.msg_id.wrapping_add(1).max(1);
+        self.msg_id
+    }
+
+    async fn send(&mut self, packet: &Packet) -> Result<(), Error> {
+        self.socket
+            .send_to(&packet.0, self.gateway)
+            .await
+            .map_err(|_| Error::Socket)?;
+        self.last_sent = Instant::now();
+
+        Ok(())
+    }
+
+    /// Sends `packet` until the gateway answers with `ack`, and copies the
+    /// answer's body to `body`. With `msg_id`, the answer has to carry that id
+    /// at the given offset of its body.
+    async fn exchange(
+        &mut self,
+        packet: &Packet,
+        ack: MsgType,
+        msg_id: Option<(u16, usize)>,
+        body: &mut [u8],
+    ) -> Result<usize, Error> {
+        for _ in 0..RETRIES {
+            self.send(packet).await?;
+
+            let deadline = Instant::now() + RETRY_TIMEOUT;
+            while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+                let mut datagram = [0u8; MAX_PACKET];
+                let Ok(received) = with_timeout(left, self.socket.recv_from(&mut datagram)).await
+                else {
+                    break;
+                };
+                let (len, _) = received.map_err(|_| Error::Socket)?;
+
+                match parse(&datagram[..len]) {
+                    Some((msg_type, answer))
+                        if msg_type == ack
+                            && msg_id.is_none_or(|(id, at)| u16_at(answer, at) == Some(id)) =>
+                    {
+                        let len = answer.len().min(body.len());
+                        body[..len].copy_from_slice(&answer[..len]);
+                        return Ok(len);
+                    }
+                    Some((msg_type, other)) => self.handle(msg_type, other).await,
+                    None => warn!("MQTT-SN: malformed datagram"),
+                }
+            }
+        }
+
+        Err(Error::Timeout)
+    }
+
+    async fn connect(&mut self, client_id: &str) -> Result<(), Error> {
+        let packet = Packet::new(
+            MsgType::Connect,
+            &[
+                &[FLAG_CLEAN_SESSION, PROTOCOL_ID],
+                &KEEP_ALIVE_SECS.to_be_bytes(),
+                client_id.as_bytes(),
+            ],
+        )?;
+
+        let mut body = [0u8; 1];
+        self.exchange(&packet, MsgType::ConnAck, None, &mut body)
+            .await?;
+        return_code(body[0])
+    }
+
+    /// Returns the topic id the gateway gave to `topic`.
+    async fn register(&mut self, topic: &str) -> Result<u16, Error> {
+        let msg_id = self.next_msg_id();
+        let packet = Packet::new(
+            MsgType::Register,
+            &[&0u16.to_be_bytes(), &msg_id.to_be_bytes(), topic.as_bytes()],
+        )?;
+
+        // Topic id, msg id, return code.
+        let mut body = [0u8; 5];
+        self.exchange(&packet, MsgType::RegAck, Some((msg_id, 2)), &mut body)
+            .await?;
+        return_code(body[4])?;
+
+        Ok(u16::from_be_bytes([body[0], body[1]]))
+    }
+
+    async fn subscribe(&mut self, topic: &str) -> Result<u16, Error> {
+        let msg_id = self.next_msg_id();
+        // QoS 0, a topic name.
+        let packet = Packet::new(
+            MsgType::Subscribe,
+            &[&[0x00], &msg_id.to_be_bytes(), topic.as_bytes()],
+        )?;
+
+        // Flags, topic id, msg id, return code.
+        let mut body = [0u8; 6];
+        self.exchange(&packet, MsgType::SubAck, Some((msg_id, 3)), &mut body)
+            .await?;
+        return_code(body[5])?;
+
+        Ok(u16::from_be_bytes([body[1], body[2]]))
+    }
+
+    async fn publish(&mut self, topic_id: u16, payload: &[u8]) -> Result<(), Error> {
+        let msg_id = self.next_msg_id();
+        let packet = Packet::new(
+            MsgType::Publish,
+            &[
+                &[FLAG_QOS_1],
+                &topic_id.to_be_bytes(),
+                &msg_id.to_be_bytes(),
+                payload,
+            ],
+        )?;
+
+        // Topic id, msg id, return code.
+        let mut body = [0u8; 5];
+        self.exchange(&packet, MsgType::PubAck, Some((msg_id, 2)), &mut body)
+            .await?;
+        return_code(body[4])
+    }
+
+    async fn ping(&mut self) -> Result<(), Error> {
+        let packet = Packet::new(MsgType::PingReq, &[])?;
+        self.exchange(&packet, MsgType::PingResp, None, &mut [])
+            .await
+            .map(|_| ())
+    }
+
+    async fn disconnect(&mut self) {
+        if let Ok(packet) = Packet::new(MsgType::Disconnect, &[]) {
+            let mut body = [];
+            self.exchange(&packet, MsgType::Disconnect, None, &mut body)
+                .await
+                .inspect_err(|err| warn!("MQTT-SN: disconnect failed: {}", err))
+                .ok();
+        }
+    }
+
+    /// Packets the gateway sends on its own: commands and the topic ids for
+    /// them.
+    async fn handle(&mut self, msg_type: MsgType, body: &[u8]) {
+        match msg_type {
+            MsgType::Publish => {
+                // Flags, topic id, msg id, data.
+                let (Some(topic_id), Some(msg_id)) = (u16_at(body, 1), u16_at(body, 3)) else {
+                    return;
+                };
+                let data = &body[5.min(body.len())..];
+
+                if Some(topic_id) == self.command_topic_id {
+                    match Command::try_from(data) {
+                        Ok(command) => {
+                            if let Err(err) = self.commands.try_send(command) {
+                                warn!("Could not apply command: {:?}", err);
+                            }
+                        }
+                        Err(err) => warn!("Error while converting payload to Command: {:?}", err),
+                    }
+                }
+
+                if body.first().is_some_and(|flags| flags & 0x60 == FLAG_QOS_1) {
+                    let ack = Packet::new(
+                        MsgType::PubAck,
+                        &[
+                            &topic_id.to_be_bytes(),
+                            &msg_id.to_be_bytes(),
+                            &[RC_ACCEPTED],
+                        ],
+                    );
+                    if let Ok(ack) = ack {
+                        self.send(&ack).await.ok();
+                    }
+                }
+            }
+            MsgType::Register => {
+                let (Some(topic_id), Some(msg_id)) = (u16_at(body, 0), u16_at(body, 2)) else {
+                    return;
+                };
+                let ack = Packet::new(
+                    MsgType::RegAck,
+                    &[
+                        &topic_id.to_be_bytes(),
+                        &msg_id.to_be_bytes(),
+                        &[RC_ACCEPTED],
+                    ],
+                );
+                if let Ok(ack) = ack {
+                    self.send(&ack).await.ok();
+                }
+            }
+            MsgType::Disconnect => warn!("MQTT-SN: the gateway dropped the session"),
+            other => info!("MQTT-SN: ignoring {}", other),
+        }
+    }
+}
+
+fn return_code(code: u8) -> Result<(), Error> {
+    match code {
+        RC_ACCEPTED => Ok(()),
+        code => Err(Error::Rejected(code)),
+    }
+}
+
+pub(crate) async fn run(
+    stack: Stack<'static>,
+    gateway: Ipv4Addr,
+    client_id: &'static str,
+    topic: &'static str,
+    format: PayloadFormat,
+    publish_receiver: SampleReceiver,
+    command_sender: CommandSender,
+) -> ! {
+    let cmd_topic = mqtt::command_topic(client_id);
+    let mut backoff = 1u64;
+
+    let mut stop = shutdown::STOP.receiver().unwrap();
+    shutdown::register(shutdown::Participant::Mqtt);
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buf = [0u8; 2 * MAX_PACKET];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buf = [0u8; 2 * MAX_PACKET];
+
+    let mut socket = UdpSocket::new(stack, &mut rx_meta, &mut rx_buf, &mut tx_meta, &mut tx_buf);
+    if let Err(err) = socket.bind(0) {
+        warn!("MQTT-SN: cannot bind a socket: {:?}", err);
+        loop {
+            core::future::pending::<()>().await;
+        }
+    }
+
+    let mut session = Session {
+        socket,
+        gateway: (gateway, GATEWAY_PORT),
+        msg_id: 0,
+        command_topic_id: None,
+        commands: command_sender,
+        last_sent: Instant::now(),
+    };
+
+    loop {
+        mqtt::wait_for_wifi().await;
+
+        info!("MQTT-SN: connecting to {}:{}", gateway, GATEWAY_PORT);
+
+        let topic_id = match setup(&mut session, client_id, topic, &cmd_topic).await {
+            Ok(topic_id) => topic_id,
+            Err(err) => {
+                warn!("MQTT-SN: connect failed: {}", err);
+                watchdog::check_in(watchdog::Task::Mqtt);
+                Timer::after_secs(backoff).await;
+                backoff = (backoff * 2).min(60);
+                continue;
+            }
+        };
+
+        info!("MQTT-SN: connected");
+        mqtt::set_ready();
+        backoff = 1;
+
+        let keep_alive = Duration::from_secs(KEEP_ALIVE_SECS as u64 * 3 / 4);
+
+        loop {
+            watchdog::check_in(watchdog::Task::Mqtt);
+
+            let ping_at = session.last_sent + keep_alive;
+            let mut datagram = [0u8; MAX_PACKET];
+
+            match select::select4(
+                publish_receiver.receive(),
+                session.socket.recv_from(&mut datagram),
+                Timer::at(ping_at.min(Instant::now() + Duration::from_secs(60))),
+                stop.changed(),
+            )
+            .await
+            {
+                select::Either4::First(sample) => {
+                    let start = Instant::now();
+                    let published_at = { net_time::TIME_STATE.lock().await.now_or_uptime() };
+                    let mut buf = [0u8; payload::MAX_LEN];
+                    let payload =
+                        match payload::build(&sample, format, client_id, published_at, &mut buf) {
+                            Ok(payload) => payload,
+                            Err(err) => {
+                                warn!("MQTT-SN: sample dropped: {}", err);
+                                diagnostics::dropped(DropCause::Overflow);
+                                continue;
+                            }
+                        };
+
+                    match session.publish(topic_id, payload).await {
+                        Ok(()) => {
+                            info!("MQTT-SN: published");
+                            diagnostics::record(Timed::MqttPublish, start);
+                            mqtt::published();
+                        }
+                        Err(err) => {
+                            warn!("MQTT-SN: publish failed: {}", err);
+                            if sensors::QUEUE.lock().await.enqueue(sample).is_err() {
+                                warn!("Could not put sample back to the queue");
+                                diagnostics::dropped(DropCause::PublishFailed);
+                            }
+                            mqtt::set_down();
+                            break;
+                        }
+                    }
+                }
+                select::Either4::Second(Ok((len, _))) => {
+                    if let Some((msg_type, body)) = parse(&datagram[..len]) {
+                        let disconnected = msg_type == MsgType::Disconnect;
+                        session.handle(msg_type, body).await;
+
+                        if disconnected {
+                            mqtt::set_down();
+                            break;
+                        }
+                    }
+                }
+                select::Either4::Second(Err(err)) => {
+                    warn!("MQTT-SN: receive failed: {:?}", Debug2Format(&err));
+                }
+                select::Either4::Third(()) => {
+                    if Instant::now() < ping_at {
+                        continue;
+                    }
+                    if let Err(err) = session.ping().await {
+                        warn!("MQTT-SN: the gateway does not answer: {}", err);
+                        mqtt::set_down();
+                        break;
+                    }
+                }
+                select::Either4::Fourth(_) => {
+                    info!("MQTT-SN: disconnecting");
+                    session.disconnect().await;
+                    shutdown::finished(shutdown::Participant::Mqtt);
+                    loop {
+                        core::future::pending::<()>().await;
+                    }
+                }
+            }
+        }
+
+        info!("MQTT-SN disconnected, retrying...");
+    }
+}
+
+/// Connects, registers the publish topic and subscribes to the commands.
+/// Returns the publish topic's id.
+async fn setup(
+    session: &mut Session<'_>,
+    client_id: &str,
+    topic: &str,
+    cmd_topic: &str,
+) -> Result<u16, Error> {
+    session.command_topic_id = None;
+    session.connect(client_id).await?;
+
+    let topic_id = session.register(topic).await?;
+
+    match session.subscribe(cmd_topic).await {
+        Ok(id) => session.command_topic_id = Some(id),
+        Err(err) => warn!("MQTT-SN: subscribe failed: {}", err),
+    }
+
+    Ok(topic_id)
+}
@@ -3,12 +3,28 @@ use core::{
     sync::atomic::{AtomicU32, Ordering},
 };
 
-use defmt::{info, warn};
-use embassy_net::{IpAddress, IpEndpoint, udp::PacketMetadata};
-use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
-use embassy_time::Instant;
+use core::cell::RefCell;
+
+use defmt::{Debug2Format, info, warn};
+use embassy_net::{IpAddress, IpEndpoint, dns::DnsQueryType, udp::PacketMetadata};
+use embassy_sync::{blocking_mutex, blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
+use embassy_time::{Duration, Instant, Timer, with_timeout};
+use heapless::String;
+
+/// Many routers run NTP, it keeps the time on a LAN without internet.
+pub const DEFAULT_SERVERS: &str = "pool.ntp.org gateway";
+/// Stands for the default gateway in the list of servers.
+const GATEWAY: &str = "gateway";
+const NTP_PORT: u16 = 123;
+/// For each lookup and each server.
+const TIMEOUT: Duration = Duration::from_secs(5);
+const SYNC_PERIOD: Duration = Duration::from_secs(6 * 60 * 60);
+/// After none of the servers answered.
+const RETRY_PERIOD: Duration = Duration::from_secs(5 * 60);
 
 pub static TIME_STATE: Mutex<CriticalSectionRawMutex, TimeState> = Mutex::new(TimeState::new());
+static SERVERS: blocking_mutex::Mutex<CriticalSectionRawMutex, RefCell<String<96>>> =
+    blocking_mutex::Mutex::new(RefCell::new(String::new()));
 
 pub struct TimeState {
     unit_at_sync: AtomicU32,
@@ -47,23 +63,75 @@ impl TimeState {
     }
 }
 
+/// Sets the NTP servers from the settings, tried in order until one answers:
+/// host names, addresses and `gateway` for the default gateway, separated by
+/// commas or spaces. [`DEFAULT_SERVERS`] when it's empty.
+pub fn set_servers(list: &str) {
+    let list = if list.trim().is_empty() {
+        DEFAULT_SERVERS
+    } else {
+        list
+    };
+
+    SERVERS.lock(|servers| servers.replace(String::try_from(list).unwrap_or_default()));
+}
+
 #[embassy_executor::task]
-pub async fn sync_task(
-    stack: embassy_net::Stack<'static>,
-) -> ! {
+pub async fn sync_task(stack: embassy_net::Stack<'static>) -> ! {
     loop {
         stack.wait_config_up().await;
 
-        match sync_time(stack).await {
-            Ok(secs) => {
-                info!("Received seconds: {}", secs);
-                let time_state = TIME_STATE.lock().await;
-                time_state.set(secs);
+        let servers = SERVERS.lock(|servers| servers.borrow().clone());
+        let mut synced = false;
+        for server in servers
+            .split([',', ' '])
+            .filter(|server| !server.is_empty())
+        {
+            let Some(address) = resolve(stack, server).await else {
+                continue;
+            };
+
+            match with_timeout(TIMEOUT, sync_time(stack, address)).await {
+                Ok(Ok(secs)) => {
+                    info!("NTP: {} from {}", secs, server);
+                    TIME_STATE.lock().await.set(secs);
+                    synced = true;
+                    break;
+                }
+                Ok(Err(_)) => {}
+                Err(_) => warn!("NTP: no answer from {}", server),
             }
-            Err(_) => {},
         }
 
-        embassy_time::Timer::after_secs(60 * 60 * 6).await;
+        Timer::after(if synced { SYNC_PERIOD } else { RETRY_PERIOD }).await;
+    }
+}
+
+/// The address of `server`, none when there is no gateway or the name
+/// doesn't resolve.
+async fn resolve(stack: embassy_net::Stack<'_>, server: &str) -> Option<IpAddress> {
+    if server == GATEWAY {
+        let gateway = stack.config_v4().and_then(|config| config.gateway);
+        if gateway.is_none() {
+            warn!("NTP: there is no default gateway");
+        }
+        return gateway.map(IpAddress::Ipv4);
+    }
+
+    if let Ok(address) = server.parse::<Ipv4Addr>() {
+        return Some(IpAddress::Ipv4(address));
+    }
+
+    match with_timeout(TIMEOUT, stack.dns_query(server, DnsQueryType::A)).await {
+        Ok(Ok(addresses)) => addresses.first().copied(),
+        Ok(Err(err)) => {
+            warn!("NTP: cannot resolve {}: {:?}", server, Debug2Format(&err));
+            None
+        }
+        Err(_) => {
+            warn!("NTP: resolving {} timed out", server);
+            None
+        }
     }
 }
 
@@ -75,7 +143,7 @@ enum NtpError {
     Other,
 }
 
-async fn sync_time(stack: embassy_net::Stack<'_>) -> Result<u32, NtpError> {
+async fn sync_time(stack: embassy_net::Stack<'_>, address: IpAddress) -> Result<u32, NtpError> {
     use embassy_net::udp::UdpSocket;
 
     info!("Getting NTP time");
@@ -86,17 +154,13 @@ async fn sync_time(stack: embassy_net::Stack<'_>) -> Result<u32, NtpError> {
     let mut tx_buf = [0u8; 48];
 
     let mut socket = UdpSocket::new(stack, &mut rx_meta, &mut rx_buf, &mut tx_meta, &mut tx_buf);
-    let addr = Ipv4Addr::new(91, 212, 242, 19);
 
     if let Err(err) = socket.bind(0) {
         warn!("Cannot bind to a socket");
         return Err(NtpError::Bind(err));
     };
 
-    let endpoint = IpEndpoint {
-        addr: IpAddress::Ipv4(addr),
-        port: 123,
-    };
+    let endpoint = IpEndpoint::new(address, NTP_PORT);
 
     let mut packet = [0u8; 48];
     packet[0] = 0b11100011;
@@ -132,3 +196,19 @@ async fn sync_time(stack: embassy_net::Stack<'_>) -> Result<u32, NtpError> {
 
     Ok(secs - NTP_UNIX_OFFSET)
 }
+
+/// Year, month and day of the days since 1970-01-01.
+pub fn civil_from_days(days: u32) -> (u32, u32, u32) {
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as u32;
+
+    (year, month, day)
+}
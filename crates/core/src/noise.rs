@@ -0,0 +1,148 @@
+//! Noise level from an INMP441 I2S microphone, as the A-weighted equivalent
+//! sound level (LAeq) over the time between two samples.
+//!
+//! The microphone is read without a break at 48 kHz. Every reading goes
+//! through the A-weighting filter and only the sum of the squares is kept,
+//! the sensors task turns it into dBA when it takes a sample.
+
+use core::cell::Cell;
+
+use defmt::{info, warn};
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use esp_hal::dma_buffers;
+use esp_hal::i2s::master::{Channels, Config, DataFormat, I2s};
+use esp_hal::peripherals::{DMA_CH0, I2S0};
+use esp_hal::time::Rate;
+
+use crate::board::{self, Pins};
+
+const SAMPLE_RATE: u32 = 48_000;
+/// -26 dBFS at 94 dB SPL, from the datasheet, for the 24 bits of a reading.
+const MIC_SENSITIVITY_DBFS: f32 = -26.0;
+const MIC_REF_DB: f32 = 94.0;
+const FULL_SCALE: f32 = ((1 << 23) - 1) as f32;
+/// The microphone needs that long after power up before its readings mean
+/// anything.
+const STARTUP_READINGS: u32 = SAMPLE_RATE / 4;
+
+static ENERGY: Mutex<CriticalSectionRawMutex, Cell<Energy>> =
+    Mutex::new(Cell::new(Energy { sum: 0.0, count: 0 }));
+
+#[derive(Clone, Copy)]
+struct Energy {
+    /// Sum of the squared, A-weighted readings.
+    sum: f64,
+    count: u32,
+}
+
+/// A-weighting for 48 kHz as three second order sections, after
+/// github.com/ikostoski/esp32-i2s-slm. Each section is `[b1, b2, a1, a2]`
+/// with `b0` = 1 and the `a` coefficients negated.
+const A_WEIGHTING_GAIN: f32 = 0.169_994_95;
+const A_WEIGHTING: [[f32; 4]; 3] = [
+    [-2.000_269_9, 1.000_270_6, -1.060_868_4, -0.163_987_45],
+    [4.359_123_8, 3.091_202_7, 1.208_419_9, -0.273_167],
+    [-0.709_303, -0.290_718_7, 1.982_242_2, -0.982_298_6],
+];
+
+#[derive(Default, Clone, Copy)]
+struct Section {
+    w1: f32,
+    w2: f32,
+}
+
+impl Section {
+    fn filter(&mut self, [b1, b2, a1, a2]: [f32; 4], input: f32) -> f32 {
+        let w = input + a1 * self.w1 + a2 * self.w2;
+        let output = w + b1 * self.w1 + b2 * self.w2;
+        self.w2 = self.w1;
+        self.w1 = w;
+
+        output
+    }
+}
+
+/// LAeq since the last call, none without readings.
+pub fn take_dba() -> Option<f32> {
+    let energy = ENERGY.lock(|energy| energy.replace(Energy { sum: 0.0, count: 0 }));
+    if energy.count == 0 {
+        return None;
+    }
+
+    let rms = libm::sqrt(energy.sum / energy.count as f64) as f32;
+    let reference = FULL_SCALE * libm::powf(10.0, MIC_SENSITIVITY_DBFS / 20.0);
+
+    Some(MIC_REF_DB + 20.0 * libm::log10f(rms / reference))
+}
+
+/// Needs [`Pins::microphone`]. The L/R pin of the microphone has to be low,
+/// only the left channel is read.
+#[embassy_executor::task]
+pub async fn task(i2s0: I2S0<'static>, dma: DMA_CH0<'static>, pins: Pins) -> ! {
+    info!("Noise: started");
+
+    let (rx_buffer, rx_descriptors, _, _) = dma_buffers!(4 * 4096, 0);
+
+    let config = Config::new_tdm_philips()
+        .with_sample_rate(Rate::from_hz(SAMPLE_RATE))
+        .with_data_format(DataFormat::Data32Channel32)
+        .with_channels(Channels::STEREO);
+    let i2s = I2s::new(i2s0, dma, config).unwrap().into_async();
+
+    let rx = i2s
+        .i2s_rx
+        .with_bclk(unsafe { board::pin(pins.mic_sck) })
+        .with_ws(unsafe { board::pin(pins.mic_ws) })
+        .with_din(unsafe { board::pin(pins.mic_sd) })
+        .build(rx_descriptors);
+
+    let mut transfer = rx.read_dma_circular_async(rx_buffer).unwrap();
+    let mut sections = [Section::default(); 3];
+    let mut skip = STARTUP_READINGS;
+    let mut chunk = [0u8; 4096];
+    // Bytes of a frame split over two reads.
+    let mut carry = 0;
+
+    loop {
+        let len = match transfer.pop(&mut chunk[carry..]).await {
+            Ok(len) => carry + len,
+            Err(err) => {
+                warn!("Noise: reading the microphone failed: {:?}", err);
+                continue;
+            }
+        };
+
+        let mut sum = 0.0f64;
+        let mut count = 0u32;
+
+        // Frames of a left and a right 32 bit word, the reading is in the top
+        // 24 bits of the left one.
+        let frames = chunk[..len].chunks_exact(8);
+        let rest = frames.remainder().len();
+        for frame in frames {
+            let reading = i32::from_le_bytes([frame[0], frame[1], frame[2], frame[3]]) >> 8;
+
+            let mut value = reading as f32 * A_WEIGHTING_GAIN;
+            for (section, coefficients) in sections.iter_mut().zip(A_WEIGHTING) {
+                value = section.filter(coefficients, value);
+            }
+
+            if skip > 0 {
+                skip -= 1;
+                continue;
+            }
+            sum += (value * value) as f64;
+            count += 1;
+        }
+
+        ENERGY.lock(|energy| {
+            let mut total = energy.get();
+            total.sum += sum;
+            total.count += count;
+            energy.set(total);
+        });
+
+        chunk.copy_within(len - rest..len, 0);
+        carry = rest;
+    }
+}
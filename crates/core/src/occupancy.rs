@@ -0,0 +1,120 @@
+//! Occupancy from the PIR sensor on the motion input.
+//!
+//! The room turns occupied on the first motion and is published right away,
+//! on `<topic>/occupancy`, without waiting for the next sample. It turns
+//! vacant again once the sensor has seen no motion for the hold time of the
+//! settings, so someone sitting still at a desk doesn't flip it every few
+//! seconds.
+//!
+//! Needs MQTT over TCP, like [`crate::events`].
+
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+
+use defmt::info;
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, with_timeout};
+use serde::Serialize;
+
+use crate::config::DEFAULT_MOTION_HOLD_SECS;
+use crate::{inputs, net_time};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static HOLD_SECS: AtomicU16 = AtomicU16::new(DEFAULT_MOTION_HOLD_SECS);
+static STATE: Mutex<CriticalSectionRawMutex, Cell<Occupancy>> = Mutex::new(Cell::new(Occupancy {
+    occupied: false,
+    timestamp: 0,
+}));
+static CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// As it's published, e.g. `{"occupied":true,"ts":1700000000}`.
+#[derive(Clone, Copy, Serialize)]
+pub struct Occupancy {
+    pub occupied: bool,
+    /// When it last changed.
+    #[serde(rename = "ts")]
+    pub timestamp: u32,
+}
+
+/// How long the room stays occupied after the last motion.
+pub fn set_hold(secs: u16) {
+    HOLD_SECS.store(secs.max(1), Ordering::Relaxed);
+}
+
+/// Whether [`task`] runs, there is nothing to publish otherwise.
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn current() -> Occupancy {
+    STATE.lock(|state| state.get())
+}
+
+/// Waits for the occupancy to change.
+pub async fn changed() -> Occupancy {
+    CHANGED.wait().await;
+
+    current()
+}
+
+/// Follows the motion input, needs [`crate::board::Pins::motion`].
+#[embassy_executor::task]
+pub async fn task() -> ! {
+    ENABLED.store(true, Ordering::Relaxed);
+    let hold = Duration::from_secs(HOLD_SECS.load(Ordering::Relaxed) as u64);
+    let mut events = inputs::subscribe();
+    info!("Occupancy: started, hold {}s", hold.as_secs());
+
+    // The output of the sensor, high while it sees motion.
+    let mut motion = false;
+    let mut occupied = false;
+
+    loop {
+        let next = if occupied && !motion {
+            with_timeout(hold, next_motion(&mut events)).await.ok()
+        } else {
+            Some(next_motion(&mut events).await)
+        };
+
+        match next {
+            Some(active) => {
+                motion = active;
+                if active && !occupied {
+                    occupied = true;
+                    set(occupied).await;
+                }
+            }
+            None => {
+                occupied = false;
+                set(occupied).await;
+            }
+        }
+    }
+}
+
+/// The next change of the motion input.
+async fn next_motion(events: &mut inputs::Events) -> bool {
+    loop {
+        let event = events.next_message_pure().await;
+        if event.id == inputs::MOTION {
+            return event.active;
+        }
+    }
+}
+
+async fn set(occupied: bool) {
+    info!(
+        "Occupancy: {}",
+        if occupied { "occupied" } else { "vacant" }
+    );
+
+    let timestamp = { net_time::TIME_STATE.lock().await.now_or_uptime() };
+    STATE.lock(|state| {
+        state.set(Occupancy {
+            occupied,
+            timestamp,
+        })
+    });
+    CHANGED.signal(());
+}
@@ -0,0 +1,425 @@
+//! Firmware updates pulled over plain HTTP.
+//!
+//! The manifest at the configured URL is a JSON object such as
+//! `{"version":"0.2.0","url":"http://192.168.1.10:8000/sensors-node.bin","sha256":"..."}`.
+//! There is no DNS, so both URLs need an IP address as the host.
+//!
+//! A firmware built with `OTA_PUBLIC_KEY` set to a hex Ed25519 public key
+//! only takes images whose manifest has a `"signature"`: the hex Ed25519
+//! signature of the raw SHA-256 digest of the image. It is checked before
+//! the new slot is activated.
+//!
+//! With Secure Boot v2 on, the bootloader only starts images that carry a
+//! signature block, so an image without one is turned down right away
+//! instead of being rolled back later. Flash encryption is not supported:
+//! the image would have to be written through the encryption, which
+//! esp-storage doesn't do, so updates are refused on such chips.
+
+extern crate alloc;
+
+use core::net::Ipv4Addr;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use defmt::{Debug2Format, error, info, warn};
+use ed25519_dalek::{Signature, VerifyingKey};
+use embassy_futures::select;
+use embassy_net::Stack;
+use embassy_net::tcp::TcpSocket;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Timer, with_timeout};
+use embedded_io_async::{Read, Write};
+use embedded_storage::Storage;
+use esp_bootloader_esp_idf::{ota::OtaImageState, ota_updater::OtaUpdater, partitions};
+use esp_hal::efuse::{self, Efuse};
+use esp_storage::FlashStorage;
+use heapless::String;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::event_log::{self, Kind};
+use crate::syslog::{self, Severity};
+use crate::{shutdown, system};
+
+static REQUEST: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static PENDING_VERIFY: AtomicBool = AtomicBool::new(false);
+
+const SOCKET_TIMEOUT: Duration = Duration::from_secs(30);
+const CHUNK_LEN: usize = 4096;
+const HEADER_LEN: usize = 512;
+/// How long a new image has to get through WiFi and MQTT before it is rolled back.
+const CONFIRM_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// Hex Ed25519 key the manifests are signed with, unsigned updates without.
+const PUBLIC_KEY: Option<&str> = option_env!("OTA_PUBLIC_KEY");
+/// The Secure Boot v2 signature block fills the last sector of an image and
+/// starts with this magic and version.
+const SIGNATURE_SECTOR: usize = 4096;
+const SIGNATURE_BLOCK_MAGIC: [u8; 2] = [0xE7, 0x02];
+
+#[derive(Debug, defmt::Format)]
+pub enum Error {
+    Url,
+    Connect,
+    Io,
+    Http,
+    Manifest,
+    Flash,
+    Checksum,
+    /// The manifest isn't signed with [`PUBLIC_KEY`].
+    Signature,
+    /// Secure Boot is on and the image has no signature block.
+    Unsigned,
+    /// The chip encrypts its flash, see the module docs.
+    Encrypted,
+}
+
+#[derive(Deserialize)]
+struct Manifest {
+    version: String<16>,
+    url: String<128>,
+    sha256: String<64>,
+    #[serde(default)]
+    signature: Option<String<128>>,
+}
+
+/// Runs first thing at boot. A new image is put on probation until
+/// [`confirm_task`] sees it connected, and an image that was on probation
+/// already did not make it last time, so the previous one is started instead.
+pub fn check_boot() {
+    let mut flash = flash();
+    let mut table = [0u8; partitions::PARTITION_TABLE_MAX_LEN];
+    let Ok(mut ota) = OtaUpdater::new(&mut flash, &mut table) else {
+        return;
+    };
+
+    match ota.current_ota_state() {
+        Ok(OtaImageState::New) => {
+            info!("OTA: new image, waiting for it to connect");
+            event_log::record(Kind::OtaInstalled);
+            match ota.set_current_ota_state(OtaImageState::PendingVerify) {
+                Ok(()) => PENDING_VERIFY.store(true, Ordering::Relaxed),
+                Err(err) => warn!("OTA: could not mark image pending: {:?}", err),
+            }
+        }
+        Ok(OtaImageState::PendingVerify) => roll_back(&mut ota),
+        _ => {}
+    }
+}
+
+/// Marks the image valid once MQTT is connected, or rolls back when that
+/// does not happen within [`CONFIRM_TIMEOUT`].
+#[embassy_executor::task]
+pub async fn confirm_task() {
+    if !PENDING_VERIFY.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let connected = with_timeout(
+        CONFIRM_TIMEOUT,
+        system::wait_for_state(|state| state == system::State::Ok),
+    )
+    .await;
+
+    let mut flash = flash();
+    let mut table = [0u8; partitions::PARTITION_TABLE_MAX_LEN];
+    let Ok(mut ota) = OtaUpdater::new(&mut flash, &mut table) else {
+        return;
+    };
+
+    if connected.is_err() {
+        roll_back(&mut ota);
+    }
+
+    match ota.set_current_ota_state(OtaImageState::Valid) {
+        Ok(()) => {
+            PENDING_VERIFY.store(false, Ordering::Relaxed);
+            info!("OTA: image confirmed");
+            event_log::record(Kind::OtaConfirmed);
+        }
+        Err(err) => warn!("OTA: could not confirm image: {:?}", err),
+    }
+}
+
+fn roll_back(ota: &mut OtaUpdater<'_, FlashStorage<'static>>) -> ! {
+    error!("OTA: image did not connect, rolling back");
+    syslog::log(
+        Severity::Error,
+        format_args!("OTA: image did not connect, rolling back"),
+    );
+
+    if let Err(err) = ota.set_current_ota_state(OtaImageState::Invalid) {
+        warn!("OTA: could not mark image invalid: {:?}", err);
+    }
+    if let Err(err) = ota.activate_next_partition() {
+        error!("OTA: could not activate previous image: {:?}", err);
+    }
+
+    esp_hal::system::software_reset()
+}
+
+/// The key-value storage holds the peripheral, but only touches its own
+/// region far from the partition table and the app slots.
+fn flash() -> FlashStorage<'static> {
+    FlashStorage::new(unsafe { esp_hal::peripherals::FLASH::steal() })
+}
+
+/// Checks for an update right away instead of waiting for the schedule.
+pub fn request_update() {
+    REQUEST.signal(());
+}
+
+/// Checks the manifest every `check_hours` (only on request when 0) and
+/// installs a newer firmware than `current_version`.
+#[embassy_executor::task]
+pub async fn task(
+    stack: Stack<'static>,
+    manifest_url: &'static str,
+    current_version: &'static str,
+    check_hours: u8,
+) -> ! {
+    loop {
+        if check_hours == 0 {
+            REQUEST.wait().await;
+        } else {
+            let period = Duration::from_secs(check_hours as u64 * 60 * 60);
+            select::select(REQUEST.wait(), Timer::after(period)).await;
+        }
+
+        match update(stack, manifest_url, current_version).await {
+            Ok(false) => info!("OTA: firmware {} is up to date", current_version),
+            Ok(true) => {
+                info!("OTA: update installed, rebooting");
+                syslog::log(Severity::Notice, format_args!("OTA: update installed"));
+                shutdown::restart(shutdown::Reason::Update).await;
+            }
+            Err(err) => {
+                warn!("OTA: update failed: {:?}", err);
+                syslog::log(
+                    Severity::Warning,
+                    format_args!("OTA: update failed: {:?}", err),
+                );
+            }
+        }
+    }
+}
+
+/// Returns whether a new image was written and activated.
+async fn update(
+    stack: Stack<'static>,
+    manifest_url: &str,
+    current_version: &str,
+) -> Result<bool, Error> {
+    let mut body = [0u8; HEADER_LEN];
+    let mut socket_buffers = ([0u8; 1024], [0u8; 1024]);
+    let mut socket = TcpSocket::new(stack, &mut socket_buffers.0, &mut socket_buffers.1);
+
+    let length = get(&mut socket, manifest_url).await?;
+    let length = length.unwrap_or(body.len()).min(body.len());
+    socket
+        .read_exact(&mut body[..length])
+        .await
+        .map_err(|_| Error::Io)?;
+    socket.close();
+    drop(socket);
+
+    let (manifest, _): (Manifest, _) =
+        serde_json_core::from_slice(&body[..length]).map_err(|_| Error::Manifest)?;
+
+    if parse_version(&manifest.version) <= parse_version(current_version) {
+        return Ok(false);
+    }
+
+    if flash_encrypted() {
+        return Err(Error::Encrypted);
+    }
+
+    info!(
+        "OTA: downloading {} from {}",
+        manifest.version.as_str(),
+        manifest.url.as_str()
+    );
+
+    let mut socket = TcpSocket::new(stack, &mut socket_buffers.0, &mut socket_buffers.1);
+    let Some(length) = get(&mut socket, &manifest.url).await? else {
+        return Err(Error::Http);
+    };
+
+    let mut flash = flash();
+    let mut table = [0u8; partitions::PARTITION_TABLE_MAX_LEN];
+    let mut ota = OtaUpdater::new(&mut flash, &mut table).map_err(|_| Error::Flash)?;
+    let (mut partition, _) = ota.next_partition().map_err(|_| Error::Flash)?;
+
+    let secure_boot = Efuse::read_bit(efuse::SECURE_BOOT_EN);
+    if secure_boot && length % SIGNATURE_SECTOR != 0 {
+        return Err(Error::Unsigned);
+    }
+
+    let mut hasher = Sha256::new();
+    let mut chunk = [0u8; CHUNK_LEN];
+    let mut offset = 0;
+    // The first bytes of the last sector, where the signature block goes.
+    let mut block_start = [0u8; 2];
+
+    while offset < length {
+        let read = socket.read(&mut chunk).await.map_err(|_| Error::Io)?;
+        if read == 0 {
+            return Err(Error::Io);
+        }
+
+        hasher.update(&chunk[..read]);
+        let last_sector = length.saturating_sub(SIGNATURE_SECTOR);
+        for (i, byte) in (offset..offset + read).zip(&chunk[..read]) {
+            if let Some(slot) = i
+                .checked_sub(last_sector)
+                .and_then(|i| block_start.get_mut(i))
+            {
+                *slot = *byte;
+            }
+        }
+        partition
+            .write(offset as u32, &chunk[..read])
+            .map_err(|_| Error::Flash)?;
+        offset += read;
+    }
+    socket.close();
+
+    let digest = hasher.finalize();
+    if !checksum_matches(&digest, &manifest.sha256) {
+        error!("OTA: checksum mismatch, image discarded");
+        return Err(Error::Checksum);
+    }
+
+    if !signature_valid(&digest, manifest.signature.as_deref()) {
+        error!("OTA: signature doesn't match, image discarded");
+        return Err(Error::Signature);
+    }
+
+    if secure_boot && block_start != SIGNATURE_BLOCK_MAGIC {
+        error!("OTA: no Secure Boot signature block, image discarded");
+        return Err(Error::Unsigned);
+    }
+
+    ota.activate_next_partition().map_err(|_| Error::Flash)?;
+    ota.set_current_ota_state(OtaImageState::New)
+        .map_err(|_| Error::Flash)?;
+
+    Ok(true)
+}
+
+/// Sends a GET request and reads the response headers. Leaves the socket at
+/// the start of the body and returns its length when the server told it.
+async fn get(socket: &mut TcpSocket<'_>, url: &str) -> Result<Option<usize>, Error> {
+    let (address, port, path) = parse_url(url).ok_or(Error::Url)?;
+
+    socket.set_timeout(Some(SOCKET_TIMEOUT));
+    socket
+        .connect((address, port))
+        .await
+        .map_err(|_| Error::Connect)?;
+
+    let request = alloc::format!("GET {path} HTTP/1.0\r\nHost: {address}\r\n\r\n");
+    socket
+        .write_all(request.as_bytes())
+        .await
+        .map_err(|_| Error::Io)?;
+
+    // Byte by byte, so nothing of the body is consumed with the headers.
+    let mut headers = [0u8; HEADER_LEN];
+    let mut len = 0;
+    while !headers[..len].ends_with(b"\r\n\r\n") {
+        if len == headers.len() {
+            return Err(Error::Http);
+        }
+
+        socket
+            .read_exact(&mut headers[len..len + 1])
+            .await
+            .map_err(|_| Error::Io)?;
+        len += 1;
+    }
+
+    let headers = core::str::from_utf8(&headers[..len]).map_err(|_| Error::Http)?;
+    let mut lines = headers.lines();
+
+    let status = lines.next().and_then(|line| line.split(' ').nth(1));
+    if status != Some("200") {
+        warn!("OTA: {} answered {:?}", url, Debug2Format(&status));
+        return Err(Error::Http);
+    }
+
+    Ok(lines.find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.eq_ignore_ascii_case("content-length")
+            .then(|| value.trim().parse().ok())
+            .flatten()
+    }))
+}
+
+/// Splits `http://192.168.1.10:8000/path` into its address, port and path.
+fn parse_url(url: &str) -> Option<(Ipv4Addr, u16, &str)> {
+    let rest = url.strip_prefix("http://")?;
+    let (host, path) = match rest.find('/') {
+        Some(index) => rest.split_at(index),
+        None => (rest, "/"),
+    };
+    let (address, port) = match host.split_once(':') {
+        Some((address, port)) => (address, port.parse().ok()?),
+        None => (host, 80),
+    };
+
+    Some((address.parse().ok()?, port, path))
+}
+
+/// `major.minor.patch`, missing or broken parts count as 0.
+fn parse_version(version: &str) -> (u16, u16, u16) {
+    let mut parts = version
+        .trim_start_matches('v')
+        .split('.')
+        .map(|part| part.parse().unwrap_or(0));
+
+    (
+        parts.next().unwrap_or(0),
+        parts.next().unwrap_or(0),
+        parts.next().unwrap_or(0),
+    )
+}
+
+/// False for anything but 64 hex digits, a manifest may have any text there.
+fn checksum_matches(digest: &[u8], expected: &str) -> bool {
+    parse_hex::<32>(expected).is_some_and(|expected| expected[..] == *digest)
+}
+
+/// Always true without a [`PUBLIC_KEY`].
+fn signature_valid(digest: &[u8], signature: Option<&str>) -> bool {
+    let Some(key) = PUBLIC_KEY else {
+        return true;
+    };
+
+    let Some(key) = parse_hex::<32>(key).and_then(|key| VerifyingKey::from_bytes(&key).ok()) else {
+        error!("OTA: OTA_PUBLIC_KEY is not an Ed25519 key");
+        return false;
+    };
+    let Some(signature) = signature.and_then(parse_hex::<64>) else {
+        return false;
+    };
+
+    key.verify_strict(digest, &Signature::from_bytes(&signature))
+        .is_ok()
+}
+
+fn parse_hex<const N: usize>(text: &str) -> Option<[u8; N]> {
+    if text.len() != N * 2 {
+        return None;
+    }
+
+    let mut bytes = [0u8; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(text.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+
+    Some(bytes)
+}
+
+/// An odd number of bits in `SPI_BOOT_CRYPT_CNT` turns the encryption on.
+fn flash_encrypted() -> bool {
+    Efuse::read_field_le::<u8>(efuse::SPI_BOOT_CRYPT_CNT).count_ones() % 2 == 1
+}
@@ -0,0 +1,407 @@
+//! The payload a sample is published with, serialized straight into the
+//! buffer handed to the client.
+//!
+//! Nothing is cut short on the way: a sample that doesn't fit is an
+//! [`Error::Overflow`] and the caller decides what to do with it.
+//!
+//! Readings can be left out of the payload, say the BMP390 temperature when
+//! the SHT40 is the one the dashboards show, see [`select_fields`].
+//!
+//! The JSON payload also says where the node is, see [`set_place`], so the
+//! broker side doesn't need a table from the client ids to the rooms.
+
+use core::cell::{Cell, RefCell};
+
+use defmt::warn;
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use heapless::String;
+use serde::Serialize;
+use serde::ser::{SerializeMap, Serializer};
+
+use crate::config::PayloadFormat;
+use crate::diagnostics::{self, Timed};
+use crate::sensors::{Sample, SampleVersion};
+use crate::{availability, power, senml, system};
+
+/// Room for a sample with every field set, the availability, the warnings
+/// and the timings, in either format. The build fails when the longest
+/// payload of either doesn't fit, see `MAX_JSON` and [`senml::MAX_LEN`].
+pub const MAX_LEN: usize = 1824;
+
+const _: () = assert!(MAX_JSON <= MAX_LEN, "the JSON payload may not fit MAX_LEN");
+const _: () = assert!(
+    senml::MAX_LEN <= MAX_LEN,
+    "the SenML payload may not fit MAX_LEN"
+);
+
+/// The longest numbers serde-json-core writes, like `-1.17549435e-38` and
+/// `4294967295`.
+pub(crate) const F32_LEN: usize = 15;
+pub(crate) const U32_LEN: usize = 10;
+const U16_LEN: usize = 5;
+const U8_LEN: usize = 3;
+
+const LOCATION_LEN: usize = 32;
+const ROOM_LEN: usize = 32;
+const LABELS_LEN: usize = 64;
+const MAX_LABELS: usize = 8;
+
+/// A string of `len` bytes in quotes, each of them a control character that
+/// goes out as `\u00XX`.
+pub(crate) const fn escaped(len: usize) -> usize {
+    6 * len + 2
+}
+
+/// `"key":` and a value of `len`, with a comma.
+pub(crate) const fn entry(key: &str, len: usize) -> usize {
+    key.len() + 3 + len + 1
+}
+
+/// The readings [`select_fields`] chooses from, by their JSON names, and how
+/// to leave each one out.
+const FIELDS: [(&str, fn(&mut Sample)); 33] = [
+    ("temp_bme680", |sample| sample.temp_bme680 = None),
+    ("press_bme680", |sample| sample.press_bme680 = None),
+    ("hum_bme680", |sample| sample.hum_bme680 = None),
+    ("gas_bme680", |sample| sample.gas_bme680 = None),
+    ("lux_bh1750", |sample| sample.lux_bh1750 = None),
+    ("lux_veml7700", |sample| sample.lux_veml7700 = None),
+    ("temp_bmp390", |sample| sample.temp_bmp390 = None),
+    ("press_bmp390", |sample| sample.press_bmp390 = None),
+    ("hum_sht40", |sample| sample.hum_sht40 = None),
+    ("temp_sht40", |sample| sample.temp_sht40 = None),
+    ("chip_temp", |sample| sample.chip_temp = None),
+    ("supply_mv", |sample| sample.supply_mv = None),
+    ("noise_dba", |sample| sample.noise_dba = None),
+    ("voc_index", |sample| sample.voc_index = None),
+    ("pm1_0", |sample| sample.pm1_0 = None),
+    ("pm2_5", |sample| sample.pm2_5 = None),
+    ("pm10", |sample| sample.pm10 = None),
+    ("co2_mhz19", |sample| sample.co2_mhz19 = None),
+    ("adc0", |sample| sample.adc0 = None),
+    ("adc1", |sample| sample.adc1 = None),
+    ("battery_mv", |sample| sample.battery_mv = None),
+    ("battery_ma", |sample| sample.battery_ma = None),
+    ("battery_percent", |sample| sample.battery_percent = None),
+    ("lux_tsl2591", |sample| sample.lux_tsl2591 = None),
+    ("temp_bme280", |sample| sample.temp_bme280 = None),
+    ("press_bme280", |sample| sample.press_bme280 = None),
+    ("hum_bme280", |sample| sample.hum_bme280 = None),
+    ("co2_scd30", |sample| sample.co2_scd30 = None),
+    ("wind_kmh", |sample| sample.wind_kmh = None),
+    ("rain_mm", |sample| sample.rain_mm = None),
+    ("dew_point", |sample| sample.dew_point = None),
+    ("abs_humidity", |sample| sample.abs_humidity = None),
+    ("heat_index", |sample| sample.heat_index = None),
+];
+
+/// The readings that go out as they are, by their JSON names.
+const READINGS: [(&str, fn(&Sample) -> Option<f32>); 23] = [
+    ("temp_bme680", |sample| sample.temp_bme680),
+    ("press_bme680", |sample| sample.press_bme680),
+    ("hum_bme680", |sample| sample.hum_bme680),
+    ("temp_bme280", |sample| sample.temp_bme280),
+    ("press_bme280", |sample| sample.press_bme280),
+    ("hum_bme280", |sample| sample.hum_bme280),
+    ("lux_bh1750", |sample| sample.lux_bh1750),
+    ("lux_veml7700", |sample| sample.lux_veml7700),
+    ("lux_tsl2591", |sample| sample.lux_tsl2591),
+    ("temp_bmp390", |sample| sample.temp_bmp390),
+    ("press_bmp390", |sample| sample.press_bmp390),
+    ("hum_sht40", |sample| sample.hum_sht40),
+    ("temp_sht40", |sample| sample.temp_sht40),
+    ("chip_temp", |sample| sample.chip_temp),
+    ("adc0", |sample| sample.adc0),
+    ("adc1", |sample| sample.adc1),
+    ("battery_ma", |sample| sample.battery_ma),
+    ("battery_percent", |sample| sample.battery_percent),
+    ("wind_kmh", |sample| sample.wind_kmh),
+    ("rain_mm", |sample| sample.rain_mm),
+    ("dew_point", |sample| sample.dew_point),
+    ("abs_humidity", |sample| sample.abs_humidity),
+    ("heat_index", |sample| sample.heat_index),
+];
+
+/// The whole number readings.
+const COUNTS: [(&str, fn(&Sample) -> Option<u16>); 7] = [
+    ("supply_mv", |sample| sample.supply_mv),
+    ("pm1_0", |sample| sample.pm1_0),
+    ("pm2_5", |sample| sample.pm2_5),
+    ("pm10", |sample| sample.pm10),
+    ("co2_mhz19", |sample| sample.co2_mhz19),
+    ("co2_scd30", |sample| sample.co2_scd30),
+    ("battery_mv", |sample| sample.battery_mv),
+];
+
+/// Bit `i` set publishes `FIELDS[i]`. Wider than the 32 bits of the atomics
+/// the chips have.
+static SELECTED: Mutex<CriticalSectionRawMutex, Cell<u64>> = Mutex::new(Cell::new(u64::MAX));
+
+const _: () = assert!(FIELDS.len() <= 64, "a field has no bit in SELECTED");
+static PLACE: Mutex<CriticalSectionRawMutex, RefCell<Place>> = Mutex::new(RefCell::new(Place {
+    location: String::new(),
+    room: String::new(),
+    labels: String::new(),
+}));
+
+/// Where the node is, as the settings have it. Empty ones are left out.
+struct Place {
+    location: String<LOCATION_LEN>,
+    room: String<ROOM_LEN>,
+    /// Separated by commas.
+    labels: String<LABELS_LEN>,
+}
+
+#[derive(Debug, defmt::Format)]
+pub enum Error {
+    /// The payload is larger than the buffer.
+    Overflow,
+}
+
+/// Publishes only the readings named in `list`, separated by commas or
+/// spaces. All of them when it's empty.
+pub fn select_fields(list: &str) {
+    let mut selected = if list.trim().is_empty() { u64::MAX } else { 0 };
+
+    for name in list.split([',', ' ']).filter(|name| !name.is_empty()) {
+        match FIELDS.iter().position(|(field, _)| *field == name) {
+            Some(i) => selected |= 1 << i,
+            None => warn!("Payload: there is no field {}", name),
+        }
+    }
+
+    SELECTED.lock(|current| current.set(selected));
+}
+
+/// Sets the `location`, `room` and `labels` every JSON payload carries, the
+/// labels separated by commas.
+pub fn set_place(location: &str, room: &str, labels: &str) {
+    let place = Place {
+        location: printable(location.trim()),
+        room: printable(room.trim()),
+        labels: printable(labels),
+    };
+
+    PLACE.lock(|current| current.replace(place));
+}
+
+/// `text` without the control characters, which would go out escaped to six
+/// times their length. Cut short at the capacity.
+fn printable<const N: usize>(text: &str) -> String<N> {
+    let mut printable = String::new();
+    for c in text.chars().filter(|c| !c.is_control()) {
+        if printable.push(c).is_err() {
+            break;
+        }
+    }
+
+    printable
+}
+
+/// Serializes the selected readings of `sample` into `buf`, the payload is
+/// the returned start of it. `published_at` is the time it goes out.
+pub fn build<'b>(
+    sample: &Sample,
+    format: PayloadFormat,
+    client_id: &str,
+    published_at: u32,
+    buf: &'b mut [u8],
+) -> Result<&'b [u8], Error> {
+    let mut sample = sample.clone();
+    let selected = SELECTED.lock(|selected| selected.get());
+    for (i, (_, leave_out)) in FIELDS.iter().enumerate() {
+        if selected & (1 << i) == 0 {
+            leave_out(&mut sample);
+        }
+    }
+
+    let len = match format {
+        PayloadFormat::Json => serde_json_core::to_slice(&Json(&sample, published_at), buf),
+        PayloadFormat::Senml => {
+            serde_json_core::to_slice(&senml::Pack::new(sample, client_id), buf)
+        }
+    }
+    .map_err(|_| Error::Overflow)?;
+
+    Ok(&buf[..len])
+}
+
+/// Our own payload: the fields that are set, flat, plus what the node knows
+/// about itself. `schema` is the version of the sample and `compat` the
+/// oldest one a parser has to know to read it, see [`crate::sensors::schema`].
+///
+/// `ts` is when the sample was taken and `published_at` when it went out,
+/// later for one that waited in the queue through an outage. Both are the
+/// uptime until the clock is synced.
+struct Json<'a>(&'a Sample, u32);
+
+#[derive(Serialize)]
+struct Availability {
+    uptime: u32,
+    wifi: f32,
+    mqtt: f32,
+}
+
+#[derive(Serialize)]
+struct Timing {
+    sensors: [u32; 3],
+    publish: [u32; 3],
+    display: [u32; 3],
+}
+
+impl Serialize for Json<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let Self(sample, published_at) = *self;
+        let mut map = serializer.serialize_map(None)?;
+
+        map.serialize_entry("ts", &sample.timestamp)?;
+        map.serialize_entry("published_at", &published_at)?;
+        map.serialize_entry("schema", &sample.version().number())?;
+        map.serialize_entry("compat", &SampleVersion::COMPATIBLE.number())?;
+
+        PLACE.lock(|place| -> Result<(), S::Error> {
+            let place = place.borrow();
+            if !place.location.is_empty() {
+                map.serialize_entry("location", place.location.as_str())?;
+            }
+            if !place.room.is_empty() {
+                map.serialize_entry("room", place.room.as_str())?;
+            }
+            let labels: heapless::Vec<&str, MAX_LABELS> = place
+                .labels
+                .split(',')
+                .map(str::trim)
+                .filter(|label| !label.is_empty())
+                .take(MAX_LABELS)
+                .collect();
+            if !labels.is_empty() {
+                map.serialize_entry("labels", &labels)?;
+            }
+
+            Ok(())
+        })?;
+
+        for (key, get) in READINGS {
+            if let Some(value) = get(sample) {
+                map.serialize_entry(key, &value)?;
+            }
+        }
+        for (key, get) in COUNTS {
+            if let Some(value) = get(sample) {
+                map.serialize_entry(key, &value)?;
+            }
+        }
+        if let Some(gas_bme680) = sample.gas_bme680 {
+            map.serialize_entry("gas_bme680", &gas_bme680)?;
+        }
+        if let Some(noise_dba) = sample.noise_dba {
+            map.serialize_entry("noise_dba", &tenths(noise_dba))?;
+        }
+        if let Some(voc_index) = sample.voc_index {
+            map.serialize_entry("voc_index", &voc_index)?;
+        }
+        if let Some(warming_up) = sample.warming_up {
+            map.serialize_entry("warming_up", &warming_up)?;
+        }
+
+        let availability = availability::totals();
+        if availability.uptime_secs > 0 {
+            map.serialize_entry(
+                "availability",
+                &Availability {
+                    uptime: availability.uptime_secs,
+                    wifi: tenths(availability.wifi_percent()),
+                    mqtt: tenths(availability.mqtt_percent()),
+                },
+            )?;
+        }
+
+        let warnings: heapless::Vec<&str, 2> = [
+            ("brownout", system::brownout()),
+            ("low_voltage", power::low_voltage()),
+        ]
+        .into_iter()
+        .filter_map(|(name, active)| active.then_some(name))
+        .collect();
+        if !warnings.is_empty() {
+            map.serialize_entry("warnings", &warnings)?;
+        }
+
+        let dropped = diagnostics::drops();
+        if dropped.total() > 0 {
+            map.serialize_entry("dropped", &dropped)?;
+        }
+
+        let diagnostics = diagnostics::latest();
+        if diagnostics.heap_free > 0 {
+            map.serialize_entry("heap_free", &diagnostics.heap_free)?;
+            map.serialize_entry("heap_max_block", &diagnostics.heap_max_block)?;
+            map.serialize_entry("stack_free", &diagnostics.stack_free)?;
+        }
+
+        map.serialize_entry(
+            "timing",
+            &Timing {
+                sensors: diagnostics::timing(Timed::SensorCycle).summary(),
+                publish: diagnostics::timing(Timed::MqttPublish).summary(),
+                display: diagnostics::timing(Timed::DisplayRefresh).summary(),
+            },
+        )?;
+
+        map.end()
+    }
+}
+
+/// The longest JSON payload: every field set with its longest value, and the
+/// place at full length in quotes and backslashes, which go out as two.
+const MAX_JSON: usize = {
+    let mut len = 2
+        + entry("ts", U32_LEN)
+        + entry("published_at", U32_LEN)
+        + entry("schema", U8_LEN)
+        + entry("compat", U8_LEN)
+        + entry("location", 2 * LOCATION_LEN + 2)
+        + entry("room", 2 * ROOM_LEN + 2)
+        // Each label in quotes, with a comma.
+        + entry("labels", 2 * LABELS_LEN + 3 * MAX_LABELS + 2);
+
+    let mut i = 0;
+    while i < READINGS.len() {
+        len += entry(READINGS[i].0, F32_LEN);
+        i += 1;
+    }
+    let mut i = 0;
+    while i < COUNTS.len() {
+        len += entry(COUNTS[i].0, U16_LEN);
+        i += 1;
+    }
+
+    let availability =
+        2 + entry("uptime", U32_LEN) + entry("wifi", F32_LEN) + entry("mqtt", F32_LEN);
+    let dropped = 2
+        + entry("queue_full", U32_LEN)
+        + entry("publish_failed", U32_LEN)
+        + entry("overflow", U32_LEN)
+        + entry("connection_lost", U32_LEN);
+    // `[min,avg,max]`
+    let summary = 2 + 3 * (U32_LEN + 1);
+    let timing =
+        2 + entry("sensors", summary) + entry("publish", summary) + entry("display", summary);
+
+    len + entry("gas_bme680", U32_LEN)
+        + entry("noise_dba", F32_LEN)
+        + entry("voc_index", U16_LEN)
+        + entry("warming_up", "false".len())
+        + entry("availability", availability)
+        + entry("warnings", r#"["brownout","low_voltage"]"#.len())
+        + entry("dropped", dropped)
+        + entry("heap_free", U32_LEN)
+        + entry("heap_max_block", U32_LEN)
+        + entry("stack_free", U32_LEN)
+        + entry("timing", timing)
+};
+
+/// Rounded to one decimal, the precision the value means anything at.
+fn tenths(value: f32) -> f32 {
+    libm::roundf(value * 10.0) / 10.0
+}
@@ -0,0 +1,220 @@
+//! Battery operation: wake up on the RTC timer, take one measurement,
+//! publish it and go back to deep sleep.
+//!
+//! What should survive the sleep is kept in the RTC fast memory, which stays
+//! powered in deep sleep.
+//!
+//! The supply voltage is read through a divider on an ADC pin, when the board
+//! has one.
+
+use core::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+
+use defmt::{info, warn};
+use embassy_futures::select;
+use embassy_time::{Duration, Timer};
+use esp_hal::analog::adc::AdcChannel;
+use esp_hal::clock::CpuClock;
+use esp_hal::rtc_cntl::{Rtc, sleep::TimerWakeupSource};
+
+use crate::adc;
+use crate::config::{GasHeater, PowerProfile};
+use crate::mqtt;
+use crate::syslog::{self, Severity};
+
+/// Longest the node stays awake per wake-up, also when it can't publish.
+const AWAKE_TIMEOUT: Duration = Duration::from_secs(30);
+const RTC_MAGIC: u32 = 0x5EE9_0001;
+
+/// The battery is connected through a 1:1 divider, the ADC can't read more
+/// than about 2.5 V.
+const SUPPLY_DIVIDER: u16 = 2;
+/// Keeps the warning from flapping around the threshold.
+const SUPPLY_HYSTERESIS_MV: u16 = 100;
+
+static SLEEP: AtomicBool = AtomicBool::new(false);
+static WIFI_POWER_SAVE: AtomicBool = AtomicBool::new(false);
+/// 0 when the supply isn't monitored.
+static SUPPLY_LOW_MV: AtomicU16 = AtomicU16::new(0);
+static LOW_VOLTAGE: AtomicBool = AtomicBool::new(false);
+static GAS_SAVING: AtomicBool = AtomicBool::new(false);
+
+/// The CPU clock of the profile of the next boot. esp-hal fixes the clock in
+/// `esp_hal::init`, long before the settings can be read.
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static mut BOOT_PROFILE: u32 = 0;
+const LOW_CLOCK_MAGIC: u32 = 0xF2A6_0001;
+
+/// Access point of the last connection, lets the WiFi skip the scan.
+#[derive(Clone, Copy)]
+struct RtcState {
+    magic: u32,
+    bssid: [u8; 6],
+    channel: u8,
+}
+
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static mut RTC_STATE: RtcState = RtcState {
+    magic: 0,
+    bssid: [0; 6],
+    channel: 0,
+};
+
+/// Switches the node to the measure, publish and sleep cycle.
+pub fn enable_sleep() {
+    SLEEP.store(true, Ordering::Relaxed);
+}
+
+pub fn sleep_enabled() -> bool {
+    SLEEP.load(Ordering::Relaxed)
+}
+
+/// The clock to pass to `esp_hal::init`, `performance` unless the last run
+/// asked for a profile with the low clock.
+pub fn cpu_clock(performance: CpuClock) -> CpuClock {
+    let profile = unsafe { core::ptr::addr_of!(BOOT_PROFILE).read_volatile() };
+    if profile == LOW_CLOCK_MAGIC {
+        CpuClock::_80MHz
+    } else {
+        performance
+    }
+}
+
+/// Applies the profile. Returns whether the CPU clock of this boot doesn't
+/// match it, the change takes a restart.
+pub fn set_profile(profile: PowerProfile) -> bool {
+    let preset = profile.preset();
+    WIFI_POWER_SAVE.store(preset.wifi_power_save, Ordering::Relaxed);
+
+    let low_clock = preset.low_cpu_clock;
+    let booted_low = unsafe {
+        let boot_profile = core::ptr::addr_of_mut!(BOOT_PROFILE);
+        boot_profile.replace(if low_clock { LOW_CLOCK_MAGIC } else { 0 }) == LOW_CLOCK_MAGIC
+    };
+
+    booted_low != low_clock
+}
+
+/// Lets the WiFi radio sleep between the access point's beacons.
+pub fn wifi_power_save() -> bool {
+    WIFI_POWER_SAVE.load(Ordering::Relaxed)
+}
+
+/// Reads the supply voltage in millivolts.
+pub trait SupplyVoltage {
+    fn millivolts(&mut self) -> Option<u16>;
+}
+
+/// The supply voltage on an ADC1 pin, set up with [`Self::ATTENUATION`].
+pub struct AdcSupply<PIN>(adc::Channel<PIN>);
+
+impl<PIN> AdcSupply<PIN> {
+    pub const ATTENUATION: adc::Attenuation = adc::Attenuation::_11dB;
+
+    pub fn new(channel: adc::Channel<PIN>) -> Self {
+        Self(channel)
+    }
+}
+
+impl<PIN: AdcChannel> SupplyVoltage for AdcSupply<PIN> {
+    fn millivolts(&mut self) -> Option<u16> {
+        self.0
+            .millivolts()
+            .map(|millivolts| millivolts * SUPPLY_DIVIDER)
+    }
+}
+
+/// Warns below `low_mv`, 0 turns the monitoring off.
+pub fn set_supply_limits(low_mv: u16, gas_heater: GasHeater) {
+    SUPPLY_LOW_MV.store(low_mv, Ordering::Relaxed);
+    GAS_SAVING.store(gas_heater == GasHeater::Saving, Ordering::Relaxed);
+}
+
+pub fn supply_monitored() -> bool {
+    SUPPLY_LOW_MV.load(Ordering::Relaxed) > 0
+}
+
+/// Updates the low voltage state with a new reading. Returns whether it
+/// changed.
+pub fn check_supply(millivolts: u16) -> bool {
+    let low_mv = SUPPLY_LOW_MV.load(Ordering::Relaxed);
+    let was_low = LOW_VOLTAGE.load(Ordering::Relaxed);
+    let low = if was_low {
+        millivolts < low_mv + SUPPLY_HYSTERESIS_MV
+    } else {
+        millivolts < low_mv
+    };
+
+    if low == was_low {
+        return false;
+    }
+
+    LOW_VOLTAGE.store(low, Ordering::Relaxed);
+    if low {
+        warn!("Power: low supply voltage, {} mV", millivolts);
+        syslog::log(
+            Severity::Warning,
+            format_args!("Low supply voltage, {} mV", millivolts),
+        );
+    } else {
+        info!("Power: supply voltage back to {} mV", millivolts);
+        syslog::log(
+            Severity::Notice,
+            format_args!("Supply voltage back to {} mV", millivolts),
+        );
+    }
+
+    true
+}
+
+pub fn low_voltage() -> bool {
+    LOW_VOLTAGE.load(Ordering::Relaxed)
+}
+
+/// Whether the BME680's gas heater should be off right now.
+pub fn gas_heater_off() -> bool {
+    GAS_SAVING.load(Ordering::Relaxed) && low_voltage()
+}
+
+/// BSSID and channel of the access point the node was connected to before
+/// the sleep.
+pub fn last_access_point() -> Option<([u8; 6], u8)> {
+    let state = unsafe { core::ptr::addr_of!(RTC_STATE).read_volatile() };
+    (state.magic == RTC_MAGIC).then_some((state.bssid, state.channel))
+}
+
+pub fn remember_access_point(bssid: [u8; 6], channel: u8) {
+    let state = RtcState {
+        magic: RTC_MAGIC,
+        bssid,
+        channel,
+    };
+    unsafe { core::ptr::addr_of_mut!(RTC_STATE).write_volatile(state) };
+}
+
+/// Drops the stored access point, e.g. when the fast connect failed. Returns
+/// whether there was one.
+pub fn forget_access_point() -> bool {
+    let marker = unsafe { core::ptr::addr_of_mut!(RTC_STATE.magic).replace(0) };
+    marker == RTC_MAGIC
+}
+
+/// Waits until the broker acknowledges a published sample, or the awake
+/// time runs out, then sleeps for `interval`.
+pub async fn sleep_after_publish(interval: Duration) -> ! {
+    match select::select(mqtt::PUBLISHED.wait(), Timer::after(AWAKE_TIMEOUT)).await {
+        select::Either::First(_) => info!("Power: sample published"),
+        select::Either::Second(_) => warn!("Power: nothing published, sleeping anyway"),
+    }
+
+    deep_sleep(interval)
+}
+
+pub fn deep_sleep(duration: Duration) -> ! {
+    info!("Power: deep sleep for {} s", duration.as_secs());
+
+    let peripherals = unsafe { esp_hal::peripherals::Peripherals::steal() };
+    let mut rtc = Rtc::new(peripherals.LPWR);
+    let timer = TimerWakeupSource::new(core::time::Duration::from_secs(duration.as_secs()));
+
+    rtc.sleep_deep(&[&timer]);
+}
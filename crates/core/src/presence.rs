@@ -0,0 +1,248 @@
+//! Room-level presence of BLE beacons, worked out by all the nodes on the
+//! broker together.
+//!
+//! Each node scans for the beacons of the settings and every [`WINDOW`]
+//! publishes the strongest RSSI it heard of each to [`OBSERVATIONS_TOPIC`].
+//! The nodes subscribe to it and keep the latest observation of every node.
+//! The one that heard a beacon the strongest lately takes it as in its room
+//! and publishes its client id to `presence/<beacon>`. A beacon no node heard
+//! for [`MAX_AGE`] is gone, the node it was last with publishes a null then.
+//!
+//! Needs MQTT over TCP, MQTT-SN nodes don't take part.
+
+use core::cell::RefCell;
+use core::fmt::Write;
+
+use defmt::{info, warn};
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant};
+use heapless::{String, Vec};
+use serde::{Deserialize, Serialize};
+
+pub const MAX_BEACONS: usize = 4;
+/// Nodes whose observations are kept, per beacon.
+const MAX_NODES: usize = 8;
+
+pub const OBSERVATIONS_TOPIC: &str = "presence/observations";
+const TOPIC_BASE: &str = "presence";
+
+pub const WINDOW: Duration = Duration::from_secs(10);
+/// Observations older than that are left out, the beacon moved on or the
+/// node is down.
+const MAX_AGE: Duration = Duration::from_secs(30);
+
+static STATE: Mutex<CriticalSectionRawMutex, RefCell<State>> =
+    Mutex::new(RefCell::new(State::new()));
+static CONFIGURED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// What a node heard of a beacon in the last window.
+#[derive(Serialize, Deserialize)]
+pub struct Observation<'a> {
+    pub node: &'a str,
+    pub beacon: &'a str,
+    pub rssi: i8,
+}
+
+/// The payload of `presence/<beacon>`, no node when the beacon is gone.
+#[derive(Serialize)]
+pub struct Estimate<'a> {
+    pub node: Option<&'a str>,
+    pub rssi: Option<i8>,
+}
+
+pub enum Change {
+    /// This node heard `beacon` the strongest, and didn't before.
+    Nearest { beacon: usize, rssi: i8 },
+    /// No node heard `beacon` lately, this one was the nearest.
+    Gone { beacon: usize },
+}
+
+struct Seen {
+    node: String<32>,
+    beacon: usize,
+    rssi: i8,
+    at: Instant,
+}
+
+struct State {
+    /// Most significant byte first, as the addresses are written.
+    beacons: Vec<[u8; 6], MAX_BEACONS>,
+    heard: [Option<i8>; MAX_BEACONS],
+    seen: Vec<Seen, { MAX_BEACONS * MAX_NODES }>,
+    nearest: [bool; MAX_BEACONS],
+}
+
+impl State {
+    const fn new() -> Self {
+        Self {
+            beacons: Vec::new(),
+            heard: [None; MAX_BEACONS],
+            seen: Vec::new(),
+            nearest: [false; MAX_BEACONS],
+        }
+    }
+}
+
+/// Looks out for the beacons in `list`, addresses like `c3:00:12:ab:cd:ef`
+/// separated by commas or spaces. Nothing is scanned for when it's empty.
+pub fn configure(list: &str) {
+    let mut beacons = Vec::new();
+
+    for text in list.split([',', ' ']).filter(|text| !text.is_empty()) {
+        match parse_address(text) {
+            Some(address) => {
+                if beacons.push(address).is_err() {
+                    warn!("Presence: more than {} beacons", MAX_BEACONS);
+                    break;
+                }
+            }
+            None => warn!("Presence: {} is not an address", text),
+        }
+    }
+
+    if beacons.is_empty() {
+        return;
+    }
+
+    info!("Presence: looking out for {} beacons", beacons.len());
+    STATE.lock(|state| state.borrow_mut().beacons = beacons);
+    CONFIGURED.signal(());
+}
+
+pub fn enabled() -> bool {
+    STATE.lock(|state| !state.borrow().beacons.is_empty())
+}
+
+/// Waits for [`configure`] to give some beacons.
+pub async fn wait_configured() {
+    CONFIGURED.wait().await;
+    CONFIGURED.signal(());
+}
+
+fn parse_address(text: &str) -> Option<[u8; 6]> {
+    let mut address = [0u8; 6];
+    let mut parts = text.split(':');
+
+    for byte in address.iter_mut() {
+        let part = parts.next()?;
+        if part.len() != 2 {
+            return None;
+        }
+        *byte = u8::from_str_radix(part, 16).ok()?;
+    }
+
+    parts.next().is_none().then_some(address)
+}
+
+/// The address of beacon `beacon`, the way it goes into the topics.
+pub fn beacon_name(beacon: usize) -> String<17> {
+    let address = STATE.lock(|state| state.borrow().beacons.get(beacon).copied());
+    let mut name = String::new();
+
+    for (i, byte) in address.unwrap_or_default().iter().enumerate() {
+        let separator = if i == 0 { "" } else { ":" };
+        write!(name, "{}{:02x}", separator, byte).ok();
+    }
+
+    name
+}
+
+/// `presence/<beacon>`.
+pub fn estimate_topic(beacon: usize) -> String<40> {
+    let mut topic = String::new();
+    write!(topic, "{}/{}", TOPIC_BASE, beacon_name(beacon)).ok();
+
+    topic
+}
+
+/// An advertisement the scanner picked up. `address` is least significant
+/// byte first, the way the controller reports it.
+pub fn heard(address: &[u8], rssi: i8) {
+    STATE.lock(|state| {
+        let mut state = state.borrow_mut();
+        let Some(beacon) = state
+            .beacons
+            .iter()
+            .position(|beacon| beacon.iter().rev().eq(address.iter()))
+        else {
+            return;
+        };
+
+        let strongest = &mut state.heard[beacon];
+        *strongest = Some(strongest.map_or(rssi, |strongest| strongest.max(rssi)));
+    });
+}
+
+/// The strongest RSSI of each beacon since the last call.
+pub fn take_heard() -> [Option<i8>; MAX_BEACONS] {
+    STATE.lock(|state| core::mem::replace(&mut state.borrow_mut().heard, [None; MAX_BEACONS]))
+}
+
+/// An observation from [`OBSERVATIONS_TOPIC`], ours included.
+pub fn observed(observation: &Observation) {
+    let now = Instant::now();
+    let (Some(address), Ok(node)) = (
+        parse_address(observation.beacon),
+        String::try_from(observation.node),
+    ) else {
+        return;
+    };
+
+    STATE.lock(|state| {
+        let mut state = state.borrow_mut();
+        let Some(beacon) = state.beacons.iter().position(|beacon| *beacon == address) else {
+            return;
+        };
+
+        state.seen.retain(|seen| {
+            now - seen.at < MAX_AGE && !(seen.beacon == beacon && seen.node == node)
+        });
+        let seen = Seen {
+            node,
+            beacon,
+            rssi: observation.rssi,
+            at: now,
+        };
+        if let Err(seen) = state.seen.push(seen) {
+            state.seen.remove(0);
+            state.seen.push(seen).ok();
+        }
+    });
+}
+
+/// Where this node, `client_id`, stands with the beacons since the last call.
+pub fn changes(client_id: &str) -> Vec<Change, MAX_BEACONS> {
+    let now = Instant::now();
+    let mut changes = Vec::new();
+
+    STATE.lock(|state| {
+        let state = &mut *state.borrow_mut();
+        state.seen.retain(|seen| now - seen.at < MAX_AGE);
+
+        for beacon in 0..state.beacons.len() {
+            let strongest = state
+                .seen
+                .iter()
+                .filter(|seen| seen.beacon == beacon)
+                .max_by_key(|seen| seen.rssi);
+
+            let was_nearest = state.nearest[beacon];
+            let change = match strongest {
+                Some(seen) if seen.node == client_id => (!was_nearest).then_some(Change::Nearest {
+                    beacon,
+                    rssi: seen.rssi,
+                }),
+                None => was_nearest.then_some(Change::Gone { beacon }),
+                Some(_) => None,
+            };
+
+            state.nearest[beacon] = strongest.is_some_and(|seen| seen.node == client_id);
+            if let Some(change) = change {
+                changes.push(change).ok();
+            }
+        }
+    });
+
+    changes
+}
@@ -0,0 +1,100 @@
+//! Who is changing the settings right now. The setup form, the console and
+//! the MQTT commands all write them, and a write of one in the middle of
+//! another's changes would leave a mix of both. The first to write holds a
+//! session until it is done or goes quiet for [`IDLE`], the writes of the
+//! others are turned away meanwhile.
+//!
+//! The session is on `/api/session` and in the console's `status`.
+
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_time::{Duration, Instant};
+use serde::Serialize;
+
+/// A session nobody wrote in for this long is over.
+pub const IDLE: Duration = Duration::from_secs(120);
+
+static SESSION: Mutex<CriticalSectionRawMutex, Cell<Option<Session>>> = Mutex::new(Cell::new(None));
+
+/// Where a settings write comes from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, defmt::Format)]
+#[serde(rename_all = "lowercase")]
+pub enum Channel {
+    /// The setup form, the pins and the cancelled reconfiguration.
+    Web,
+    /// `set` on the console, until `done`.
+    Console,
+    /// The `led`, `calibrate` and `co2calibrate` commands.
+    Mqtt,
+}
+
+#[derive(Clone, Copy)]
+struct Session {
+    channel: Channel,
+    last_write: Instant,
+}
+
+/// The open session, as the status pages show it.
+#[derive(Clone, Copy, Serialize)]
+pub struct Status {
+    pub channel: Channel,
+    /// Since the last write of the session.
+    pub idle_secs: u64,
+}
+
+/// Another channel has the session.
+#[derive(Clone, Copy, defmt::Format)]
+pub struct Busy(pub Channel);
+
+impl Busy {
+    /// For the one that was turned away.
+    pub fn message(self) -> &'static str {
+        match self.0 {
+            Channel::Web => "The settings are being changed on the setup page, try again later\n",
+            Channel::Console => "The settings are being changed on the console, try again later\n",
+            Channel::Mqtt => "The settings are being changed over MQTT, try again later\n",
+        }
+    }
+}
+
+/// Opens a session for `channel` before it writes, or goes on with its own.
+/// Fails while another channel's session is open.
+pub fn claim(channel: Channel) -> Result<(), Busy> {
+    SESSION.lock(|session| match session.get() {
+        Some(current) if current.channel != channel && current.last_write.elapsed() < IDLE => {
+            Err(Busy(current.channel))
+        }
+        _ => {
+            session.set(Some(Session {
+                channel,
+                last_write: Instant::now(),
+            }));
+            Ok(())
+        }
+    })
+}
+
+/// Ends the session of `channel` once its changes are written, the others
+/// may write again.
+pub fn release(channel: Channel) {
+    SESSION.lock(|session| {
+        if session
+            .get()
+            .is_some_and(|current| current.channel == channel)
+        {
+            session.set(None);
+        }
+    });
+}
+
+/// The open session, none while anyone may write.
+pub fn status() -> Option<Status> {
+    let session = SESSION.lock(|session| session.get())?;
+    let idle = session.last_write.elapsed();
+
+    (idle < IDLE).then_some(Status {
+        channel: session.channel,
+        idle_secs: idle.as_secs(),
+    })
+}
@@ -0,0 +1,229 @@
+//! Relays and MOSFETs on plain outputs, switched by MQTT commands or by a
+//! rule on one of the readings.
+//!
+//! A rule is `<metric> <on at> <off at>`: "humidity 70 60" turns the output
+//! on at 70 % and off again at 60 %, "temperature 18 21" works the other way
+//! round for a heater. In between the output stays as it is, so a command
+//! holds until the reading leaves the band. The states are kept in the
+//! storage and come back after a reboot.
+
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use defmt::{info, warn};
+use embassy_futures::select::{Either, select};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use esp_hal::gpio::{Level, Output, OutputConfig};
+
+use crate::board;
+use crate::kv_storage::{self, Db, DbResult};
+use crate::sensors::{self, Sample};
+
+pub const RELAYS: usize = 2;
+
+static STATE_KEYS: [&'static str; RELAYS] = ["relay.state1", "relay.state2"];
+
+/// A bit per relay.
+static PRESENT: AtomicU8 = AtomicU8::new(0);
+static STATES: AtomicU8 = AtomicU8::new(0);
+static CHANGED: AtomicBool = AtomicBool::new(false);
+static REQUESTS: Channel<CriticalSectionRawMutex, (usize, Option<bool>), 4> = Channel::new();
+
+#[derive(Clone, Copy, defmt::Format)]
+enum Metric {
+    Temperature,
+    Humidity,
+    Light,
+    Pressure,
+    Noise,
+}
+
+impl Metric {
+    fn read(&self, sample: &Sample) -> Option<f32> {
+        match self {
+            Metric::Temperature => sample.temperature(),
+            Metric::Humidity => sample.humidity(),
+            Metric::Light => sample.light(),
+            Metric::Pressure => sample.pressure(),
+            Metric::Noise => sample.noise_dba,
+        }
+    }
+}
+
+#[derive(Clone, Copy, defmt::Format)]
+struct Rule {
+    metric: Metric,
+    on: f32,
+    off: f32,
+}
+
+impl Rule {
+    fn parse(text: &str) -> Option<Self> {
+        let mut words = text.split_whitespace();
+
+        let metric = match words.next()? {
+            "temperature" => Metric::Temperature,
+            "humidity" => Metric::Humidity,
+            "light" => Metric::Light,
+            "pressure" => Metric::Pressure,
+            "noise" => Metric::Noise,
+            _ => return None,
+        };
+        let on = words.next()?.parse().ok()?;
+        let off = words.next()?.parse().ok()?;
+
+        words.next().is_none().then_some(Self { metric, on, off })
+    }
+
+    /// The state `value` asks for, none inside the band.
+    fn decide(&self, value: f32) -> Option<bool> {
+        let rising = self.on >= self.off;
+
+        if (rising && value >= self.on) || (!rising && value <= self.on) {
+            Some(true)
+        } else if (rising && value <= self.off) || (!rising && value >= self.off) {
+            Some(false)
+        } else {
+            None
+        }
+    }
+}
+
+/// Switches relay `number`, counted from 1. Toggles it without a `state`.
+pub fn request(number: u8, state: Option<bool>) {
+    let index = (number as usize).wrapping_sub(1);
+    if index >= RELAYS || PRESENT.load(Ordering::Relaxed) & (1 << index) == 0 {
+        warn!("Relay: there is no relay {}", number);
+        return;
+    }
+
+    if REQUESTS.try_send((index, state)).is_err() {
+        warn!("Relay: too many requests");
+    }
+}
+
+/// The state of each relay, none for the ones the board doesn't have.
+pub fn states() -> [Option<bool>; RELAYS] {
+    let present = PRESENT.load(Ordering::Relaxed);
+    let states = STATES.load(Ordering::Relaxed);
+
+    core::array::from_fn(|i| (present & (1 << i) != 0).then_some(states & (1 << i) != 0))
+}
+
+/// Whether a relay switched since the last call.
+pub fn take_changed() -> bool {
+    CHANGED.swap(false, Ordering::Relaxed)
+}
+
+/// Runs the relays on `pins` with the `rules` of the settings, an empty one
+/// for none.
+#[embassy_executor::task]
+pub async fn task(db: &'static Db, pins: [Option<u8>; RELAYS], rules: [&'static str; RELAYS]) -> ! {
+    let rules = rules.map(|text| {
+        let rule = Rule::parse(text);
+        if rule.is_none() && !text.trim().is_empty() {
+            warn!("Relay: can't make sense of the rule \"{}\"", text);
+        }
+        rule
+    });
+
+    let mut outputs = pins.map(|pin| {
+        pin.map(|pin| {
+            Output::new(
+                unsafe { board::pin(pin) },
+                Level::Low,
+                OutputConfig::default(),
+            )
+        })
+    });
+    let present = outputs
+        .iter()
+        .enumerate()
+        .filter(|(_, output)| output.is_some())
+        .fold(0, |bits, (i, _)| bits | 1 << i);
+    PRESENT.store(present, Ordering::Relaxed);
+
+    let saved = load(db).await.unwrap_or_else(|err| {
+        warn!("Relay: could not read the states: {:?}", err);
+        [false; RELAYS]
+    });
+    for ((output, state), i) in outputs.iter_mut().zip(saved).zip(0..) {
+        if let Some(output) = output.as_mut().filter(|_| state) {
+            output.set_high();
+            STATES.fetch_or(1 << i, Ordering::Relaxed);
+        }
+    }
+    info!("Relay: started, {}, rules {}", states(), rules);
+
+    let mut samples = sensors::LATEST.receiver().unwrap();
+
+    loop {
+        match select(REQUESTS.receive(), samples.changed()).await {
+            Either::First((i, state)) => {
+                let state = state.unwrap_or(!states()[i].unwrap_or_default());
+                switch(db, &mut outputs, i, state).await;
+            }
+            Either::Second(sample) => {
+                for (i, rule) in rules.iter().enumerate() {
+                    let Some(rule) = rule else { continue };
+                    let Some(state) = rule
+                        .metric
+                        .read(&sample)
+                        .and_then(|value| rule.decide(value))
+                    else {
+                        continue;
+                    };
+                    switch(db, &mut outputs, i, state).await;
+                }
+            }
+        }
+    }
+}
+
+async fn switch(
+    db: &'static Db,
+    outputs: &mut [Option<Output<'static>>; RELAYS],
+    index: usize,
+    state: bool,
+) {
+    let Some(output) = outputs[index].as_mut() else {
+        return;
+    };
+    if output.is_set_high() == state {
+        return;
+    }
+
+    info!("Relay: {} {}", index + 1, if state { "on" } else { "off" });
+    output.set_level(Level::from(state));
+
+    if state {
+        STATES.fetch_or(1 << index, Ordering::Relaxed);
+    } else {
+        STATES.fetch_and(!(1 << index), Ordering::Relaxed);
+    }
+    CHANGED.store(true, Ordering::Relaxed);
+
+    if let Err(err) = save(db, index, state).await {
+        warn!("Relay: could not save the state: {:?}", err);
+    }
+}
+
+async fn load(db: &'static Db) -> DbResult<[bool; RELAYS]> {
+    let mut tx = db.read_transaction().await;
+    let mut states = [false; RELAYS];
+
+    for (state, key) in states.iter_mut().zip(STATE_KEYS) {
+        *state = kv_storage::read_bool(&mut tx, key)
+            .await?
+            .unwrap_or_default();
+    }
+
+    Ok(states)
+}
+
+async fn save(db: &'static Db, index: usize, state: bool) -> DbResult<()> {
+    let mut tx = db.write_transaction().await;
+    kv_storage::write_bool(&mut tx, STATE_KEYS[index], state).await?;
+    tx.commit().await?;
+
+    Ok(())
+}
@@ -0,0 +1,68 @@
+use serde::Deserialize;
+
+use crate::net_time;
+
+/// What a light emitting peripheral does during the night hours.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, defmt::Format)]
+#[serde(rename_all = "lowercase")]
+pub enum NightMode {
+    #[default]
+    Disabled,
+    Dim,
+    Off,
+}
+
+impl From<u8> for NightMode {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => NightMode::Dim,
+            2 => NightMode::Off,
+            _ => NightMode::Disabled,
+        }
+    }
+}
+
+impl From<NightMode> for u8 {
+    fn from(value: NightMode) -> Self {
+        match value {
+            NightMode::Disabled => 0,
+            NightMode::Dim => 1,
+            NightMode::Off => 2,
+        }
+    }
+}
+
+/// Daily window between two full hours of local time. The window may wrap
+/// around midnight, e.g. from 22 to 7. Equal hours mean an empty window.
+#[derive(Debug, Clone, Copy, defmt::Format)]
+pub struct Schedule {
+    pub start_hour: u8,
+    pub end_hour: u8,
+    /// Offset of the local time from UTC in minutes.
+    pub utc_offset_min: i16,
+}
+
+impl Schedule {
+    pub fn contains_hour(&self, hour: u8) -> bool {
+        if self.start_hour <= self.end_hour {
+            (self.start_hour..self.end_hour).contains(&hour)
+        } else {
+            hour >= self.start_hour || hour < self.end_hour
+        }
+    }
+
+    /// Whether the current local time falls within the window. Always false
+    /// until the time has been synchronized.
+    pub async fn is_active(&self) -> bool {
+        let now = { net_time::TIME_STATE.lock().await.now() };
+
+        now.is_some_and(|unix| self.contains_hour(local_hour(unix, self.utc_offset_min)))
+    }
+}
+
+pub fn local_hour(unix: u32, utc_offset_min: i16) -> u8 {
+    const SECS_PER_DAY: i64 = 24 * 60 * 60;
+
+    let local = unix as i64 + utc_offset_min as i64 * 60;
+    (local.rem_euclid(SECS_PER_DAY) / (60 * 60)) as u8
+}
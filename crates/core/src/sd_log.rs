@@ -0,0 +1,193 @@
+//! Keeps every sample on an SD card, for nodes without a network that are
+//! read out by hand every few weeks.
+//!
+//! There is a file per day, named after the date of its samples, e.g.
+//! `20261016.CSV`. Samples taken before the clock was ever synced go to
+//! `NOCLOCK.CSV`, their time is the uptime. The card is opened for each
+//! sample, so it can be swapped while the node runs.
+
+use core::cell::Cell;
+use core::fmt::Write;
+
+use defmt::{Debug2Format, info, warn};
+use embedded_hal_bus::spi::ExclusiveDevice;
+use embedded_sdmmc::{Mode, SdCard, TimeSource, Timestamp, VolumeIdx, VolumeManager};
+use esp_hal::delay::Delay;
+use esp_hal::gpio::{Level, Output, OutputConfig};
+use esp_hal::peripherals::SPI2;
+use esp_hal::spi::master::{Config, Spi};
+use esp_hal::time::Rate;
+use heapless::String;
+
+use crate::board::{self, Pins};
+use crate::config::SdFormat;
+use crate::net_time;
+use crate::sensors::{self, Sample};
+
+type Card = SdCard<ExclusiveDevice<Spi<'static, esp_hal::Blocking>, Output<'static>, Delay>, Delay>;
+
+/// Unix times below that are the uptime, the clock wasn't synced.
+const MIN_UNIX_TIME: u32 = 1_600_000_000;
+const CSV_HEADER: &str = "ts,temp_bme680,press_bme680,hum_bme680,gas_bme680,lux_bh1750,\
+lux_veml7700,temp_bmp390,press_bmp390,hum_sht40,temp_sht40,chip_temp,supply_mv,noise_dba,\
+voc_index,pm1_0,pm2_5,pm10,co2_mhz19,adc0,adc1,\
+battery_mv,battery_ma,battery_percent,warming_up,lux_tsl2591,\
+temp_bme280,press_bme280,hum_bme280,co2_scd30,wind_kmh,rain_mm,\
+dew_point,abs_humidity,heat_index\n";
+
+/// The FAT timestamps of the files, the time of the sample being written.
+struct Clock(Cell<u32>);
+
+impl TimeSource for &Clock {
+    fn get_timestamp(&self) -> Timestamp {
+        let unix = self.0.get().max(MIN_UNIX_TIME);
+        let (year, month, day) = net_time::civil_from_days(unix / 86_400);
+        let secs = unix % 86_400;
+
+        Timestamp {
+            year_since_1970: (year - 1970) as u8,
+            zero_based_month: (month - 1) as u8,
+            zero_based_day: (day - 1) as u8,
+            hours: (secs / 3600) as u8,
+            minutes: (secs / 60 % 60) as u8,
+            seconds: (secs % 60) as u8,
+        }
+    }
+}
+
+/// Needs [`Pins::sd_card`].
+#[embassy_executor::task]
+pub async fn task(spi2: SPI2<'static>, pins: Pins, format: SdFormat) -> ! {
+    info!("SD log: started, {}", format);
+
+    let spi = Spi::new(spi2, Config::default().with_frequency(Rate::from_mhz(4)))
+        .unwrap()
+        .with_sck(unsafe { board::pin(pins.sd_sck) })
+        .with_mosi(unsafe { board::pin(pins.sd_mosi) })
+        .with_miso(unsafe { board::pin(pins.sd_miso) });
+    let cs = Output::new(
+        unsafe { board::pin(pins.sd_cs) },
+        Level::High,
+        OutputConfig::default(),
+    );
+    let device = ExclusiveDevice::new(spi, cs, Delay::new()).unwrap();
+
+    let clock = Clock(Cell::new(0));
+    let volumes = VolumeManager::new(SdCard::new(device, Delay::new()), &clock);
+    let mut samples = sensors::LATEST.receiver().unwrap();
+
+    loop {
+        let sample = samples.changed().await;
+        clock.0.set(sample.timestamp);
+
+        let mut record = [0u8; 256];
+        let Some(record) = encode(&sample, format, &mut record) else {
+            warn!("SD log: sample doesn't fit");
+            continue;
+        };
+
+        let name = file_name(sample.timestamp, format);
+        if let Err(err) = append(&volumes, &name, format, record) {
+            warn!("SD log: writing {} failed: {:?}", name, Debug2Format(&err));
+        }
+    }
+}
+
+fn file_name(timestamp: u32, format: SdFormat) -> String<12> {
+    let extension = match format {
+        SdFormat::Csv => "CSV",
+        SdFormat::Postcard => "BIN",
+    };
+
+    let mut name = String::new();
+    if timestamp < MIN_UNIX_TIME {
+        write!(name, "NOCLOCK.{}", extension).ok();
+    } else {
+        let (year, month, day) = net_time::civil_from_days(timestamp / 86_400);
+        write!(name, "{:04}{:02}{:02}.{}", year, month, day, extension).ok();
+    }
+
+    name
+}
+
+fn encode<'a>(sample: &Sample, format: SdFormat, buf: &'a mut [u8]) -> Option<&'a [u8]> {
+    match format {
+        SdFormat::Csv => {
+            let line = csv_line(sample);
+            let bytes = buf.get_mut(..line.len())?;
+            bytes.copy_from_slice(line.as_bytes());
+            Some(bytes)
+        }
+        SdFormat::Postcard => postcard::to_slice_cobs(sample, buf)
+            .ok()
+            .map(|bytes| &*bytes),
+    }
+}
+
+fn csv_line(sample: &Sample) -> String<256> {
+    fn field(line: &mut String<256>, value: Option<impl core::fmt::Display>) {
+        line.push(',').ok();
+        if let Some(value) = value {
+            write!(line, "{}", value).ok();
+        }
+    }
+
+    let mut line = String::new();
+    write!(line, "{}", sample.timestamp).ok();
+    field(&mut line, sample.temp_bme680);
+    field(&mut line, sample.press_bme680);
+    field(&mut line, sample.hum_bme680);
+    field(&mut line, sample.gas_bme680);
+    field(&mut line, sample.lux_bh1750);
+    field(&mut line, sample.lux_veml7700);
+    field(&mut line, sample.temp_bmp390);
+    field(&mut line, sample.press_bmp390);
+    field(&mut line, sample.hum_sht40);
+    field(&mut line, sample.temp_sht40);
+    field(&mut line, sample.chip_temp);
+    field(&mut line, sample.supply_mv);
+    field(&mut line, sample.noise_dba);
+    field(&mut line, sample.voc_index);
+    field(&mut line, sample.pm1_0);
+    field(&mut line, sample.pm2_5);
+    field(&mut line, sample.pm10);
+    field(&mut line, sample.co2_mhz19);
+    field(&mut line, sample.adc0);
+    field(&mut line, sample.adc1);
+    field(&mut line, sample.battery_mv);
+    field(&mut line, sample.battery_ma);
+    field(&mut line, sample.battery_percent);
+    field(&mut line, sample.warming_up);
+    field(&mut line, sample.lux_tsl2591);
+    field(&mut line, sample.temp_bme280);
+    field(&mut line, sample.press_bme280);
+    field(&mut line, sample.hum_bme280);
+    field(&mut line, sample.co2_scd30);
+    field(&mut line, sample.wind_kmh);
+    field(&mut line, sample.rain_mm);
+    field(&mut line, sample.dew_point);
+    field(&mut line, sample.abs_humidity);
+    field(&mut line, sample.heat_index);
+    line.push('\n').ok();
+
+    line
+}
+
+/// Blocks for the few milliseconds the card takes, once per sample.
+fn append(
+    volumes: &VolumeManager<Card, &Clock>,
+    name: &str,
+    format: SdFormat,
+    record: &[u8],
+) -> Result<(), embedded_sdmmc::Error<embedded_sdmmc::SdCardError>> {
+    let volume = volumes.open_volume(VolumeIdx(0))?;
+    let root = volume.open_root_dir()?;
+    let file = root.open_file_in_dir(name, Mode::ReadWriteCreateOrAppend)?;
+
+    if format == SdFormat::Csv && file.length() == 0 {
+        file.write(CSV_HEADER.as_bytes())?;
+    }
+    file.write(record)?;
+
+    file.close()
+}
@@ -0,0 +1,204 @@
+//! Power-on self test: what of the hardware answered at boot.
+//!
+//! The checks run where the parts come up anyway, the flash where the
+//! storage is mounted and the sensors where they are probed, and each one
+//! leaves its outcome here. The whole matrix is logged once the sensors are
+//! done, published with the first MQTT connection and shown on the system
+//! page.
+
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use defmt::{info, warn};
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use serde::Serialize;
+
+use crate::syslog::{self, Severity};
+
+extern crate alloc;
+
+/// Less than that and the display and MQTT formatting won't get far.
+const MIN_HEAP_FREE: usize = 32 * 1024;
+const HEAP_PROBE_LEN: usize = 4 * 1024;
+
+static REPORT: Mutex<CriticalSectionRawMutex, Cell<Report>> =
+    Mutex::new(Cell::new(Report::UNTESTED));
+static COMPLETE: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy, PartialEq, Serialize, defmt::Format)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    /// Not checked (yet).
+    Untested,
+    Pass,
+    /// Worked after a repair, e.g. the storage had to be formatted.
+    Recovered,
+    /// Nothing answered. Fine for the sensors, a board has only some of them.
+    Absent,
+    Fail,
+}
+
+#[derive(Clone, Copy, defmt::Format)]
+pub enum Check {
+    Flash,
+    Rtc,
+    Heap,
+    Veml7700,
+    Sht40,
+    Bme680,
+    Bh1750,
+    Bmp390,
+    Sgp40,
+    Max17048,
+    Ina219,
+    Tsl2591,
+    Bme280,
+    Scd30,
+}
+
+#[derive(Clone, Copy, Serialize, defmt::Format)]
+pub struct Report {
+    pub flash: Outcome,
+    pub rtc: Outcome,
+    pub heap: Outcome,
+    pub veml7700: Outcome,
+    pub sht40: Outcome,
+    pub bme680: Outcome,
+    pub bh1750: Outcome,
+    pub bmp390: Outcome,
+    pub sgp40: Outcome,
+    pub max17048: Outcome,
+    pub ina219: Outcome,
+    pub tsl2591: Outcome,
+    pub bme280: Outcome,
+    pub scd30: Outcome,
+}
+
+impl Report {
+    const UNTESTED: Self = Self {
+        flash: Outcome::Untested,
+        rtc: Outcome::Untested,
+        heap: Outcome::Untested,
+        veml7700: Outcome::Untested,
+        sht40: Outcome::Untested,
+        bme680: Outcome::Untested,
+        bh1750: Outcome::Untested,
+        bmp390: Outcome::Untested,
+        sgp40: Outcome::Untested,
+        max17048: Outcome::Untested,
+        ina219: Outcome::Untested,
+        tsl2591: Outcome::Untested,
+        bme280: Outcome::Untested,
+        scd30: Outcome::Untested,
+    };
+
+    fn outcome_mut(&mut self, check: Check) -> &mut Outcome {
+        match check {
+            Check::Flash => &mut self.flash,
+            Check::Rtc => &mut self.rtc,
+            Check::Heap => &mut self.heap,
+            Check::Veml7700 => &mut self.veml7700,
+            Check::Sht40 => &mut self.sht40,
+            Check::Bme680 => &mut self.bme680,
+            Check::Bh1750 => &mut self.bh1750,
+            Check::Bmp390 => &mut self.bmp390,
+            Check::Sgp40 => &mut self.sgp40,
+            Check::Max17048 => &mut self.max17048,
+            Check::Ina219 => &mut self.ina219,
+            Check::Tsl2591 => &mut self.tsl2591,
+            Check::Bme280 => &mut self.bme280,
+            Check::Scd30 => &mut self.scd30,
+        }
+    }
+
+    pub fn failed(&self) -> bool {
+        [
+            self.flash,
+            self.rtc,
+            self.heap,
+            self.veml7700,
+            self.sht40,
+            self.bme680,
+            self.bh1750,
+            self.bmp390,
+            self.sgp40,
+            self.max17048,
+            self.ina219,
+            self.tsl2591,
+            self.bme280,
+            self.scd30,
+        ]
+        .contains(&Outcome::Fail)
+    }
+}
+
+pub fn record(check: Check, outcome: Outcome) {
+    REPORT.lock(|report| {
+        let mut all = report.get();
+        *all.outcome_mut(check) = outcome;
+        report.set(all);
+    });
+}
+
+pub fn report() -> Report {
+    REPORT.lock(|report| report.get())
+}
+
+/// Whether all the checks ran, so the report is worth publishing.
+pub fn complete() -> bool {
+    COMPLETE.load(Ordering::Relaxed)
+}
+
+/// The checks that need nothing but the chip. Meant to be called right after
+/// the heap is set up.
+pub fn check_chip() {
+    record(Check::Rtc, check_rtc());
+    record(Check::Heap, check_heap());
+}
+
+/// Called by the sensors task once every sensor was probed.
+pub fn finish() {
+    let report = report();
+    COMPLETE.store(true, Ordering::Relaxed);
+
+    if report.failed() {
+        warn!("Self test: {}", report);
+        syslog::log(Severity::Warning, format_args!("Self test failed"));
+    } else {
+        info!("Self test: {}", report);
+    }
+}
+
+/// The RTC timer keeps running on the slow clock, so it has to move within a
+/// couple of milliseconds.
+fn check_rtc() -> Outcome {
+    let peripherals = unsafe { esp_hal::peripherals::Peripherals::steal() };
+    let rtc = esp_hal::rtc_cntl::Rtc::new(peripherals.LPWR);
+
+    let start = rtc.current_time_us();
+    esp_hal::delay::Delay::new().delay_millis(2);
+
+    if rtc.current_time_us() > start {
+        Outcome::Pass
+    } else {
+        Outcome::Fail
+    }
+}
+
+fn check_heap() -> Outcome {
+    if esp_alloc::HEAP.free() < MIN_HEAP_FREE {
+        return Outcome::Fail;
+    }
+
+    let mut probe = alloc::vec::Vec::<u8>::new();
+    if probe.try_reserve_exact(HEAP_PROBE_LEN).is_err() {
+        return Outcome::Fail;
+    }
+    probe.resize(HEAP_PROBE_LEN, 0xA5);
+
+    if probe.iter().all(|byte| *byte == 0xA5) {
+        Outcome::Pass
+    } else {
+        Outcome::Fail
+    }
+}
@@ -0,0 +1,158 @@
+//! Samples as SenML (RFC 8428) JSON, for platforms that speak it rather than
+//! our own payload.
+//!
+//! A sample becomes one pack: the first record carries the base name, the
+//! client id and a colon, and the base time, the rest only a name, a unit and
+//! a value. The units are the ones of the SenML registry, so the pressure goes
+//! out in Pa and the supply in V.
+
+use serde::ser::{Serialize, SerializeSeq, Serializer};
+
+use crate::config::MQTT_CLIENT_ID_LEN;
+use crate::payload::{F32_LEN, U32_LEN, entry, escaped};
+use crate::sensors::Sample;
+
+/// SenML times below 2**28 are relative to now. A timestamp that small is the
+/// uptime, the clock isn't synced yet, and the records go out without a time.
+const MIN_ABSOLUTE_TIME: u32 = 1 << 28;
+
+/// The measurements by their SenML name and unit.
+const MEASUREMENTS: [(&str, &str, fn(&Sample) -> Option<f32>); 33] = [
+    ("temp_bme680", "Cel", |sample| sample.temp_bme680),
+    ("press_bme680", "Pa", |sample| {
+        sample.press_bme680.map(|hpa| hpa * 100.0)
+    }),
+    ("hum_bme680", "%RH", |sample| sample.hum_bme680),
+    ("temp_bme280", "Cel", |sample| sample.temp_bme280),
+    ("press_bme280", "Pa", |sample| {
+        sample.press_bme280.map(|hpa| hpa * 100.0)
+    }),
+    ("hum_bme280", "%RH", |sample| sample.hum_bme280),
+    ("gas_bme680", "Ohm", |sample| {
+        sample.gas_bme680.map(|ohm| ohm as f32)
+    }),
+    ("lux_bh1750", "lx", |sample| sample.lux_bh1750),
+    ("lux_veml7700", "lx", |sample| sample.lux_veml7700),
+    ("lux_tsl2591", "lx", |sample| sample.lux_tsl2591),
+    ("temp_bmp390", "Cel", |sample| sample.temp_bmp390),
+    ("press_bmp390", "Pa", |sample| {
+        sample.press_bmp390.map(|hpa| hpa * 100.0)
+    }),
+    ("hum_sht40", "%RH", |sample| sample.hum_sht40),
+    ("temp_sht40", "Cel", |sample| sample.temp_sht40),
+    ("chip_temp", "Cel", |sample| sample.chip_temp),
+    ("supply", "V", |sample| {
+        sample.supply_mv.map(|mv| mv as f32 / 1000.0)
+    }),
+    // SenML has no dBA, the sound pressure level is in bels.
+    ("noise", "Bspl", |sample| {
+        sample.noise_dba.map(|dba| dba / 10.0)
+    }),
+    // An index, it goes out without a unit.
+    ("voc_index", "", |sample| {
+        sample.voc_index.map(|index| index as f32)
+    }),
+    ("pm1_0", "ug/m3", |sample| sample.pm1_0.map(|pm| pm as f32)),
+    ("pm2_5", "ug/m3", |sample| sample.pm2_5.map(|pm| pm as f32)),
+    ("pm10", "ug/m3", |sample| sample.pm10.map(|pm| pm as f32)),
+    ("co2_mhz19", "ppm", |sample| {
+        sample.co2_mhz19.map(|ppm| ppm as f32)
+    }),
+    ("co2_scd30", "ppm", |sample| {
+        sample.co2_scd30.map(|ppm| ppm as f32)
+    }),
+    // Millivolts or whatever the map turns them into, no unit then.
+    ("adc0", "", |sample| sample.adc0),
+    ("adc1", "", |sample| sample.adc1),
+    ("battery", "V", |sample| {
+        sample.battery_mv.map(|mv| mv as f32 / 1000.0)
+    }),
+    ("battery_current", "A", |sample| {
+        sample.battery_ma.map(|ma| ma / 1000.0)
+    }),
+    ("battery_level", "%EL", |sample| sample.battery_percent),
+    ("wind_speed", "m/s", |sample| {
+        sample.wind_kmh.map(|kmh| kmh / 3.6)
+    }),
+    // The depth that fell since the previous sample.
+    ("rain", "m", |sample| sample.rain_mm.map(|mm| mm / 1000.0)),
+    ("dew_point", "Cel", |sample| sample.dew_point),
+    ("abs_humidity", "g/m3", |sample| sample.abs_humidity),
+    ("heat_index", "Cel", |sample| sample.heat_index),
+];
+
+/// The longest pack: every measurement with the longest value, and the
+/// base name of a client id of control characters.
+pub const MAX_LEN: usize = {
+    let mut len = 2 + entry("bn", escaped(MQTT_CLIENT_ID_LEN + 1)) + entry("bt", U32_LEN);
+
+    let mut i = 0;
+    while i < MEASUREMENTS.len() {
+        let (name, unit, _) = MEASUREMENTS[i];
+        len += 2 + entry("n", name.len() + 2) + entry("u", unit.len() + 2) + entry("v", F32_LEN);
+        i += 1;
+    }
+
+    len
+};
+
+pub struct Pack<'a> {
+    sample: Sample,
+    client_id: &'a str,
+}
+
+impl<'a> Pack<'a> {
+    pub fn new(sample: Sample, client_id: &'a str) -> Self {
+        Self { sample, client_id }
+    }
+
+    fn base_time(&self) -> Option<u32> {
+        (self.sample.timestamp >= MIN_ABSOLUTE_TIME).then_some(self.sample.timestamp)
+    }
+
+    fn measurements(&self) -> impl Iterator<Item = (&'static str, &'static str, f32)> {
+        MEASUREMENTS
+            .into_iter()
+            .filter_map(|(name, unit, get)| get(&self.sample).map(|value| (name, unit, value)))
+    }
+}
+
+/// Serializes as the client id with the colon the names are appended to.
+struct BaseName<'a>(&'a str);
+
+impl Serialize for BaseName<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&format_args!("{}:", self.0))
+    }
+}
+
+#[derive(serde::Serialize)]
+struct Record<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bn: Option<BaseName<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bt: Option<u32>,
+    n: &'static str,
+    #[serde(skip_serializing_if = "str::is_empty")]
+    u: &'static str,
+    v: f32,
+}
+
+impl Serialize for Pack<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(None)?;
+
+        for (i, (n, u, v)) in self.measurements().enumerate() {
+            let first = i == 0;
+            seq.serialize_element(&Record {
+                bn: first.then_some(BaseName(self.client_id)),
+                bt: self.base_time().filter(|_| first),
+                n,
+                u,
+                v,
+            })?;
+        }
+
+        seq.end()
+    }
+}
@@ -1,28 +1,75 @@
-use core::cell::RefCell;
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::cell::Cell;
+use core::future::Future;
 
-use bh1750::BH1750;
-use bme680::{Bme680, I2CAddress, IIRFilterSize, PowerMode, SettingsBuilder};
 use defmt::{error, info, warn};
-use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex, signal::Signal};
+use embassy_embedded_hal::shared_bus::asynch::i2c;
+use embassy_futures::select::select;
+use embassy_sync::{
+    blocking_mutex::{Mutex, raw::CriticalSectionRawMutex},
+    mutex,
+    signal::Signal,
+    watch::Watch,
+};
 use embassy_time::{Duration, Instant, Timer};
-pub use embedded_hal_bus::i2c::RefCellDevice;
-use esp_hal::{Async, delay::Delay, i2c};
+use esp_hal::{Async, tsens::TemperatureSensor};
 use heapless::spsc::Queue;
 use serde::{Deserialize, Serialize};
-use uom::si::{pressure::hectopascal, thermodynamic_temperature::degree_celsius};
-
-use crate::{air_quality, net_time};
 
-pub static LATEST_SAMPLE: Signal<CriticalSectionRawMutex, Sample> = Signal::new();
+use crate::diagnostics::{self, DropCause, Timed};
+use crate::self_test::{self, Check, Outcome};
+use crate::syslog::{self, Severity};
+use crate::{air_quality, alerts, events, net_time, noise, power, system, watchdog};
+
+pub mod analog;
+pub mod calibration;
+pub mod co2;
+pub mod derived;
+mod drivers;
+mod fields;
+pub mod filter;
+pub mod health;
+pub mod mhz19;
+pub mod pms5003;
+pub mod scan;
+pub mod schema;
+pub mod weather;
+
+use drivers::Registration;
+pub use drivers::SensorDriver;
+pub use schema::SampleVersion;
+
+/// Receivers that wait for new samples, the display, the SD card log and the
+/// relay rules. One-off readers such as the web API use anonymous receivers
+/// and don't count.
+pub const LATEST_RECEIVERS: usize = 3;
+
+const SAMPLE_PERIOD: Duration = Duration::from_secs(60);
+
+/// The driver being called right now, or 0. A panic or a watchdog reset in
+/// the middle of a call leaves it set, and the next boot blames the driver.
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static mut IN_DRIVER: u32 = 0;
+/// Drivers that took the node down, kept until the power is cut.
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static mut QUARANTINE: u32 = 0;
+/// In the upper half of both, the drivers go to the lower one.
+const DRIVER_MAGIC: u32 = 0xD21E_0000;
+
+/// The most recent sample. Keeps the value, so consumers that start or
+/// restart later still get the current readings.
+pub static LATEST: Watch<CriticalSectionRawMutex, Sample, LATEST_RECEIVERS> = Watch::new();
 pub static HAS_DATA: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+/// Cuts the wait for the next measurement short.
+pub static MEASURE_NOW: Signal<CriticalSectionRawMutex, ()> = Signal::new();
 pub static QUEUE: mutex::Mutex<CriticalSectionRawMutex, Queue<Sample, 64>> =
     mutex::Mutex::new(Queue::new());
-
-#[derive(Default, Serialize, Deserialize, Clone)]
-enum SampleVersion {
-    #[default]
-    V1,
-}
+/// Per [`Driver`], see [`set_warm_up`].
+static WARM_UP_SECS: Mutex<CriticalSectionRawMutex, Cell<[u16; Driver::ALL.len()]>> =
+    Mutex::new(Cell::new([0; Driver::ALL.len()]));
 
 #[derive(Default, Serialize, Deserialize, Clone)]
 pub struct Sample {
@@ -37,273 +84,505 @@ pub struct Sample {
     pub temp_bmp390: Option<f32>,
     pub lux_veml7700: Option<f32>,
     pub lux_bh1750: Option<f32>,
+    pub gas_bme680: Option<u32>,
+    /// The chip's own sensor, tells self-heating and an overheating
+    /// enclosure apart from the ambient.
+    pub chip_temp: Option<f32>,
+    pub supply_mv: Option<u16>,
+    /// A-weighted equivalent sound level since the previous sample.
+    pub noise_dba: Option<f32>,
+    /// Sensirion's VOC index, 100 is the average of the last hours, above it
+    /// is worse.
+    pub voc_index: Option<u16>,
+    /// Particulate matter from a PMS5003, in µg/m³.
+    pub pm1_0: Option<u16>,
+    pub pm2_5: Option<u16>,
+    pub pm10: Option<u16>,
+    /// CO2 from an MH-Z19B, in ppm.
+    pub co2_mhz19: Option<u16>,
+    /// The analog channels, in millivolts or what the settings map them to.
+    pub adc0: Option<f32>,
+    pub adc1: Option<f32>,
+    /// From a MAX17048 fuel gauge or an INA219, whichever is fitted.
+    pub battery_mv: Option<u16>,
+    pub battery_ma: Option<f32>,
+    pub battery_percent: Option<f32>,
+    /// Set while a sensor's readings are left out because it's still warming
+    /// up, see [`set_warm_up`].
+    pub warming_up: Option<bool>,
+    pub lux_tsl2591: Option<f32>,
+    pub temp_bme280: Option<f32>,
+    pub press_bme280: Option<f32>,
+    pub hum_bme280: Option<f32>,
+    /// CO2 from an SCD30, in ppm.
+    pub co2_scd30: Option<u16>,
+    /// The average wind speed since the previous sample, and the rain that
+    /// fell in it, see [`weather`].
+    pub wind_kmh: Option<f32>,
+    pub rain_mm: Option<f32>,
+    /// In °C, g/m³ and °C, from one sensor's temperature and humidity, see
+    /// [`derived`].
+    pub dew_point: Option<f32>,
+    pub abs_humidity: Option<f32>,
+    pub heat_index: Option<f32>,
 }
 
-pub type I2C<'a> = i2c::master::I2c<'a, Async>;
-pub type RefCellDevI2C<'a> = RefCellDevice<'a, I2C<'a>>;
-
-#[embassy_executor::task]
-pub async fn task(i2c: &'static RefCell<I2C<'static>>) -> ! {
-    Timer::after(Duration::from_secs(1)).await;
-
-    let mut veml = if check_i2c_address(&i2c, 0x10).await {
-        info!("I2C: VEML7700 detected");
-        create_veml7700(&i2c)
-    } else {
-        None
-    };
+impl Sample {
+    /// The layout the sample was taken with, see [`schema`].
+    pub fn version(&self) -> SampleVersion {
+        self.version
+    }
 
-    let mut sht40 = create_sht40(&i2c);
+    pub fn temperature(&self) -> Option<f32> {
+        self.temp_sht40
+            .or(self.temp_bmp390)
+            .or(self.temp_bme280)
+            .or(self.temp_bme680)
+    }
 
-    let mut bme680 = if check_i2c_address(&i2c, 0x76).await {
-        info!("I2C: BME680 detected");
-        create_bme680(&i2c)
-    } else {
-        None
-    };
+    pub fn humidity(&self) -> Option<f32> {
+        self.hum_sht40.or(self.hum_bme280).or(self.hum_bme680)
+    }
 
-    let mut bh1750 = if check_i2c_address(&i2c, 0x23).await {
-        info!("I2C: BH1750 detected");
-        create_bh1750(&i2c)
-    } else {
-        None
-    };
+    pub fn light(&self) -> Option<f32> {
+        self.lux_tsl2591.or(self.lux_veml7700).or(self.lux_bh1750)
+    }
 
-    let mut bmp390 = create_bmp390(&i2c);
+    /// The SCD30 is the more accurate one, if both are fitted.
+    pub fn co2(&self) -> Option<u16> {
+        self.co2_scd30.or(self.co2_mhz19)
+    }
 
-    let mut skip: u8 = 10;
+    pub fn pressure(&self) -> Option<f32> {
+        self.press_bmp390
+            .or(self.press_bme280)
+            .or(self.press_bme680)
+    }
 
-    loop {
-        let start = Instant::now();
+    /// The charge in whole percent, as the BLE battery level has it.
+    pub fn battery_level(&self) -> Option<u8> {
+        self.battery_percent
+            .map(|percent| libm::roundf(percent).clamp(0.0, 100.0) as u8)
+    }
+}
 
-        let lux_veml7700 = veml.as_mut().and_then(|device| match device.read_lux() {
-            Ok(lux) => Some(lux),
-            Err(_) => {
-                warn!("Could not read value out of VEML7700");
-                None
-            }
-        });
+/// Current sample, if the sensors task has produced one yet.
+pub fn latest() -> Option<Sample> {
+    LATEST.anon_receiver().try_get()
+}
 
-        let bme680_data = bme680.as_mut().and_then(|(bme, delayer)| {
-            bme.set_sensor_mode(delayer, PowerMode::ForcedMode).ok()?;
-            let (data, _state) = bme.get_sensor_data(delayer).ok()?;
+pub type I2C<'a> = esp_hal::i2c::master::I2c<'a, Async>;
+/// An I2C bus the sensors, the display and the debug tools take turns on, a
+/// transaction at a time. Waiting for it leaves the executor to the others.
+pub type I2cBus = mutex::Mutex<CriticalSectionRawMutex, I2C<'static>>;
+/// A driver's share of an [`I2cBus`], `embedded-hal-async` on top of it.
+pub type I2cDevice = i2c::I2cDevice<'static, CriticalSectionRawMutex, I2C<'static>>;
+
+/// The I2C buses the sensors are looked for on, the one the display shares
+/// and a second one on the boards that have it. Sensors on the same address
+/// go one on each, a driver is set up on the first bus it answers on.
+#[derive(Clone, Copy)]
+pub struct Buses {
+    pub i2c: &'static I2cBus,
+    pub i2c1: Option<&'static I2cBus>,
+}
 
-            Some((
-                data.humidity_percent(),
-                data.pressure_hpa(),
-                data.temperature_celsius(),
-            ))
-        });
+impl Buses {
+    /// The bus numbered `index` in the health report.
+    fn get(self, index: u8) -> Option<&'static I2cBus> {
+        match index {
+            0 => Some(self.i2c),
+            1 => self.i2c1,
+            _ => None,
+        }
+    }
 
-        let lux_bh1750 = bh1750
-            .as_mut()
-            .and_then(|bh| bh.get_one_time_measurement(bh1750::Resolution::High2).ok());
+    /// The buses there are, with their numbers.
+    fn all(self) -> impl Iterator<Item = (u8, &'static I2cBus)> {
+        (0..2).filter_map(move |index| Some((index, self.get(index)?)))
+    }
+}
 
-        let sht40_data = sht40.as_mut().and_then(|(device, delay)| {
-            device
-                .measure(sht4x::Precision::High, delay)
-                .inspect_err(|err| warn!("Could not measure with SHT40: {}", err))
-                .ok()
-        });
+#[derive(Debug, Clone, Copy, defmt::Format)]
+pub enum Driver {
+    Veml7700 = 0,
+    Sht40 = 1,
+    Bme680 = 2,
+    Bh1750 = 3,
+    Bmp390 = 4,
+    Sgp40 = 5,
+    Max17048 = 6,
+    Ina219 = 7,
+    Tsl2591 = 8,
+    Bme280 = 9,
+    Scd30 = 10,
+}
 
-        let bmp390_data = bmp390.as_mut().and_then(|device| device.measure().ok());
+impl Driver {
+    const ALL: [Self; 11] = [
+        Self::Veml7700,
+        Self::Sht40,
+        Self::Bme680,
+        Self::Bh1750,
+        Self::Bmp390,
+        Self::Sgp40,
+        Self::Max17048,
+        Self::Ina219,
+        Self::Tsl2591,
+        Self::Bme280,
+        Self::Scd30,
+    ];
+
+    fn bit(self) -> u32 {
+        1 << self as u32
+    }
 
-        if skip > 0 {
-            skip -= 1;
-            info!("Skip measurement. {} more to skip", skip);
-            Timer::after(embassy_time::Duration::from_secs(3)).await;
-            continue;
+    /// As the warm-up setting names it.
+    fn name(self) -> &'static str {
+        match self {
+            Self::Veml7700 => "veml7700",
+            Self::Sht40 => "sht40",
+            Self::Bme680 => "bme680",
+            Self::Bh1750 => "bh1750",
+            Self::Bmp390 => "bmp390",
+            Self::Sgp40 => "sgp40",
+            Self::Max17048 => "max17048",
+            Self::Ina219 => "ina219",
+            Self::Tsl2591 => "tsl2591",
+            Self::Bme280 => "bme280",
+            Self::Scd30 => "scd30",
         }
+    }
 
-        let timestamp = { net_time::TIME_STATE.lock().await.now_or_uptime() };
+    /// How long the readings are left out after the start when the settings
+    /// don't say. The BME680 gas resistance drifts for minutes while the
+    /// plate heats up, the SGP40 reads too low for the first seconds.
+    fn default_warm_up_secs(self) -> u16 {
+        match self {
+            Self::Bme680 => 300,
+            Self::Sgp40 => 45,
+            _ => 0,
+        }
+    }
 
-        let mut sample = Sample {
-            timestamp,
-            lux_bh1750,
-            lux_veml7700,
-            ..Default::default()
-        };
+    fn check(self) -> Check {
+        match self {
+            Self::Veml7700 => Check::Veml7700,
+            Self::Sht40 => Check::Sht40,
+            Self::Bme680 => Check::Bme680,
+            Self::Bh1750 => Check::Bh1750,
+            Self::Bmp390 => Check::Bmp390,
+            Self::Sgp40 => Check::Sgp40,
+            Self::Max17048 => Check::Max17048,
+            Self::Ina219 => Check::Ina219,
+            Self::Tsl2591 => Check::Tsl2591,
+            Self::Bme280 => Check::Bme280,
+            Self::Scd30 => Check::Scd30,
+        }
+    }
+}
 
-        bme680_data.map(|data| {
-            sample.hum_bme680 = Some(data.0);
-            sample.press_bme680 = Some(data.1);
-            sample.temp_bme680 = Some(data.2);
+/// Sets the warm-up of the sensors named in `list`, `<sensor>:<seconds>`
+/// separated by commas or spaces like `bme680:600 sgp40:0`. The others keep
+/// their default.
+pub fn set_warm_up(list: &str) {
+    let mut secs = Driver::ALL.map(Driver::default_warm_up_secs);
+
+    for entry in list.split([',', ' ']).filter(|entry| !entry.is_empty()) {
+        let parsed = entry.split_once(':').and_then(|(name, value)| {
+            let driver = Driver::ALL
+                .into_iter()
+                .find(|driver| driver.name() == name)?;
+            Some((driver, value.parse().ok()?))
         });
+        match parsed {
+            Some((driver, value)) => secs[driver as usize] = value,
+            None => warn!("Sensors: {} is not a warm-up", entry),
+        }
+    }
 
-        sht40_data.map(|data| {
-            sample.hum_sht40 = Some(data.humidity_milli_percent() as f32 / 1000.0);
-            sample.temp_sht40 = Some(data.temperature_milli_celsius() as f32 / 1000.0);
-        });
+    WARM_UP_SECS.lock(|current| current.set(secs));
+}
 
-        bmp390_data.map(|data| {
-            sample.temp_bmp390 = Some(data.temperature.get::<degree_celsius>());
-            sample.press_bmp390 = Some(data.pressure.get::<hectopascal>());
-        });
+/// Runs a call into `driver`, so a reset in the middle of it is blamed on it.
+/// The other tasks run while it waits for the bus and its sensor, a reset of
+/// theirs in those few milliseconds is blamed on it too.
+async fn call<T>(driver: Driver, f: impl Future<Output = T>) -> T {
+    unsafe { core::ptr::addr_of_mut!(IN_DRIVER).write_volatile(DRIVER_MAGIC | driver.bit()) };
+    let result = f.await;
+    unsafe { core::ptr::addr_of_mut!(IN_DRIVER).write_volatile(0) };
 
-        {
-            let mut queue = QUEUE.lock().await;
-            queue.enqueue(sample.clone()).ok();
-        }
+    result
+}
 
-        LATEST_SAMPLE.signal(sample);
-        HAS_DATA.signal(());
+/// Moves the driver that was running when the node went down to the
+/// quarantine, and returns the quarantined ones.
+fn update_quarantine() -> u32 {
+    let in_driver = unsafe { core::ptr::addr_of_mut!(IN_DRIVER).replace(0) };
+    let quarantine = unsafe { core::ptr::addr_of!(QUARANTINE).read_volatile() };
 
-        let delay = embassy_time::Duration::from_secs(60) - (Instant::now() - start);
+    let mut drivers = if quarantine & 0xFFFF_0000 == DRIVER_MAGIC {
+        quarantine & 0xFFFF
+    } else {
+        0
+    };
 
-        Timer::after(delay).await;
+    if in_driver & 0xFFFF_0000 == DRIVER_MAGIC {
+        let crashed = Driver::ALL
+            .into_iter()
+            .find(|driver| in_driver & driver.bit() != 0);
+        if let Some(driver) = crashed {
+            error!("{}: the node went down in the driver, disabling it", driver);
+            syslog::log(
+                Severity::Error,
+                format_args!("{:?}: the node went down in the driver, disabled", driver),
+            );
+        }
+        drivers |= in_driver & 0xFFFF;
     }
+
+    unsafe { core::ptr::addr_of_mut!(QUARANTINE).write_volatile(DRIVER_MAGIC | drivers) };
+
+    drivers
 }
 
-async fn check_i2c_address<'a>(i2c: &RefCell<I2C<'a>>, addr: u8) -> bool {
+#[embassy_executor::task]
+pub async fn task(
+    buses: Buses,
+    chip_sensor: Option<TemperatureSensor<'static>>,
+    mut supply: Option<&'static mut dyn power::SupplyVoltage>,
+) -> ! {
     Timer::after(Duration::from_secs(1)).await;
 
-    let mut data = [0u8; 22];
-    i2c.borrow_mut()
-        .write_read(addr, &[0x00], &mut data)
-        .map_err(|err| warn!("I2C: Error scanning at 0x{:X}: {}", addr, err))
-        .ok()
-        .is_some()
-}
+    let quarantine = update_quarantine();
+    let table = scan::run(buses).await;
+
+    let mut sensors: Vec<Box<dyn SensorDriver>> = Vec::new();
+    // The bus each sensor was found on.
+    let mut on_bus = [0u8; Driver::ALL.len()];
+    for registration in &drivers::REGISTRY {
+        if let Some((bus, sensor)) = probe(buses, &table, quarantine, registration).await {
+            health::found(registration.driver, bus);
+            on_bus[registration.driver as usize] = bus;
+            sensors.push(sensor);
+        }
+    }
 
-fn create_veml7700<'a>(i2c: &'a RefCell<I2C<'a>>) -> Option<veml7700::Veml7700<RefCellDevI2C<'a>>> {
-    let mut veml = veml7700::Veml7700::new(RefCellDevice::new(i2c));
+    self_test::finish();
 
-    veml.set_integration_time(veml7700::IntegrationTime::_100ms)
-        .ok()?;
-    veml.set_gain(veml7700::Gain::OneQuarter).ok()?;
+    if sensors.is_empty() {
+        system::report_fault(system::Fault::SensorBus);
+    }
 
-    if let Err(_err) = veml.enable() {
-        warn!("Could not enable VEML7700");
-        None
+    // A node waking from deep sleep has time for a single measurement only.
+    let warm_up_secs = if power::sleep_enabled() {
+        [0; Driver::ALL.len()]
     } else {
-        Some(veml)
-    }
-}
+        WARM_UP_SECS.lock(|secs| secs.get())
+    };
+    let started = Instant::now();
+    // The ones given up on, looked for again now and then.
+    let mut lost = 0u32;
+    let mut reprobe_at = started + health::REPROBE_PERIOD;
+    let mut filters = filter::Filters::default();
+    let mut last_timestamp = 0u32;
+    let mut counted_since = started;
 
-fn create_bme680<'a>(
-    i2c: &'a RefCell<I2C<'a>>,
-) -> Option<(
-    Bme680<RefCellDevI2C<'a>, esp_hal::delay::Delay>,
-    esp_hal::delay::Delay,
-)> {
-    info!("Setting up BME680");
-    let mut delayer = esp_hal::delay::Delay::new();
-    let mut bme = Bme680::init(RefCellDevice::new(i2c), &mut delayer, I2CAddress::Primary)
-        .map_err(bme680_error)
-        .ok()?;
-
-    info!("Setting up settings for BME680");
-    let settings = SettingsBuilder::new()
-        .with_temperature_oversampling(bme680::OversamplingSetting::OS2x)
-        .with_pressure_oversampling(bme680::OversamplingSetting::OS4x)
-        .with_humidity_oversampling(bme680::OversamplingSetting::OS2x)
-        .with_temperature_filter(IIRFilterSize::Size3)
-        .build();
-
-    bme.set_sensor_settings(&mut delayer, settings).ok()?;
-
-    info!("Setting forced power modes");
-    bme.set_sensor_mode(&mut delayer, PowerMode::ForcedMode)
-        .ok()?;
-
-    Some((bme, delayer))
-}
+    loop {
+        let start = Instant::now();
+        watchdog::check_in(watchdog::Task::Sensors);
+
+        let supply_mv = supply.as_mut().and_then(|supply| supply.millivolts());
+        if supply_mv.is_some_and(power::check_supply) {
+            let heater = !power::gas_heater_off();
+            for sensor in sensors.iter_mut() {
+                call(sensor.driver(), sensor.set_heater(heater)).await;
+            }
+        }
 
-fn bme680_error(err: bme680::Error<esp_hal::i2c::master::Error>) {
-    match err {
-        bme680::Error::I2C(err) => {
-            error!("BME init error: I2C");
-            match err {
-                i2c::master::Error::FifoExceeded => error!("  I2C error: FifoExceeded"),
-                i2c::master::Error::AcknowledgeCheckFailed(err) => {
-                    error!("  I2C error: AcknowledgeCheckFailed");
-                    match err {
-                        i2c::master::AcknowledgeCheckFailedReason::Address => error!("    Address"),
-                        i2c::master::AcknowledgeCheckFailedReason::Data => error!("    Data"),
-                        i2c::master::AcknowledgeCheckFailedReason::Unknown => error!("    Unknown"),
-                        _ => error!("    ????"),
+        // The conversions run side by side, the executor serves the other
+        // tasks while they take.
+        let mut conversion = Duration::from_ticks(0);
+        for sensor in sensors.iter_mut() {
+            conversion = conversion.max(call(sensor.driver(), sensor.start()).await);
+        }
+        Timer::after(conversion).await;
+
+        let mut sample = Sample::default();
+        let mut warming_up = false;
+        let mut kept = Vec::with_capacity(sensors.len());
+        for mut sensor in core::mem::take(&mut sensors) {
+            let driver = sensor.driver();
+            // Still read, some sensors only settle while they measure.
+            let warm = started.elapsed().as_secs() >= warm_up_secs[driver as usize] as u64;
+            let mut discarded = Sample::default();
+            let target = if warm { &mut sample } else { &mut discarded };
+            warming_up |= !warm;
+
+            let ok = call(driver, sensor.read(target)).await.is_some();
+            match health::note(driver, ok) {
+                health::Action::Keep => {}
+                health::Action::Reinit => {
+                    let created = match buses.get(on_bus[driver as usize]) {
+                        Some(i2c) => recreate(i2c, driver).await,
+                        None => None,
+                    };
+                    health::reinit(driver, created.is_some());
+                    if let Some(created) = created {
+                        sensor = created;
                     }
                 }
-                i2c::master::Error::Timeout => error!("  I2C error: Timeout"),
-                i2c::master::Error::ArbitrationLost => error!("  I2C error: ArbitrationLost"),
-                i2c::master::Error::ExecutionIncomplete => {
-                    error!("  I2C error: ExecutionIncomplete")
-                }
-                i2c::master::Error::CommandNumberExceeded => {
-                    error!("  I2C error: CommandNumberExceeded")
-                }
-                i2c::master::Error::ZeroLengthInvalid => error!("  I2C error: ZeroLengthInvalid"),
-                i2c::master::Error::AddressInvalid(i2c_address) => {
-                    error!("  I2C error: AddressInvalid: {}", i2c_address)
+                health::Action::GiveUp => {
+                    lost |= driver.bit();
+                    continue;
                 }
-                _ => todo!(),
-            };
+            }
+            kept.push(sensor);
+        }
+        sensors = kept;
+        sample.warming_up = warming_up.then_some(true);
+
+        // Never at or before the previous sample, a sync may set the clock
+        // back a little.
+        let now = { net_time::TIME_STATE.lock().await.now_or_uptime() };
+        sample.timestamp = now.max(last_timestamp.saturating_add(1));
+        last_timestamp = sample.timestamp;
+        sample.chip_temp = chip_sensor
+            .as_ref()
+            .map(|sensor| sensor.get_temperature().to_celsius());
+        sample.supply_mv = supply_mv;
+        sample.noise_dba = noise::take_dba();
+        if let Some(reading) = pms5003::take() {
+            sample.pm1_0 = Some(reading.pm1_0);
+            sample.pm2_5 = Some(reading.pm2_5);
+            sample.pm10 = Some(reading.pm10);
+        }
+        sample.co2_mhz19 = mhz19::take();
+        [sample.adc0, sample.adc1] = analog::read();
+        let counted_until = Instant::now();
+        (sample.wind_kmh, sample.rain_mm) = weather::read(counted_until - counted_since);
+        counted_since = counted_until;
+        calibration::apply(&mut sample);
+        filters.apply(&mut sample);
+        derived::apply(&mut sample);
+
+        {
+            let mut queue = QUEUE.lock().await;
+            if queue.enqueue(sample.clone()).is_err() {
+                warn!("Sensors: queue full, sample dropped");
+                diagnostics::dropped(DropCause::QueueFull);
+            }
         }
-        bme680::Error::Delay => error!("BME init error: Delay"),
-        bme680::Error::DeviceNotFound => error!("BME init error: DeviceNotFound"),
-        bme680::Error::InvalidLength => error!("BME init error: InvalidLength"),
-        bme680::Error::DefinePwrMode => error!("BME init error: DefinePwrMode"),
-        bme680::Error::NoNewData => error!("BME init error: NoNewData"),
-        bme680::Error::BoundaryCheckFailure(_) => error!("BME init error: BoundaryCheckFailure"),
+
+        events::sample(&sample).await;
+        alerts::check(&sample);
+        LATEST.sender().send(sample);
+        HAS_DATA.signal(());
+
+        diagnostics::record(Timed::SensorCycle, start);
+
+        let elapsed = start.elapsed();
+        let delay = SAMPLE_PERIOD.checked_sub(elapsed).unwrap_or_else(|| {
+            warn!(
+                "Sensor cycle took {} ms, over its budget",
+                elapsed.as_millis()
+            );
+            Duration::from_ticks(0)
+        });
+
+        if lost != 0 && Instant::now() >= reprobe_at {
+            reprobe_at = Instant::now() + health::REPROBE_PERIOD;
+            reprobe(buses, &mut sensors, &mut lost, &mut on_bus).await;
+        }
+
+        select(Timer::after(delay), MEASURE_NOW.wait()).await;
     }
 }
 
-fn create_bh1750<'a>(
-    i2c: &'a RefCell<I2C<'a>>,
-) -> Option<BH1750<RefCellDevI2C<'a>, esp_hal::delay::Delay>> {
-    let delayer = esp_hal::delay::Delay::new();
-    let bh1750 = BH1750::new(RefCellDevice::new(&i2c), delayer, false);
-
-    info!(
-        "Lux measurement time for HIGH2: {} ms",
-        bh1750.get_typical_measurement_time_ms(bh1750::Resolution::High2)
-    );
-    info!(
-        "Lux measurement time for HIGH:  {} ms",
-        bh1750.get_typical_measurement_time_ms(bh1750::Resolution::High)
-    );
-    info!(
-        "Lux measurement time for LOW:    {} ms",
-        bh1750.get_typical_measurement_time_ms(bh1750::Resolution::Low)
-    );
-
-    Some(bh1750)
+/// Where `driver` is in the registry, and how to set it up.
+fn registration(driver: Driver) -> Option<(usize, &'static Registration)> {
+    drivers::REGISTRY
+        .iter()
+        .enumerate()
+        .find(|(_, registration)| registration.driver as usize == driver as usize)
+}
+
+/// Sets `driver` up again, none when it doesn't answer.
+async fn recreate(i2c: &'static I2cBus, driver: Driver) -> Option<Box<dyn SensorDriver>> {
+    let (_, registration) = registration(driver)?;
+
+    call(driver, (registration.create)(i2c)).await
 }
 
-fn create_sht40<'a>(
-    i2c: &'a RefCell<I2C<'a>>,
-) -> Option<(sht4x::Sht4x<RefCellDevI2C<'a>, Delay>, Delay)> {
-    let mut delay = Delay::new();
-
-    for addr in [
-        sht4x::Address::Address0x44,
-        sht4x::Address::Address0x45,
-        sht4x::Address::Address0x46,
-    ] {
-        let mut sht40 = sht4x::Sht4x::new_with_address(RefCellDevice::new(&i2c), addr);
-        if sht40.serial_number(&mut delay).is_ok() {
-            info!("I2C: SHT40 detected at 0x{:X}", u8::from(addr));
-            return Some((sht40, delay));
+/// Sets the `lost` sensors up again where they answer, on either bus, in the
+/// order of the registry so the readings they depend on come first.
+async fn reprobe(
+    buses: Buses,
+    sensors: &mut Vec<Box<dyn SensorDriver>>,
+    lost: &mut u32,
+    on_bus: &mut [u8; Driver::ALL.len()],
+) {
+    for driver in Driver::ALL {
+        if *lost & driver.bit() == 0 {
+            continue;
+        }
+
+        let mut found = None;
+        for (bus, i2c) in buses.all() {
+            if let Some(sensor) = recreate(i2c, driver).await {
+                found = Some((bus, sensor));
+                break;
+            }
+        }
+        if let Some((bus, sensor)) = found {
+            info!("{}: found again on I2C bus {}", driver, bus);
+            health::found(driver, bus);
+            on_bus[driver as usize] = bus;
+            sensors.push(sensor);
+            *lost &= !driver.bit();
         }
     }
 
-    None
+    sensors.sort_by_key(|sensor| registration(sensor.driver()).map(|(index, _)| index));
 }
 
-fn create_bmp390<'a>(i2c: &'a RefCell<I2C<'a>>) -> Option<bmp390::sync::Bmp390<RefCellDevI2C<'a>>> {
-    use bmp390::{Address, Configuration, sync::Bmp390};
-
-    let delay = esp_hal::delay::Delay::new();
-    let config = Configuration::default();
+/// Sets up `driver` on the first bus it answers on, unless it is in the
+/// quarantine. With an `address`, something has to answer there in the
+/// scan, and failing the setup after that on every bus is a fault.
+async fn probe(
+    buses: Buses,
+    table: &scan::Table,
+    quarantine: u32,
+    registration: &Registration,
+) -> Option<(u8, Box<dyn SensorDriver>)> {
+    let Registration {
+        driver,
+        address,
+        create,
+    } = *registration;
+
+    if quarantine & driver.bit() != 0 {
+        warn!("{}: disabled until the next power cycle", driver);
+        self_test::record(driver.check(), Outcome::Fail);
+        return None;
+    }
 
-    for addr in [Address::Up, Address::Down] {
-        let sensor = Bmp390::try_new(RefCellDevice::new(i2c), addr, delay, &config).ok();
+    let mut outcome = Outcome::Absent;
+    for (bus, i2c) in buses.all() {
+        if address.is_some_and(|address| !table.found(bus, address)) {
+            continue;
+        }
 
-        if sensor.is_some() {
-            info!("I2C: BMP390 detected");
-            return sensor;
+        match call(driver, create(i2c)).await {
+            Some(device) => {
+                self_test::record(driver.check(), Outcome::Pass);
+                return Some((bus, device));
+            }
+            None if address.is_some() => outcome = Outcome::Fail,
+            None => {}
         }
     }
+    self_test::record(driver.check(), outcome);
 
     None
 }
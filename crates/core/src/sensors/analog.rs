@@ -0,0 +1,151 @@
+//! Two generic analog channels, `adc0` and `adc1` of the sample, for
+//! capacitive soil moisture probes, a battery divider and the like.
+//!
+//! The board pins choose the GPIOs among the ADC1 ones the supply divider
+//! leaves free. A reading is the averaged millivolts at the pin, see
+//! [`adc`], turned into the probe's unit by the map of the settings when
+//! there is one.
+
+use core::cell::{Cell, RefCell};
+
+use alloc::boxed::Box;
+use defmt::{info, warn};
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use esp_hal::peripherals::Peripherals;
+
+use crate::adc;
+use crate::board::Pins;
+
+extern crate alloc;
+
+pub const CHANNELS: usize = 2;
+/// Up to about 2.5 V, where the probes powered from 3.3 V end up.
+const ATTENUATION: adc::Attenuation = adc::Attenuation::_11dB;
+
+static INPUTS: Mutex<CriticalSectionRawMutex, RefCell<[Option<Box<dyn adc::Input>>; CHANNELS]>> =
+    Mutex::new(RefCell::new([None, None]));
+static MAPS: Mutex<CriticalSectionRawMutex, Cell<[Option<Map>; CHANNELS]>> =
+    Mutex::new(Cell::new([None; CHANNELS]));
+
+/// The line through two points of the probe, from millivolts to its unit.
+#[derive(Clone, Copy)]
+struct Map {
+    from: (f32, f32),
+    to: (f32, f32),
+}
+
+impl Map {
+    /// Two `millivolts:value` points, like `2800:0 1200:100` for a soil
+    /// probe that reads 2.8 V dry and 1.2 V in water.
+    fn parse(text: &str) -> Option<Self> {
+        let mut points = text
+            .split([',', ' '])
+            .filter(|point| !point.is_empty())
+            .map(|point| {
+                let (millivolts, value) = point.split_once(':')?;
+                Some((millivolts.parse().ok()?, value.parse().ok()?))
+            });
+        let (from, to) = (points.next()??, points.next()??);
+
+        (points.next().is_none() && from.0 != to.0).then_some(Self { from, to })
+    }
+
+    fn apply(&self, millivolts: f32) -> f32 {
+        let slope = (self.to.1 - self.from.1) / (self.to.0 - self.from.0);
+
+        self.from.1 + (millivolts - self.from.0) * slope
+    }
+}
+
+/// Sets the channels of `pins` up on `config`, before [`adc::start`].
+pub fn setup(config: &mut adc::Config, pins: &Pins) {
+    for (index, pin) in pins.analog().into_iter().enumerate() {
+        let Some(pin) = pin else {
+            continue;
+        };
+
+        match input(config, pin) {
+            Some(input) => {
+                info!("Analog: adc{} on GPIO{}", index, pin);
+                INPUTS.lock(|inputs| inputs.borrow_mut()[index] = Some(input));
+            }
+            None => warn!("Analog: GPIO{} is not a free ADC1 pin", pin),
+        }
+    }
+}
+
+/// The calibration maps of the channels, readings stay in millivolts where
+/// it's empty.
+pub fn set_maps(maps: [&str; CHANNELS]) {
+    let maps = maps.map(|text| {
+        let map = Map::parse(text);
+        if map.is_none() && !text.trim().is_empty() {
+            warn!("Analog: {} is not a map", text);
+        }
+        map
+    });
+
+    MAPS.lock(|current| current.set(maps));
+}
+
+/// A reading of every channel that is set up.
+pub fn read() -> [Option<f32>; CHANNELS] {
+    let maps = MAPS.lock(|maps| maps.get());
+    let mut readings = [None; CHANNELS];
+
+    for (index, reading) in readings.iter_mut().enumerate() {
+        // Taken out for the conversions, they don't run in a critical section.
+        let Some(mut input) = INPUTS.lock(|inputs| inputs.borrow_mut()[index].take()) else {
+            continue;
+        };
+        let millivolts = input.millivolts();
+        INPUTS.lock(|inputs| inputs.borrow_mut()[index] = Some(input));
+        let Some(millivolts) = millivolts else {
+            continue;
+        };
+        let millivolts = millivolts as f32;
+
+        *reading = Some(maps[index].map_or(millivolts, |map| map.apply(millivolts)));
+    }
+
+    readings
+}
+
+/// The supply divider is on GPIO2.
+#[cfg(feature = "esp32c6")]
+fn input(config: &mut adc::Config, pin: u8) -> Option<Box<dyn adc::Input>> {
+    let peripherals = unsafe { Peripherals::steal() };
+
+    let input: Box<dyn adc::Input> = match pin {
+        0 => Box::new(config.channel(peripherals.GPIO0, ATTENUATION)),
+        1 => Box::new(config.channel(peripherals.GPIO1, ATTENUATION)),
+        3 => Box::new(config.channel(peripherals.GPIO3, ATTENUATION)),
+        4 => Box::new(config.channel(peripherals.GPIO4, ATTENUATION)),
+        5 => Box::new(config.channel(peripherals.GPIO5, ATTENUATION)),
+        6 => Box::new(config.channel(peripherals.GPIO6, ATTENUATION)),
+        _ => return None,
+    };
+
+    Some(input)
+}
+
+/// The supply divider is on GPIO4.
+#[cfg(feature = "esp32s3")]
+fn input(config: &mut adc::Config, pin: u8) -> Option<Box<dyn adc::Input>> {
+    let peripherals = unsafe { Peripherals::steal() };
+
+    let input: Box<dyn adc::Input> = match pin {
+        1 => Box::new(config.channel(peripherals.GPIO1, ATTENUATION)),
+        2 => Box::new(config.channel(peripherals.GPIO2, ATTENUATION)),
+        3 => Box::new(config.channel(peripherals.GPIO3, ATTENUATION)),
+        5 => Box::new(config.channel(peripherals.GPIO5, ATTENUATION)),
+        6 => Box::new(config.channel(peripherals.GPIO6, ATTENUATION)),
+        7 => Box::new(config.channel(peripherals.GPIO7, ATTENUATION)),
+        8 => Box::new(config.channel(peripherals.GPIO8, ATTENUATION)),
+        9 => Box::new(config.channel(peripherals.GPIO9, ATTENUATION)),
+        10 => Box::new(config.channel(peripherals.GPIO10, ATTENUATION)),
+        _ => return None,
+    };
+
+    Some(input)
+}
@@ -0,0 +1,135 @@
+//! Offsets and scale factors of the sample fields, for the sensors that read
+//! off by a known amount, like a BME680 warmed by its own heater.
+//!
+//! Set from the settings and changed with the `calibrate <field> <offset>
+//! [scale]` command, which saves them back. The correction is
+//! `value * scale + offset`, applied before the filters.
+
+use core::cell::Cell;
+use core::fmt::Write;
+
+use defmt::warn;
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use heapless::String;
+
+use super::Sample;
+use super::fields::FIELDS;
+
+/// Capacity of the calibration setting.
+pub const SETTING_LEN: usize = 128;
+
+static CORRECTIONS: Mutex<CriticalSectionRawMutex, Cell<[Option<Correction>; FIELDS.len()]>> =
+    Mutex::new(Cell::new([None; FIELDS.len()]));
+
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+struct Correction {
+    offset: f32,
+    scale: f32,
+}
+
+impl Correction {
+    const NONE: Self = Self {
+        offset: 0.0,
+        scale: 1.0,
+    };
+}
+
+/// The correction of one field, from the `calibrate` command.
+#[derive(Clone, Copy, defmt::Format)]
+pub struct Change {
+    /// In the fields of the sample.
+    field: u8,
+    correction: Correction,
+}
+
+impl Change {
+    /// The arguments of `calibrate`, `<field> <offset> [scale]`. A zero
+    /// offset without a scale drops the correction.
+    pub fn parse(args: &str) -> Option<Self> {
+        let mut args = args.split_whitespace();
+        let field = find(args.next()?)?;
+        let offset = args.next()?.parse().ok()?;
+        let scale = match args.next() {
+            Some(scale) => scale.parse().ok()?,
+            None => 1.0,
+        };
+
+        args.next().is_none().then_some(Self {
+            field: field as u8,
+            correction: Correction { offset, scale },
+        })
+    }
+}
+
+fn find(name: &str) -> Option<usize> {
+    FIELDS.iter().position(|field| field.name == name)
+}
+
+/// Sets the corrections from `list`, `<field>:<offset>[:<scale>]` separated
+/// by commas or spaces like `temp_bme680:-1.5 hum_sht40:2:1.02`.
+pub fn set(list: &str) {
+    let mut corrections = [None; FIELDS.len()];
+
+    for entry in list.split([',', ' ']).filter(|entry| !entry.is_empty()) {
+        let parsed = (|| {
+            let mut parts = entry.split(':');
+            let field = find(parts.next()?)?;
+            let offset = parts.next()?.parse().ok()?;
+            let scale = match parts.next() {
+                Some(scale) => scale.parse().ok()?,
+                None => 1.0,
+            };
+            parts
+                .next()
+                .is_none()
+                .then_some((field, Correction { offset, scale }))
+        })();
+        match parsed {
+            Some((field, correction)) => corrections[field] = Some(correction),
+            None => warn!("Sensors: {} is not a calibration", entry),
+        }
+    }
+
+    CORRECTIONS.lock(|current| current.set(corrections));
+}
+
+/// Applies `change` and returns the setting to save for it, none when the
+/// corrections no longer fit.
+pub fn change(change: Change) -> Option<String<SETTING_LEN>> {
+    let corrections = CORRECTIONS.lock(|current| {
+        let mut corrections = current.get();
+        corrections[change.field as usize] =
+            (change.correction != Correction::NONE).then_some(change.correction);
+        current.set(corrections);
+        corrections
+    });
+
+    let mut setting = String::new();
+    for (field, correction) in FIELDS.iter().zip(corrections) {
+        let Some(correction) = correction else {
+            continue;
+        };
+
+        let separator = if setting.is_empty() { "" } else { " " };
+        write!(setting, "{}{}:{}", separator, field.name, correction.offset).ok()?;
+        if correction.scale != 1.0 {
+            write!(setting, ":{}", correction.scale).ok()?;
+        }
+    }
+
+    Some(setting)
+}
+
+/// Corrects the fields of `sample` in place.
+pub fn apply(sample: &mut Sample) {
+    let corrections = CORRECTIONS.lock(|corrections| corrections.get());
+
+    for (field, correction) in FIELDS.iter().zip(corrections) {
+        let Some(correction) = correction else {
+            continue;
+        };
+        if let Some(value) = (field.get)(sample) {
+            (field.set)(sample, value * correction.scale + correction.offset);
+        }
+    }
+}
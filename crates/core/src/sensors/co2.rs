@@ -0,0 +1,262 @@
+//! The calibration of the CO2 sensors, the MH-Z19B and the SCD30 alike.
+//!
+//! The automatic baseline calibration takes the lowest reading of a window,
+//! a day for the MH-Z19B and a week for the SCD30, as the outdoor level. The
+//! settings turn it on or off for both, see [`Co2Calibration`]. A forced
+//! calibration sets the current reading to a known level instead, with the
+//! sensor in fresh air for a few minutes: the `co2calibrate [ppm]` command.
+//! The MH-Z19B only knows 400 ppm.
+//!
+//! When the automatic calibration was turned on and when each sensor was
+//! last forced are kept in the key-value storage, on `/api/co2`.
+
+use core::cell::Cell;
+
+use defmt::{info, warn};
+use embassy_futures::select::{Either, select};
+use embassy_sync::blocking_mutex::{self, raw::CriticalSectionRawMutex};
+use embassy_sync::channel::Channel;
+use embassy_sync::mutex::Mutex;
+use embassy_time::{Duration, Timer};
+use serde::Serialize;
+
+use crate::config::Co2Calibration;
+use crate::kv_storage::{self, Db, DbResult};
+use crate::{net_time, provisioning};
+
+/// Outdoor air lately, for a forced calibration without a level.
+pub const DEFAULT_PPM: u16 = 420;
+/// What the sensors take for a forced calibration.
+pub const PPM_RANGE: core::ops::RangeInclusive<u16> = 400..=2000;
+/// How often the start of the automatic calibration is checked for, it
+/// waits for the clock.
+const CHECK_PERIOD: Duration = Duration::from_secs(60);
+
+static MODE: blocking_mutex::Mutex<CriticalSectionRawMutex, Cell<Co2Calibration>> =
+    blocking_mutex::Mutex::new(Cell::new(Co2Calibration::Automatic));
+/// The forced calibrations the drivers have yet to do, per [`Sensor`].
+static PENDING: blocking_mutex::Mutex<CriticalSectionRawMutex, Cell<[Option<u16>; 2]>> =
+    blocking_mutex::Mutex::new(Cell::new([None; 2]));
+static DONE: Channel<CriticalSectionRawMutex, (Sensor, u16), 2> = Channel::new();
+/// Loaded by [`task`].
+static STATUS: Mutex<CriticalSectionRawMutex, Report> = Mutex::new(Report::new());
+
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub enum Sensor {
+    Mhz19,
+    Scd30,
+}
+
+impl Sensor {
+    const ALL: [Self; 2] = [Self::Mhz19, Self::Scd30];
+
+    /// The automatic calibration has a baseline after this.
+    fn window_secs(self) -> u32 {
+        match self {
+            Self::Mhz19 => 24 * 60 * 60,
+            Self::Scd30 => 7 * 24 * 60 * 60,
+        }
+    }
+
+    fn keys(self) -> Keys {
+        match self {
+            Self::Mhz19 => Keys {
+                automatic_since: "co2.mhz19.abc",
+                forced_at: "co2.mhz19.forced",
+                forced_ppm: "co2.mhz19.ppm",
+            },
+            Self::Scd30 => Keys {
+                automatic_since: "co2.scd30.abc",
+                forced_at: "co2.scd30.forced",
+                forced_ppm: "co2.scd30.ppm",
+            },
+        }
+    }
+}
+
+struct Keys {
+    automatic_since: &'static str,
+    forced_at: &'static str,
+    forced_ppm: &'static str,
+}
+
+/// The calibration of one sensor, times in Unix seconds or the uptime when
+/// the clock wasn't synced.
+#[derive(Clone, Copy, Default, Serialize)]
+pub struct Status {
+    /// When the automatic calibration was turned on.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub automatic_since: Option<u32>,
+    /// Whether the first window of the automatic calibration is over.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub baseline: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub forced_at: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub forced_ppm: Option<u16>,
+}
+
+#[derive(Clone, Copy, Serialize)]
+pub struct Report {
+    pub mhz19: Status,
+    pub scd30: Status,
+}
+
+impl Report {
+    const fn new() -> Self {
+        Self {
+            mhz19: Status {
+                automatic_since: None,
+                baseline: None,
+                forced_at: None,
+                forced_ppm: None,
+            },
+            scd30: Status {
+                automatic_since: None,
+                baseline: None,
+                forced_at: None,
+                forced_ppm: None,
+            },
+        }
+    }
+
+    fn status_mut(&mut self, sensor: Sensor) -> &mut Status {
+        match sensor {
+            Sensor::Mhz19 => &mut self.mhz19,
+            Sensor::Scd30 => &mut self.scd30,
+        }
+    }
+}
+
+/// Sets the automatic calibration from the settings, at startup.
+pub fn set_mode(mode: Co2Calibration) {
+    MODE.lock(|current| current.set(mode));
+}
+
+/// Whether the sensors should calibrate their baseline on their own.
+pub fn automatic() -> bool {
+    MODE.lock(|mode| mode.get()) == Co2Calibration::Automatic
+}
+
+/// Asks every CO2 sensor to take its current reading as `ppm`.
+pub fn force(ppm: u16) {
+    info!("CO2: forced calibration to {} ppm requested", ppm);
+    PENDING.lock(|pending| pending.set([Some(ppm); 2]));
+}
+
+/// The forced calibration `sensor` has to do, if there is one.
+pub fn take_forced(sensor: Sensor) -> Option<u16> {
+    PENDING.lock(|pending| {
+        let mut all = pending.get();
+        let ppm = all[sensor as usize].take();
+        pending.set(all);
+        ppm
+    })
+}
+
+/// Notes that `sensor` took its reading as `ppm`, for [`task`] to keep.
+pub fn forced(sensor: Sensor, ppm: u16) {
+    info!("CO2: {} calibrated to {} ppm", sensor, ppm);
+    if DONE.try_send((sensor, ppm)).is_err() {
+        warn!("CO2: calibration of {} not kept, queue full", sensor);
+    }
+}
+
+/// The calibration of both sensors, with the baseline worked out now.
+pub async fn status() -> Report {
+    let mut report = *STATUS.lock().await;
+    let now = net_time::TIME_STATE.lock().await.now();
+
+    for sensor in Sensor::ALL {
+        let status = report.status_mut(sensor);
+        status.baseline = status
+            .automatic_since
+            .zip(now)
+            .map(|(since, now)| now.saturating_sub(since) >= sensor.window_secs());
+    }
+
+    report
+}
+
+/// Loads the calibration history and keeps it up to date.
+#[embassy_executor::task]
+pub async fn task(db: &'static Db) -> ! {
+    if let Err(err) = load(db).await {
+        warn!("CO2: could not load the calibration history: {:?}", err);
+    }
+
+    loop {
+        match select(DONE.receive(), Timer::after(CHECK_PERIOD)).await {
+            Either::First((sensor, ppm)) => {
+                let now = net_time::TIME_STATE.lock().await.now_or_uptime();
+                {
+                    let mut status = STATUS.lock().await;
+                    let status = status.status_mut(sensor);
+                    status.forced_at = Some(now);
+                    status.forced_ppm = Some(ppm);
+                }
+                if let Err(err) = save_forced(db, sensor, ppm, now).await {
+                    warn!("CO2: could not save the calibration: {:?}", err);
+                }
+                provisioning::release(provisioning::Channel::Mqtt);
+            }
+            Either::Second(()) => {}
+        }
+
+        if let Err(err) = update_automatic(db).await {
+            warn!("CO2: could not save the automatic calibration: {:?}", err);
+        }
+    }
+}
+
+async fn load(db: &'static Db) -> DbResult<()> {
+    let mut tx = db.read_transaction().await;
+    let mut status = STATUS.lock().await;
+
+    for sensor in Sensor::ALL {
+        let keys = sensor.keys();
+        *status.status_mut(sensor) = Status {
+            automatic_since: kv_storage::read_u32(&mut tx, keys.automatic_since)
+                .await?
+                .filter(|since| *since != 0),
+            baseline: None,
+            forced_at: kv_storage::read_u32(&mut tx, keys.forced_at).await?,
+            forced_ppm: kv_storage::read_u16(&mut tx, keys.forced_ppm).await?,
+        };
+    }
+
+    Ok(())
+}
+
+async fn save_forced(db: &'static Db, sensor: Sensor, ppm: u16, now: u32) -> DbResult<()> {
+    let keys = sensor.keys();
+    let mut tx = db.write_transaction().await;
+    kv_storage::write_u32(&mut tx, keys.forced_at, now).await?;
+    kv_storage::write_u16(&mut tx, keys.forced_ppm, ppm).await?;
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Stamps the start of the automatic calibration once the clock is synced,
+/// and forgets it when the calibration is off. Stored as 0 for none.
+async fn update_automatic(db: &'static Db) -> DbResult<()> {
+    let automatic = automatic();
+    let now = net_time::TIME_STATE.lock().await.now();
+
+    for sensor in Sensor::ALL {
+        let since = STATUS.lock().await.status_mut(sensor).automatic_since;
+        let value = match (automatic, since, now) {
+            (true, None, Some(now)) => now,
+            (false, Some(_), _) => 0,
+            _ => continue,
+        };
+
+        let mut tx = db.write_transaction().await;
+        kv_storage::write_u32(&mut tx, sensor.keys().automatic_since, value).await?;
+        tx.commit().await?;
+        STATUS.lock().await.status_mut(sensor).automatic_since = (value != 0).then_some(value);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,82 @@
+//! Dew point, absolute humidity and heat index, worked out on the node so
+//! the dashboards and the automations don't each carry the formulas.
+//!
+//! They come from the temperature and the humidity of one sensor, the first
+//! of the SHT40, BME280 and BME680 that has both, after the calibration and
+//! the filters. Never a temperature of one sensor with the humidity of
+//! another, their self-heating differs.
+
+use super::Sample;
+
+/// Magnus coefficients over water, within 0.35 °C from -45 to 60 °C.
+const MAGNUS_A: f32 = 17.62;
+const MAGNUS_B: f32 = 243.12;
+/// Saturation vapour pressure at 0 °C, in hPa.
+const MAGNUS_HPA: f32 = 6.112;
+/// Grams per cubic metre for a vapour pressure in hPa over the kelvins, the
+/// gas constant of water vapour turned around.
+const VAPOUR_DENSITY: f32 = 216.7;
+const ZERO_CELSIUS: f32 = 273.15;
+
+/// Sets `dew_point`, `abs_humidity` and `heat_index` of `sample`, none of
+/// them without a pair of readings.
+pub fn apply(sample: &mut Sample) {
+    let pair = [
+        (sample.temp_sht40, sample.hum_sht40),
+        (sample.temp_bme280, sample.hum_bme280),
+        (sample.temp_bme680, sample.hum_bme680),
+    ]
+    .into_iter()
+    .find_map(|(temperature, humidity)| temperature.zip(humidity));
+    let Some((temperature, humidity)) = pair else {
+        return;
+    };
+    // The logarithm of a dry 0 % has no dew point.
+    let humidity = humidity.clamp(1.0, 100.0);
+
+    sample.dew_point = Some(dew_point(temperature, humidity));
+    sample.abs_humidity = Some(absolute_humidity(temperature, humidity));
+    sample.heat_index = Some(heat_index(temperature, humidity));
+}
+
+fn dew_point(temperature: f32, humidity: f32) -> f32 {
+    let gamma = libm::logf(humidity / 100.0) + MAGNUS_A * temperature / (MAGNUS_B + temperature);
+
+    MAGNUS_B * gamma / (MAGNUS_A - gamma)
+}
+
+/// In g/m³.
+fn absolute_humidity(temperature: f32, humidity: f32) -> f32 {
+    let saturation = MAGNUS_HPA * libm::expf(MAGNUS_A * temperature / (MAGNUS_B + temperature));
+
+    VAPOUR_DENSITY * saturation * humidity / 100.0 / (temperature + ZERO_CELSIUS)
+}
+
+/// The apparent temperature of the US National Weather Service: Steadman's
+/// simple formula where it stays below 80 °F, the Rothfusz regression with
+/// its adjustments above. Works in °F, like the coefficients.
+fn heat_index(temperature: f32, humidity: f32) -> f32 {
+    let t = temperature * 1.8 + 32.0;
+    let rh = humidity;
+
+    let simple = 0.5 * (t + 61.0 + (t - 68.0) * 1.2 + rh * 0.094);
+    let index = if (simple + t) / 2.0 < 80.0 {
+        simple
+    } else {
+        let mut index = -42.379 + 2.049_015_2 * t + 10.143_331 * rh
+            - 0.224_755_4 * t * rh
+            - 0.006_837_83 * t * t
+            - 0.054_817_17 * rh * rh
+            + 0.001_228_74 * t * t * rh
+            + 0.000_852_82 * t * rh * rh
+            - 0.000_001_99 * t * t * rh * rh;
+        if rh < 13.0 && (80.0..=112.0).contains(&t) {
+            index -= (13.0 - rh) / 4.0 * libm::sqrtf((17.0 - libm::fabsf(t - 95.0)) / 17.0);
+        } else if rh > 85.0 && (80.0..=87.0).contains(&t) {
+            index += (rh - 85.0) / 10.0 * (87.0 - t) / 5.0;
+        }
+        index
+    };
+
+    (index - 32.0) / 1.8
+}
@@ -0,0 +1,1370 @@
+//! The I2C sensors behind [`SensorDriver`], and the [`REGISTRY`] the sensors
+//! task sets them up from.
+//!
+//! A new sensor needs a [`Driver`] variant, its self-test check, an impl and
+//! a line in the registry, the polling loop picks it up from there.
+//!
+//! The drivers talk `embedded-hal-async` through an [`I2cDevice`], so a
+//! transaction waits for the bus and the sensor without holding up the
+//! executor. The BME680 crate only has the blocking traits, it goes through
+//! a [`BlockingDevice`] instead.
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use core::future::Future;
+use core::pin::Pin;
+
+use bme680::{Bme680, I2CAddress, IIRFilterSize, PowerMode, SettingsBuilder};
+use defmt::{error, info, warn};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_hal::i2c::{ErrorKind, ErrorType, Operation};
+use embedded_hal_async::i2c::I2c;
+use esp_hal::{delay::Delay, i2c};
+use uom::si::{pressure::hectopascal, thermodynamic_temperature::degree_celsius};
+
+use super::{Driver, I2cBus, I2cDevice, Sample, co2};
+
+/// A call into a [`SensorDriver`], it waits for the bus and the sensor.
+pub type Pending<'a, T> = Pin<Box<dyn Future<Output = T> + 'a>>;
+
+/// A sensor the sensors task polls.
+pub trait SensorDriver {
+    fn driver(&self) -> Driver;
+
+    /// Starts a conversion the sensor takes a while for and returns how long
+    /// [`Self::read`] has to wait for it. The task waits for the longest one
+    /// and the executor serves the rest meanwhile, rather than the driver
+    /// blocking it. A failure shows in the read.
+    fn start(&mut self) -> Pending<'_, Duration> {
+        Box::pin(async { Duration::from_ticks(0) })
+    }
+
+    /// Fills in the fields of `sample` that belong to the sensor. None when
+    /// the reading failed, the fields are left alone then.
+    fn read<'a>(&'a mut self, sample: &'a mut Sample) -> Pending<'a, Option<()>>;
+
+    /// The gas heater of the sensor, if it has one, should be `on`.
+    fn set_heater(&mut self, _on: bool) -> Pending<'_, ()> {
+        Box::pin(async {})
+    }
+}
+
+pub type Create = fn(&'static I2cBus) -> Pending<'static, Option<Box<dyn SensorDriver>>>;
+
+/// The [`Create`] of a driver set up by `new`.
+fn create<T: SensorDriver + 'static>(
+    new: impl Future<Output = Option<T>> + 'static,
+) -> Pending<'static, Option<Box<dyn SensorDriver>>> {
+    Box::pin(async move { Some(Box::new(new.await?) as Box<dyn SensorDriver>) })
+}
+
+pub struct Registration {
+    pub driver: Driver,
+    /// Something has to answer there before the driver is set up. Drivers
+    /// that look for their sensor at several addresses go without.
+    pub address: Option<u8>,
+    pub create: Create,
+}
+
+/// In the order the sensors are set up and read.
+pub const REGISTRY: [Registration; 11] = [
+    Registration {
+        driver: Driver::Veml7700,
+        address: Some(0x10),
+        create: |i2c| create(Veml7700::new(i2c)),
+    },
+    Registration {
+        driver: Driver::Sht40,
+        address: None,
+        create: |i2c| create(Sht40::new(i2c)),
+    },
+    Registration {
+        driver: Driver::Bme680,
+        address: Some(0x76),
+        create: |i2c| create(Bme680Sensor::new(i2c)),
+    },
+    Registration {
+        driver: Driver::Bme280,
+        address: None,
+        create: |i2c| create(Bme280::new(i2c)),
+    },
+    Registration {
+        driver: Driver::Bh1750,
+        address: Some(0x23),
+        create: |i2c| create(Bh1750::new(i2c)),
+    },
+    Registration {
+        driver: Driver::Tsl2591,
+        address: Some(0x29),
+        create: |i2c| create(Tsl2591::new(i2c)),
+    },
+    Registration {
+        driver: Driver::Bmp390,
+        address: None,
+        create: |i2c| create(Bmp390::new(i2c)),
+    },
+    Registration {
+        driver: Driver::Max17048,
+        address: None,
+        create: |i2c| create(Max17048::new(i2c)),
+    },
+    Registration {
+        driver: Driver::Ina219,
+        address: None,
+        create: |i2c| create(Ina219::new(i2c)),
+    },
+    // After the pressure sensors, it's compensated with their pressure.
+    Registration {
+        driver: Driver::Scd30,
+        address: Some(0x61),
+        create: |i2c| create(Scd30::new(i2c)),
+    },
+    // Last, it's compensated with the humidity and temperature of the others.
+    Registration {
+        driver: Driver::Sgp40,
+        address: None,
+        create: |i2c| create(Sgp40::new(i2c)),
+    },
+];
+
+/// A blocking share of an [`I2cBus`], for the drivers that only come with
+/// `embedded-hal`. A transaction fails rather than wait when another task
+/// has the bus, so the driver waits for it with [`BlockingDevice::ready`]
+/// first. The executor only switches tasks at an await, nothing gets in
+/// between that and the blocking calls after it.
+struct BlockingDevice(&'static I2cBus);
+
+impl BlockingDevice {
+    /// Waits until no other task has `bus`.
+    async fn ready(bus: &I2cBus) {
+        drop(bus.lock().await);
+    }
+}
+
+/// Why a [`BlockingDevice`] transaction failed.
+#[derive(Debug)]
+enum BusError {
+    /// Another task had the bus.
+    Busy,
+    I2c(i2c::master::Error),
+}
+
+impl embedded_hal::i2c::Error for BusError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Self::Busy => ErrorKind::Other,
+            Self::I2c(err) => embedded_hal::i2c::Error::kind(err),
+        }
+    }
+}
+
+impl ErrorType for BlockingDevice {
+    type Error = BusError;
+}
+
+impl embedded_hal::i2c::I2c for BlockingDevice {
+    fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        let mut bus = self.0.try_lock().map_err(|_| BusError::Busy)?;
+
+        embedded_hal::i2c::I2c::transaction(&mut *bus, address, operations).map_err(BusError::I2c)
+    }
+}
+
+struct Veml7700(veml7700::Veml7700<I2cDevice>);
+
+impl Veml7700 {
+    async fn new(i2c: &'static I2cBus) -> Option<Self> {
+        let mut veml = veml7700::Veml7700::new(I2cDevice::new(i2c));
+
+        veml.set_integration_time(veml7700::IntegrationTime::_100ms)
+            .await
+            .ok()?;
+        veml.set_gain(veml7700::Gain::OneQuarter).await.ok()?;
+
+        if let Err(_err) = veml.enable().await {
+            warn!("Could not enable VEML7700");
+            None
+        } else {
+            Some(Self(veml))
+        }
+    }
+}
+
+impl SensorDriver for Veml7700 {
+    fn driver(&self) -> Driver {
+        Driver::Veml7700
+    }
+
+    fn read<'a>(&'a mut self, sample: &'a mut Sample) -> Pending<'a, Option<()>> {
+        Box::pin(async move {
+            let lux = self
+                .0
+                .read_lux()
+                .await
+                .inspect_err(|_| warn!("Could not read value out of VEML7700"))
+                .ok()?;
+            sample.lux_veml7700 = Some(lux);
+
+            Some(())
+        })
+    }
+}
+
+struct Bme680Sensor {
+    bus: &'static I2cBus,
+    bme: Bme680<BlockingDevice, Delay>,
+    delayer: Delay,
+    heater: bool,
+}
+
+impl Bme680Sensor {
+    /// A forced measurement with [`bme680_settings`]: about 20 ms for the
+    /// oversampled temperature, pressure and humidity, and the 150 ms of the
+    /// gas heater on top.
+    const MEASUREMENT: Duration = Duration::from_millis(175);
+    const MEASUREMENT_WITHOUT_GAS: Duration = Duration::from_millis(25);
+
+    async fn new(i2c: &'static I2cBus) -> Option<Self> {
+        info!("Setting up BME680");
+        let mut delayer = Delay::new();
+        BlockingDevice::ready(i2c).await;
+        let mut bme = Bme680::init(BlockingDevice(i2c), &mut delayer, I2CAddress::Primary)
+            .map_err(bme680_error)
+            .ok()?;
+
+        info!("Setting up settings for BME680");
+        bme.set_sensor_settings(&mut delayer, bme680_settings(true))
+            .ok()?;
+
+        info!("Setting forced power modes");
+        bme.set_sensor_mode(&mut delayer, PowerMode::ForcedMode)
+            .ok()?;
+
+        Some(Self {
+            bus: i2c,
+            bme,
+            delayer,
+            heater: true,
+        })
+    }
+}
+
+impl SensorDriver for Bme680Sensor {
+    fn driver(&self) -> Driver {
+        Driver::Bme680
+    }
+
+    fn start(&mut self) -> Pending<'_, Duration> {
+        Box::pin(async move {
+            BlockingDevice::ready(self.bus).await;
+            self.bme
+                .set_sensor_mode(&mut self.delayer, PowerMode::ForcedMode)
+                .inspect_err(|_| warn!("Could not start a BME680 measurement"))
+                .ok();
+
+            if self.heater {
+                Self::MEASUREMENT
+            } else {
+                Self::MEASUREMENT_WITHOUT_GAS
+            }
+        })
+    }
+
+    fn read<'a>(&'a mut self, sample: &'a mut Sample) -> Pending<'a, Option<()>> {
+        Box::pin(async move {
+            BlockingDevice::ready(self.bus).await;
+            let (data, _state) = self.bme.get_sensor_data(&mut self.delayer).ok()?;
+
+            sample.hum_bme680 = Some(data.humidity_percent());
+            sample.press_bme680 = Some(data.pressure_hpa());
+            sample.temp_bme680 = Some(data.temperature_celsius());
+            sample.gas_bme680 =
+                (data.gas_valid() && data.heat_stable()).then(|| data.gas_resistance_ohm());
+
+            Some(())
+        })
+    }
+
+    fn set_heater(&mut self, on: bool) -> Pending<'_, ()> {
+        Box::pin(async move {
+            info!("BME680: gas heater {}", if on { "on" } else { "off" });
+            BlockingDevice::ready(self.bus).await;
+            self.heater = self
+                .bme
+                .set_sensor_settings(&mut self.delayer, bme680_settings(on))
+                .inspect_err(|_| warn!("Could not switch the BME680 gas heater"))
+                .map_or(self.heater, |_| on);
+        })
+    }
+}
+
+fn bme680_settings(run_gas: bool) -> bme680::Settings {
+    SettingsBuilder::new()
+        .with_temperature_oversampling(bme680::OversamplingSetting::OS2x)
+        .with_pressure_oversampling(bme680::OversamplingSetting::OS4x)
+        .with_humidity_oversampling(bme680::OversamplingSetting::OS2x)
+        .with_temperature_filter(IIRFilterSize::Size3)
+        .with_gas_measurement(core::time::Duration::from_millis(150), 320, 25)
+        .with_run_gas(run_gas)
+        .build()
+}
+
+fn bme680_error(err: bme680::Error<BusError>) {
+    match err {
+        bme680::Error::I2C(BusError::Busy) => error!("BME init error: I2C bus busy"),
+        bme680::Error::I2C(BusError::I2c(err)) => {
+            error!("BME init error: I2C");
+            match err {
+                i2c::master::Error::FifoExceeded => error!("  I2C error: FifoExceeded"),
+                i2c::master::Error::AcknowledgeCheckFailed(err) => {
+                    error!("  I2C error: AcknowledgeCheckFailed");
+                    match err {
+                        i2c::master::AcknowledgeCheckFailedReason::Address => error!("    Address"),
+                        i2c::master::AcknowledgeCheckFailedReason::Data => error!("    Data"),
+                        i2c::master::AcknowledgeCheckFailedReason::Unknown => error!("    Unknown"),
+                        _ => error!("    ????"),
+                    }
+                }
+                i2c::master::Error::Timeout => error!("  I2C error: Timeout"),
+                i2c::master::Error::ArbitrationLost => error!("  I2C error: ArbitrationLost"),
+                i2c::master::Error::ExecutionIncomplete => {
+                    error!("  I2C error: ExecutionIncomplete")
+                }
+                i2c::master::Error::CommandNumberExceeded => {
+                    error!("  I2C error: CommandNumberExceeded")
+                }
+                i2c::master::Error::ZeroLengthInvalid => error!("  I2C error: ZeroLengthInvalid"),
+                i2c::master::Error::AddressInvalid(i2c_address) => {
+                    error!("  I2C error: AddressInvalid: {}", i2c_address)
+                }
+                _ => todo!(),
+            };
+        }
+        bme680::Error::Delay => error!("BME init error: Delay"),
+        bme680::Error::DeviceNotFound => error!("BME init error: DeviceNotFound"),
+        bme680::Error::InvalidLength => error!("BME init error: InvalidLength"),
+        bme680::Error::DefinePwrMode => error!("BME init error: DefinePwrMode"),
+        bme680::Error::NoNewData => error!("BME init error: NoNewData"),
+        bme680::Error::BoundaryCheckFailure(_) => error!("BME init error: BoundaryCheckFailure"),
+    }
+}
+
+/// A BH1750 in its one time, high resolution 2 mode: half a lux per count.
+struct Bh1750(I2cDevice);
+
+impl Bh1750 {
+    const ADDRESS: u8 = 0x23;
+    const POWER_ON: u8 = 0x01;
+    const ONE_TIME_HIGH_2: u8 = 0x21;
+    /// The longest the datasheet gives for a high resolution measurement.
+    const MEASUREMENT: Duration = Duration::from_millis(180);
+
+    async fn new(i2c: &'static I2cBus) -> Option<Self> {
+        let mut bh1750 = Self(I2cDevice::new(i2c));
+        bh1750
+            .0
+            .write(Self::ADDRESS, &[Self::POWER_ON])
+            .await
+            .ok()?;
+
+        Some(bh1750)
+    }
+}
+
+impl SensorDriver for Bh1750 {
+    fn driver(&self) -> Driver {
+        Driver::Bh1750
+    }
+
+    fn start(&mut self) -> Pending<'_, Duration> {
+        Box::pin(async move {
+            self.0
+                .write(Self::ADDRESS, &[Self::ONE_TIME_HIGH_2])
+                .await
+                .inspect_err(|_| warn!("Could not start a BH1750 measurement"))
+                .ok();
+
+            Self::MEASUREMENT
+        })
+    }
+
+    fn read<'a>(&'a mut self, sample: &'a mut Sample) -> Pending<'a, Option<()>> {
+        Box::pin(async move {
+            let mut counts = [0u8; 2];
+            self.0.read(Self::ADDRESS, &mut counts).await.ok()?;
+            // 1.2 counts per lux, twice that in the mode 2.
+            sample.lux_bh1750 = Some(u16::from_be_bytes(counts) as f32 / 2.4);
+
+            Some(())
+        })
+    }
+}
+
+/// A Sensirion SHT40, SHT41 or SHT45, measured with the high precision.
+struct Sht40 {
+    i2c: I2cDevice,
+    address: u8,
+}
+
+impl Sht40 {
+    const ADDRESSES: [u8; 3] = [0x44, 0x45, 0x46];
+    const SERIAL_NUMBER: u8 = 0x89;
+    const MEASURE_HIGH: u8 = 0xFD;
+    /// The datasheet gives 8.3 ms at most for the high precision.
+    const MEASUREMENT: Duration = Duration::from_millis(10);
+
+    async fn new(i2c: &'static I2cBus) -> Option<Self> {
+        let mut sht = Self {
+            i2c: I2cDevice::new(i2c),
+            address: 0,
+        };
+
+        for address in Self::ADDRESSES {
+            sht.address = address;
+            let mut serial = [0u8; 6];
+            if sht
+                .command(Self::SERIAL_NUMBER, 1, &mut serial)
+                .await
+                .is_some()
+            {
+                info!("I2C: SHT40 detected at 0x{:X}", address);
+                return Some(sht);
+            }
+        }
+
+        None
+    }
+
+    /// Sends `command` and, after `millis`, reads `answer`, words of two
+    /// bytes each followed by its CRC.
+    async fn command(&mut self, command: u8, millis: u64, answer: &mut [u8]) -> Option<()> {
+        self.i2c.write(self.address, &[command]).await.ok()?;
+        Timer::after_millis(millis).await;
+        self.i2c.read(self.address, answer).await.ok()?;
+
+        sensirion_words(answer).then_some(())
+    }
+}
+
+impl SensorDriver for Sht40 {
+    fn driver(&self) -> Driver {
+        Driver::Sht40
+    }
+
+    fn start(&mut self) -> Pending<'_, Duration> {
+        Box::pin(async move {
+            self.i2c
+                .write(self.address, &[Self::MEASURE_HIGH])
+                .await
+                .inspect_err(|_| warn!("Could not start an SHT40 measurement"))
+                .ok();
+
+            Self::MEASUREMENT
+        })
+    }
+
+    fn read<'a>(&'a mut self, sample: &'a mut Sample) -> Pending<'a, Option<()>> {
+        Box::pin(async move {
+            let mut data = [0u8; 6];
+            let read = self.i2c.read(self.address, &mut data).await;
+            if read.is_err() || !sensirion_words(&data) {
+                warn!("Could not measure with SHT40");
+                return None;
+            }
+
+            let temperature = u16::from_be_bytes([data[0], data[1]]) as f32;
+            let humidity = u16::from_be_bytes([data[3], data[4]]) as f32;
+            sample.temp_sht40 = Some(-45.0 + 175.0 * temperature / 65535.0);
+            sample.hum_sht40 = Some(-6.0 + 125.0 * humidity / 65535.0);
+
+            Some(())
+        })
+    }
+}
+
+struct Bmp390(bmp390::Bmp390<I2cDevice>);
+
+impl Bmp390 {
+    async fn new(i2c: &'static I2cBus) -> Option<Self> {
+        use bmp390::{Address, Configuration};
+
+        let config = Configuration::default();
+
+        for addr in [Address::Up, Address::Down] {
+            let sensor =
+                bmp390::Bmp390::try_new(I2cDevice::new(i2c), addr, embassy_time::Delay, &config)
+                    .await
+                    .ok();
+
+            if let Some(sensor) = sensor {
+                info!("I2C: BMP390 detected");
+                return Some(Self(sensor));
+            }
+        }
+
+        None
+    }
+}
+
+impl SensorDriver for Bmp390 {
+    fn driver(&self) -> Driver {
+        Driver::Bmp390
+    }
+
+    fn read<'a>(&'a mut self, sample: &'a mut Sample) -> Pending<'a, Option<()>> {
+        Box::pin(async move {
+            let data = self.0.measure().await.ok()?;
+
+            sample.temp_bmp390 = Some(data.temperature.get::<degree_celsius>());
+            sample.press_bmp390 = Some(data.pressure.get::<hectopascal>());
+
+            Some(())
+        })
+    }
+}
+
+struct Sgp40 {
+    i2c: I2cDevice,
+    heater: bool,
+    voc: VocIndex,
+}
+
+impl Sgp40 {
+    const ADDRESS: u8 = 0x59;
+    const GET_SERIAL: [u8; 2] = [0x36, 0x82];
+    const MEASURE_RAW: [u8; 2] = [0x26, 0x0F];
+    const HEATER_OFF: [u8; 2] = [0x36, 0x15];
+
+    async fn new(i2c: &'static I2cBus) -> Option<Self> {
+        let mut sgp = Self {
+            i2c: I2cDevice::new(i2c),
+            heater: true,
+            voc: VocIndex::new(),
+        };
+
+        let mut serial = [0u8; 9];
+        sgp.command(&Self::GET_SERIAL, 1, &mut serial).await?;
+        info!("I2C: SGP40 detected");
+
+        Some(sgp)
+    }
+
+    /// Sends `command` and, after `millis`, reads `answer`: words of two
+    /// bytes, each followed by its CRC.
+    async fn command(&mut self, command: &[u8], millis: u64, answer: &mut [u8]) -> Option<()> {
+        self.i2c.write(Self::ADDRESS, command).await.ok()?;
+        if answer.is_empty() {
+            return Some(());
+        }
+
+        Timer::after_millis(millis).await;
+        self.i2c.read(Self::ADDRESS, answer).await.ok()?;
+
+        sensirion_words(answer).then_some(())
+    }
+}
+
+impl SensorDriver for Sgp40 {
+    fn driver(&self) -> Driver {
+        Driver::Sgp40
+    }
+
+    fn read<'a>(&'a mut self, sample: &'a mut Sample) -> Pending<'a, Option<()>> {
+        Box::pin(async move {
+            if !self.heater {
+                return Some(());
+            }
+
+            // The datasheet defaults, 50 % and 25 °C, without the other sensors.
+            let humidity = sample.humidity().unwrap_or(50.0).clamp(0.0, 100.0);
+            let temperature = sample.temperature().unwrap_or(25.0).clamp(-45.0, 130.0);
+            let humidity = ((humidity * 65535.0 / 100.0) as u16).to_be_bytes();
+            let temperature = (((temperature + 45.0) * 65535.0 / 175.0) as u16).to_be_bytes();
+
+            let mut command = [0u8; 8];
+            command[..2].copy_from_slice(&Self::MEASURE_RAW);
+            command[2..4].copy_from_slice(&humidity);
+            command[4] = sensirion_crc(&humidity);
+            command[5..7].copy_from_slice(&temperature);
+            command[7] = sensirion_crc(&temperature);
+
+            let mut answer = [0u8; 3];
+            if self.command(&command, 30, &mut answer).await.is_none() {
+                warn!("Could not measure with SGP40");
+                return None;
+            }
+
+            sample.voc_index = self.voc.update(u16::from_be_bytes([answer[0], answer[1]]));
+
+            Some(())
+        })
+    }
+
+    fn set_heater(&mut self, on: bool) -> Pending<'_, ()> {
+        Box::pin(async move {
+            // A measurement turns it back on.
+            if !on && self.heater {
+                info!("SGP40: heater off");
+                self.command(&Self::HEATER_OFF, 0, &mut []).await;
+            }
+            self.heater = on;
+        })
+    }
+}
+
+/// A Sensirion SCD30, CO2 by NDIR. It measures on its own every two seconds
+/// and is told the ambient pressure the sensors before it in the
+/// [`REGISTRY`] read, which the CO2 reading depends on.
+struct Scd30 {
+    i2c: I2cDevice,
+    /// The pressure it was last started with, in mbar, 0 for none.
+    pressure_mbar: u16,
+}
+
+impl Scd30 {
+    const ADDRESS: u8 = 0x61;
+    const START: [u8; 2] = [0x00, 0x10];
+    const AUTOMATIC_CALIBRATION: [u8; 2] = [0x53, 0x06];
+    const FORCED_CALIBRATION: [u8; 2] = [0x52, 0x04];
+    const DATA_READY: [u8; 2] = [0x02, 0x02];
+    const READ_MEASUREMENT: [u8; 2] = [0x03, 0x00];
+    const FIRMWARE_VERSION: [u8; 2] = [0xD1, 0x00];
+    /// The range the compensation takes, in mbar.
+    const PRESSURE_RANGE: core::ops::RangeInclusive<u16> = 700..=1400;
+
+    async fn new(i2c: &'static I2cBus) -> Option<Self> {
+        let mut scd = Self {
+            i2c: I2cDevice::new(i2c),
+            pressure_mbar: 0,
+        };
+
+        let mut version = [0u8; 3];
+        scd.command(&Self::FIRMWARE_VERSION, &mut version).await?;
+        info!(
+            "I2C: SCD30 detected, firmware {}.{}",
+            version[0], version[1]
+        );
+        scd.start(0).await?;
+        if scd
+            .write(&Self::AUTOMATIC_CALIBRATION, co2::automatic() as u16)
+            .await
+            .is_none()
+        {
+            warn!("SCD30: could not set the automatic calibration");
+        }
+
+        Some(scd)
+    }
+
+    /// Starts the continuous measurement, compensated for `pressure_mbar`
+    /// unless it's 0. Also how a new pressure is passed on.
+    async fn start(&mut self, pressure_mbar: u16) -> Option<()> {
+        self.write(&Self::START, pressure_mbar).await?;
+        self.pressure_mbar = pressure_mbar;
+
+        Some(())
+    }
+
+    /// Sends `command` with its argument word and the CRC of that.
+    async fn write(&mut self, command: &[u8; 2], argument: u16) -> Option<()> {
+        let argument = argument.to_be_bytes();
+        let mut bytes = [0u8; 5];
+        bytes[..2].copy_from_slice(command);
+        bytes[2..4].copy_from_slice(&argument);
+        bytes[4] = sensirion_crc(&argument);
+
+        self.i2c.write(Self::ADDRESS, &bytes).await.ok()
+    }
+
+    /// Sends `command` and reads `answer`, words of two bytes each followed
+    /// by its CRC.
+    async fn command(&mut self, command: &[u8], answer: &mut [u8]) -> Option<()> {
+        self.i2c.write(Self::ADDRESS, command).await.ok()?;
+        // It wants a pause between the command and the read.
+        Timer::after_millis(3).await;
+        self.i2c.read(Self::ADDRESS, answer).await.ok()?;
+
+        sensirion_words(answer).then_some(())
+    }
+}
+
+impl SensorDriver for Scd30 {
+    fn driver(&self) -> Driver {
+        Driver::Scd30
+    }
+
+    fn read<'a>(&'a mut self, sample: &'a mut Sample) -> Pending<'a, Option<()>> {
+        Box::pin(async move {
+            let pressure_mbar = sample
+                .pressure()
+                .map(|hpa| libm::roundf(hpa) as u16)
+                .filter(|mbar| Self::PRESSURE_RANGE.contains(mbar))
+                .unwrap_or(0);
+            if pressure_mbar != self.pressure_mbar && self.start(pressure_mbar).await.is_none() {
+                warn!(
+                    "SCD30: could not set the pressure to {} mbar",
+                    pressure_mbar
+                );
+            }
+            if let Some(ppm) = co2::take_forced(co2::Sensor::Scd30) {
+                match self.write(&Self::FORCED_CALIBRATION, ppm).await {
+                    Some(()) => co2::forced(co2::Sensor::Scd30, ppm),
+                    None => warn!("SCD30: could not calibrate to {} ppm", ppm),
+                }
+            }
+
+            let mut ready = [0u8; 3];
+            if self.command(&Self::DATA_READY, &mut ready).await.is_none() {
+                warn!("Could not read SCD30");
+                return None;
+            }
+            // Nothing new since the last sample.
+            if u16::from_be_bytes([ready[0], ready[1]]) != 1 {
+                return Some(());
+            }
+
+            // CO2, temperature and humidity, each a float over two words.
+            let mut data = [0u8; 18];
+            if self
+                .command(&Self::READ_MEASUREMENT, &mut data)
+                .await
+                .is_none()
+            {
+                warn!("Could not read SCD30");
+                return None;
+            }
+            let co2 = f32::from_be_bytes([data[0], data[1], data[3], data[4]]);
+            sample.co2_scd30 = Some(libm::roundf(co2.max(0.0)) as u16);
+
+            Some(())
+        })
+    }
+}
+
+/// A MAX17048 fuel gauge on the battery, it works the charge out from the
+/// voltage alone.
+struct Max17048(I2cDevice);
+
+impl Max17048 {
+    const ADDRESS: u8 = 0x36;
+    const VCELL: u8 = 0x02;
+    const SOC: u8 = 0x04;
+    const VERSION: u8 = 0x08;
+
+    async fn new(i2c: &'static I2cBus) -> Option<Self> {
+        let mut gauge = Self(I2cDevice::new(i2c));
+
+        // 0x0011 or 0x0012, depending on the silicon.
+        if gauge.register(Self::VERSION).await? & 0xFFF0 != 0x0010 {
+            return None;
+        }
+        info!("I2C: MAX17048 detected");
+
+        Some(gauge)
+    }
+
+    async fn register(&mut self, register: u8) -> Option<u16> {
+        let mut word = [0u8; 2];
+        self.0
+            .write_read(Self::ADDRESS, &[register], &mut word)
+            .await
+            .ok()?;
+
+        Some(u16::from_be_bytes(word))
+    }
+}
+
+impl SensorDriver for Max17048 {
+    fn driver(&self) -> Driver {
+        Driver::Max17048
+    }
+
+    fn read<'a>(&'a mut self, sample: &'a mut Sample) -> Pending<'a, Option<()>> {
+        Box::pin(async move {
+            let vcell = self.register(Self::VCELL).await;
+            let soc = self.register(Self::SOC).await;
+            let (Some(vcell), Some(soc)) = (vcell, soc) else {
+                warn!("Could not read MAX17048");
+                return None;
+            };
+
+            // 78.125 µV per bit.
+            sample.battery_mv = Some((vcell as u32 * 78_125 / 1_000_000) as u16);
+            // 1/256 % per bit, a full battery reads a little over 100 %.
+            sample.battery_percent = Some((soc as f32 / 256.0).min(100.0));
+
+            Some(())
+        })
+    }
+}
+
+/// An INA219 on the battery lead, with the 0.1 Ω shunt of the usual modules.
+/// The current is positive from VIN+ to VIN-.
+struct Ina219(I2cDevice);
+
+impl Ina219 {
+    const ADDRESS: u8 = 0x40;
+    const CONFIG: u8 = 0x00;
+    const SHUNT_VOLTAGE: u8 = 0x01;
+    const BUS_VOLTAGE: u8 = 0x02;
+    const RESET: u16 = 0x8000;
+    /// The configuration after a reset: 32 V, ±320 mV on the shunt, 12 bit
+    /// conversions, continuously.
+    const DEFAULT_CONFIG: u16 = 0x399F;
+    const SHUNT_MILLIOHMS: f32 = 100.0;
+
+    async fn new(i2c: &'static I2cBus) -> Option<Self> {
+        let mut monitor = Self(I2cDevice::new(i2c));
+
+        let [high, low] = Self::RESET.to_be_bytes();
+        monitor
+            .0
+            .write(Self::ADDRESS, &[Self::CONFIG, high, low])
+            .await
+            .ok()?;
+        if monitor.register(Self::CONFIG).await? != Self::DEFAULT_CONFIG {
+            return None;
+        }
+        info!("I2C: INA219 detected");
+
+        Some(monitor)
+    }
+
+    async fn register(&mut self, register: u8) -> Option<u16> {
+        let mut word = [0u8; 2];
+        self.0
+            .write_read(Self::ADDRESS, &[register], &mut word)
+            .await
+            .ok()?;
+
+        Some(u16::from_be_bytes(word))
+    }
+}
+
+impl SensorDriver for Ina219 {
+    fn driver(&self) -> Driver {
+        Driver::Ina219
+    }
+
+    fn read<'a>(&'a mut self, sample: &'a mut Sample) -> Pending<'a, Option<()>> {
+        Box::pin(async move {
+            let shunt = self.register(Self::SHUNT_VOLTAGE).await;
+            let bus = self.register(Self::BUS_VOLTAGE).await;
+            let (Some(shunt), Some(bus)) = (shunt, bus) else {
+                warn!("Could not read INA219");
+                return None;
+            };
+
+            // 10 µV per bit on the shunt, µV over mΩ are mA.
+            sample.battery_ma = Some(shunt as i16 as f32 * 10.0 / Self::SHUNT_MILLIOHMS);
+            // Bits 15 to 3, 4 mV each.
+            sample.battery_mv = Some((bus >> 3) * 4);
+
+            Some(())
+        })
+    }
+}
+
+/// A BME280, the BME680 without the gas sensor. Read in forced mode, with
+/// the floating point compensation of the datasheet.
+struct Bme280 {
+    i2c: I2cDevice,
+    address: u8,
+    calibration: Bme280Calibration,
+}
+
+struct Bme280Calibration {
+    t1: f64,
+    t2: f64,
+    t3: f64,
+    p: [f64; 9],
+    h1: f64,
+    h2: f64,
+    h3: f64,
+    h4: f64,
+    h5: f64,
+    h6: f64,
+}
+
+impl Bme280Calibration {
+    /// From the registers at 0x88 to 0xA1 and at 0xE1 to 0xE7.
+    fn parse(low: &[u8; 26], high: &[u8; 7]) -> Self {
+        let unsigned = |i: usize| u16::from_le_bytes([low[i], low[i + 1]]) as f64;
+        let signed = |i: usize| i16::from_le_bytes([low[i], low[i + 1]]) as f64;
+
+        let mut p = [0.0; 9];
+        p[0] = unsigned(6);
+        for (n, value) in p.iter_mut().enumerate().skip(1) {
+            *value = signed(6 + 2 * n);
+        }
+
+        Self {
+            t1: unsigned(0),
+            t2: signed(2),
+            t3: signed(4),
+            p,
+            h1: low[25] as f64,
+            h2: i16::from_le_bytes([high[0], high[1]]) as f64,
+            h3: high[2] as f64,
+            // Twelve bits each, sharing the nibbles of 0xE5.
+            h4: ((high[3] as i8 as i16) << 4 | (high[4] & 0x0F) as i16) as f64,
+            h5: ((high[5] as i8 as i16) << 4 | (high[4] >> 4) as i16) as f64,
+            h6: high[6] as i8 as f64,
+        }
+    }
+}
+
+impl Bme280 {
+    const ADDRESSES: [u8; 2] = [0x76, 0x77];
+    const CHIP_ID: u8 = 0xD0;
+    const BME280_ID: u8 = 0x60;
+    const CALIBRATION_LOW: u8 = 0x88;
+    const CALIBRATION_HIGH: u8 = 0xE1;
+    const CTRL_HUM: u8 = 0xF2;
+    const CTRL_MEAS: u8 = 0xF4;
+    const CONFIG: u8 = 0xF5;
+    const DATA: u8 = 0xF7;
+    /// Humidity oversampled once.
+    const HUMIDITY_X1: u8 = 0x01;
+    /// Temperature oversampled twice, the pressure four times, forced mode.
+    const FORCED_MEASUREMENT: u8 = 0x4D;
+    /// IIR filter coefficient 4.
+    const FILTER_4: u8 = 0x08;
+    /// A forced measurement with the oversampling above takes under 18 ms.
+    const MEASUREMENT: Duration = Duration::from_millis(20);
+
+    async fn new(i2c: &'static I2cBus) -> Option<Self> {
+        let mut device = I2cDevice::new(i2c);
+
+        for address in Self::ADDRESSES {
+            let mut id = [0u8];
+            if device
+                .write_read(address, &[Self::CHIP_ID], &mut id)
+                .await
+                .is_err()
+                || id[0] != Self::BME280_ID
+            {
+                continue;
+            }
+
+            let mut low = [0u8; 26];
+            let mut high = [0u8; 7];
+            device
+                .write_read(address, &[Self::CALIBRATION_LOW], &mut low)
+                .await
+                .ok()?;
+            device
+                .write_read(address, &[Self::CALIBRATION_HIGH], &mut high)
+                .await
+                .ok()?;
+            device
+                .write(address, &[Self::CONFIG, Self::FILTER_4])
+                .await
+                .ok()?;
+            info!("I2C: BME280 detected at 0x{:X}", address);
+
+            return Some(Self {
+                i2c: device,
+                address,
+                calibration: Bme280Calibration::parse(&low, &high),
+            });
+        }
+
+        None
+    }
+
+    async fn trigger(&mut self) -> Option<()> {
+        // The humidity setting only takes with the write to CTRL_MEAS.
+        self.i2c
+            .write(self.address, &[Self::CTRL_HUM, Self::HUMIDITY_X1])
+            .await
+            .ok()?;
+        self.i2c
+            .write(self.address, &[Self::CTRL_MEAS, Self::FORCED_MEASUREMENT])
+            .await
+            .ok()
+    }
+
+    /// The raw pressure, temperature and humidity of the measurement.
+    async fn measure(&mut self) -> Option<(u32, u32, u32)> {
+        let mut data = [0u8; 8];
+        self.i2c
+            .write_read(self.address, &[Self::DATA], &mut data)
+            .await
+            .ok()?;
+        let twenty_bits = |i: usize| {
+            (data[i] as u32) << 12 | (data[i + 1] as u32) << 4 | (data[i + 2] as u32) >> 4
+        };
+
+        Some((
+            twenty_bits(0),
+            twenty_bits(3),
+            (data[6] as u32) << 8 | data[7] as u32,
+        ))
+    }
+}
+
+impl SensorDriver for Bme280 {
+    fn driver(&self) -> Driver {
+        Driver::Bme280
+    }
+
+    fn start(&mut self) -> Pending<'_, Duration> {
+        Box::pin(async move {
+            if self.trigger().await.is_none() {
+                warn!("Could not start a BME280 measurement");
+            }
+
+            Self::MEASUREMENT
+        })
+    }
+
+    fn read<'a>(&'a mut self, sample: &'a mut Sample) -> Pending<'a, Option<()>> {
+        Box::pin(async move {
+            let Some((raw_pressure, raw_temperature, raw_humidity)) = self.measure().await else {
+                warn!("Could not read BME280");
+                return None;
+            };
+            let c = &self.calibration;
+
+            let var1 = (raw_temperature as f64 / 16384.0 - c.t1 / 1024.0) * c.t2;
+            let var2 = raw_temperature as f64 / 131072.0 - c.t1 / 8192.0;
+            let t_fine = var1 + var2 * var2 * c.t3;
+            sample.temp_bme280 = Some((t_fine / 5120.0) as f32);
+
+            let var1 = t_fine / 2.0 - 64000.0;
+            let var2 = var1 * var1 * c.p[5] / 32768.0 + var1 * c.p[4] * 2.0;
+            let var2 = var2 / 4.0 + c.p[3] * 65536.0;
+            let var1 = (c.p[2] * var1 * var1 / 524288.0 + c.p[1] * var1) / 524288.0;
+            let var1 = (1.0 + var1 / 32768.0) * c.p[0];
+            // Zero before the first measurement after a reset.
+            sample.press_bme280 = (var1 != 0.0).then(|| {
+                let pressure = (1048576.0 - raw_pressure as f64 - var2 / 4096.0) * 6250.0 / var1;
+                let var1 = c.p[8] * pressure * pressure / 2147483648.0;
+                let var2 = pressure * c.p[7] / 32768.0;
+                ((pressure + (var1 + var2 + c.p[6]) / 16.0) / 100.0) as f32
+            });
+
+            let h = t_fine - 76800.0;
+            let h = (raw_humidity as f64 - (c.h4 * 64.0 + c.h5 / 16384.0 * h))
+                * (c.h2 / 65536.0 * (1.0 + c.h6 / 67108864.0 * h * (1.0 + c.h3 / 67108864.0 * h)));
+            let h = h * (1.0 - c.h1 * h / 524288.0);
+            sample.hum_bme280 = Some(h.clamp(0.0, 100.0) as f32);
+
+            Some(())
+        })
+    }
+}
+
+/// A TSL2591, from the dark of a closed room to daylight. It moves its gain
+/// and integration time along [`Tsl2591::STEPS`] as the light changes, so the
+/// counts stay well inside the range of the ADC. It measures continuously,
+/// a new step is waited for before the read.
+struct Tsl2591 {
+    i2c: I2cDevice,
+    /// Index into [`Tsl2591::STEPS`].
+    step: usize,
+}
+
+/// A gain and integration time of the TSL2591.
+struct Tsl2591Step {
+    /// The CONTROL register, the gain in bits 5:4 and the time in 2:0.
+    control: u8,
+    gain: f32,
+    millis: u16,
+}
+
+impl Tsl2591Step {
+    /// The counts of a channel at full scale, 100 ms is too short for all of
+    /// the 16 bits.
+    fn max_count(&self) -> u16 {
+        if self.millis == 100 { 36863 } else { 65535 }
+    }
+
+    fn sensitivity(&self) -> f32 {
+        self.gain * self.millis as f32
+    }
+
+    /// An integration takes a little longer than its nominal time.
+    fn integration(&self) -> Duration {
+        Duration::from_millis(self.millis as u64 * 11 / 10)
+    }
+}
+
+impl Tsl2591 {
+    const ADDRESS: u8 = 0x29;
+    /// Set on every register address, a normal transaction.
+    const COMMAND: u8 = 0xA0;
+    const ENABLE: u8 = 0x00;
+    const CONTROL: u8 = 0x01;
+    const ID: u8 = 0x12;
+    const STATUS: u8 = 0x13;
+    const C0DATAL: u8 = 0x14;
+    const POWER_ON: u8 = 0x01;
+    const ALS_ENABLE: u8 = 0x02;
+    const DEVICE_ID: u8 = 0x50;
+    /// In STATUS, an integration finished since the ADC was enabled.
+    const VALID: u8 = 0x01;
+    /// Counts per lux at a gain of 1 and 1 ms, after Adafruit's library.
+    const LUX_DF: f32 = 408.0;
+
+    /// From the least sensitive to the most. Low, medium, high and maximum
+    /// gain, then longer integrations at the maximum.
+    const STEPS: [Tsl2591Step; 6] = [
+        Tsl2591Step {
+            control: 0x00,
+            gain: 1.0,
+            millis: 100,
+        },
+        Tsl2591Step {
+            control: 0x10,
+            gain: 25.0,
+            millis: 100,
+        },
+        Tsl2591Step {
+            control: 0x20,
+            gain: 428.0,
+            millis: 100,
+        },
+        Tsl2591Step {
+            control: 0x30,
+            gain: 9876.0,
+            millis: 100,
+        },
+        Tsl2591Step {
+            control: 0x32,
+            gain: 9876.0,
+            millis: 300,
+        },
+        Tsl2591Step {
+            control: 0x35,
+            gain: 9876.0,
+            millis: 600,
+        },
+    ];
+
+    async fn new(i2c: &'static I2cBus) -> Option<Self> {
+        let mut tsl = Self {
+            i2c: I2cDevice::new(i2c),
+            step: 1,
+        };
+
+        let mut id = [0u8];
+        tsl.i2c
+            .write_read(Self::ADDRESS, &[Self::COMMAND | Self::ID], &mut id)
+            .await
+            .ok()?;
+        if id[0] != Self::DEVICE_ID {
+            return None;
+        }
+        info!("I2C: TSL2591 detected");
+
+        tsl.set_step(tsl.step).await?;
+
+        Some(tsl)
+    }
+
+    /// Switches to `step`, the ADC starts over with it.
+    async fn set_step(&mut self, step: usize) -> Option<()> {
+        let control = Self::STEPS[step].control;
+        self.write(Self::ENABLE, Self::POWER_ON).await?;
+        self.write(Self::CONTROL, control).await?;
+        self.write(Self::ENABLE, Self::POWER_ON | Self::ALS_ENABLE)
+            .await?;
+        self.step = step;
+
+        Some(())
+    }
+
+    async fn valid(&mut self) -> Option<bool> {
+        let mut status = [0u8];
+        self.i2c
+            .write_read(Self::ADDRESS, &[Self::COMMAND | Self::STATUS], &mut status)
+            .await
+            .ok()?;
+
+        Some(status[0] & Self::VALID != 0)
+    }
+
+    async fn write(&mut self, register: u8, value: u8) -> Option<()> {
+        self.i2c
+            .write(Self::ADDRESS, &[Self::COMMAND | register, value])
+            .await
+            .ok()
+    }
+
+    /// The full spectrum and the infrared channel.
+    async fn channels(&mut self) -> Option<(u16, u16)> {
+        let mut data = [0u8; 4];
+        self.i2c
+            .write_read(Self::ADDRESS, &[Self::COMMAND | Self::C0DATAL], &mut data)
+            .await
+            .ok()?;
+
+        Some((
+            u16::from_le_bytes([data[0], data[1]]),
+            u16::from_le_bytes([data[2], data[3]]),
+        ))
+    }
+
+    fn saturated(&self, full: u16) -> bool {
+        full >= Self::STEPS[self.step].max_count()
+    }
+
+    /// The most sensitive step that keeps `full`, counted at the current
+    /// step, under half the full scale.
+    fn best_step(&self, full: u16) -> usize {
+        let current = &Self::STEPS[self.step];
+
+        Self::STEPS
+            .iter()
+            .rposition(|step| {
+                let expected = full as f32 * step.sensitivity() / current.sensitivity();
+                expected < step.max_count() as f32 / 2.0
+            })
+            .unwrap_or(0)
+    }
+}
+
+impl SensorDriver for Tsl2591 {
+    fn driver(&self) -> Driver {
+        Driver::Tsl2591
+    }
+
+    /// Moves to a better step for the light of the last integration, and
+    /// waits for a first one with it.
+    fn start(&mut self) -> Pending<'_, Duration> {
+        Box::pin(async move {
+            let integration = Self::STEPS[self.step].integration();
+            let (Some(true), Some((full, _))) = (self.valid().await, self.channels().await) else {
+                return integration;
+            };
+
+            // Too bright to tell how much, start over from the least sensitive.
+            let step = if self.saturated(full) {
+                0
+            } else {
+                self.best_step(full)
+            };
+            if step == self.step {
+                return Duration::from_ticks(0);
+            }
+            if self.set_step(step).await.is_none() {
+                warn!("Could not switch the TSL2591 gain");
+            }
+
+            Self::STEPS[self.step].integration()
+        })
+    }
+
+    fn read<'a>(&'a mut self, sample: &'a mut Sample) -> Pending<'a, Option<()>> {
+        Box::pin(async move {
+            let (Some(valid), Some((full, infrared))) = (self.valid().await, self.channels().await)
+            else {
+                warn!("Could not read TSL2591");
+                return None;
+            };
+            // Still integrating after a restart.
+            if !valid {
+                return Some(());
+            }
+
+            // Past the least sensitive step too, in direct sunlight.
+            if self.saturated(full) {
+                warn!("TSL2591: saturated");
+                return Some(());
+            }
+
+            let step = &Self::STEPS[self.step];
+            let lux = if full == 0 {
+                0.0
+            } else {
+                let (full, infrared) = (full as f32, infrared as f32);
+                let counts_per_lux = step.sensitivity() / Self::LUX_DF;
+                ((full - infrared) * (1.0 - infrared / full) / counts_per_lux).max(0.0)
+            };
+            sample.lux_tsl2591 = Some(lux);
+
+            Some(())
+        })
+    }
+}
+
+/// CRC-8 of the Sensirion sensors, 0x31 from 0xFF.
+fn sensirion_crc(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0xFF, |crc, byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x31
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+/// Whether every word of a Sensirion `answer`, two bytes and their CRC,
+/// came through.
+fn sensirion_words(answer: &[u8]) -> bool {
+    answer
+        .chunks(3)
+        .all(|word| sensirion_crc(&word[..2]) == word[2])
+}
+
+/// The VOC index out of the raw SGP40 signal, after Sensirion's: how far the
+/// signal is from its mean of the last hours, in standard deviations, with
+/// the mean at 100. More VOCs lower the raw signal and raise the index.
+struct VocIndex {
+    /// The mean starts out at the first signal.
+    seeded: bool,
+    last: Instant,
+    mean: f32,
+    variance: f32,
+}
+
+impl VocIndex {
+    /// The time the mean and the deviation follow the signal with.
+    const LEARNING_SECS: f32 = 12.0 * 3600.0;
+    /// In raw ticks, keeps the noise of a steady signal off the index.
+    const MIN_DEVIATION: f32 = 100.0;
+    /// Index points per standard deviation.
+    const GAIN: f32 = 50.0;
+
+    fn new() -> Self {
+        Self {
+            seeded: false,
+            last: Instant::now(),
+            mean: 0.0,
+            variance: 0.0,
+        }
+    }
+
+    fn update(&mut self, raw: u16) -> Option<u16> {
+        let raw = raw as f32;
+        let now = Instant::now();
+
+        if !self.seeded {
+            self.seeded = true;
+            self.last = now;
+            self.mean = raw;
+            self.variance = Self::MIN_DEVIATION * Self::MIN_DEVIATION;
+            return None;
+        }
+
+        let deviation = libm::sqrtf(self.variance).max(Self::MIN_DEVIATION);
+        let index = 100.0 + Self::GAIN * (self.mean - raw) / deviation;
+
+        let elapsed = (now - self.last).as_millis() as f32 / 1000.0;
+        let weight = (elapsed / Self::LEARNING_SECS).min(1.0);
+        self.last = now;
+        self.mean += weight * (raw - self.mean);
+        self.variance += weight * ((raw - self.mean) * (raw - self.mean) - self.variance);
+
+        Some(index.clamp(1.0, 500.0) as u16)
+    }
+}
@@ -0,0 +1,128 @@
+//! The numeric fields of the sample by name, for the code that works on all
+//! of them alike.
+
+use super::Sample;
+
+/// What a field measures, the filters are set for all of its fields.
+#[derive(Clone, Copy, PartialEq)]
+pub(super) enum Metric {
+    Temperature,
+    Humidity,
+    Pressure,
+    Lux,
+    Gas,
+    Co2,
+    Voc,
+    Pm,
+    Noise,
+}
+
+impl Metric {
+    pub const ALL: [Self; 9] = [
+        Self::Temperature,
+        Self::Humidity,
+        Self::Pressure,
+        Self::Lux,
+        Self::Gas,
+        Self::Co2,
+        Self::Voc,
+        Self::Pm,
+        Self::Noise,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Temperature => "temperature",
+            Self::Humidity => "humidity",
+            Self::Pressure => "pressure",
+            Self::Lux => "lux",
+            Self::Gas => "gas",
+            Self::Co2 => "co2",
+            Self::Voc => "voc",
+            Self::Pm => "pm",
+            Self::Noise => "noise",
+        }
+    }
+}
+
+/// A field of the sample, for the filters and the calibration.
+pub(super) struct Field {
+    /// As in the sample.
+    pub name: &'static str,
+    pub metric: Metric,
+    pub get: fn(&Sample) -> Option<f32>,
+    pub set: fn(&mut Sample, f32),
+    pub clear: fn(&mut Sample),
+}
+
+/// The sample fields as `f32`, whatever their type.
+trait Value: Copy {
+    fn to_f32(self) -> f32;
+    fn from_f32(value: f32) -> Self;
+}
+
+impl Value for f32 {
+    fn to_f32(self) -> f32 {
+        self
+    }
+
+    fn from_f32(value: f32) -> Self {
+        value
+    }
+}
+
+impl Value for u16 {
+    fn to_f32(self) -> f32 {
+        self as f32
+    }
+
+    fn from_f32(value: f32) -> Self {
+        (value + 0.5) as u16
+    }
+}
+
+impl Value for u32 {
+    fn to_f32(self) -> f32 {
+        self as f32
+    }
+
+    fn from_f32(value: f32) -> Self {
+        (value + 0.5) as u32
+    }
+}
+
+macro_rules! field {
+    ($metric:ident, $field:ident) => {
+        Field {
+            name: stringify!($field),
+            metric: Metric::$metric,
+            get: |sample| sample.$field.map(Value::to_f32),
+            set: |sample, value| sample.$field = Some(Value::from_f32(value)),
+            clear: |sample| sample.$field = None,
+        }
+    };
+}
+
+pub(super) const FIELDS: [Field; 21] = [
+    field!(Temperature, temp_bme680),
+    field!(Temperature, temp_sht40),
+    field!(Temperature, temp_bmp390),
+    field!(Temperature, temp_bme280),
+    field!(Humidity, hum_bme680),
+    field!(Humidity, hum_sht40),
+    field!(Humidity, hum_bme280),
+    field!(Pressure, press_bme680),
+    field!(Pressure, press_bmp390),
+    field!(Pressure, press_bme280),
+    field!(Lux, lux_veml7700),
+    field!(Lux, lux_bh1750),
+    field!(Lux, lux_tsl2591),
+    field!(Gas, gas_bme680),
+    field!(Co2, co2_mhz19),
+    field!(Co2, co2_scd30),
+    field!(Voc, voc_index),
+    field!(Pm, pm1_0),
+    field!(Pm, pm2_5),
+    field!(Pm, pm10),
+    field!(Noise, noise_dba),
+];
@@ -0,0 +1,151 @@
+//! Smoothing and spike rejection between the raw reads and the published
+//! sample, for the noisy lux and gas readings and the like.
+//!
+//! Each metric of the settings gets an exponential moving average and, when
+//! it has a sigma, drops the values that far off the running mean. A level
+//! that stays off is real and taken after [`MAX_REJECTED`] samples in a row.
+
+use core::cell::Cell;
+
+use defmt::{debug, warn};
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+
+use super::Sample;
+use super::fields::{FIELDS, Metric};
+
+/// Spikes in a row after which the value is taken as the new level.
+pub const MAX_REJECTED: u8 = 3;
+/// Samples before the spread means anything and spikes get rejected.
+pub const MIN_SAMPLES: u8 = 5;
+/// Weight of the new value in the running mean and spread the spikes are
+/// measured against, apart from the smoothing so an unsmoothed metric still
+/// has a spread.
+const SPREAD_ALPHA: f32 = 0.1;
+/// The least spread as a share of the mean, so a reading that held still
+/// for a while can still move a little.
+const MIN_SPREAD: f32 = 0.01;
+
+static SETTINGS: Mutex<CriticalSectionRawMutex, Cell<[Option<Setting>; Metric::ALL.len()]>> =
+    Mutex::new(Cell::new([None; Metric::ALL.len()]));
+
+#[derive(Clone, Copy)]
+struct Setting {
+    /// Weight of the new value in the average, 1 for none.
+    alpha: f32,
+    /// Distance from the mean in standard deviations that makes a spike, 0
+    /// for no rejection.
+    sigma: f32,
+}
+
+/// Sets the filtered metrics from `list`, `<metric>:<alpha>[:<sigma>]`
+/// separated by commas or spaces like `lux:0.3:3 gas:0.2:3`. The others go
+/// out as read.
+pub fn set(list: &str) {
+    let mut settings = [None; Metric::ALL.len()];
+
+    for entry in list.split([',', ' ']).filter(|entry| !entry.is_empty()) {
+        let parsed = (|| {
+            let mut parts = entry.split(':');
+            let name = parts.next()?;
+            let metric = Metric::ALL
+                .into_iter()
+                .find(|metric| metric.name() == name)?;
+            let alpha: f32 = parts.next()?.parse().ok()?;
+            let sigma: f32 = match parts.next() {
+                Some(sigma) => sigma.parse().ok()?,
+                None => 0.0,
+            };
+            let valid = alpha > 0.0 && alpha <= 1.0 && sigma >= 0.0;
+            (valid && parts.next().is_none()).then_some((metric, Setting { alpha, sigma }))
+        })();
+        match parsed {
+            Some((metric, setting)) => settings[metric as usize] = Some(setting),
+            None => warn!("Sensors: {} is not a filter", entry),
+        }
+    }
+
+    SETTINGS.lock(|current| current.set(settings));
+}
+
+/// The average, running mean and spread of a field.
+#[derive(Clone, Copy, Default)]
+struct State {
+    average: f32,
+    mean: f32,
+    variance: f32,
+    samples: u8,
+    rejected: u8,
+}
+
+/// The state of the filtered fields, kept by the sensor task.
+#[derive(Default)]
+pub struct Filters([State; FIELDS.len()]);
+
+impl Filters {
+    /// Smooths the fields of `sample` in place, and drops the spikes.
+    pub fn apply(&mut self, sample: &mut Sample) {
+        let settings = SETTINGS.lock(|settings| settings.get());
+
+        for (field, state) in FIELDS.iter().zip(self.0.iter_mut()) {
+            let Some(setting) = settings[field.metric as usize] else {
+                continue;
+            };
+            let Some(value) = (field.get)(sample) else {
+                continue;
+            };
+
+            match state.update(setting, value) {
+                Some(filtered) => (field.set)(sample, filtered),
+                None => {
+                    debug!(
+                        "Sensors: {} spike of {} dropped",
+                        field.metric.name(),
+                        value
+                    );
+                    (field.clear)(sample);
+                }
+            }
+        }
+    }
+}
+
+impl State {
+    /// The filtered value, none for a spike.
+    fn update(&mut self, setting: Setting, value: f32) -> Option<f32> {
+        if self.samples == 0 {
+            *self = Self {
+                average: value,
+                mean: value,
+                samples: 1,
+                ..Self::default()
+            };
+            return Some(value);
+        }
+
+        let deviation = value - self.mean;
+        let min_spread = MIN_SPREAD * self.mean;
+        let variance = self.variance.max(min_spread * min_spread);
+        let spike = setting.sigma > 0.0
+            && self.samples >= MIN_SAMPLES
+            && deviation * deviation > setting.sigma * setting.sigma * variance;
+        if spike && self.rejected < MAX_REJECTED {
+            self.rejected += 1;
+            return None;
+        }
+
+        if spike {
+            // Stayed off, a new level rather than a spike.
+            *self = Self::default();
+            return self.update(setting, value);
+        }
+
+        self.rejected = 0;
+        self.samples = self.samples.saturating_add(1);
+        self.mean += SPREAD_ALPHA * deviation;
+        self.variance =
+            (1.0 - SPREAD_ALPHA) * (self.variance + SPREAD_ALPHA * deviation * deviation);
+        self.average += setting.alpha * (value - self.average);
+
+        Some(self.average)
+    }
+}
@@ -0,0 +1,168 @@
+//! How the sensors fare since they were set up. A sensor that fails
+//! [`REINIT_AFTER`] readings in a row is set up again, a loose wire or a
+//! brownout may have reset it, and one that keeps failing up to
+//! [`MAX_FAILURES`] is given up and probed again every [`REPROBE_PERIOD`].
+//!
+//! Published retained on `<topic>/health` when it changes, like
+//! `{"bme680":{"status":"ok","failures":0,"reinits":1,"bus":0}}`.
+
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use defmt::warn;
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_time::Duration;
+use serde::Serialize;
+use serde::ser::{SerializeMap, Serializer};
+
+use super::Driver;
+use crate::syslog::{self, Severity};
+
+/// Failed readings in a row after which a sensor is set up again, and again
+/// after as many more.
+const REINIT_AFTER: u8 = 3;
+/// Failed readings in a row after which a sensor is given up.
+const MAX_FAILURES: u8 = 10;
+/// How often the given up sensors are looked for.
+pub(super) const REPROBE_PERIOD: Duration = Duration::from_secs(10 * 60);
+
+static HEALTH: Mutex<CriticalSectionRawMutex, Cell<[Option<Health>; Driver::ALL.len()]>> =
+    Mutex::new(Cell::new([None; Driver::ALL.len()]));
+static CHANGED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    /// The last reading failed.
+    Failing,
+    /// Given up, until a probe finds it again.
+    Lost,
+}
+
+#[derive(Clone, Copy, Serialize)]
+pub struct Health {
+    pub status: Status,
+    /// Failed readings in a row.
+    pub failures: u8,
+    /// Times it was set up again since the boot.
+    pub reinits: u16,
+    /// The I2C bus it is on, 1 for the second one.
+    pub bus: u8,
+}
+
+/// The sensors that were found, by name.
+pub struct Report([Option<Health>; Driver::ALL.len()]);
+
+impl Serialize for Report {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let found = self.0.iter().flatten().count();
+        let mut map = serializer.serialize_map(Some(found))?;
+        for (driver, health) in Driver::ALL.into_iter().zip(self.0) {
+            if let Some(health) = health {
+                map.serialize_entry(driver.name(), &health)?;
+            }
+        }
+
+        map.end()
+    }
+}
+
+/// What the sensor task should do about a sensor after a reading.
+#[derive(PartialEq)]
+pub(super) enum Action {
+    Keep,
+    Reinit,
+    GiveUp,
+}
+
+pub fn report() -> Report {
+    Report(HEALTH.lock(|health| health.get()))
+}
+
+/// Whether the health changed since the last call.
+pub fn take_changed() -> bool {
+    CHANGED.swap(false, Ordering::Relaxed)
+}
+
+fn update(driver: Driver, f: impl FnOnce(&mut Option<Health>)) {
+    HEALTH.lock(|health| {
+        let mut all = health.get();
+        f(&mut all[driver as usize]);
+        health.set(all);
+    });
+    CHANGED.store(true, Ordering::Relaxed);
+}
+
+/// Notes that `driver` was set up on `bus`, at the start or found again.
+pub(super) fn found(driver: Driver, bus: u8) {
+    update(driver, |health| {
+        let reinits = health.map_or(0, |health| health.reinits);
+        *health = Some(Health {
+            status: Status::Ok,
+            failures: 0,
+            reinits,
+            bus,
+        });
+    });
+}
+
+/// Notes a reading of `driver`, and says what to do about it.
+pub(super) fn note(driver: Driver, ok: bool) -> Action {
+    let mut action = Action::Keep;
+    let changed = HEALTH.lock(|health| {
+        let mut all = health.get();
+        let Some(current) = all[driver as usize].as_mut() else {
+            return false;
+        };
+        let before = (current.status, current.failures);
+
+        if ok {
+            current.status = Status::Ok;
+            current.failures = 0;
+        } else {
+            current.failures = current.failures.saturating_add(1);
+            current.status = Status::Failing;
+            if current.failures >= MAX_FAILURES {
+                current.status = Status::Lost;
+                action = Action::GiveUp;
+            } else if current.failures % REINIT_AFTER == 0 {
+                action = Action::Reinit;
+            }
+        }
+
+        let after = (current.status, current.failures);
+        health.set(all);
+        after != before
+    });
+    if changed {
+        CHANGED.store(true, Ordering::Relaxed);
+    }
+
+    if action == Action::GiveUp {
+        warn!(
+            "{}: {} failed readings in a row, giving up",
+            driver, MAX_FAILURES
+        );
+        syslog::log(
+            Severity::Warning,
+            format_args!("{:?}: too many failed readings, sensor unavailable", driver),
+        );
+    }
+
+    action
+}
+
+/// Notes that `driver` was set up again, or failed to be.
+pub(super) fn reinit(driver: Driver, ok: bool) {
+    if ok {
+        warn!("{}: set up again after failed readings", driver);
+    } else {
+        warn!("{}: could not set it up again", driver);
+    }
+    update(driver, |health| {
+        if let Some(health) = health {
+            health.reinits = health.reinits.saturating_add(1);
+        }
+    });
+}
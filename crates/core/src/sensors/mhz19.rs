@@ -0,0 +1,118 @@
+//! CO2 from a Winsen MH-Z19B on the [`UartBus`].
+//!
+//! The NDIR lamp doesn't mind running all the time, so unlike the PMS5003 it
+//! stays on. Its readings only mean something after [`PREHEAT`], until then
+//! it answers with a made up value. The automatic baseline calibration is set
+//! as the settings say on every start, the sensor keeps it across power
+//! cycles otherwise and may still have the factory one. A forced calibration
+//! of [`co2`] sets the zero point, 400 ppm whatever the level asked for.
+
+use core::cell::Cell;
+
+use defmt::{info, warn};
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_time::{Duration, Instant, Timer};
+
+use super::SAMPLE_PERIOD;
+use super::co2::{self, Sensor};
+use crate::board::Pins;
+use crate::uart_bus::{Device, Frame, MAX_FRAME, UartBus};
+
+const PREHEAT: Duration = Duration::from_secs(3 * 60);
+const TIMEOUT: Duration = Duration::from_secs(1);
+const READ_CO2: u8 = 0x86;
+const SET_ABC: u8 = 0x79;
+const ZERO_POINT: u8 = 0x87;
+/// What [`ZERO_POINT`] takes the reading for.
+const ZERO_PPM: u16 = 400;
+const ABC_ON: u8 = 0xA0;
+const ABC_OFF: u8 = 0x00;
+const ANSWER: Frame = Frame {
+    header: &[0xFF, READ_CO2],
+    len: 9,
+};
+
+static PPM: Mutex<CriticalSectionRawMutex, Cell<Option<u16>>> = Mutex::new(Cell::new(None));
+
+/// The CO2 concentration in ppm since the last call, if there is one.
+pub fn take() -> Option<u16> {
+    PPM.lock(|ppm| ppm.take())
+}
+
+/// Needs [`Pins::mhz19`].
+#[embassy_executor::task]
+pub async fn task(bus: &'static UartBus, pins: Pins) -> ! {
+    let device = Device {
+        tx: pins.mhz_tx,
+        rx: pins.mhz_rx,
+        baud_rate: 9600,
+        timeout: TIMEOUT,
+    };
+    let automatic = co2::automatic();
+    info!("MH-Z19B: started, automatic calibration {}", automatic);
+
+    let abc = if automatic { ABC_ON } else { ABC_OFF };
+    if let Err(err) = bus.lock(&device).await.write(&command(SET_ABC, abc)).await {
+        warn!("MH-Z19B: calibration command: {}", err);
+    }
+
+    // Counted from the boot, the sensor is powered with the node.
+    Timer::at(Instant::from_ticks(0) + PREHEAT).await;
+
+    loop {
+        if let Some(ppm) = co2::take_forced(Sensor::Mhz19) {
+            if ppm != ZERO_PPM {
+                warn!("MH-Z19B: calibrates to {} ppm only, not {}", ZERO_PPM, ppm);
+            }
+            match bus.lock(&device).await.write(&command(ZERO_POINT, 0)).await {
+                Ok(()) => co2::forced(Sensor::Mhz19, ZERO_PPM),
+                Err(err) => warn!("MH-Z19B: calibration command: {}", err),
+            }
+        }
+
+        let mut buf = [0u8; MAX_FRAME];
+        let answer = {
+            let mut bus = bus.lock(&device).await;
+            match bus.write(&command(READ_CO2, 0)).await {
+                Ok(()) => bus.read_frame(ANSWER, &mut buf).await,
+                Err(err) => Err(err),
+            }
+        };
+        match answer.map(parse) {
+            Ok(Some(ppm)) => PPM.lock(|current| current.set(Some(ppm))),
+            Ok(None) => warn!("MH-Z19B: bad answer"),
+            Err(err) => warn!("MH-Z19B: no answer: {}", err),
+        }
+
+        Timer::after(SAMPLE_PERIOD).await;
+    }
+}
+
+/// Commands are nine bytes: the start byte, the sensor number, the command,
+/// its argument, four unused bytes and the checksum.
+fn command(command: u8, argument: u8) -> [u8; 9] {
+    let mut bytes = [0xFF, 0x01, command, argument, 0, 0, 0, 0, 0];
+    bytes[8] = checksum(&bytes);
+
+    bytes
+}
+
+/// The two's complement of the sum of all the bytes but the first and the
+/// last one.
+fn checksum(bytes: &[u8; 9]) -> u8 {
+    let sum = bytes[1..8]
+        .iter()
+        .fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+
+    (!sum).wrapping_add(1)
+}
+
+/// The concentration is the big endian word after the command.
+fn parse(answer: &[u8]) -> Option<u16> {
+    let answer: &[u8; 9] = answer.try_into().ok()?;
+    if checksum(answer) != answer[8] {
+        return None;
+    }
+
+    Some(u16::from_be_bytes([answer[2], answer[3]]))
+}
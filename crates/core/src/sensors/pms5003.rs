@@ -0,0 +1,115 @@
+//! Particulate matter from a Plantower PMS5003 on the [`UartBus`].
+//!
+//! The fan and the laser wear out after a few thousand hours, so the sensor
+//! sleeps between the samples. It's woken [`WARM_UP`] ahead of each, which
+//! the datasheet asks for before the readings settle, a frame is read and it
+//! goes back to sleep. The sensors task takes the reading with the sample.
+
+use core::cell::Cell;
+
+use defmt::{info, warn};
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_time::{Duration, Timer};
+use esp_hal::gpio::{Level, Output, OutputConfig};
+
+use super::SAMPLE_PERIOD;
+use crate::board::{self, Pins};
+use crate::uart_bus::{Device, Frame, MAX_FRAME, UartBus};
+
+const WARM_UP: Duration = Duration::from_secs(30);
+/// It sends a frame every one to three seconds while awake.
+const TIMEOUT: Duration = Duration::from_secs(3);
+const FRAME: Frame = Frame {
+    header: &[0x42, 0x4D],
+    len: 32,
+};
+const SLEEP: u8 = 0;
+const WAKE_UP: u8 = 1;
+
+static READING: Mutex<CriticalSectionRawMutex, Cell<Option<Reading>>> = Mutex::new(Cell::new(None));
+
+/// Mass concentrations in µg/m³, the atmospheric ones.
+#[derive(Clone, Copy, defmt::Format)]
+pub struct Reading {
+    pub pm1_0: u16,
+    pub pm2_5: u16,
+    pub pm10: u16,
+}
+
+/// The reading since the last call, if there is one.
+pub fn take() -> Option<Reading> {
+    READING.lock(|reading| reading.take())
+}
+
+/// Needs [`Pins::pms5003`].
+#[embassy_executor::task]
+pub async fn task(bus: &'static UartBus, pins: Pins) -> ! {
+    let device = Device {
+        tx: pins.pms_tx,
+        rx: pins.pms_rx,
+        baud_rate: 9600,
+        timeout: TIMEOUT,
+    };
+    let mut set = pins.pms5003_set().map(|pin| {
+        Output::new(
+            unsafe { board::pin(pin) },
+            Level::High,
+            OutputConfig::default(),
+        )
+    });
+    info!("PMS5003: started");
+
+    loop {
+        power(bus, &device, set.as_mut(), WAKE_UP).await;
+        Timer::after(WARM_UP).await;
+
+        let mut buf = [0u8; MAX_FRAME];
+        let frame = bus.lock(&device).await.read_frame(FRAME, &mut buf).await;
+        match frame.map(parse) {
+            Ok(Some(reading)) => READING.lock(|current| current.set(Some(reading))),
+            Ok(None) => warn!("PMS5003: bad frame"),
+            Err(err) => warn!("PMS5003: no frame: {}", err),
+        }
+
+        power(bus, &device, set.as_mut(), SLEEP).await;
+        Timer::after(
+            SAMPLE_PERIOD
+                .checked_sub(WARM_UP)
+                .unwrap_or(Duration::from_ticks(0)),
+        )
+        .await;
+    }
+}
+
+/// Through SET when it's wired, by a command otherwise.
+async fn power(bus: &UartBus, device: &Device, set: Option<&mut Output<'_>>, mode: u8) {
+    if let Some(set) = set {
+        set.set_level(Level::from(mode == WAKE_UP));
+        return;
+    }
+
+    let mut command = [0x42, 0x4D, 0xE4, 0x00, mode, 0, 0];
+    let checksum = command[..5].iter().map(|&byte| byte as u16).sum::<u16>();
+    command[5..].copy_from_slice(&checksum.to_be_bytes());
+
+    if let Err(err) = bus.lock(device).await.write(&command).await {
+        warn!("PMS5003: sleep command: {}", err);
+    }
+}
+
+/// A frame is the header, the length, thirteen words of data and the sum of
+/// all the bytes before it. Words 5 to 7 are the atmospheric concentrations.
+fn parse(frame: &[u8]) -> Option<Reading> {
+    let word = |i: usize| u16::from_be_bytes([frame[2 * i], frame[2 * i + 1]]);
+
+    let checksum = frame[..30].iter().map(|&byte| byte as u16).sum::<u16>();
+    if word(1) != 28 || word(15) != checksum {
+        return None;
+    }
+
+    Some(Reading {
+        pm1_0: word(5),
+        pm2_5: word(6),
+        pm10: word(7),
+    })
+}
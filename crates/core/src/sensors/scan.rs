@@ -0,0 +1,97 @@
+//! Everything that answers on the I2C buses, looked for once at boot so the
+//! wiring can be checked without a debug build. The sensors are only set up
+//! where something answered.
+//!
+//! Logged, on `/api/i2cscan` and published retained on `<topic>/i2cscan`,
+//! like `{"0":["0x3c","0x44","0x76"],"1":["0x44"]}`.
+
+use core::cell::Cell;
+use core::fmt::Write;
+
+use defmt::info;
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use heapless::String;
+use serde::Serialize;
+use serde::ser::{SerializeMap, SerializeSeq, Serializer};
+
+use super::Buses;
+
+/// The addresses that aren't reserved.
+const ADDRESSES: core::ops::RangeInclusive<u8> = 0x08..=0x77;
+
+static TABLE: Mutex<CriticalSectionRawMutex, Cell<Option<Table>>> = Mutex::new(Cell::new(None));
+
+/// The addresses that answered, a bit each, per bus. None for a bus the
+/// board doesn't have.
+#[derive(Clone, Copy)]
+pub struct Table([Option<u128>; 2]);
+
+impl Table {
+    /// Whether something answered on `address` of `bus`.
+    pub fn found(&self, bus: u8, address: u8) -> bool {
+        self.0[bus as usize].is_some_and(|found| found & (1 << address) != 0)
+    }
+
+    fn addresses(found: u128) -> impl Iterator<Item = u8> {
+        ADDRESSES.filter(move |address| found & (1 << address) != 0)
+    }
+}
+
+struct Addresses(u128);
+
+impl Serialize for Addresses {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(None)?;
+        for address in Table::addresses(self.0) {
+            let mut hex: String<4> = String::new();
+            write!(hex, "0x{:02x}", address).ok();
+            seq.serialize_element(hex.as_str())?;
+        }
+
+        seq.end()
+    }
+}
+
+impl Serialize for Table {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let buses = self.0.iter().flatten().count();
+        let mut map = serializer.serialize_map(Some(buses))?;
+        for (name, found) in ["0", "1"].into_iter().zip(self.0) {
+            if let Some(found) = found {
+                map.serialize_entry(name, &Addresses(found))?;
+            }
+        }
+
+        map.end()
+    }
+}
+
+/// The result of the scan, none before it is done.
+pub fn table() -> Option<Table> {
+    TABLE.lock(|table| table.get())
+}
+
+/// Reads a byte from every address of `buses`, and keeps what answered.
+/// Each bus is held for its whole scan.
+pub(super) async fn run(buses: Buses) -> Table {
+    let mut table = Table([None; 2]);
+
+    for (bus, i2c) in buses.all() {
+        let mut i2c = i2c.lock().await;
+        let mut found = 0u128;
+        for address in ADDRESSES {
+            if i2c.read_async(address, &mut [0u8; 1]).await.is_ok() {
+                info!("I2C: bus {} has a device at 0x{:02x}", bus, address);
+                found |= 1 << address;
+            }
+        }
+        if found == 0 {
+            info!("I2C: nothing answers on bus {}", bus);
+        }
+        table.0[bus as usize] = Some(found);
+    }
+
+    TABLE.lock(|current| current.set(Some(table)));
+
+    table
+}
@@ -0,0 +1,98 @@
+//! Versions of the [`Sample`] layout, as the JSON payload and the postcard
+//! records on the SD card carry it.
+//!
+//! How the layout may change:
+//!
+//! - Fields are only ever appended to [`Sample`], never removed, reordered or
+//!   given another unit. They are options, and every batch of new fields is
+//!   a new version.
+//! - A reader of an older version still reads newer payloads, it skips the
+//!   keys it doesn't know. [`SampleVersion::COMPATIBLE`] is the oldest version
+//!   that holds for, and it only moves when a field has to change after all.
+//! - [`decode`] reads the postcard records of every version back, older ones
+//!   have the fields added since then unset. A new version only needs its
+//!   field count in [`SampleVersion::fields`].
+
+use serde::{Deserialize, Serialize};
+
+use super::Sample;
+
+#[derive(Default, Serialize, Deserialize, Clone, Copy, PartialEq, PartialOrd, defmt::Format)]
+pub enum SampleVersion {
+    /// The BME680, SHT40, BMP390, VEML7700 and BH1750 readings.
+    V1,
+    /// Adds `gas_bme680`, `chip_temp`, `supply_mv` and `noise_dba`.
+    V2,
+    /// Adds `voc_index`.
+    V3,
+    /// Adds `pm1_0`, `pm2_5` and `pm10`.
+    V4,
+    /// Adds `co2_mhz19`.
+    V5,
+    /// Adds `adc0` and `adc1`.
+    V6,
+    /// Adds `battery_mv`, `battery_ma` and `battery_percent`.
+    V7,
+    /// Adds `warming_up`.
+    V8,
+    /// Adds `lux_tsl2591`.
+    V9,
+    /// Adds `temp_bme280`, `press_bme280` and `hum_bme280`.
+    V10,
+    /// Adds `co2_scd30`.
+    V11,
+    /// Adds `wind_kmh` and `rain_mm`.
+    V12,
+    /// Adds `dew_point`, `abs_humidity` and `heat_index`.
+    #[default]
+    V13,
+}
+
+impl SampleVersion {
+    /// What the firmware takes its samples as.
+    pub const CURRENT: Self = Self::V13;
+    /// The oldest version whose readers understand [`Self::CURRENT`].
+    pub const COMPATIBLE: Self = Self::V1;
+
+    pub const fn number(self) -> u8 {
+        self as u8 + 1
+    }
+
+    /// Fields of [`Sample`] in the version, the version itself included.
+    pub const fn fields(self) -> usize {
+        match self {
+            Self::V1 => 11,
+            Self::V2 => 15,
+            Self::V3 => 16,
+            Self::V4 => 19,
+            Self::V5 => 20,
+            Self::V6 => 22,
+            Self::V7 => 25,
+            Self::V8 => 26,
+            Self::V9 => 27,
+            Self::V10 => 30,
+            Self::V11 => 31,
+            Self::V12 => 33,
+            Self::V13 => 36,
+        }
+    }
+}
+
+const _: () = assert!(SampleVersion::COMPATIBLE.number() <= SampleVersion::CURRENT.number());
+
+/// Longer than a postcard [`Sample`] with every field set, 166 bytes.
+pub const MAX_RECORD: usize = 176;
+
+/// A postcard record of any version, without the COBS framing. None when
+/// it's not a sample, or one of a newer firmware.
+pub fn decode(bytes: &[u8]) -> Option<Sample> {
+    let (version, _) = postcard::take_from_bytes::<SampleVersion>(bytes).ok()?;
+
+    // The fields an older record lacks are all options at its end, so a
+    // None for each of them turns it into a current one.
+    let missing = SampleVersion::CURRENT.fields() - version.fields();
+    let mut record = [0u8; MAX_RECORD];
+    record.get_mut(..bytes.len())?.copy_from_slice(bytes);
+
+    postcard::from_bytes(record.get(..bytes.len() + missing)?).ok()
+}
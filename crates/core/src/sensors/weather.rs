@@ -0,0 +1,64 @@
+//! An anemometer and a tipping-bucket rain gauge, `wind_kmh` and `rain_mm`
+//! of the sample.
+//!
+//! Both close a reed contact once per turn or tip, counted by the
+//! [`inputs`] tasks on the `wind` and `rain` board pins. The pin interrupts
+//! rather than the PCNT: its glitch filter is too short to debounce a reed
+//! contact, and the counts are a few a second at most. The settings give the
+//! pulses per unit, the defaults are those of the common Davis and Misol
+//! gauges.
+
+use core::cell::Cell;
+
+use defmt::warn;
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_time::Duration;
+
+use crate::inputs;
+
+/// Pulses a second at 1 km/h, one a second is 2.4 km/h.
+const DEFAULT_WIND: f32 = 1.0 / 2.4;
+/// Pulses per mm, a tip is 0.2794 mm.
+const DEFAULT_RAIN: f32 = 1.0 / 0.2794;
+
+/// The pulses per unit of the gauges that are wired, none for the others.
+static FACTORS: Mutex<CriticalSectionRawMutex, Cell<[Option<f32>; 2]>> =
+    Mutex::new(Cell::new([None; 2]));
+
+/// The gauges on `wind` and `rain` of the board pins, with the pulses per
+/// unit of the settings, empty for the defaults.
+pub fn set(wind: Option<&str>, rain: Option<&str>) {
+    let factors = [(wind, DEFAULT_WIND), (rain, DEFAULT_RAIN)].map(|(text, default)| {
+        let text = text?.trim();
+        if text.is_empty() {
+            return Some(default);
+        }
+
+        match text.parse::<f32>() {
+            Ok(factor) if factor > 0.0 => Some(factor),
+            _ => {
+                warn!(
+                    "Weather: {} is not a number of pulses, using the default",
+                    text
+                );
+                Some(default)
+            }
+        }
+    });
+
+    FACTORS.lock(|current| current.set(factors));
+}
+
+/// The average wind speed in km/h over `elapsed`, and the rain in mm that
+/// fell in it. Takes the pulses, so each is counted once.
+pub fn read(elapsed: Duration) -> (Option<f32>, Option<f32>) {
+    let [wind, rain] = FACTORS.lock(|factors| factors.get());
+    let secs = elapsed.as_millis() as f32 / 1000.0;
+
+    let wind = wind
+        .filter(|_| secs > 0.0)
+        .map(|factor| inputs::take_pulses(inputs::WIND) as f32 / secs / factor);
+    let rain = rain.map(|factor| inputs::take_pulses(inputs::RAIN) as f32 / factor);
+
+    (wind, rain)
+}
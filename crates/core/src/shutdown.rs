@@ -0,0 +1,87 @@
+//! Orderly restarts: the tasks are told to wrap up, get a moment to do it and
+//! only then the chip is reset.
+//!
+//! A task that has something to finish registers itself and waits on
+//! [`STOP`]. Whatever it didn't finish within [`FLUSH_TIMEOUT`] is lost,
+//! the restart doesn't wait any longer.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use defmt::{info, warn};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal, watch::Watch};
+use embassy_time::{Duration, Timer, with_timeout};
+
+use crate::syslog::{self, Severity};
+
+/// MQTT and the display.
+pub const STOP_RECEIVERS: usize = 2;
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(3);
+
+pub static STOP: Watch<CriticalSectionRawMutex, Reason, STOP_RECEIVERS> = Watch::new();
+static REGISTERED: AtomicU8 = AtomicU8::new(0);
+static FINISHED: AtomicU8 = AtomicU8::new(0);
+static FINISHED_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub enum Reason {
+    Reboot,
+    Reconfigure,
+    Update,
+    FactoryReset,
+    SafeMode,
+}
+
+impl Reason {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Reason::Reboot => "reboot",
+            Reason::Reconfigure => "setup",
+            Reason::Update => "update",
+            Reason::FactoryReset => "factory reset",
+            Reason::SafeMode => "safe mode",
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Participant {
+    Mqtt = 1 << 0,
+    Display = 1 << 1,
+}
+
+/// Makes [`restart`] wait for `participant` to call [`finished`].
+pub fn register(participant: Participant) {
+    REGISTERED.fetch_or(participant as u8, Ordering::Relaxed);
+}
+
+pub fn finished(participant: Participant) {
+    FINISHED.fetch_or(participant as u8, Ordering::Relaxed);
+    FINISHED_SIGNAL.signal(());
+}
+
+/// Tells the registered tasks to wrap up, waits for them up to
+/// [`FLUSH_TIMEOUT`] and resets.
+pub async fn restart(reason: Reason) -> ! {
+    info!("Shutdown: restarting for {}", reason);
+    syslog::log(
+        Severity::Notice,
+        format_args!("Restarting for {}", reason.label()),
+    );
+
+    STOP.sender().send(reason);
+
+    let all_finished = async {
+        let registered = REGISTERED.load(Ordering::Relaxed);
+        while FINISHED.load(Ordering::Relaxed) & registered != registered {
+            FINISHED_SIGNAL.wait().await;
+        }
+    };
+
+    if with_timeout(FLUSH_TIMEOUT, all_finished).await.is_err() {
+        warn!("Shutdown: not everything finished in time");
+    }
+
+    // Lets the syslog datagram and the last MQTT packets leave.
+    Timer::after_millis(100).await;
+    esp_hal::system::software_reset()
+}
@@ -0,0 +1,183 @@
+//! Remote logging: a ring of the notable events, forwarded as RFC 5424
+//! syslog datagrams to the configured host.
+//!
+//! The defmt output can only be read with the firmware's ELF at hand, so the
+//! events that matter in the field also go to the ring as plain text. The
+//! most recent ones are also kept for the log page of the web server.
+
+use core::cell::RefCell;
+use core::fmt::Write;
+use core::net::{Ipv4Addr, SocketAddrV4};
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+use defmt::{info, warn};
+use embassy_net::Stack;
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_sync::signal::Signal;
+use heapless::{Deque, String};
+use serde::Serialize;
+
+use crate::{diagnostics, net_time};
+
+extern crate alloc;
+
+pub const DEFAULT_PORT: u16 = 514;
+
+const RING_LEN: usize = 16;
+/// Entries for the log page, a lot more with PSRAM.
+const RECENT_LEN: usize = 16;
+const RECENT_LEN_PSRAM: usize = 256;
+const MESSAGE_LEN: usize = 96;
+const DATAGRAM_LEN: usize = 192;
+/// local0
+const FACILITY: u8 = 16;
+const APP_NAME: &str = "sensors-node";
+
+static RING: Mutex<CriticalSectionRawMutex, RefCell<Deque<Entry, RING_LEN>>> =
+    Mutex::new(RefCell::new(Deque::new()));
+static NEW_ENTRY: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+/// Same as the ring, but not drained by the sending.
+static RECENT: Mutex<CriticalSectionRawMutex, RefCell<VecDeque<Entry>>> =
+    Mutex::new(RefCell::new(VecDeque::new()));
+
+#[derive(Clone, Copy, Serialize, defmt::Format)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error = 3,
+    Warning = 4,
+    Notice = 5,
+    Info = 6,
+}
+
+#[derive(Clone, Serialize)]
+pub struct Entry {
+    severity: Severity,
+    /// Unix time, when the clock was synced already.
+    unix: Option<u32>,
+    message: String<MESSAGE_LEN>,
+}
+
+/// Adds an entry to the ring, dropping the oldest one when it is full.
+pub fn log(severity: Severity, args: core::fmt::Arguments) {
+    let mut message = String::new();
+    write!(message, "{}", args).ok();
+
+    let entry = Entry {
+        severity,
+        unix: net_time::TIME_STATE
+            .try_lock()
+            .ok()
+            .and_then(|time| time.now()),
+        message,
+    };
+
+    RECENT.lock(|recent| {
+        let mut recent = recent.borrow_mut();
+        let len = if diagnostics::has_psram() {
+            RECENT_LEN_PSRAM
+        } else {
+            RECENT_LEN
+        };
+        if recent.len() >= len {
+            recent.pop_front();
+        }
+        recent.push_back(entry.clone());
+    });
+    RING.lock(|ring| push(&mut ring.borrow_mut(), entry));
+
+    NEW_ENTRY.signal(());
+}
+
+/// The most recent entries, oldest first.
+pub fn recent() -> Vec<Entry> {
+    RECENT.lock(|recent| recent.borrow().iter().cloned().collect())
+}
+
+fn push(ring: &mut Deque<Entry, RING_LEN>, entry: Entry) {
+    if ring.is_full() {
+        ring.pop_front();
+    }
+    ring.push_back(entry).ok();
+}
+
+/// Sends the ring's entries to `host` (`IP[:port]`), tagged with `hostname`.
+#[embassy_executor::task]
+pub async fn task(stack: Stack<'static>, host: &'static str, hostname: &'static str) -> ! {
+    let Some(endpoint) = parse_host(host) else {
+        warn!("Syslog: cannot parse host {}", host);
+        loop {
+            core::future::pending::<()>().await;
+        }
+    };
+
+    let mut rx_meta = [PacketMetadata::EMPTY];
+    let mut rx_buf = [0u8; 16];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buf = [0u8; 4 * DATAGRAM_LEN];
+
+    let mut socket = UdpSocket::new(stack, &mut rx_meta, &mut rx_buf, &mut tx_meta, &mut tx_buf);
+    if let Err(err) = socket.bind(0) {
+        warn!("Syslog: cannot bind a socket: {:?}", err);
+        loop {
+            core::future::pending::<()>().await;
+        }
+    }
+
+    info!("Syslog: sending to {}", host);
+
+    loop {
+        NEW_ENTRY.wait().await;
+
+        while let Some(entry) = RING.lock(|ring| ring.borrow_mut().pop_front()) {
+            let datagram = format_entry(&entry, hostname);
+            if let Err(err) = socket.send_to(datagram.as_bytes(), endpoint).await {
+                warn!("Syslog: send failed: {:?}", err);
+            }
+        }
+    }
+}
+
+/// `<PRI>1 TIMESTAMP HOSTNAME APP-NAME PROCID MSGID SD MSG`
+fn format_entry(entry: &Entry, hostname: &str) -> String<DATAGRAM_LEN> {
+    let mut datagram = String::new();
+
+    write!(datagram, "<{}>1 ", FACILITY * 8 + entry.severity as u8).ok();
+    match entry.unix {
+        Some(unix) => {
+            let (year, month, day) = net_time::civil_from_days(unix / 86_400);
+            let secs = unix % 86_400;
+            write!(
+                datagram,
+                "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+                year,
+                month,
+                day,
+                secs / 3600,
+                secs / 60 % 60,
+                secs % 60
+            )
+            .ok()
+        }
+        None => datagram.push('-').ok(),
+    };
+    write!(
+        datagram,
+        " {} {} - - - {}",
+        hostname, APP_NAME, entry.message
+    )
+    .ok();
+
+    datagram
+}
+
+fn parse_host(host: &str) -> Option<SocketAddrV4> {
+    let (address, port) = match host.split_once(':') {
+        Some((address, port)) => (address, port.parse().ok()?),
+        None => (host, DEFAULT_PORT),
+    };
+
+    Some(SocketAddrV4::new(address.parse::<Ipv4Addr>().ok()?, port))
+}
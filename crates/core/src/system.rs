@@ -1,12 +1,101 @@
+use core::cell::RefCell;
+use core::fmt::Write;
 use core::sync::atomic::{AtomicBool, Ordering};
 
-use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
-use embassy_time::Timer;
+use defmt::{error, info, warn};
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_sync::watch::Watch;
+use embassy_time::{Duration, Instant, Timer};
+use heapless::String;
 
-pub static STATE: Signal<CriticalSectionRawMutex, State> = Signal::new();
+use crate::syslog::{self, Severity};
+use crate::event_log::{self, Kind};
+use crate::{mqtt, shutdown, wifi};
+
+/// The display and the LED.
+pub const FAULT_RECEIVERS: usize = 2;
+/// The LED, plus whoever is in [`wait_for_state`] at the same time: MQTT
+/// and the OTA confirmation.
+pub const STATE_RECEIVERS: usize = 3;
+
+/// The one place the node's state is kept. Every task reading it sees the
+/// same value, nothing is consumed by one task and missed by another.
+pub static STATE: Watch<CriticalSectionRawMutex, State, STATE_RECEIVERS> = Watch::new();
 pub static NEED_REBOOT: AtomicBool = AtomicBool::new(false);
+/// The previous run ended with the supply dipping below the brownout level.
+static BROWNOUT: AtomicBool = AtomicBool::new(false);
+pub static FAULT: Watch<CriticalSectionRawMutex, Fault, FAULT_RECEIVERS> = Watch::new();
+
+/// How long a panic of the previous run is reported for.
+const PANIC_FAULT_DURATION: Duration = Duration::from_secs(60);
+
+pub const PANIC_MESSAGE_LEN: usize = 128;
+
+/// Survives the software reset done by the panic handler.
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static mut PANIC_RECORD: PanicRecord = PanicRecord {
+    magic: 0,
+    len: 0,
+    message: [0; PANIC_MESSAGE_LEN],
+};
+const PANIC_MAGIC: u32 = 0xDEAD_BEEF;
+
+/// Quick boots in a row, survives the resets.
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static mut BOOT_COUNTER: BootCounter = BootCounter { magic: 0, count: 0 };
+const BOOT_COUNTER_MAGIC: u32 = 0xB007_C0DE;
+/// Runs shorter than this count towards a crash loop.
+const STABLE_AFTER: Duration = Duration::from_secs(60);
+/// Quick boots in a row after which the node starts in the safe mode.
+const CRASH_LOOP_BOOTS: u32 = 5;
 
-#[derive(Default, defmt::Format)]
+/// Set by a command or the button before the reset into the safe mode.
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static mut SAFE_MODE_REQUEST: u32 = 0;
+const SAFE_MODE_MAGIC: u32 = 0x5AFE_0001;
+
+static BOOT_REPORT: Mutex<CriticalSectionRawMutex, RefCell<Option<BootReport>>> =
+    Mutex::new(RefCell::new(None));
+
+#[derive(Clone, Copy)]
+struct BootCounter {
+    magic: u32,
+    count: u32,
+}
+
+#[derive(Clone, Copy)]
+struct PanicRecord {
+    magic: u32,
+    len: u8,
+    message: [u8; PANIC_MESSAGE_LEN],
+}
+
+/// Fills the record and cuts off what doesn't fit.
+impl Write for PanicRecord {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for &byte in s.as_bytes() {
+            if self.len as usize == PANIC_MESSAGE_LEN {
+                break;
+            }
+            self.message[self.len as usize] = byte;
+            self.len += 1;
+        }
+
+        Ok(())
+    }
+}
+
+/// Why the node started, published once on the first MQTT connection.
+#[derive(serde::Serialize)]
+pub struct BootReport {
+    #[serde(rename = "reset")]
+    pub reset_reason: String<32>,
+    /// Location and message of the panic that ended the previous run.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub panic: Option<String<PANIC_MESSAGE_LEN>>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, defmt::Format)]
 pub enum State {
     #[default]
     Booting,
@@ -18,18 +107,294 @@ pub enum State {
     Sensors,
     Ok,
     Panic,
+    /// Serving the setup access point, off the broker until the settings are
+    /// saved.
+    Setup,
 }
 
+impl State {
+    /// The WiFi link is up, every later state builds on it.
+    pub fn wifi_up(&self) -> bool {
+        matches!(
+            self,
+            State::Dhcp | State::NtpSync | State::MqttConnecting | State::Sensors | State::Ok
+        )
+    }
+}
+
+/// Receivers are only woken up by an actual change.
 pub fn set_state(state: State) {
-    STATE.signal(state);
+    STATE.sender().send_if_modified(|current| {
+        let changed = *current != Some(state);
+        *current = Some(state);
+        changed
+    });
+}
+
+/// Moves to `to` only from one of the `from` states, so a late event of one
+/// subsystem doesn't hide what another one reported since.
+pub fn transition(from: &[State], to: State) {
+    STATE.sender().send_if_modified(|current| {
+        let changed = from.contains(&current.unwrap_or_default()) && *current != Some(to);
+        if changed {
+            *current = Some(to);
+        }
+        changed
+    });
+}
+
+pub fn state() -> State {
+    STATE.try_get().unwrap_or_default()
+}
+
+/// Returns the current state as soon as `predicate` holds for it.
+pub async fn wait_for_state(predicate: impl Fn(State) -> bool) -> State {
+    let mut receiver = STATE.receiver().unwrap();
+    receiver.get_and(|state| predicate(*state)).await
+}
+
+/// Critical failures the node can't recover from by itself.
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub enum Fault {
+    WifiDown,
+    /// The access point rejected the password.
+    WifiAuth,
+    /// Connected to the WiFi, but got no address.
+    DhcpTimeout,
+    /// The broker is reachable, but doesn't accept the connection.
+    MqttRefused,
+    /// None of the sensors answers on the I2C bus.
+    SensorBus,
+    /// The key-value storage can't be read or written.
+    Storage,
+    /// The previous run ended with a panic.
+    Panic,
+    /// Rebooted too often in a row, only the setup access point runs.
+    CrashLoop,
+    /// Started in the safe mode on request, only the setup access point runs.
+    SafeMode,
+}
+
+impl Fault {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Fault::WifiDown => "E10",
+            Fault::WifiAuth => "E11",
+            Fault::DhcpTimeout => "E12",
+            Fault::Storage => "E20",
+            Fault::MqttRefused => "E30",
+            Fault::SensorBus => "E40",
+            Fault::Panic => "E90",
+            Fault::CrashLoop => "E91",
+            Fault::SafeMode => "E92",
+        }
+    }
+
+    pub fn hint(&self) -> &'static str {
+        match self {
+            Fault::WifiDown => "Check WiFi/router",
+            Fault::WifiAuth => "Wrong WiFi password",
+            Fault::DhcpTimeout => "No IP from router",
+            Fault::MqttRefused => "Broker refused",
+            Fault::SensorBus => "No sensors found",
+            Fault::Storage => "Flash storage failed",
+            Fault::Panic => "Crashed, restarted",
+            Fault::CrashLoop => "Crash loop, safe mode",
+            Fault::SafeMode => "Safe mode",
+        }
+    }
+
+    /// Whether the fault reported at `since` no longer applies.
+    pub fn is_cleared(&self, since: Instant) -> bool {
+        match self {
+            Fault::WifiDown | Fault::WifiAuth => wifi::CONNECTED.load(Ordering::Relaxed),
+            Fault::DhcpTimeout => wifi::CONFIGURED.load(Ordering::Relaxed),
+            Fault::MqttRefused => mqtt::CONNECTED.load(Ordering::Relaxed),
+            Fault::SensorBus | Fault::Storage | Fault::CrashLoop | Fault::SafeMode => false,
+            Fault::Panic => since.elapsed() > PANIC_FAULT_DURATION,
+        }
+    }
+}
+
+pub fn report_fault(fault: Fault) {
+    error!("Fault {}: {}", fault.code(), fault.hint());
+    syslog::log(
+        Severity::Error,
+        format_args!("Fault {}: {}", fault.code(), fault.hint()),
+    );
+    FAULT.sender().send(fault);
+}
+
+/// Counts this boot towards a crash loop and tells whether there is one.
+/// Meant to be called early, before anything that could crash.
+pub fn count_boot() -> bool {
+    // Waking up from the deep sleep is the normal cycle, not a crash.
+    let woke_up = matches!(
+        esp_hal::rtc_cntl::reset_reason(esp_hal::system::Cpu::ProCpu),
+        Some(esp_hal::rtc_cntl::SocResetReason::CoreDeepSleep)
+    );
+
+    let counter = unsafe { core::ptr::addr_of!(BOOT_COUNTER).read_volatile() };
+    let count = if counter.magic == BOOT_COUNTER_MAGIC && !woke_up {
+        counter.count.saturating_add(1)
+    } else {
+        1
+    };
+    set_boot_count(count);
+
+    if count >= CRASH_LOOP_BOOTS {
+        error!("Crash loop: {} quick boots in a row", count);
+        return true;
+    }
+
+    false
+}
+
+/// Restarts into the safe mode: only the setup access point, the web server
+/// and the log page, no sensors, BLE or MQTT. The restart after that is a
+/// normal one again.
+pub async fn reboot_to_safe_mode() -> ! {
+    warn!("Restarting into the safe mode");
+    unsafe { core::ptr::addr_of_mut!(SAFE_MODE_REQUEST).write_volatile(SAFE_MODE_MAGIC) };
+    shutdown::restart(shutdown::Reason::SafeMode).await
+}
+
+/// Why the node starts in the safe mode: a crash loop or a request before the
+/// reset. Clears the request, so meant to be called once, early in `main`.
+pub fn safe_mode(crash_loop: bool) -> Option<Fault> {
+    let requested =
+        unsafe { core::ptr::addr_of_mut!(SAFE_MODE_REQUEST).replace(0) == SAFE_MODE_MAGIC };
+
+    if crash_loop {
+        Some(Fault::CrashLoop)
+    } else if requested {
+        Some(Fault::SafeMode)
+    } else {
+        None
+    }
+}
+
+/// Clears the boot counter once the node has been running for a while.
+#[embassy_executor::task]
+pub async fn mark_stable() {
+    Timer::after(STABLE_AFTER).await;
+    set_boot_count(0);
+}
+
+fn set_boot_count(count: u32) {
+    let counter = BootCounter {
+        magic: BOOT_COUNTER_MAGIC,
+        count,
+    };
+    unsafe { core::ptr::addr_of_mut!(BOOT_COUNTER).write_volatile(counter) };
+}
+
+/// Stores where and why the current run panicked. Meant to be called from
+/// the panic handler right before the reset.
+pub fn record_panic(info: &core::panic::PanicInfo) {
+    let mut record = PanicRecord {
+        magic: PANIC_MAGIC,
+        len: 0,
+        message: [0; PANIC_MESSAGE_LEN],
+    };
+
+    if let Some(location) = info.location() {
+        write!(record, "{}:{}: ", location.file(), location.line()).ok();
+    }
+    write!(record, "{}", info.message()).ok();
+
+    unsafe { core::ptr::addr_of_mut!(PANIC_RECORD).write_volatile(record) };
+}
+
+/// Reads the reset reason and the panic of the previous run, if any, and
+/// keeps them for [`take_boot_report`]. Returns whether there was a panic.
+/// A wake from the deep sleep is the normal cycle, it gets no report and no
+/// entry in the event log.
+pub fn record_boot() -> bool {
+    let record = unsafe {
+        let record = core::ptr::addr_of_mut!(PANIC_RECORD);
+        let value = record.read_volatile();
+        (*record).magic = 0;
+        value
+    };
+
+    let panic = (record.magic == PANIC_MAGIC).then(|| {
+        let message = &record.message[..(record.len as usize).min(PANIC_MESSAGE_LEN)];
+        // The cut at the end of the buffer may have split a character.
+        let message = match core::str::from_utf8(message) {
+            Ok(message) => message,
+            Err(err) => core::str::from_utf8(&message[..err.valid_up_to()]).unwrap_or_default(),
+        };
+        String::try_from(message).unwrap_or_default()
+    });
+
+    let reason = esp_hal::rtc_cntl::reset_reason(esp_hal::system::Cpu::ProCpu);
+    let mut reset_reason = String::new();
+    match reason {
+        Some(reason) => write!(reset_reason, "{:?}", reason).ok(),
+        None => reset_reason.push_str("Unknown").ok(),
+    };
+
+    let woke_up = matches!(reason, Some(esp_hal::rtc_cntl::SocResetReason::CoreDeepSleep));
+    if !woke_up {
+        event_log::record(Kind::Boot);
+    }
+    if matches!(reason, Some(esp_hal::rtc_cntl::SocResetReason::SysBrownOut)) {
+        BROWNOUT.store(true, Ordering::Relaxed);
+        event_log::record(Kind::Brownout);
+        warn!("Previous run ended in a brownout");
+        syslog::log(
+            Severity::Warning,
+            format_args!("Previous run ended in a brownout"),
+        );
+    }
+
+    info!("Reset reason: {}", reset_reason.as_str());
+    syslog::log(
+        Severity::Notice,
+        format_args!("Booted, reset reason {}", reset_reason),
+    );
+    if let Some(panic) = &panic {
+        event_log::record(Kind::Panic);
+        error!("Previous run panicked: {}", panic.as_str());
+        syslog::log(
+            Severity::Error,
+            format_args!("Previous run panicked: {}", panic),
+        );
+    }
+
+    let panicked = panic.is_some();
+    if woke_up && !panicked {
+        return false;
+    }
+    BOOT_REPORT.lock(|report| {
+        report.replace(Some(BootReport {
+            reset_reason,
+            panic,
+        }))
+    });
+
+    panicked
+}
+
+/// Whether this boot follows a brownout reset.
+pub fn brownout() -> bool {
+    BROWNOUT.load(Ordering::Relaxed)
+}
+
+/// The boot report if it wasn't taken yet, none after a wake from the deep
+/// sleep.
+pub fn take_boot_report() -> Option<BootReport> {
+    BOOT_REPORT.lock(|report| report.borrow_mut().take())
 }
 
 #[embassy_executor::task]
 pub async fn reboot_on_request() -> ! {
     loop{
         if NEED_REBOOT.load(Ordering::SeqCst) {
+            // Lets the web server answer the request first.
             Timer::after_millis(500).await;
-            esp_hal::system::software_reset();
+            shutdown::restart(shutdown::Reason::Reboot).await;
         }
 
         Timer::after_secs(1).await;
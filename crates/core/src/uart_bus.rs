@@ -0,0 +1,167 @@
+//! One UART shared by several UART sensors, each on its own pair of pins.
+//!
+//! The chips only have one or two UARTs to spare, but the particle, CO2 and
+//! similar sensors only talk when asked or send a frame every few seconds.
+//! So a sensor task locks the bus, which routes the UART to its pins at its
+//! baud rate, reads its frame and lets go again.
+
+use defmt::{debug, warn};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::{Mutex, MutexGuard};
+use embassy_time::{Duration, with_timeout};
+use esp_hal::Async;
+use esp_hal::gpio::{Level, Output, OutputConfig};
+use esp_hal::peripherals::UART1;
+use esp_hal::uart::{self, Uart};
+use static_cell::StaticCell;
+
+use crate::board;
+
+/// Longest frame of the sensors supported so far, the PMS5003 one.
+pub const MAX_FRAME: usize = 32;
+
+#[derive(Debug, defmt::Format)]
+pub enum Error {
+    Timeout,
+    Io,
+    /// Doesn't fit [`MAX_FRAME`].
+    FrameLen,
+}
+
+/// A sensor on the bus.
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub struct Device {
+    pub tx: u8,
+    pub rx: u8,
+    pub baud_rate: u32,
+    /// Longest the sensor takes to answer, or between two frames of one that
+    /// keeps sending.
+    pub timeout: Duration,
+}
+
+/// How a frame of a sensor starts and how long it is, header included.
+#[derive(Clone, Copy)]
+pub struct Frame {
+    pub header: &'static [u8],
+    pub len: usize,
+}
+
+struct Port {
+    uart: Option<Uart<'static, Async>>,
+    current: Option<Device>,
+}
+
+pub struct UartBus(Mutex<CriticalSectionRawMutex, Port>);
+
+/// The bus, routed to `device`. Lets go of it when dropped.
+pub struct Guard<'a> {
+    port: MutexGuard<'a, CriticalSectionRawMutex, Port>,
+    timeout: Duration,
+}
+
+impl UartBus {
+    pub fn new(uart1: UART1<'static>) -> &'static Self {
+        static BUS: StaticCell<UartBus> = StaticCell::new();
+
+        let uart = Uart::new(uart1, uart::Config::default())
+            .unwrap()
+            .into_async();
+
+        BUS.init(Self(Mutex::new(Port {
+            uart: Some(uart),
+            current: None,
+        })))
+    }
+
+    /// Waits for the bus and routes it to `device`.
+    pub async fn lock(&self, device: &Device) -> Guard<'_> {
+        let mut port = self.0.lock().await;
+
+        if port.current != Some(*device) {
+            if let Some(previous) = port.current.filter(|previous| previous.tx != device.tx) {
+                // The TX signal stays on the old pin otherwise, park it idle.
+                Output::new(
+                    unsafe { board::pin(previous.tx) },
+                    Level::High,
+                    OutputConfig::default(),
+                );
+            }
+
+            let mut uart = port
+                .uart
+                .take()
+                .unwrap()
+                .with_tx(unsafe { board::pin(device.tx) })
+                .with_rx(unsafe { board::pin(device.rx) });
+            let config = uart::Config::default().with_baudrate(device.baud_rate);
+            if let Err(err) = uart.apply_config(&config) {
+                warn!("UART bus: {} baud: {:?}", device.baud_rate, err);
+            }
+
+            debug!("UART bus: switched to {}", device);
+            port.uart = Some(uart);
+            port.current = Some(*device);
+        }
+
+        Guard {
+            port,
+            timeout: device.timeout,
+        }
+    }
+}
+
+impl Guard<'_> {
+    fn uart(&mut self) -> &mut Uart<'static, Async> {
+        self.port.uart.as_mut().unwrap()
+    }
+
+    pub async fn write(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        embedded_io_async::Write::write_all(self.uart(), bytes)
+            .await
+            .map_err(|_| Error::Io)
+    }
+
+    /// Reads the next `frame` into `buf`, skipping whatever comes before its
+    /// header. Anything left over from earlier is dropped first.
+    pub async fn read_frame<'b>(
+        &mut self,
+        frame: Frame,
+        buf: &'b mut [u8; MAX_FRAME],
+    ) -> Result<&'b [u8], Error> {
+        if frame.len > MAX_FRAME || frame.header.len() > frame.len {
+            return Err(Error::FrameLen);
+        }
+
+        let timeout = self.timeout;
+        let uart = self.uart();
+        let mut stale = [0u8; MAX_FRAME];
+        while uart.read_ready() {
+            uart.read_buffered(&mut stale).map_err(|_| Error::Io)?;
+        }
+
+        with_timeout(timeout, async move {
+            let mut matched = 0;
+            while matched < frame.header.len() {
+                let mut byte = [0u8];
+                embedded_io_async::Read::read_exact(uart, &mut byte)
+                    .await
+                    .map_err(|_| Error::Io)?;
+
+                matched = if byte[0] == frame.header[matched] {
+                    matched + 1
+                } else {
+                    usize::from(byte[0] == frame.header[0])
+                };
+            }
+
+            buf[..matched].copy_from_slice(frame.header);
+            embedded_io_async::Read::read_exact(uart, &mut buf[matched..frame.len])
+                .await
+                .map_err(|_| Error::Io)?;
+
+            Ok(&buf[..frame.len])
+        })
+        .await
+        .map_err(|_| Error::Timeout)?
+    }
+}
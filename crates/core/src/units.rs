@@ -0,0 +1,67 @@
+use serde::Deserialize;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, defmt::Format)]
+#[serde(rename_all = "lowercase")]
+pub enum Units {
+    #[default]
+    Metric,
+    Imperial,
+}
+
+impl Units {
+    pub fn temperature(&self, celsius: f32) -> f32 {
+        match self {
+            Units::Metric => celsius,
+            Units::Imperial => celsius_to_fahrenheit(celsius),
+        }
+    }
+
+    pub fn pressure(&self, hpa: f32) -> f32 {
+        match self {
+            Units::Metric => hpa,
+            Units::Imperial => hpa_to_inhg(hpa),
+        }
+    }
+
+    pub fn temperature_symbol(&self) -> &'static str {
+        match self {
+            Units::Metric => "C",
+            Units::Imperial => "F",
+        }
+    }
+
+    pub fn pressure_symbol(&self) -> &'static str {
+        match self {
+            Units::Metric => "hPa",
+            Units::Imperial => "inHg",
+        }
+    }
+}
+
+impl From<u8> for Units {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => Units::Imperial,
+            _ => Units::Metric,
+        }
+    }
+}
+
+impl From<Units> for u8 {
+    fn from(value: Units) -> Self {
+        match value {
+            Units::Metric => 0,
+            Units::Imperial => 1,
+        }
+    }
+}
+
+pub fn celsius_to_fahrenheit(celsius: f32) -> f32 {
+    celsius * 9.0 / 5.0 + 32.0
+}
+
+pub fn hpa_to_inhg(hpa: f32) -> f32 {
+    const INHG_PER_HPA: f32 = 0.029_529_983;
+
+    hpa * INHG_PER_HPA
+}
@@ -0,0 +1,18 @@
+//! What the firmware was built from, for auditing what a node runs.
+
+/// Short hash of the commit the firmware was built from.
+pub const GIT_HASH: &str = env!("GIT_HASH");
+
+#[cfg(feature = "esp32c6")]
+pub const CHIP: &str = "esp32c6";
+#[cfg(feature = "esp32s3")]
+pub const CHIP: &str = "esp32s3";
+
+pub const FEATURES: &[&str] = &[
+    #[cfg(feature = "display")]
+    "display",
+    #[cfg(feature = "display-128x64")]
+    "display-128x64",
+    #[cfg(feature = "display-sh1106")]
+    "display-sh1106",
+];
@@ -0,0 +1,105 @@
+//! Hardware watchdog that is only fed while the watched tasks keep checking
+//! in, so a hung I2C transaction or a stuck socket reboots the node instead
+//! of freezing it.
+//!
+//! An external watchdog IC on [`Pins::external_watchdog`] is fed along with
+//! it. It resets the node even when the chip itself is too far gone for its
+//! own watchdog, and it keeps counting through deep sleep, so its interval
+//! has to be longer than the sleep.
+//!
+//! [`Pins::external_watchdog`]: crate::board::Pins::external_watchdog
+
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicU8, AtomicU32, Ordering};
+
+use defmt::{error, info};
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_time::{Duration, Instant, Timer};
+use esp_hal::gpio::{Level, Output, OutputConfig};
+use esp_hal::peripherals::TIMG1;
+use esp_hal::timer::timg::{MwdtStage, Wdt};
+
+use crate::board;
+
+/// The node resets this long after the last feed.
+const HARDWARE_TIMEOUT_SECS: u64 = 30;
+const FEED_INTERVAL: Duration = Duration::from_secs(5);
+/// Longest a watched task may go without checking in.
+const CHECK_IN_TIMEOUT_SECS: u32 = 3 * 60;
+
+#[derive(Clone, Copy, defmt::Format)]
+pub enum Task {
+    Sensors,
+    Mqtt,
+    Wifi,
+}
+
+const TASKS: [Task; 3] = [Task::Sensors, Task::Mqtt, Task::Wifi];
+
+/// Bit per task that checked in at least once.
+static WATCHED: AtomicU8 = AtomicU8::new(0);
+/// Seconds since boot of the last check-in of every task.
+static LAST_CHECK_IN: [AtomicU32; TASKS.len()] = [const { AtomicU32::new(0) }; TASKS.len()];
+static EXTERNAL: Mutex<CriticalSectionRawMutex, RefCell<Option<Output<'static>>>> =
+    Mutex::new(RefCell::new(None));
+
+/// Tells the watchdog the task is alive. The first check-in puts the task
+/// under watch.
+pub fn check_in(task: Task) {
+    LAST_CHECK_IN[task as usize].store(Instant::now().as_secs() as u32, Ordering::Relaxed);
+    WATCHED.fetch_or(1 << task as u8, Ordering::Relaxed);
+}
+
+/// Feeds the external watchdog on `pin` from now on.
+pub fn use_external(pin: u8) {
+    let done = Output::new(
+        unsafe { board::pin(pin) },
+        Level::Low,
+        OutputConfig::default(),
+    );
+    EXTERNAL.lock(|external| external.replace(Some(done)));
+    info!("Watchdog: external one on GPIO{}", pin);
+}
+
+/// A rising edge on DONE, the TPL5010 wants at least 100 ns high.
+fn feed_external() {
+    EXTERNAL.lock(|external| {
+        if let Some(done) = external.borrow_mut().as_mut() {
+            done.set_high();
+            esp_hal::delay::Delay::new().delay_micros(1);
+            done.set_low();
+        }
+    });
+}
+
+/// First watched task that hasn't checked in for too long.
+fn stalled() -> Option<Task> {
+    let now = Instant::now().as_secs() as u32;
+    let watched = WATCHED.load(Ordering::Relaxed);
+
+    TASKS.into_iter().find(|task| {
+        let last = LAST_CHECK_IN[*task as usize].load(Ordering::Relaxed);
+        watched & (1 << *task as u8) != 0 && now.saturating_sub(last) > CHECK_IN_TIMEOUT_SECS
+    })
+}
+
+#[embassy_executor::task]
+pub async fn task(mut wdt: Wdt<TIMG1<'static>>) -> ! {
+    wdt.set_timeout(
+        MwdtStage::Stage0,
+        esp_hal::time::Duration::from_secs(HARDWARE_TIMEOUT_SECS),
+    );
+    wdt.enable();
+
+    loop {
+        match stalled() {
+            None => {
+                wdt.feed();
+                feed_external();
+            }
+            Some(task) => error!("Watchdog: {} stopped checking in, resetting", task),
+        }
+
+        Timer::after(FEED_INTERVAL).await;
+    }
+}
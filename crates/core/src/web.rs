@@ -1,24 +1,131 @@
-use core::sync::atomic::Ordering;
+use core::sync::atomic::{AtomicU32, Ordering};
 use defmt::Debug2Format;
-use embassy_net::Stack;
-use picoserve::{AppBuilder, AppRouter, extract::Form, response::File};
+use embassy_net::{Stack, tcp::TcpSocket};
+use heapless::String;
+use picoserve::{
+    AppBuilder, AppRouter,
+    extract::Form,
+    response::{File, Json, StatusCode},
+};
 use static_cell::StaticCell;
 
-use crate::{config::SettingsEnum, kv_storage};
+use crate::{
+    board,
+    config::{
+        Co2Calibration, GasHeater, I2cDebug, LargeMetric, LedMode, MqttTransport, PayloadFormat,
+        PowerProfile, Rotation, SdFormat, Settings, SettingsEnum, StripMode,
+    },
+    diagnostics, event_log, i2c_debug, kv_storage, led,
+    provisioning::{self, Channel},
+    schedule::NightMode,
+    self_test, senml, sensors, syslog,
+    units::Units,
+};
 
 extern crate alloc;
 
-pub const WEB_TASK_POOL_SIZE: usize = 2;
+/// Most connections the settings can ask the web server to take at once.
+pub const WEB_TASK_POOL_SIZE: usize = 4;
+/// What the buffers of all the connections may take of the heap. The C6 has
+/// 136 KiB of it, and the WiFi and BLE need most.
+const HEAP_BUDGET: usize = 16 * 1024;
 static INDEX_PAGE: StaticCell<alloc::string::String> = StaticCell::new();
+static CLIENT_ID: StaticCell<alloc::string::String> = StaticCell::new();
+static PASSWORDS: StaticCell<Passwords> = StaticCell::new();
+
+/// What the routes that change something answer outside the setup mode,
+/// the station LAN gets to look only.
+const SETUP_ONLY: (StatusCode, &str) = (StatusCode::FORBIDDEN, "Only on the setup access point\n");
+
+/// Opens the settings session of the setup page, turned away while the
+/// console or MQTT has it.
+fn claim() -> Result<(), (StatusCode, &'static str)> {
+    provisioning::claim(Channel::Web).map_err(|busy| (StatusCode::CONFLICT, busy.message()))
+}
+
+static REQUESTS: AtomicU32 = AtomicU32::new(0);
+static CONNECTIONS: AtomicU32 = AtomicU32::new(0);
+static ACTIVE: AtomicU32 = AtomicU32::new(0);
+static ERRORS: AtomicU32 = AtomicU32::new(0);
+
+/// How many connections the web server takes at once and the buffers of
+/// each, from the settings. The buffers come from the heap.
+#[derive(Clone, Copy, serde::Serialize)]
+pub struct Capacity {
+    pub tasks: u8,
+    pub http_buffer: u16,
+    pub tcp_buffer: u16,
+}
+
+impl From<&Settings> for Capacity {
+    /// Fewer connections than the settings ask for when their buffers would
+    /// go over [`HEAP_BUDGET`].
+    fn from(settings: &Settings) -> Self {
+        let http_buffer = settings.web_http_buffer.clamp(1024, 4 * 1024);
+        let tcp_buffer = settings.web_tcp_buffer.clamp(512, 2 * 1024);
+        let per_connection = http_buffer as usize + 2 * tcp_buffer as usize;
+        let tasks = settings
+            .web_tasks
+            .clamp(1, WEB_TASK_POOL_SIZE as u8)
+            .min((HEAP_BUDGET / per_connection) as u8);
+
+        Self {
+            tasks,
+            http_buffer,
+            tcp_buffer,
+        }
+    }
+}
+
+/// What the web server did since boot, on `/api/web`.
+#[derive(serde::Serialize)]
+pub struct Metrics {
+    pub requests: u32,
+    pub connections: u32,
+    /// Connections being served now.
+    pub active: u32,
+    /// Connections that failed to be accepted or ended in an error.
+    pub errors: u32,
+    pub capacity: Capacity,
+}
+
+/// `/api/latest` in the configured payload format.
+#[derive(serde::Serialize)]
+#[serde(untagged)]
+enum Latest<'a> {
+    Json(Option<sensors::Sample>),
+    Senml(senml::Pack<'a>),
+}
+
+/// The stored passwords, never rendered into the form. An empty field
+/// keeps them.
+struct Passwords {
+    wifi: String<64>,
+    setup: String<64>,
+}
 
 pub struct App {
     pub db: &'static kv_storage::Db,
     settings: SettingsEnum,
+    capacity: Capacity,
+    /// Serving the setup access point, the only place the settings, the
+    /// pins, the registers and the restarts can be changed from.
+    setup: bool,
 }
 
 impl App {
-    pub fn new(db: &'static kv_storage::Db, settings: SettingsEnum) -> Self {
-        Self { db, settings }
+    pub fn new(
+        db: &'static kv_storage::Db,
+        settings: SettingsEnum,
+        capacity: Capacity,
+        setup: bool,
+    ) -> Self {
+        Self {
+            db,
+            settings,
+            capacity,
+            setup,
+        }
     }
 }
 
@@ -27,24 +134,458 @@ impl picoserve::AppBuilder for App {
 
     fn build_app(self) -> picoserve::Router<Self::PathRouter> {
         let db = self.db;
+        let capacity = self.capacity;
+        let setup = self.setup;
         let template = include_str!("../../../html/index.html");
+        // Complete settings the node can go back to.
+        let reconfigure = matches!(
+            &self.settings,
+            SettingsEnum::FilledIn(settings) if settings.reboot_to_reconfigure
+        );
         let settings = self.settings.to_filled_in_with_default();
 
-        let index_page = template
+        // The form is only served on the setup access point, no need to fill
+        // it in for the station LAN.
+        let index_page = if setup { template } else { "" }
+            .replace(
+                "%_reconfigure_hidden_%",
+                if reconfigure { "" } else { "hidden" },
+            )
             .replace("%_wifi_ssid_%", &settings.wifi_ssid)
-            .replace("%_wifi_password_%", &settings.wifi_password)
+            .replace("%_setup_suffix_%", &settings.setup_suffix)
+            .replace(
+                "%_setup_channel_%",
+                &alloc::format!("{}", settings.setup_channel),
+            )
+            .replace("%_web_tasks_%", &alloc::format!("{}", settings.web_tasks))
+            .replace(
+                "%_web_http_buffer_%",
+                &alloc::format!("{}", settings.web_http_buffer),
+            )
+            .replace(
+                "%_web_tcp_buffer_%",
+                &alloc::format!("{}", settings.web_tcp_buffer),
+            )
             .replace("%_mqtt_broker_%", &settings.mqtt_broker)
             .replace("%_mqtt_client_id_%", &settings.mqtt_client_id)
-            .replace("%_mqtt_topic_%", &settings.mqtt_topic);
+            .replace("%_mqtt_topic_%", &settings.mqtt_topic)
+            .replace(
+                "%_units_metric_%",
+                selected(settings.units == Units::Metric),
+            )
+            .replace(
+                "%_units_imperial_%",
+                selected(settings.units == Units::Imperial),
+            )
+            .replace(
+                "%_utc_offset_min_%",
+                &alloc::format!("{}", settings.utc_offset_min),
+            )
+            .replace(
+                "%_night_start_hour_%",
+                &alloc::format!("{}", settings.night_start_hour),
+            )
+            .replace(
+                "%_night_end_hour_%",
+                &alloc::format!("{}", settings.night_end_hour),
+            )
+            .replace(
+                "%_led_brightness_%",
+                &alloc::format!("{}", settings.led_brightness),
+            )
+            .replace(
+                "%_led_mode_pattern_%",
+                selected(settings.led_mode == LedMode::Pattern),
+            )
+            .replace(
+                "%_led_mode_air_%",
+                selected(settings.led_mode == LedMode::Air),
+            )
+            .replace(
+                "%_i2c_debug_off_%",
+                selected(settings.i2c_debug == I2cDebug::Off),
+            )
+            .replace(
+                "%_i2c_debug_read_%",
+                selected(settings.i2c_debug == I2cDebug::Read),
+            )
+            .replace(
+                "%_i2c_debug_write_%",
+                selected(settings.i2c_debug == I2cDebug::Write),
+            )
+            .replace(
+                "%_led_night_disabled_%",
+                selected(settings.led_night == NightMode::Disabled),
+            )
+            .replace(
+                "%_led_night_dim_%",
+                selected(settings.led_night == NightMode::Dim),
+            )
+            .replace(
+                "%_led_night_off_%",
+                selected(settings.led_night == NightMode::Off),
+            )
+            .replace(
+                "%_sleep_minutes_%",
+                &alloc::format!("{}", settings.sleep_minutes),
+            )
+            .replace(
+                "%_power_profile_mains_%",
+                selected(settings.power_profile == PowerProfile::Mains),
+            )
+            .replace(
+                "%_power_profile_frugal_%",
+                selected(settings.power_profile == PowerProfile::Frugal),
+            )
+            .replace(
+                "%_power_profile_battery_%",
+                selected(settings.power_profile == PowerProfile::Battery),
+            )
+            .replace(
+                "%_power_profile_solar_%",
+                selected(settings.power_profile == PowerProfile::Solar),
+            )
+            .replace(
+                "%_supply_low_mv_%",
+                &alloc::format!("{}", settings.supply_low_mv),
+            )
+            .replace(
+                "%_gas_heater_always_%",
+                selected(settings.gas_heater == GasHeater::Always),
+            )
+            .replace(
+                "%_gas_heater_saving_%",
+                selected(settings.gas_heater == GasHeater::Saving),
+            )
+            .replace(
+                "%_co2_calibration_automatic_%",
+                selected(settings.co2_calibration == Co2Calibration::Automatic),
+            )
+            .replace(
+                "%_co2_calibration_off_%",
+                selected(settings.co2_calibration == Co2Calibration::Off),
+            )
+            .replace(
+                "%_mqtt_transport_tcp_%",
+                selected(settings.mqtt_transport == MqttTransport::Tcp),
+            )
+            .replace(
+                "%_mqtt_transport_sn_%",
+                selected(settings.mqtt_transport == MqttTransport::Sn),
+            )
+            .replace(
+                "%_mqtt_transport_lora_%",
+                selected(settings.mqtt_transport == MqttTransport::Lora),
+            )
+            .replace(
+                "%_lora_frequency_khz_%",
+                &alloc::format!("{}", settings.lora_frequency_khz),
+            )
+            .replace(
+                "%_mqtt_keep_alive_secs_%",
+                &alloc::format!("{}", settings.mqtt_keep_alive_secs),
+            )
+            .replace(
+                "%_payload_format_json_%",
+                selected(settings.payload_format == PayloadFormat::Json),
+            )
+            .replace(
+                "%_payload_format_senml_%",
+                selected(settings.payload_format == PayloadFormat::Senml),
+            )
+            .replace(
+                "%_sd_format_csv_%",
+                selected(settings.sd_format == SdFormat::Csv),
+            )
+            .replace(
+                "%_sd_format_postcard_%",
+                selected(settings.sd_format == SdFormat::Postcard),
+            )
+            .replace("%_mqtt_fields_%", &settings.mqtt_fields)
+            .replace("%_ntp_servers_%", &settings.ntp_servers)
+            .replace("%_location_%", &settings.location)
+            .replace("%_room_%", &settings.room)
+            .replace("%_labels_%", &settings.labels)
+            .replace("%_presence_beacons_%", &settings.presence_beacons)
+            .replace("%_adc0_map_%", &settings.adc0_map)
+            .replace("%_adc1_map_%", &settings.adc1_map)
+            .replace("%_wind_pulses_%", &settings.wind_pulses)
+            .replace("%_rain_pulses_%", &settings.rain_pulses)
+            .replace("%_sensor_warm_up_%", &settings.sensor_warm_up)
+            .replace("%_sensor_filter_%", &settings.sensor_filter)
+            .replace("%_calibration_%", &settings.calibration)
+            .replace("%_ota_url_%", &settings.ota_url)
+            .replace("%_syslog_host_%", &settings.syslog_host)
+            .replace("%_relay_rule_1_%", &settings.relay_rule_1)
+            .replace("%_relay_rule_2_%", &settings.relay_rule_2)
+            .replace("%_alert_rules_%", &settings.alert_rules)
+            .replace("%_input_1_%", &settings.input_1)
+            .replace("%_input_2_%", &settings.input_2)
+            .replace(
+                "%_motion_hold_secs_%",
+                &alloc::format!("{}", settings.motion_hold_secs),
+            )
+            .replace(
+                "%_ota_check_hours_%",
+                &alloc::format!("{}", settings.ota_check_hours),
+            )
+            .replace("%_led_count_%", &alloc::format!("{}", settings.led_count))
+            .replace(
+                "%_led_strip_status_%",
+                selected(settings.led_strip == StripMode::Status),
+            )
+            .replace(
+                "%_led_strip_subsystems_%",
+                selected(settings.led_strip == StripMode::Subsystems),
+            )
+            .replace(
+                "%_led_strip_temperature_%",
+                selected(settings.led_strip == StripMode::Temperature),
+            )
+            .replace(
+                "%_led_strip_humidity_%",
+                selected(settings.led_strip == StripMode::Humidity),
+            )
+            .replace(
+                "%_led_strip_light_%",
+                selected(settings.led_strip == StripMode::Light),
+            )
+            .replace(
+                "%_led_strip_pressure_%",
+                selected(settings.led_strip == StripMode::Pressure),
+            )
+            .replace(
+                "%_led_strip_air_%",
+                selected(settings.led_strip == StripMode::Air),
+            )
+            .replace(
+                "%_display_contrast_%",
+                &alloc::format!("{}", settings.display_contrast),
+            )
+            .replace(
+                "%_display_night_disabled_%",
+                selected(settings.display_night == NightMode::Disabled),
+            )
+            .replace(
+                "%_display_night_dim_%",
+                selected(settings.display_night == NightMode::Dim),
+            )
+            .replace(
+                "%_display_night_off_%",
+                selected(settings.display_night == NightMode::Off),
+            )
+            .replace(
+                "%_display_large_off_%",
+                selected(settings.display_large == LargeMetric::Off),
+            )
+            .replace(
+                "%_display_large_cycle_%",
+                selected(settings.display_large == LargeMetric::Cycle),
+            )
+            .replace(
+                "%_display_large_temperature_%",
+                selected(settings.display_large == LargeMetric::Temperature),
+            )
+            .replace(
+                "%_display_large_humidity_%",
+                selected(settings.display_large == LargeMetric::Humidity),
+            )
+            .replace(
+                "%_display_large_light_%",
+                selected(settings.display_large == LargeMetric::Light),
+            )
+            .replace(
+                "%_display_large_pressure_%",
+                selected(settings.display_large == LargeMetric::Pressure),
+            )
+            .replace(
+                "%_display_rotation_0_%",
+                selected(settings.display_rotation == Rotation::Rotate0),
+            )
+            .replace(
+                "%_display_rotation_90_%",
+                selected(settings.display_rotation == Rotation::Rotate90),
+            )
+            .replace(
+                "%_display_rotation_180_%",
+                selected(settings.display_rotation == Rotation::Rotate180),
+            )
+            .replace(
+                "%_display_rotation_270_%",
+                selected(settings.display_rotation == Rotation::Rotate270),
+            );
 
-        let page: &'static str = INDEX_PAGE.init(index_page).as_str();
+        // Away from the setup access point the form couldn't be saved anyway.
+        let page: &'static str = if setup {
+            INDEX_PAGE.init(index_page).as_str()
+        } else {
+            include_str!("../../../html/system.html")
+        };
+        let passwords: &'static Passwords = PASSWORDS.init(Passwords {
+            wifi: settings.wifi_password.clone(),
+            setup: settings.setup_password.clone(),
+        });
+        let client_id: &'static str = CLIENT_ID
+            .init(alloc::string::String::from(
+                settings.mqtt_client_id.as_str(),
+            ))
+            .as_str();
+        let payload_format = settings.payload_format;
 
         picoserve::Router::new()
             .route("/", picoserve::routing::get_service(File::html(&page)))
+            .route(
+                "/identify",
+                picoserve::routing::post(move || async move {
+                    if !setup {
+                        return Err(SETUP_ONLY);
+                    }
+                    led::identify(led::IDENTIFY_DURATION);
+                    Ok(())
+                }),
+            )
+            .route(
+                "/system",
+                picoserve::routing::get_service(File::html(include_str!(
+                    "../../../html/system.html"
+                ))),
+            )
+            .route(
+                "/api/system",
+                picoserve::routing::get(|| async { Json(diagnostics::latest()) }),
+            )
+            .route(
+                "/api/web",
+                picoserve::routing::get(move || async move {
+                    Json(Metrics {
+                        requests: REQUESTS.load(Ordering::Relaxed),
+                        connections: CONNECTIONS.load(Ordering::Relaxed),
+                        active: ACTIVE.load(Ordering::Relaxed),
+                        errors: ERRORS.load(Ordering::Relaxed),
+                        capacity,
+                    })
+                }),
+            )
+            .route(
+                "/api/i2cscan",
+                picoserve::routing::get(|| async { Json(sensors::scan::table()) }),
+            )
+            .route(
+                "/api/selftest",
+                picoserve::routing::get(|| async { Json(self_test::report()) }),
+            )
+            .route(
+                "/api/health",
+                picoserve::routing::get(|| async { Json(sensors::health::report()) }),
+            )
+            .route(
+                "/api/board",
+                picoserve::routing::get(|| async { Json(board::pins()) }),
+            )
+            .route(
+                "/api/session",
+                picoserve::routing::get(|| async { Json(provisioning::status()) }),
+            )
+            .route(
+                "/board",
+                picoserve::routing::post(move |Form(pins): Form<board::Pins>| async move {
+                    if !setup {
+                        return Err(SETUP_ONLY);
+                    }
+                    // A pin the chip lacks could keep the node from booting.
+                    if let Err(err) = pins.check() {
+                        defmt::warn!("Pins not saved: {}", err);
+                        return Err((StatusCode::BAD_REQUEST, "Unusable pins\n"));
+                    }
+                    claim()?;
+                    match board::save(db, &pins).await {
+                        Err(err) => defmt::error!("Saving the pins failed: {}", err),
+                        Ok(()) => {
+                            event_log::append(db, event_log::Kind::ConfigChanged).await;
+                            crate::system::NEED_REBOOT.store(true, Ordering::SeqCst);
+                        }
+                    }
+                    provisioning::release(Channel::Web);
+                    Ok(())
+                }),
+            )
+            .route(
+                "/log",
+                picoserve::routing::get_service(File::html(include_str!("../../../html/log.html"))),
+            )
+            .route(
+                "/api/log",
+                picoserve::routing::get(|| async { Json(syslog::recent()) }),
+            )
+            .route(
+                "/api/co2",
+                picoserve::routing::get(|| async { Json(sensors::co2::status().await) }),
+            )
+            .route(
+                "/api/i2c",
+                picoserve::routing::post(
+                    move |Form(request): Form<i2c_debug::Request>| async move {
+                        if !setup {
+                            return Err(SETUP_ONLY);
+                        }
+                        Ok(Json(i2c_debug::run(request).await))
+                    },
+                ),
+            )
+            .route(
+                "/api/events",
+                picoserve::routing::get(|| async { Json(event_log::entries().await) }),
+            )
+            .route(
+                "/cancel-reconfigure",
+                picoserve::routing::post(move || async move {
+                    if !setup {
+                        return Err(SETUP_ONLY);
+                    }
+                    claim()?;
+                    match crate::config::cancel_reconfigure(db).await {
+                        Err(err) => defmt::error!("Cancelling the reconfiguration failed: {}", err),
+                        Ok(()) => crate::system::NEED_REBOOT.store(true, Ordering::SeqCst),
+                    }
+                    provisioning::release(Channel::Web);
+                    Ok(())
+                }),
+            )
+            .route(
+                "/reboot",
+                picoserve::routing::post(move || async move {
+                    if !setup {
+                        return Err(SETUP_ONLY);
+                    }
+                    crate::system::NEED_REBOOT.store(true, Ordering::SeqCst);
+                    Ok(())
+                }),
+            )
+            .route(
+                "/api/latest",
+                picoserve::routing::get(move || async move {
+                    match (payload_format, sensors::latest()) {
+                        (PayloadFormat::Senml, Some(sample)) => {
+                            Json(Latest::Senml(senml::Pack::new(sample, client_id)))
+                        }
+                        (_, sample) => Json(Latest::Json(sample)),
+                    }
+                }),
+            )
             .route(
                 "/save",
                 picoserve::routing::post(
-                    move |Form(data): Form<crate::config::Settings>| async move {
+                    move |Form(mut data): Form<crate::config::Settings>| async move {
+                        if !setup {
+                            return Err(SETUP_ONLY);
+                        }
+                        claim()?;
+                        if data.wifi_password.is_empty() {
+                            data.wifi_password = passwords.wifi.clone();
+                        }
+                        if data.setup_password.is_empty() {
+                            data.setup_password = passwords.setup.clone();
+                        }
                         match crate::config::save_settings(db, &data).await {
                             Err(err) => {
                                 defmt::error!("Saving error: {}", err);
@@ -52,30 +593,47 @@ impl picoserve::AppBuilder for App {
                             }
                             Ok(_) => {
                                 defmt::info!("Saved!");
+                                event_log::append(db, event_log::Kind::ConfigChanged).await;
                                 crate::system::NEED_REBOOT.store(true, Ordering::SeqCst);
                             }
                         }
+                        provisioning::release(Channel::Web);
+                        Ok(())
                     },
                 ),
             )
     }
 }
 
+fn selected(is_selected: bool) -> &'static str {
+    if is_selected { "selected" } else { "" }
+}
+
 pub struct WebApp {
     pub router: &'static picoserve::Router<<App as picoserve::AppBuilder>::PathRouter>,
     pub config: &'static picoserve::Config,
+    pub capacity: Capacity,
 }
 
 impl WebApp {
-    pub fn new(db: &'static kv_storage::Db, settings: SettingsEnum) -> Self {
-        let router = picoserve::make_static!(AppRouter<App>, App::new(db, settings).build_app());
+    /// The write routes answer only when serving the `setup` access point.
+    pub fn new(db: &'static kv_storage::Db, settings: SettingsEnum, setup: bool) -> Self {
+        let capacity = Capacity::from(&settings.clone().to_filled_in_with_default());
+        let router = picoserve::make_static!(
+            AppRouter<App>,
+            App::new(db, settings, capacity, setup).build_app()
+        );
 
         let config = picoserve::make_static!(
             picoserve::Config,
             picoserve::Config::const_default().keep_connection_alive()
         );
 
-        Self { router, config }
+        Self {
+            router,
+            config,
+            capacity,
+        }
     }
 }
 
@@ -85,14 +643,37 @@ pub async fn task(
     stack: Stack<'static>,
     router: &'static picoserve::AppRouter<App>,
     config: &'static picoserve::Config,
+    capacity: Capacity,
 ) -> ! {
     let port = 80;
-    let mut tcp_rx_buf = [0; 1024];
-    let mut tcp_tx_buf = [0; 1024];
-    let mut http_buf = [0; 2048];
-
-    picoserve::Server::new(router, &*config, &mut http_buf)
-        .listen_and_serve(task_id, stack, port, &mut tcp_rx_buf, &mut tcp_tx_buf)
-        .await
-        .into_never()
+    let mut tcp_rx_buf = alloc::vec![0u8; capacity.tcp_buffer as usize];
+    let mut tcp_tx_buf = alloc::vec![0u8; capacity.tcp_buffer as usize];
+    let mut http_buf = alloc::vec![0u8; capacity.http_buffer as usize];
+
+    // What `listen_and_serve` does, with the connections counted.
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut tcp_rx_buf, &mut tcp_tx_buf);
+        if let Err(err) = socket.accept(port).await {
+            defmt::warn!("Web {}: accepting failed: {}", task_id, err);
+            ERRORS.fetch_add(1, Ordering::Relaxed);
+            continue;
+        }
+
+        CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+        ACTIVE.fetch_add(1, Ordering::Relaxed);
+        let served = picoserve::Server::new(router, config, &mut http_buf)
+            .serve(socket)
+            .await;
+        ACTIVE.fetch_sub(1, Ordering::Relaxed);
+
+        match served {
+            Ok(info) => {
+                REQUESTS.fetch_add(info.handled_requests_count as u32, Ordering::Relaxed);
+            }
+            Err(err) => {
+                defmt::warn!("Web {}: {}", task_id, Debug2Format(&err));
+                ERRORS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
 }
@@ -1,34 +1,205 @@
+extern crate alloc;
+
+use core::fmt::Write;
+use core::net::Ipv4Addr;
+use core::sync::atomic::{AtomicBool, AtomicI32, AtomicU8, Ordering};
+
 use defmt::{error, info, warn};
+use embassy_futures::select::{Either, select};
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
-use embassy_time::Timer;
-use esp_radio::wifi::{ClientConfig, PowerSaveMode, WifiError};
+use embassy_time::{Duration, Timer, with_timeout};
+use esp_hal::efuse::Efuse;
+use esp_radio::wifi::{
+    AccessPointConfig, AuthMethod, ClientConfig, PowerSaveMode, ScanConfig, WifiError,
+};
+use heapless::{String, Vec};
+
+use crate::config::{self, SettingsEnum};
+use crate::syslog::{self, Severity};
+use crate::event_log::{self, Kind};
+use crate::{power, system, watchdog};
+
+/// Start of the SSID of the access point started in setup mode, the suffix
+/// of the settings or the end of the MAC address follows.
+pub const SETUP_SSID_PREFIX: &str = "esp32-setup-";
+/// Address of the node (and of its DHCP and web servers) in setup mode.
+pub const SETUP_ADDRESS: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 1);
+
+pub static CONNECTED: AtomicBool = AtomicBool::new(false);
+/// Got an address from the router.
+pub static CONFIGURED: AtomicBool = AtomicBool::new(false);
+pub static RSSI: AtomicI32 = AtomicI32::new(0);
+/// Number of stations connected to the setup access point.
+pub static SETUP_CLIENTS: AtomicU8 = AtomicU8::new(0);
+
+/// The access point of setup mode. Open until the settings give it a
+/// password, a node that was never set up has nothing to protect yet.
+#[derive(Clone)]
+pub struct SetupAp {
+    pub ssid: String<32>,
+    /// WPA2, none when empty.
+    pub password: String<64>,
+    pub channel: u8,
+}
+
+impl SetupAp {
+    pub fn from_settings(settings: &SettingsEnum) -> Self {
+        let settings = settings.clone().to_filled_in_with_default();
+
+        let mut ssid = String::new();
+        let _ = ssid.push_str(SETUP_SSID_PREFIX);
+        if settings.setup_suffix.is_empty() {
+            let mac = Efuse::mac_address();
+            let _ = write!(ssid, "{:02x}{:02x}{:02x}", mac[3], mac[4], mac[5]);
+        } else {
+            let _ = ssid.push_str(&settings.setup_suffix);
+        }
 
-pub static UP: Signal<CriticalSectionRawMutex, ()> = Signal::new();
-pub static DOWN: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+        let mut password = settings.setup_password;
+        if !password.is_empty() && password.len() < 8 {
+            warn!("WiFi: the setup password needs 8 characters, the access point is open");
+            password.clear();
+        }
+
+        let channel = if (1..=13).contains(&settings.setup_channel) {
+            settings.setup_channel
+        } else {
+            warn!(
+                "WiFi: no setup channel {}, using {}",
+                settings.setup_channel,
+                config::DEFAULT_SETUP_CHANNEL
+            );
+            config::DEFAULT_SETUP_CHANNEL
+        };
+
+        Self {
+            ssid,
+            password,
+            channel,
+        }
+    }
+
+    pub fn config(&self) -> AccessPointConfig {
+        let config = AccessPointConfig::default()
+            .with_ssid(self.ssid.as_str().into())
+            .with_channel(self.channel);
+
+        if self.password.is_empty() {
+            config
+        } else {
+            config
+                .with_auth_method(AuthMethod::Wpa2Personal)
+                .with_password(self.password.as_str().into())
+        }
+    }
+
+    /// The text of the QR code that joins the access point.
+    pub fn qr_code(&self) -> alloc::string::String {
+        let mut text = alloc::string::String::from("WIFI:");
+        if !self.password.is_empty() {
+            text.push_str("T:WPA;");
+        }
+        text.push_str("S:");
+        escape(&mut text, &self.ssid);
+        if !self.password.is_empty() {
+            text.push_str(";P:");
+            escape(&mut text, &self.password);
+        }
+        text.push_str(";;");
+
+        text
+    }
+}
+
+/// The QR code of a network wants a backslash before these.
+fn escape(text: &mut alloc::string::String, value: &str) {
+    for c in value.chars() {
+        if matches!(c, '\\' | ';' | ',' | ':' | '"') {
+            text.push('\\');
+        }
+        text.push(c);
+    }
+}
+
+/// Asks the WiFi task for a scan, it only gets to it while connected.
+pub static SCAN_REQUEST: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+pub static SCAN_RESULT: Signal<CriticalSectionRawMutex, Vec<AccessPoint, MAX_SCAN_RESULTS>> =
+    Signal::new();
+
+pub const MAX_SCAN_RESULTS: usize = 16;
+
+/// Consecutive failed connection attempts after which the WiFi is reported
+/// as down.
+const FAULT_ATTEMPTS: u32 = 10;
+/// How long the router gets to hand out an address before it's reported.
+const DHCP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Reason of the last disconnect, as reported by the WiFi driver.
+static DISCONNECT_REASON: AtomicU8 = AtomicU8::new(0);
+
+pub struct AccessPoint {
+    pub ssid: String<32>,
+    pub channel: u8,
+    pub rssi: i8,
+}
+/// Disconnect reasons meaning the password is wrong: 4-way handshake
+/// timeout, auth expired, auth failed and handshake timeout.
+const AUTH_REASONS: [u8; 4] = [15, 2, 202, 204];
 
 #[embassy_executor::task]
 pub async fn task(mut wifi: esp_radio::wifi::WifiController<'static>, ssid: &'static str, password: &'static str) -> ! {
     setup(&mut wifi, ssid, password).await;
 
     let mut backoff = 1u64;
+    let mut failures = 0u32;
 
     loop {
+        watchdog::check_in(watchdog::Task::Wifi);
+
         if wifi.is_connected().ok().unwrap_or_default() {
-            UP.signal(());
+            if let Ok(rssi) = wifi.rssi() {
+                RSSI.store(rssi, Ordering::Relaxed);
+            }
+
+            set_up();
             backoff = 1;
-            Timer::after_secs(5).await;
+            failures = 0;
+            if let Either::Second(()) = select(Timer::after_secs(5), SCAN_REQUEST.wait()).await {
+                SCAN_RESULT.signal(scan(&mut wifi).await);
+            }
             continue;
         }
 
+        set_down();
+
         info!("WiFi: connecting...");
         match wifi.connect_async().await {
             Ok(_) => {
                 info!("WiFI: connected");
-                UP.signal(());
+                set_up();
                 backoff = 1;
+                failures = 0;
             }
             Err(err) => {
                 warn!("WiFi error: {:?}", err);
+
+                if power::forget_access_point() {
+                    info!("WiFi: fast connect failed, scanning");
+                    if let Err(err) = wifi.set_config(&client_config(ssid, password)) {
+                        print_wifi_error(err);
+                    }
+                }
+
+                failures += 1;
+                if failures == FAULT_ATTEMPTS {
+                    let reason = DISCONNECT_REASON.load(Ordering::Relaxed);
+                    system::report_fault(if AUTH_REASONS.contains(&reason) {
+                        system::Fault::WifiAuth
+                    } else {
+                        system::Fault::WifiDown
+                    });
+                }
+
                 Timer::after_secs(backoff).await;
                 backoff = (backoff * 2).min(30);
             }
@@ -36,17 +207,120 @@ pub async fn task(mut wifi: esp_radio::wifi::WifiController<'static>, ssid: &'st
     }
 }
 
+/// The strongest access points around, none when the scan fails.
+async fn scan(
+    wifi: &mut esp_radio::wifi::WifiController<'static>,
+) -> Vec<AccessPoint, MAX_SCAN_RESULTS> {
+    let mut found = match wifi.scan_with_config_async(ScanConfig::default()).await {
+        Ok(found) => found,
+        Err(err) => {
+            print_wifi_error(err);
+            return Vec::new();
+        }
+    };
+    found.sort_unstable_by_key(|ap| core::cmp::Reverse(ap.signal_strength));
+
+    found
+        .iter()
+        .take(MAX_SCAN_RESULTS)
+        .map(|ap| AccessPoint {
+            ssid: String::try_from(ap.ssid.as_str()).unwrap_or_default(),
+            channel: ap.channel,
+            rssi: ap.signal_strength,
+        })
+        .collect()
+}
+
+/// Waits for the address from the router, reporting a fault when it takes
+/// too long.
+pub async fn wait_config_up(stack: embassy_net::Stack<'_>) {
+    if with_timeout(DHCP_TIMEOUT, stack.wait_config_up()).await.is_err() {
+        system::report_fault(system::Fault::DhcpTimeout);
+        stack.wait_config_up().await;
+    }
+
+    CONFIGURED.store(true, Ordering::Relaxed);
+}
+
+/// Goes straight to the access point of the last connection when there is
+/// one from before a deep sleep.
+fn client_config(ssid: &str, password: &str) -> esp_radio::wifi::ModeConfig {
+    let config = ClientConfig::default()
+        .with_ssid(ssid.into())
+        .with_password(password.into())
+        .with_failure_retry_cnt(3);
+
+    let config = match power::last_access_point() {
+        Some((bssid, channel)) => config.with_bssid(bssid).with_channel(channel),
+        None => config,
+    };
+
+    esp_radio::wifi::ModeConfig::Client(config)
+}
+
+fn record_access_point() {
+    use esp_radio::wifi::event::{EventExt, StaConnected};
+
+    StaConnected::update_handler(|event| {
+        power::remember_access_point(event.bssid(), event.channel());
+    });
+}
+
+fn record_disconnect_reason() {
+    use esp_radio::wifi::event::{EventExt, StaDisconnected};
+
+    StaDisconnected::update_handler(|event| {
+        DISCONNECT_REASON.store(event.reason(), Ordering::Relaxed);
+    });
+}
+
+/// Keeps [`SETUP_CLIENTS`] up to date while the access point is running.
+pub fn count_setup_clients() {
+    use esp_radio::wifi::event::{ApStaConnected, ApStaDisconnected, EventExt};
+
+    ApStaConnected::update_handler(|_| {
+        SETUP_CLIENTS.fetch_add(1, Ordering::Relaxed);
+    });
+    ApStaDisconnected::update_handler(|_| {
+        let _ = SETUP_CLIENTS.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+            Some(count.saturating_sub(1))
+        });
+    });
+}
+
+/// Called every few seconds while the link is up, records only the
+/// connection itself.
+fn set_up() {
+    if !CONNECTED.swap(true, Ordering::Relaxed) {
+        syslog::log(Severity::Info, format_args!("WiFi connected"));
+        event_log::record(Kind::WifiUp);
+        // Not over the states MQTT and the rest moved on to since.
+        system::transition(
+            &[system::State::WifiConnecting, system::State::Booting],
+            system::State::Dhcp,
+        );
+    }
+}
+
+fn set_down() {
+    if CONNECTED.swap(false, Ordering::Relaxed) {
+        syslog::log(Severity::Warning, format_args!("WiFi disconnected"));
+        event_log::record(Kind::WifiDown);
+        system::set_state(system::State::WifiConnecting);
+    }
+}
+
 async fn setup(wifi: &mut esp_radio::wifi::WifiController<'static>, ssid: &'static str, password: &'static str) {
     info!("Setting up WiFi");
-    let wifi_config = esp_radio::wifi::ModeConfig::Client(
-        ClientConfig::default()
-            .with_ssid(ssid.into())
-            .with_password(password.into())
-            .with_failure_retry_cnt(3),
-    );
+    let wifi_config = client_config(ssid, password);
 
     info!("  Setting up WiFi power saving");
-    if let Err(err) = wifi.set_power_saving(PowerSaveMode::None) {
+    let power_saving = if power::wifi_power_save() {
+        PowerSaveMode::Maximum
+    } else {
+        PowerSaveMode::None
+    };
+    if let Err(err) = wifi.set_power_saving(power_saving) {
         print_wifi_error(err);
     };
 
@@ -54,6 +328,9 @@ async fn setup(wifi: &mut esp_radio::wifi::WifiController<'static>, ssid: &'stat
         print_wifi_error(err);
     };
 
+    record_disconnect_reason();
+    record_access_point();
+
     info!("  Starting up the WiFi controller");
     if let Err(err) = wifi.start_async().await {
         print_wifi_error(err);
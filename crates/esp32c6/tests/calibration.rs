@@ -0,0 +1,80 @@
+//! The calibration offsets and scales of the sample fields.
+//!
+//! You can run this using `cargo test` as usual.
+
+#![no_std]
+#![no_main]
+
+use panic_rtt_target as _;
+
+esp_bootloader_esp_idf::esp_app_desc!();
+
+#[cfg(test)]
+#[embedded_test::tests(executor = esp_rtos::embassy::Executor::new())]
+mod tests {
+    use defmt::{assert, assert_eq};
+    use sensors_node_core::sensors::Sample;
+    use sensors_node_core::sensors::calibration::{self, Change};
+
+    #[init]
+    fn init() {
+        let peripherals = esp_hal::init(esp_hal::Config::default());
+
+        let timg1 = esp_hal::timer::timg::TimerGroup::new(peripherals.TIMG1);
+        let sw_interrupt =
+            esp_hal::interrupt::software::SoftwareInterruptControl::new(peripherals.SW_INTERRUPT);
+        esp_rtos::start(timg1.timer0, sw_interrupt.software_interrupt0);
+
+        rtt_target::rtt_init_defmt!();
+    }
+
+    #[test]
+    fn parses_a_change() {
+        let change = Change::parse("temp_bme680 -1.5").unwrap();
+        let setting = calibration::change(change).unwrap();
+        assert_eq!(setting.as_str(), "temp_bme680:-1.5");
+
+        let change = Change::parse(" hum_sht40  2 1.02 ").unwrap();
+        let setting = calibration::change(change).unwrap();
+        assert_eq!(setting.as_str(), "temp_bme680:-1.5 hum_sht40:2:1.02");
+
+        assert!(Change::parse("temp_nowhere 1").is_none());
+        assert!(Change::parse("temp_bme680").is_none());
+        assert!(Change::parse("temp_bme680 warm").is_none());
+        assert!(Change::parse("temp_bme680 1 1 1").is_none());
+    }
+
+    // One test, the corrections are global.
+    #[test]
+    fn sets_applies_and_changes_the_corrections() {
+        calibration::set("temp_bme680:-1.5, hum_sht40:2:1.02 nothing:1 temp_sht40:cold");
+
+        let mut sample = Sample::default();
+        sample.temp_bme680 = Some(25.0);
+        sample.hum_sht40 = Some(50.0);
+        sample.temp_sht40 = Some(20.0);
+        calibration::apply(&mut sample);
+        assert_eq!(sample.temp_bme680, Some(23.5));
+        assert!((sample.hum_sht40.unwrap() - 53.0).abs() < 1e-4);
+        assert_eq!(sample.temp_sht40, Some(20.0));
+        assert_eq!(sample.hum_bme680, None);
+
+        let change = Change::parse("temp_sht40 0.5").unwrap();
+        let setting = calibration::change(change).unwrap();
+        assert_eq!(
+            setting.as_str(),
+            "temp_bme680:-1.5 temp_sht40:0.5 hum_sht40:2:1.02"
+        );
+
+        // A zero offset without a scale drops the correction.
+        let change = Change::parse("temp_bme680 0").unwrap();
+        let setting = calibration::change(change).unwrap();
+        assert_eq!(setting.as_str(), "temp_sht40:0.5 hum_sht40:2:1.02");
+
+        calibration::set("");
+        let mut sample = Sample::default();
+        sample.temp_sht40 = Some(20.0);
+        calibration::apply(&mut sample);
+        assert_eq!(sample.temp_sht40, Some(20.0));
+    }
+}
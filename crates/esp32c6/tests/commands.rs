@@ -0,0 +1,143 @@
+//! Parsing of the commands that come over MQTT and MQTT-SN.
+//!
+//! You can run this using `cargo test` as usual.
+
+#![no_std]
+#![no_main]
+
+use panic_rtt_target as _;
+
+esp_bootloader_esp_idf::esp_app_desc!();
+
+#[cfg(test)]
+#[embedded_test::tests(executor = esp_rtos::embassy::Executor::new())]
+mod tests {
+    use defmt::assert;
+    use sensors_node_core::Command;
+    use sensors_node_core::sensors::co2;
+
+    fn parse(payload: &str) -> Option<Command> {
+        Command::try_from(payload.as_bytes()).ok()
+    }
+
+    #[init]
+    fn init() {
+        let peripherals = esp_hal::init(esp_hal::Config::default());
+
+        let timg1 = esp_hal::timer::timg::TimerGroup::new(peripherals.TIMG1);
+        let sw_interrupt =
+            esp_hal::interrupt::software::SoftwareInterruptControl::new(peripherals.SW_INTERRUPT);
+        esp_rtos::start(timg1.timer0, sw_interrupt.software_interrupt0);
+
+        rtt_target::rtt_init_defmt!();
+    }
+
+    #[test]
+    fn parses_the_led_brightness() {
+        assert!(matches!(parse("led 0"), Some(Command::LedBrightness(0))));
+        assert!(matches!(
+            parse("led 100"),
+            Some(Command::LedBrightness(100))
+        ));
+        assert!(parse("led 101").is_none());
+        assert!(parse("led -1").is_none());
+        assert!(parse("led").is_none());
+    }
+
+    #[test]
+    fn parses_identify() {
+        assert!(matches!(parse("identify"), Some(Command::Identify(None))));
+        assert!(matches!(
+            parse("identify 5"),
+            Some(Command::Identify(Some(5)))
+        ));
+        assert!(parse("identify soon").is_none());
+        assert!(parse("identify 300").is_none());
+    }
+
+    #[test]
+    fn parses_update() {
+        assert!(matches!(parse("update"), Some(Command::Update)));
+        assert!(matches!(parse(" update\n"), Some(Command::Update)));
+        assert!(parse("update now").is_none());
+    }
+
+    #[test]
+    fn parses_version() {
+        assert!(matches!(parse("version"), Some(Command::Version)));
+        assert!(parse("version 2").is_none());
+    }
+
+    #[test]
+    fn parses_safemode() {
+        assert!(matches!(parse("safemode"), Some(Command::SafeMode)));
+        assert!(parse("safe mode").is_none());
+    }
+
+    #[test]
+    fn parses_a_raw_payload() {
+        assert!(matches!(parse("0"), Some(Command::RebootToReconfigure)));
+        assert!(matches!(parse("0\r\n"), Some(Command::RebootToReconfigure)));
+        assert!(parse("reboot").is_none());
+        assert!(parse("").is_none());
+        assert!(Command::try_from(&[0xff, 0xfe][..]).is_err());
+    }
+
+    #[test]
+    fn parses_the_relays() {
+        assert!(matches!(
+            parse("relay 1 on"),
+            Some(Command::Relay(1, Some(true)))
+        ));
+        assert!(matches!(
+            parse("relay 2 off"),
+            Some(Command::Relay(2, Some(false)))
+        ));
+        assert!(matches!(
+            parse("relay 1 toggle"),
+            Some(Command::Relay(1, None))
+        ));
+        assert!(matches!(parse("relay 2"), Some(Command::Relay(2, None))));
+        assert!(parse("relay 1 maybe").is_none());
+        assert!(parse("relay one on").is_none());
+    }
+
+    #[test]
+    fn parses_eventlog() {
+        assert!(matches!(parse("eventlog"), Some(Command::EventLog)));
+        assert!(parse("eventlog 10").is_none());
+    }
+
+    #[test]
+    fn parses_the_i2c_access() {
+        assert!(matches!(parse("i2cget 0x76 0xd0"), Some(Command::I2c(_))));
+        assert!(matches!(
+            parse("i2cset 0x76 0xe0 0xb6"),
+            Some(Command::I2c(_))
+        ));
+        assert!(parse("i2cset 0x76 0xe0").is_none());
+    }
+
+    #[test]
+    fn parses_the_calibration() {
+        assert!(matches!(
+            parse("calibrate temp_bme680 -1.5"),
+            Some(Command::Calibrate(_))
+        ));
+        assert!(parse("calibrate nothing 1").is_none());
+    }
+
+    #[test]
+    fn parses_the_co2_calibration() {
+        assert!(matches!(
+            parse("co2calibrate"),
+            Some(Command::Co2Calibrate(co2::DEFAULT_PPM))
+        ));
+        assert!(matches!(
+            parse("co2calibrate 450"),
+            Some(Command::Co2Calibrate(450))
+        ));
+        assert!(parse("co2calibrate 100").is_none());
+        assert!(parse("co2calibrate 5000").is_none());
+    }
+}
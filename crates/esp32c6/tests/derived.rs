@@ -0,0 +1,103 @@
+//! The dew point, absolute humidity and heat index of the samples.
+//!
+//! You can run this using `cargo test` as usual.
+
+#![no_std]
+#![no_main]
+
+use panic_rtt_target as _;
+
+esp_bootloader_esp_idf::esp_app_desc!();
+
+#[cfg(test)]
+#[embedded_test::tests(executor = esp_rtos::embassy::Executor::new())]
+mod tests {
+    use defmt::{assert, assert_eq};
+    use sensors_node_core::sensors::{Sample, derived};
+
+    fn assert_close(value: Option<f32>, expected: f32, tolerance: f32) {
+        let value = value.unwrap();
+        assert!(
+            (value - expected).abs() <= tolerance,
+            "{} is not within {} of {}",
+            value,
+            tolerance,
+            expected
+        );
+    }
+
+    /// A sample with the SHT40 pair, its derived values worked out.
+    fn with_derived(temperature: f32, humidity: f32) -> Sample {
+        let mut sample = Sample::default();
+        sample.temp_sht40 = Some(temperature);
+        sample.hum_sht40 = Some(humidity);
+        derived::apply(&mut sample);
+
+        sample
+    }
+
+    #[init]
+    fn init() {
+        let peripherals = esp_hal::init(esp_hal::Config::default());
+
+        let timg1 = esp_hal::timer::timg::TimerGroup::new(peripherals.TIMG1);
+        let sw_interrupt =
+            esp_hal::interrupt::software::SoftwareInterruptControl::new(peripherals.SW_INTERRUPT);
+        esp_rtos::start(timg1.timer0, sw_interrupt.software_interrupt0);
+
+        rtt_target::rtt_init_defmt!();
+    }
+
+    #[test]
+    fn works_out_the_dew_point() {
+        assert_close(with_derived(20.0, 50.0).dew_point, 9.26, 0.05);
+        assert_close(with_derived(-10.0, 80.0).dew_point, -12.8, 0.05);
+        // Saturated air is at its dew point.
+        assert_close(with_derived(25.0, 100.0).dew_point, 25.0, 0.01);
+    }
+
+    #[test]
+    fn works_out_the_absolute_humidity() {
+        assert_close(with_derived(20.0, 50.0).abs_humidity, 8.62, 0.05);
+        assert_close(with_derived(30.0, 100.0).abs_humidity, 30.26, 0.05);
+    }
+
+    #[test]
+    fn works_out_the_heat_index() {
+        // Steadman's formula, a bit below the temperature in mild air.
+        assert_close(with_derived(20.0, 50.0).heat_index, 19.36, 0.05);
+        // The Rothfusz regression, 106 °F at 90 °F and 70 % in the NWS table.
+        assert_close(with_derived(32.22, 70.0).heat_index, 41.06, 0.05);
+        // With the adjustments for dry and for muggy air.
+        assert_close(with_derived(35.0, 10.0).heat_index, 31.92, 0.05);
+        assert_close(with_derived(29.0, 95.0).heat_index, 38.67, 0.05);
+    }
+
+    #[test]
+    fn takes_the_pair_of_one_sensor() {
+        let mut sample = Sample::default();
+        sample.temp_sht40 = Some(20.0);
+        sample.hum_sht40 = Some(50.0);
+        sample.temp_bme680 = Some(30.0);
+        sample.hum_bme680 = Some(20.0);
+        derived::apply(&mut sample);
+        assert_close(sample.dew_point, 9.26, 0.05);
+        assert_close(sample.abs_humidity, 8.62, 0.05);
+
+        // A temperature without its humidity is no pair.
+        let mut sample = Sample::default();
+        sample.temp_sht40 = Some(20.0);
+        sample.hum_bme280 = Some(50.0);
+        derived::apply(&mut sample);
+        assert_eq!(sample.dew_point, None);
+        assert_eq!(sample.abs_humidity, None);
+        assert_eq!(sample.heat_index, None);
+
+        // A dry 0 % still has a dew point.
+        let mut sample = Sample::default();
+        sample.temp_bme280 = Some(20.0);
+        sample.hum_bme280 = Some(0.0);
+        derived::apply(&mut sample);
+        assert!(sample.dew_point.unwrap().is_finite());
+    }
+}
@@ -0,0 +1,118 @@
+//! Smoothing the readings and dropping their spikes.
+//!
+//! You can run this using `cargo test` as usual.
+
+#![no_std]
+#![no_main]
+
+use panic_rtt_target as _;
+
+esp_bootloader_esp_idf::esp_app_desc!();
+
+#[cfg(test)]
+#[embedded_test::tests(executor = esp_rtos::embassy::Executor::new())]
+mod tests {
+    use defmt::{assert, assert_eq};
+    use sensors_node_core::sensors::Sample;
+    use sensors_node_core::sensors::filter::{self, Filters, MAX_REJECTED, MIN_SAMPLES};
+
+    fn assert_close(value: Option<f32>, expected: f32) {
+        let value = value.unwrap();
+        assert!(
+            (value - expected).abs() <= 1e-4,
+            "{} is not {}",
+            value,
+            expected
+        );
+    }
+
+    /// The temperature the filters make of `value`.
+    fn temperature(filters: &mut Filters, value: f32) -> Option<f32> {
+        let mut sample = Sample::default();
+        sample.temp_sht40 = Some(value);
+        filters.apply(&mut sample);
+
+        sample.temp_sht40
+    }
+
+    #[init]
+    fn init() {
+        let peripherals = esp_hal::init(esp_hal::Config::default());
+
+        let timg1 = esp_hal::timer::timg::TimerGroup::new(peripherals.TIMG1);
+        let sw_interrupt =
+            esp_hal::interrupt::software::SoftwareInterruptControl::new(peripherals.SW_INTERRUPT);
+        esp_rtos::start(timg1.timer0, sw_interrupt.software_interrupt0);
+
+        rtt_target::rtt_init_defmt!();
+    }
+
+    // One test, the settings are global.
+    #[test]
+    fn parses_the_settings() {
+        filter::set("lux:0.3:3, gas:0.2 humidity:0 temperature:1.5 pressure:0.5:-1 nothing:1");
+        let mut filters = Filters::default();
+
+        for value in [10.0, 20.0] {
+            let mut sample = Sample::default();
+            sample.lux_bh1750 = Some(value);
+            sample.gas_bme680 = Some(value as u32);
+            sample.hum_sht40 = Some(value);
+            sample.temp_sht40 = Some(value);
+            sample.press_bmp390 = Some(value);
+            filters.apply(&mut sample);
+
+            if value == 20.0 {
+                assert_close(sample.lux_bh1750, 13.0);
+                assert_eq!(sample.gas_bme680, Some(12));
+                // An alpha of 0, 1.5 or a negative sigma isn't a filter.
+                assert_close(sample.hum_sht40, 20.0);
+                assert_close(sample.temp_sht40, 20.0);
+                assert_close(sample.press_bmp390, 20.0);
+            }
+        }
+
+        filter::set("");
+        let mut sample = Sample::default();
+        sample.lux_bh1750 = Some(30.0);
+        filters.apply(&mut sample);
+        assert_close(sample.lux_bh1750, 30.0);
+    }
+
+    #[test]
+    fn smooths_the_values() {
+        filter::set("temperature:0.5");
+        let mut filters = Filters::default();
+
+        assert_close(temperature(&mut filters, 10.0), 10.0);
+        assert_close(temperature(&mut filters, 20.0), 15.0);
+        assert_close(temperature(&mut filters, 20.0), 17.5);
+        // Without a sigma nothing is a spike.
+        assert_close(temperature(&mut filters, 1000.0), 508.75);
+    }
+
+    #[test]
+    fn drops_the_spikes_until_they_stay() {
+        filter::set("temperature:1:3");
+        let mut filters = Filters::default();
+
+        // Too few samples for a spread, nothing is a spike yet.
+        for _ in 0..MIN_SAMPLES - 1 {
+            assert_close(temperature(&mut filters, 10.0), 10.0);
+        }
+        assert_close(temperature(&mut filters, 10.05), 10.05);
+        assert_close(temperature(&mut filters, 10.0), 10.0);
+
+        for _ in 0..MAX_REJECTED {
+            assert_eq!(temperature(&mut filters, 20.0), None);
+        }
+        // Within the spread again, the spikes in a row start over.
+        assert_close(temperature(&mut filters, 10.0), 10.0);
+        for _ in 0..MAX_REJECTED {
+            assert_eq!(temperature(&mut filters, 20.0), None);
+        }
+        // Stayed off, the new level.
+        assert_close(temperature(&mut filters, 20.0), 20.0);
+        assert_close(temperature(&mut filters, 20.0), 20.0);
+    }
+}
@@ -5,6 +5,8 @@
 #![no_std]
 #![no_main]
 
+use panic_rtt_target as _;
+
 esp_bootloader_esp_idf::esp_app_desc!();
 
 #[cfg(test)]
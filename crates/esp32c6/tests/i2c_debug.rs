@@ -0,0 +1,62 @@
+//! Parsing the raw I2C register reads and writes.
+//!
+//! You can run this using `cargo test` as usual.
+
+#![no_std]
+#![no_main]
+
+use panic_rtt_target as _;
+
+esp_bootloader_esp_idf::esp_app_desc!();
+
+#[cfg(test)]
+#[embedded_test::tests(executor = esp_rtos::embassy::Executor::new())]
+mod tests {
+    use defmt::{assert, assert_eq};
+    use sensors_node_core::i2c_debug::Request;
+
+    fn fields(request: Request) -> (u8, u8, u8, Option<u8>) {
+        (
+            request.address,
+            request.register,
+            request.len,
+            request.value,
+        )
+    }
+
+    #[init]
+    fn init() {
+        let peripherals = esp_hal::init(esp_hal::Config::default());
+
+        let timg1 = esp_hal::timer::timg::TimerGroup::new(peripherals.TIMG1);
+        let sw_interrupt =
+            esp_hal::interrupt::software::SoftwareInterruptControl::new(peripherals.SW_INTERRUPT);
+        esp_rtos::start(timg1.timer0, sw_interrupt.software_interrupt0);
+
+        rtt_target::rtt_init_defmt!();
+    }
+
+    #[test]
+    fn parses_a_read() {
+        let request = Request::parse_get("0x76 0xd0").unwrap();
+        assert_eq!(fields(request), (0x76, 0xd0, 1, None));
+        let request = Request::parse_get(" 118  208 6 ").unwrap();
+        assert_eq!(fields(request), (0x76, 0xd0, 6, None));
+
+        assert!(Request::parse_get("0x76").is_none());
+        assert!(Request::parse_get("0x76 0xd0 6 1").is_none());
+        assert!(Request::parse_get("0x76 0xd0 six").is_none());
+        assert!(Request::parse_get("0x176 0xd0").is_none());
+        assert!(Request::parse_get("0xzz 0xd0").is_none());
+    }
+
+    #[test]
+    fn parses_a_write() {
+        let request = Request::parse_set("0x76 0xe0 0xb6").unwrap();
+        assert_eq!(fields(request), (0x76, 0xe0, 0, Some(0xb6)));
+
+        assert!(Request::parse_set("0x76 0xe0").is_none());
+        assert!(Request::parse_set("0x76 0xe0 0xb6 0").is_none());
+        assert!(Request::parse_set("0x76 0xe0 256").is_none());
+    }
+}
@@ -0,0 +1,194 @@
+//! The MQTT payload: the selected fields, the place and the buffer it has to
+//! fit.
+//!
+//! You can run this using `cargo test` as usual.
+
+#![no_std]
+#![no_main]
+
+use panic_rtt_target as _;
+
+esp_bootloader_esp_idf::esp_app_desc!();
+
+#[cfg(test)]
+#[embedded_test::tests(executor = esp_rtos::embassy::Executor::new())]
+mod tests {
+    use defmt::{assert, assert_eq};
+    use sensors_node_core::config::PayloadFormat;
+    use sensors_node_core::payload::{self, Error, MAX_LEN};
+    use sensors_node_core::sensors::Sample;
+
+    /// The fields a payload can carry, in the order of the settings.
+    const FIELDS: [&str; 33] = [
+        "temp_bme680",
+        "press_bme680",
+        "hum_bme680",
+        "gas_bme680",
+        "lux_bh1750",
+        "lux_veml7700",
+        "temp_bmp390",
+        "press_bmp390",
+        "hum_sht40",
+        "temp_sht40",
+        "chip_temp",
+        "supply_mv",
+        "noise_dba",
+        "voc_index",
+        "pm1_0",
+        "pm2_5",
+        "pm10",
+        "co2_mhz19",
+        "adc0",
+        "adc1",
+        "battery_mv",
+        "battery_ma",
+        "battery_percent",
+        "lux_tsl2591",
+        "temp_bme280",
+        "press_bme280",
+        "hum_bme280",
+        "co2_scd30",
+        "wind_kmh",
+        "rain_mm",
+        "dew_point",
+        "abs_humidity",
+        "heat_index",
+    ];
+
+    /// A sample with every reading set to its longest value.
+    fn full_sample() -> Sample {
+        let mut sample = Sample::default();
+        sample.timestamp = u32::MAX;
+        sample.gas_bme680 = Some(u32::MAX);
+        sample.warming_up = Some(false);
+        for reading in [
+            &mut sample.temp_bme680,
+            &mut sample.press_bme680,
+            &mut sample.hum_bme680,
+            &mut sample.hum_sht40,
+            &mut sample.temp_sht40,
+            &mut sample.press_bmp390,
+            &mut sample.temp_bmp390,
+            &mut sample.lux_veml7700,
+            &mut sample.lux_bh1750,
+            &mut sample.chip_temp,
+            &mut sample.noise_dba,
+            &mut sample.adc0,
+            &mut sample.adc1,
+            &mut sample.battery_ma,
+            &mut sample.battery_percent,
+            &mut sample.lux_tsl2591,
+            &mut sample.temp_bme280,
+            &mut sample.press_bme280,
+            &mut sample.hum_bme280,
+            &mut sample.wind_kmh,
+            &mut sample.rain_mm,
+            &mut sample.dew_point,
+            &mut sample.abs_humidity,
+            &mut sample.heat_index,
+        ] {
+            *reading = Some(-1.175_494_4e-38);
+        }
+        for count in [
+            &mut sample.supply_mv,
+            &mut sample.voc_index,
+            &mut sample.pm1_0,
+            &mut sample.pm2_5,
+            &mut sample.pm10,
+            &mut sample.co2_mhz19,
+            &mut sample.battery_mv,
+            &mut sample.co2_scd30,
+        ] {
+            *count = Some(u16::MAX);
+        }
+
+        sample
+    }
+
+    fn json<'b>(sample: &Sample, buf: &'b mut [u8; MAX_LEN]) -> &'b str {
+        let payload = payload::build(sample, PayloadFormat::Json, "node", 0, buf).unwrap();
+        core::str::from_utf8(payload).unwrap()
+    }
+
+    /// Whether `payload` has `key` as a key, not only within a longer one.
+    fn has_key(payload: &str, key: &str) -> bool {
+        payload.match_indices(key).any(|(i, _)| {
+            payload[..i].ends_with('"') && payload[i + key.len()..].starts_with("\":")
+        })
+    }
+
+    #[init]
+    fn init() {
+        let peripherals = esp_hal::init(esp_hal::Config::default());
+
+        let timg1 = esp_hal::timer::timg::TimerGroup::new(peripherals.TIMG1);
+        let sw_interrupt =
+            esp_hal::interrupt::software::SoftwareInterruptControl::new(peripherals.SW_INTERRUPT);
+        esp_rtos::start(timg1.timer0, sw_interrupt.software_interrupt0);
+
+        rtt_target::rtt_init_defmt!();
+    }
+
+    // One test, the selection and the place are global.
+    #[test]
+    fn publishes_the_selected_fields() {
+        let mut buf = [0u8; MAX_LEN];
+
+        payload::select_fields("");
+        let payload = json(&full_sample(), &mut buf);
+        for name in FIELDS {
+            assert!(has_key(payload, name), "{} is missing", name);
+        }
+
+        payload::select_fields("temp_sht40, hum_sht40 gas_bme680");
+        let payload = json(&full_sample(), &mut buf);
+        for name in FIELDS {
+            let selected = ["temp_sht40", "hum_sht40", "gas_bme680"].contains(&name);
+            assert_eq!(has_key(payload, name), selected, "{}", name);
+        }
+
+        payload::select_fields("");
+    }
+
+    #[test]
+    fn fits_the_longest_place_into_the_buffer() {
+        let mut buf = [0u8; MAX_LEN];
+        payload::set_place(
+            "llllllllllllllllllllllllllllllll",
+            "rrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrr",
+            "a,b,c,d,e,f,g,h",
+        );
+
+        assert!(payload::build(&full_sample(), PayloadFormat::Json, "node", 0, &mut buf).is_ok());
+
+        payload::set_place("", "", "");
+    }
+
+    #[test]
+    fn leaves_the_control_characters_out_of_the_place() {
+        let mut buf = [0u8; MAX_LEN];
+        payload::set_place("a\tb\nc", " kitchen ", "");
+
+        let payload = json(&Sample::default(), &mut buf);
+        assert!(payload.contains("\"location\":\"abc\""));
+        assert!(payload.contains("\"room\":\"kitchen\""));
+        assert!(!has_key(payload, "labels"));
+
+        payload::set_place("", "", "");
+    }
+
+    #[test]
+    fn fits_senml_into_the_buffer() {
+        let mut buf = [0u8; MAX_LEN];
+        assert!(payload::build(&full_sample(), PayloadFormat::Senml, "node", 0, &mut buf).is_ok());
+    }
+
+    #[test]
+    fn reports_an_overflow() {
+        let mut buf = [0u8; 16];
+        assert!(matches!(
+            payload::build(&full_sample(), PayloadFormat::Json, "node", 0, &mut buf),
+            Err(Error::Overflow)
+        ));
+    }
+}
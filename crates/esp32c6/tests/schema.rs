@@ -0,0 +1,144 @@
+//! Reading the postcard sample records back, of every version.
+//!
+//! You can run this using `cargo test` as usual.
+
+#![no_std]
+#![no_main]
+
+use panic_rtt_target as _;
+
+esp_bootloader_esp_idf::esp_app_desc!();
+
+#[cfg(test)]
+#[embedded_test::tests(executor = esp_rtos::embassy::Executor::new())]
+mod tests {
+    use defmt::{assert, assert_eq};
+    use sensors_node_core::sensors::Sample;
+    use sensors_node_core::sensors::schema::{self, MAX_RECORD, SampleVersion};
+
+    /// The record `sample` would have been as `version`, which wrote none of
+    /// the fields added since. The version leads the record, in one byte.
+    fn record(sample: &Sample, version: SampleVersion, buf: &mut [u8; MAX_RECORD]) -> usize {
+        let len = postcard::to_slice(sample, buf).unwrap().len();
+        buf[0] = version as u8;
+
+        len - (SampleVersion::CURRENT.fields() - version.fields())
+    }
+
+    /// A sample with every field set.
+    fn full_sample() -> Sample {
+        let mut sample = Sample::default();
+        sample.timestamp = u32::MAX;
+        sample.gas_bme680 = Some(u32::MAX);
+        sample.warming_up = Some(true);
+        for reading in [
+            &mut sample.temp_bme680,
+            &mut sample.press_bme680,
+            &mut sample.hum_bme680,
+            &mut sample.hum_sht40,
+            &mut sample.temp_sht40,
+            &mut sample.press_bmp390,
+            &mut sample.temp_bmp390,
+            &mut sample.lux_veml7700,
+            &mut sample.lux_bh1750,
+            &mut sample.chip_temp,
+            &mut sample.noise_dba,
+            &mut sample.adc0,
+            &mut sample.adc1,
+            &mut sample.battery_ma,
+            &mut sample.battery_percent,
+            &mut sample.lux_tsl2591,
+            &mut sample.temp_bme280,
+            &mut sample.press_bme280,
+            &mut sample.hum_bme280,
+            &mut sample.wind_kmh,
+            &mut sample.rain_mm,
+            &mut sample.dew_point,
+            &mut sample.abs_humidity,
+            &mut sample.heat_index,
+        ] {
+            *reading = Some(1.0);
+        }
+        for count in [
+            &mut sample.supply_mv,
+            &mut sample.voc_index,
+            &mut sample.pm1_0,
+            &mut sample.pm2_5,
+            &mut sample.pm10,
+            &mut sample.co2_mhz19,
+            &mut sample.battery_mv,
+            &mut sample.co2_scd30,
+        ] {
+            *count = Some(u16::MAX);
+        }
+
+        sample
+    }
+
+    #[init]
+    fn init() {
+        let peripherals = esp_hal::init(esp_hal::Config::default());
+
+        let timg1 = esp_hal::timer::timg::TimerGroup::new(peripherals.TIMG1);
+        let sw_interrupt =
+            esp_hal::interrupt::software::SoftwareInterruptControl::new(peripherals.SW_INTERRUPT);
+        esp_rtos::start(timg1.timer0, sw_interrupt.software_interrupt0);
+
+        rtt_target::rtt_init_defmt!();
+    }
+
+    #[test]
+    fn reads_a_current_record() {
+        let mut sample = Sample::default();
+        sample.timestamp = 1_700_000_000;
+        sample.temp_sht40 = Some(21.5);
+        sample.heat_index = Some(20.9);
+        let mut buf = [0u8; MAX_RECORD];
+        let len = record(&sample, SampleVersion::CURRENT, &mut buf);
+
+        let decoded = schema::decode(&buf[..len]).unwrap();
+        assert!(decoded.version() == SampleVersion::CURRENT);
+        assert_eq!(decoded.timestamp, 1_700_000_000);
+        assert_eq!(decoded.temp_sht40, Some(21.5));
+        assert_eq!(decoded.heat_index, Some(20.9));
+    }
+
+    #[test]
+    fn pads_the_fields_an_older_record_lacks() {
+        let mut sample = Sample::default();
+        sample.timestamp = 42;
+        sample.temp_bme680 = Some(19.0);
+        sample.lux_bh1750 = Some(300.0);
+        let mut buf = [0u8; MAX_RECORD];
+        let len = record(&sample, SampleVersion::V1, &mut buf);
+
+        let decoded = schema::decode(&buf[..len]).unwrap();
+        assert!(decoded.version() == SampleVersion::V1);
+        assert_eq!(decoded.timestamp, 42);
+        assert_eq!(decoded.temp_bme680, Some(19.0));
+        assert_eq!(decoded.lux_bh1750, Some(300.0));
+        assert_eq!(decoded.gas_bme680, None);
+        assert_eq!(decoded.heat_index, None);
+
+        let len = record(&sample, SampleVersion::V12, &mut buf);
+        let decoded = schema::decode(&buf[..len]).unwrap();
+        assert!(decoded.version() == SampleVersion::V12);
+        assert_eq!(decoded.temp_bme680, Some(19.0));
+        assert_eq!(decoded.dew_point, None);
+    }
+
+    #[test]
+    fn rejects_what_it_cannot_read() {
+        assert!(schema::decode(&[]).is_none());
+        // A version after the current one.
+        assert!(schema::decode(&[SampleVersion::CURRENT as u8 + 1]).is_none());
+        // Cut short within the fields of its version.
+        assert!(schema::decode(&[SampleVersion::V1 as u8, 42]).is_none());
+    }
+
+    #[test]
+    fn fits_a_full_record() {
+        let mut buf = [0u8; MAX_RECORD];
+        assert!(postcard::to_slice(&full_sample(), &mut buf).is_ok());
+    }
+}
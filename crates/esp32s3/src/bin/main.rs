@@ -1,6 +1,5 @@
 #![no_std]
 #![no_main]
-#![feature(addr_parse_ascii)]
 #![deny(
     clippy::mem_forget,
     reason = "mem::forget is generally not safe to do with esp_hal types, especially those \
@@ -8,35 +7,19 @@
 )]
 #![deny(clippy::large_stack_frames)]
 
-use core::cell::RefCell;
-use core::net::Ipv4Addr;
-
-use defmt::{info, warn};
+use defmt::{Debug2Format, error, info, warn};
 use embassy_executor::Spawner;
-use embassy_net::{Runner, StackResources};
-use embassy_sync::blocking_mutex::raw::NoopRawMutex;
-use embassy_time::Timer;
 use esp_hal::clock::CpuClock;
-use esp_hal::i2c;
-use esp_hal::peripherals::Peripherals;
-use esp_hal::rmt::Rmt;
-use esp_hal::time::Rate;
+use esp_hal::peripherals::GPIO4;
 use esp_hal::timer::timg::TimerGroup;
-use esp_hal_smartled::{SmartLedsAdapter, smart_led_buffer};
-use esp_radio::wifi::AccessPointConfig;
-use esp_radio::{
-    ble::controller::BleConnector,
-    wifi::{self, WifiController, WifiDevice},
-};
+use esp_hal::tsens::{self, TemperatureSensor};
+use esp_radio::ble::controller::BleConnector;
 use esp_rtos::main;
-use sensors_node_core::config::{self, SettingsEnum};
-use sensors_node_core::wifi::print_wifi_error;
+use sensors_node_core::config::get_initial_settings;
 use sensors_node_core::{
-    ble,
-    config::{Settings, get_initial_settings},
-    kv_storage, led, net_time, system, web,
+    adc, app, ble, board, diagnostics, display, factory_reset, inputs, kv_storage, noise, ota,
+    power, self_test, sensors, system, watchdog,
 };
-use sensors_node_core::{dhcp, sensors};
 use static_cell::StaticCell;
 use {esp_backtrace as _, esp_println as _};
 
@@ -47,14 +30,8 @@ extern crate alloc;
 esp_bootloader_esp_idf::esp_app_desc!();
 
 static RADIO: StaticCell<esp_radio::Controller<'static>> = StaticCell::new();
-static RESOURCES: StaticCell<StackResources<16>> = StaticCell::new();
 static FLASH_KV_START: usize = 0x400_000;
 
-#[embassy_executor::task]
-async fn net_task(mut runner: Runner<'static, wifi::WifiDevice<'static>>) -> ! {
-    runner.run().await;
-}
-
 #[allow(
     clippy::large_stack_frames,
     reason = "it's not unusual to allocate larger buffers etc. in main"
@@ -62,34 +39,52 @@ async fn net_task(mut runner: Runner<'static, wifi::WifiDevice<'static>>) -> ! {
 #[main]
 async fn main(spawner: Spawner) -> ! {
     // generator version: 1.2.0
+    diagnostics::paint_stack();
+
     info!("Starting up");
 
-    let config = esp_hal::Config::default().with_cpu_clock(CpuClock::_80MHz);
+    let config = esp_hal::Config::default().with_cpu_clock(power::cpu_clock(CpuClock::_80MHz));
     let peripherals = esp_hal::init(config);
 
+    ota::check_boot();
+    let crash_loop = system::count_boot();
+    let safe_mode = system::safe_mode(crash_loop);
+
+    diagnostics::use_psram(esp_hal::psram::psram_raw_parts(&peripherals.PSRAM));
     esp_alloc::heap_allocator!(#[esp_hal::ram(reclaimed)] size: 73744);
     // COEX needs more RAM - so we've added some more
     esp_alloc::heap_allocator!(size: 72 * 1024);
+    self_test::check_chip();
 
     let timg0 = TimerGroup::new(peripherals.TIMG0);
     esp_rtos::start(timg0.timer0);
 
     info!("Embassy initialized!");
 
-    system::set_state(system::State::Booting);
+    spawner.must_spawn(watchdog::task(TimerGroup::new(peripherals.TIMG1).wdt));
+    spawner.must_spawn(diagnostics::task());
+    spawner.must_spawn(system::mark_stable());
 
-    info!("Setting up I2C");
-    let i2c = i2c::master::I2c::new(peripherals.I2C0, i2c::master::Config::default())
-        .unwrap()
-        .with_sda(peripherals.GPIO2)
-        .with_scl(peripherals.GPIO1)
-        .into_async();
+    system::set_state(system::State::Booting);
 
-    let i2c: &'static RefCell<sensors::I2C> = {
-        static I2C_STATIC: StaticCell<RefCell<sensors::I2C>> = StaticCell::new();
-        I2C_STATIC.init(RefCell::new(i2c))
+    let kv_db = match kv_storage::init(peripherals.FLASH, FLASH_KV_START).await {
+        Ok(db) => db,
+        Err(err) => {
+            error!("Couldn't initialize storage. Error: {:?}", err);
+            let i2c = board::i2c(peripherals.I2C0, &board::DEFAULT_PINS);
+            app::storage_fault(spawner, i2c).await
+        }
     };
 
+    let pins = board::load(kv_db).await;
+    if let Some(pin) = pins.external_watchdog() {
+        watchdog::use_external(pin);
+    }
+
+    info!("Setting up I2C");
+    let i2c = board::i2c(peripherals.I2C0, &pins);
+    let i2c1 = board::i2c1(peripherals.I2C1, &pins);
+
     let radio_init =
         RADIO.init(esp_radio::init().expect("Failed to initialize Wi-Fi/BLE controller"));
 
@@ -102,200 +97,61 @@ async fn main(spawner: Spawner) -> ! {
     let transport = BleConnector::new(radio_init, peripherals.BT, Default::default()).unwrap();
     let ble_controller = trouble_host::prelude::ExternalController::<_, 20>::new(transport);
 
-    spawner.must_spawn(ble::task(ble_controller));
-
-    let kv_db = match kv_storage::init(peripherals.FLASH, FLASH_KV_START).await {
-        Ok(db) => db,
-        Err(err) => panic!(
-            "Couldn't initialize storage. It won't be available. Error: {:?}",
-            err
-        ),
-    };
-
-    match get_initial_settings(kv_db).await {
-        Ok(settings) => match settings {
-            SettingsEnum::Optional(settings) => {
-                init_start(
-                    spawner,
-                    wifi_controller,
-                    interfaces.ap,
-                    kv_db,
-                    SettingsEnum::Optional(settings),
-                )
-                .await
-            }
-            SettingsEnum::FilledIn(settings) => {
-                info!("###    WiFi SSID:        {}", settings.wifi_ssid);
-                info!("###    MQTT broker:      {}", settings.mqtt_broker);
-                info!("###    MQTT client id:   {}", settings.mqtt_client_id);
-                info!("###    MQTT topic:       {}", settings.mqtt_topic);
-                info!(
-                    "###    Reconfigure:      {:?}",
-                    settings.reboot_to_reconfigure
-                );
-
-                if settings.reboot_to_reconfigure {
-                    init_start(
-                        spawner,
-                        wifi_controller,
-                        interfaces.ap,
-                        kv_db,
-                        SettingsEnum::FilledIn(settings),
-                    )
-                    .await
-                } else {
-                    run(
-                        spawner,
-                        kv_db,
-                        wifi_controller,
-                        interfaces.sta,
-                        &i2c,
-                        settings,
-                    )
-                    .await
-                }
-            }
-        },
-
-        Err(err) => panic!("Could not get initial settings: {:?}", err),
+    if safe_mode.is_none() {
+        spawner.must_spawn(ble::task(ble_controller));
     }
-}
 
-async fn run(
-    spawner: Spawner,
-    db: &'static kv_storage::Db,
-    wifi_controller: WifiController<'static>,
-    device: WifiDevice<'static>,
-    i2c: &'static RefCell<sensors::I2C<'static>>,
-    settings: Settings,
-) -> ! {
-    let settings = {
-        static SETTINGS_STATIC: StaticCell<Settings> = StaticCell::new();
-        SETTINGS_STATIC.init(settings)
-    };
+    // Panics go to esp-backtrace here, so there is only the reset reason.
+    system::record_boot();
 
-    spawner.must_spawn(sensors_node_core::wifi::task(
-        wifi_controller,
-        settings.wifi_ssid.as_str(),
-        settings.wifi_password.as_str(),
+    spawner.must_spawn(factory_reset::task(kv_db));
+    spawner.must_spawn(inputs::task(
+        inputs::BUTTON,
+        pins.button,
+        inputs::Config::BUTTON,
     ));
 
-    let net_config = embassy_net::Config::dhcpv4(Default::default());
-
-    let (stack, runner) = embassy_net::new(
-        device,
-        net_config,
-        RESOURCES.init(StackResources::new()),
-        embassy_time::Instant::now().as_millis(),
-    );
-
-    spawner.must_spawn(net_task(runner));
-
-    system::set_state(system::State::WifiConnecting);
-    info!("Waiting for link...");
-    stack.wait_link_up().await;
-    info!("  Link is up!");
-
-    info!("Waiting for DHCP...");
-    stack.wait_config_up().await;
-    info!("  IPv4 config: {:?}", stack.config_v4());
-
-    spawner.must_spawn(net_time::sync_task(stack));
+    let chip_sensor = TemperatureSensor::new(peripherals.TSENS, tsens::Config::default())
+        .inspect_err(|err| warn!("Chip temperature sensor: {:?}", Debug2Format(err)))
+        .ok();
+
+    // Battery through a divider, only read when a low voltage level is set.
+    let supply: &'static mut dyn power::SupplyVoltage = {
+        static SUPPLY: StaticCell<power::AdcSupply<GPIO4<'static>>> = StaticCell::new();
+        let mut adc = adc::Config::new();
+        let supply = adc.channel(peripherals.GPIO4, power::AdcSupply::<GPIO4>::ATTENUATION);
+        sensors::analog::setup(&mut adc, &pins);
+        adc::start(peripherals.ADC1, adc);
+        SUPPLY.init(power::AdcSupply::new(supply))
+    };
 
-    let broker_address = match Ipv4Addr::parse_ascii(settings.mqtt_broker.as_bytes()) {
+    let settings = match get_initial_settings(kv_db).await {
+        Ok(settings) => settings,
         Err(err) => {
-            warn!("Error parsing broker IP: {}", err);
-            config::set_reboot(db).await.unwrap();
-            unreachable!();
+            error!("Could not get initial settings: {:?}", err);
+            app::storage_fault(spawner, i2c).await
         }
-        Ok(address) => address,
     };
 
-    spawner.must_spawn(sensors_node_core::mqtt::task(
-        db,
-        stack,
-        broker_address,
-        settings.mqtt_client_id.as_str(),
-        settings.mqtt_topic.as_str(),
-    ));
-
-    spawner.must_spawn(sensors_node_core::sensors::task(i2c));
+    spawner.must_spawn(display::task(i2c, display::Config::from(&settings)));
 
-    system::set_state(system::State::Ok);
-    loop {
-        let forever = embassy_sync::signal::Signal::<NoopRawMutex, ()>::new();
-        forever.wait().await;
+    if pins.microphone() && safe_mode.is_none() {
+        spawner.must_spawn(noise::task(peripherals.I2S0, peripherals.DMA_CH0, pins));
     }
-}
-
-async fn init_start(
-    spawner: Spawner,
-    mut wifi_controller: WifiController<'static>,
-    device: WifiDevice<'static>,
-    kv_db: &'static kv_storage::Db,
-    settings: SettingsEnum,
-) -> ! {
-    let net_config = embassy_net::Config::ipv4_static(embassy_net::StaticConfigV4 {
-        address: embassy_net::Ipv4Cidr::new(Ipv4Addr::new(192, 168, 1, 1), 24),
-        dns_servers: heapless_08::Vec::new(),
-        gateway: None,
-    });
-
-    let ap_config = AccessPointConfig::default().with_ssid("esp32-setup".into());
-
-    let _ = wifi_controller.set_config(&wifi::ModeConfig::AccessPoint(ap_config));
 
-    let (stack, runner) = embassy_net::new(
-        device,
-        net_config,
-        RESOURCES.init(StackResources::new()),
-        embassy_time::Instant::now().as_millis(),
-    );
-
-    spawner.must_spawn(net_task(runner));
-
-    loop {
-        info!("Starting WIFI");
-        if let Err(err) = wifi_controller.start_async().await {
-            print_wifi_error(err);
-            Timer::after_secs(5).await;
-        } else {
-            break;
-        }
-    }
-
-    spawner.must_spawn(dhcp_task(stack));
-
-    info!("Waiting for link...");
-    stack.wait_link_up().await;
-    info!("  Link is up!");
-
-    info!("Waiting for DHCP...");
-    stack.wait_config_up().await;
-    info!("  IPv4 config: {:?}", stack.config_v4());
-
-    spawner.must_spawn(system::reboot_on_request());
-
-    info!("Starting up web-server");
-    let web_app = {
-        static WEB_APP_STATIC: StaticCell<web::WebApp> = StaticCell::new();
-        WEB_APP_STATIC.init(web::WebApp::new(kv_db, settings))
+    let node = app::Node {
+        spawner,
+        db: kv_db,
+        wifi_controller,
+        interfaces,
+        i2c,
+        i2c1,
+        chip_sensor,
+        supply,
+        spi2: peripherals.SPI2,
+        uart1: peripherals.UART1,
+        firmware_version: env!("CARGO_PKG_VERSION"),
     };
 
-    for task_id in 0..web::WEB_TASK_POOL_SIZE {
-        spawner.must_spawn(web::task(task_id, stack, web_app.router, web_app.config));
-    }
-
-    loop {
-        let forever = embassy_sync::signal::Signal::<NoopRawMutex, ()>::new();
-        forever.wait().await;
-    }
-}
-
-#[embassy_executor::task]
-async fn dhcp_task(stack: embassy_net::Stack<'static>) -> ! {
-    let buffers = edge_nal_embassy::UdpBuffers::<2, 1024, 1024, 8>::new();
-    let unbound_socket = edge_nal_embassy::Udp::new(stack, &buffers);
-
-    dhcp::run(unbound_socket).await
+    app::start(node, settings, safe_mode).await
 }